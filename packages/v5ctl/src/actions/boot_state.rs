@@ -0,0 +1,28 @@
+use anyhow::bail;
+use log::info;
+use tokio::{io::BufReader, net::UnixStream};
+use v5d_interface::{get_response, send_command_to, BootState, DaemonCommand, DaemonResponse};
+
+pub async fn boot_state(socket: &mut BufReader<UnixStream>, route: u8) -> anyhow::Result<()> {
+    send_command_to(socket, route, DaemonCommand::BootState).await?;
+    let state = match get_response(socket).await? {
+        DaemonResponse::BootState(Some(state)) => state,
+        DaemonResponse::BootState(None) => bail!("The brain didn't report its boot state"),
+        DaemonResponse::BasicAck { successful: false } => {
+            bail!("Failed to read the brain's status")
+        }
+        response => bail!("Unexpected response from daemon: {:?}", response),
+    };
+
+    match state {
+        BootState::Normal => info!("The brain booted normally"),
+        BootState::RamBootloader => info!("The brain booted into the RAM bootloader"),
+        BootState::RomBootloader => info!("The brain booted into the ROM bootloader"),
+        BootState::Unknown(flags) => info!(
+            "The brain booted into a mode v5ctl doesn't recognize (status flags {:#06x})",
+            flags
+        ),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,294 @@
+//! Finding the program a cargo project builds, for `upload --from-cargo`.
+
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{bail, Context};
+use clap::ValueEnum;
+use log::debug;
+use serde_json::Value;
+use v5d_interface::layout::ProgramRegion;
+
+use super::upload::ProgramIcon;
+
+/// The target vexide projects build for.
+const V5_TARGET: &str = "armv7a-vex-v5";
+
+/// A binary built by cargo, with the upload defaults its package sets.
+#[derive(Debug)]
+pub struct CargoArtifact {
+    pub path: PathBuf,
+    /// The package's name.
+    pub name: String,
+    pub description: Option<String>,
+    /// From `[package.metadata.v5]`.
+    pub icon: Option<ProgramIcon>,
+}
+
+/// Reads `cargo metadata` output from `path`, or runs cargo to get it.
+pub fn load_metadata(path: Option<&Path>) -> anyhow::Result<Value> {
+    let json = match path {
+        Some(path) => std::fs::read(path)
+            .with_context(|| format!("Failed to read cargo metadata from {}", path.display()))?,
+        None => {
+            let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+            let output = Command::new(cargo)
+                .args(["metadata", "--format-version", "1", "--no-deps"])
+                .output()
+                .context("Failed to run cargo metadata")?;
+            if !output.status.success() {
+                bail!(
+                    "cargo metadata failed: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            output.stdout
+        }
+    };
+    serde_json::from_slice(&json).context("cargo metadata output isn't valid JSON")
+}
+
+/// Finds the binary target to upload among the workspace's members, and where it was built.
+pub fn find_artifact(
+    metadata: &Value,
+    bin: Option<&str>,
+    release: bool,
+) -> anyhow::Result<CargoArtifact> {
+    let members = metadata["workspace_members"]
+        .as_array()
+        .context("cargo metadata has no workspace members")?;
+    let packages = metadata["packages"]
+        .as_array()
+        .context("cargo metadata has no packages")?
+        .iter()
+        .filter(|package| members.contains(&package["id"]));
+
+    let mut targets = Vec::new();
+    for package in packages {
+        for target in package["targets"].as_array().into_iter().flatten() {
+            let is_bin = target["kind"]
+                .as_array()
+                .is_some_and(|kinds| kinds.iter().any(|kind| kind == "bin"));
+            if let (true, Some(name)) = (is_bin, target["name"].as_str()) {
+                targets.push((package, name));
+            }
+        }
+    }
+
+    let (package, name) = match bin {
+        Some(bin) => targets
+            .into_iter()
+            .find(|(_, name)| *name == bin)
+            .with_context(|| format!("The workspace has no binary target named {bin:?}"))?,
+        None => match targets.as_slice() {
+            [] => bail!("The workspace has no binary targets"),
+            [target] => *target,
+            targets => {
+                let names = targets.iter().map(|(_, name)| *name).collect::<Vec<_>>();
+                bail!(
+                    "The workspace has several binary targets ({}). Pick one with --bin",
+                    names.join(", ")
+                );
+            }
+        },
+    };
+
+    let target_dir = PathBuf::from(
+        metadata["target_directory"]
+            .as_str()
+            .context("cargo metadata has no target directory")?,
+    );
+    let profile = if release { "release" } else { "debug" };
+    let expected = target_dir.join(V5_TARGET).join(profile).join(name);
+    let path = [expected.clone(), target_dir.join(profile).join(name)]
+        .into_iter()
+        .find(|path| path.is_file())
+        .with_context(|| {
+            format!(
+                "{name} hasn't been built. Run `cargo build{}` first; expected path: {}",
+                if release { " --release" } else { "" },
+                expected.display()
+            )
+        })?;
+    debug!("Found {} at {}", name, path.display());
+
+    let icon = match package["metadata"]["v5"]["icon"].as_str() {
+        Some(icon) => Some(
+            ProgramIcon::from_str(icon, true)
+                .map_err(|_| anyhow::anyhow!("Unknown icon {icon:?} in [package.metadata.v5]"))?,
+        ),
+        None => None,
+    };
+    Ok(CargoArtifact {
+        path,
+        name: package["name"].as_str().unwrap_or(name).to_string(),
+        description: package["description"].as_str().map(ToString::to_string),
+        icon,
+    })
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Turns a 32-bit little-endian ELF into the flat binary the brain loads, like
+/// `objcopy -O binary`. Anything that isn't an ELF is assumed to be a binary already.
+pub fn elf_to_bin(elf: &[u8]) -> anyhow::Result<Vec<u8>> {
+    const PT_LOAD: u32 = 1;
+    if !elf.starts_with(b"\x7fELF") {
+        return Ok(elf.to_vec());
+    }
+    if elf.get(4..6) != Some(&[1, 1]) {
+        bail!("Only 32-bit little-endian ELF files can be uploaded");
+    }
+
+    let malformed = || anyhow::anyhow!("The ELF file is malformed");
+    let header_offset = read_u32(elf, 0x1c).ok_or_else(malformed)? as usize;
+    let header_size = read_u16(elf, 0x2a).ok_or_else(malformed)? as usize;
+    let header_count = read_u16(elf, 0x2c).ok_or_else(malformed)? as usize;
+
+    // (load address, file contents) of each segment with data
+    let mut segments = Vec::new();
+    for i in 0..header_count {
+        let header = header_offset + i * header_size;
+        if read_u32(elf, header).ok_or_else(malformed)? != PT_LOAD {
+            continue;
+        }
+        let offset = read_u32(elf, header + 4).ok_or_else(malformed)? as usize;
+        let address = read_u32(elf, header + 12).ok_or_else(malformed)?;
+        let size = read_u32(elf, header + 16).ok_or_else(malformed)? as usize;
+        if size > 0 {
+            let data = elf.get(offset..offset + size).ok_or_else(malformed)?;
+            segments.push((address, data));
+        }
+    }
+
+    let start = segments
+        .iter()
+        .map(|(address, _)| *address)
+        .min()
+        .context("The ELF file has nothing to load")?;
+    if start != ProgramRegion::MONOLITH.load_address {
+        bail!(
+            "The program is linked to load at {:#010x}, but the brain loads it at {:#010x}",
+            start,
+            ProgramRegion::MONOLITH.load_address
+        );
+    }
+    let end = segments
+        .iter()
+        .map(|(address, data)| (address - start) as usize + data.len())
+        .max()
+        .unwrap_or_default();
+
+    let mut bin = vec![0; end];
+    for (address, data) in segments {
+        let offset = (address - start) as usize;
+        bin[offset..offset + data.len()].copy_from_slice(data);
+    }
+    Ok(bin)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    /// Loads a fixture, as `--metadata-path` would, pointing its target directory at a fresh
+    /// directory holding `built`, which are paths relative to it.
+    fn fixture(name: &str, built: &[&str]) -> Value {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/cargo-metadata")
+            .join(format!("{name}.json"));
+        let mut metadata = load_metadata(Some(&path)).unwrap();
+
+        let target_dir = env::temp_dir().join(format!(
+            "v5ctl-cargo-{}-{name}-{}",
+            std::process::id(),
+            built.join(",").replace('/', "_")
+        ));
+        let _ = fs::remove_dir_all(&target_dir);
+        for artifact in built {
+            let artifact = target_dir.join(artifact);
+            fs::create_dir_all(artifact.parent().unwrap()).unwrap();
+            fs::write(artifact, b"\x7fELF").unwrap();
+        }
+        metadata["target_directory"] = target_dir.to_str().unwrap().into();
+        metadata
+    }
+
+    #[test]
+    fn a_single_binary_is_found_with_its_package_defaults() {
+        let metadata = fixture("single-target", &["armv7a-vex-v5/release/clawbot"]);
+        let artifact = find_artifact(&metadata, None, true).unwrap();
+        assert!(artifact.path.ends_with("armv7a-vex-v5/release/clawbot"));
+        assert_eq!(artifact.name, "clawbot");
+        assert_eq!(artifact.description.as_deref(), Some("Drives the clawbot"));
+        assert!(matches!(artifact.icon, Some(ProgramIcon::Clawbot)));
+    }
+
+    #[test]
+    fn an_unbuilt_binary_names_the_build_to_run() {
+        let metadata = fixture("single-target", &["armv7a-vex-v5/debug/clawbot"]);
+        let err = find_artifact(&metadata, None, true)
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("clawbot hasn't been built. Run `cargo build --release` first"));
+        assert!(err.ends_with("armv7a-vex-v5/release/clawbot"));
+    }
+
+    // Builds without the V5 target in their path are found too
+    #[test]
+    fn binaries_built_for_the_default_target_are_found() {
+        let metadata = fixture("single-target", &["debug/clawbot"]);
+        let artifact = find_artifact(&metadata, None, false).unwrap();
+        assert!(artifact.path.ends_with("debug/clawbot"));
+    }
+
+    #[test]
+    fn several_binaries_need_choosing_between() {
+        let metadata = fixture("multi-target", &["armv7a-vex-v5/debug/skills"]);
+        let err = find_artifact(&metadata, None, false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The workspace has several binary targets (competition, skills). Pick one with --bin"
+        );
+
+        let artifact = find_artifact(&metadata, Some("skills"), false).unwrap();
+        assert!(artifact.path.ends_with("armv7a-vex-v5/debug/skills"));
+        // The package's name, not the binary's
+        assert_eq!(artifact.name, "robot");
+        assert_eq!(artifact.description, None);
+        assert!(artifact.icon.is_none());
+
+        let err = find_artifact(&metadata, Some("robot"), false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The workspace has no binary target named \"robot\""
+        );
+    }
+
+    // Only workspace members count, so a dependency's binary doesn't need ruling out with --bin
+    #[test]
+    fn workspace_members_binaries_are_found() {
+        let metadata = fixture("workspace", &["armv7a-vex-v5/release/match-bot"]);
+        let artifact = find_artifact(&metadata, None, true).unwrap();
+        assert_eq!(artifact.name, "match-bot");
+        assert_eq!(artifact.description.as_deref(), Some("The match robot"));
+        assert!(matches!(artifact.icon, Some(ProgramIcon::Pizza)));
+
+        assert!(find_artifact(&metadata, Some("vexide-tools"), true).is_err());
+    }
+}
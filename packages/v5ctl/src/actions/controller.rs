@@ -0,0 +1,116 @@
+use std::{
+    fs::File,
+    io::{stdout, IsTerminal, Write},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use serde_json::json;
+use tokio::{
+    io::BufReader,
+    time::{interval, MissedTickBehavior},
+};
+use v5d_interface::{
+    connect_to_socket, get_response, send_command_to, ControllerStatus, DaemonCommand,
+    DaemonResponse, DaemonStream,
+};
+
+/// How often the controller status is polled.
+///
+/// The daemon locks a device for the whole of a file transfer,
+/// so polls simply wait until any upload on the same link finishes.
+/// Every poll goes over the same connection, so none of them pay for a new one.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How often a status line is printed when stdout isn't a terminal.
+const PLAIN_LINE_INTERVAL: Duration = Duration::from_secs(1);
+const BATTERY_BAR_WIDTH: usize = 10;
+
+async fn controller_status(
+    socket: &mut impl DaemonStream,
+    route: u8,
+) -> anyhow::Result<ControllerStatus> {
+    send_command_to(socket, route, DaemonCommand::ControllerStatus).await?;
+    match get_response(socket).await? {
+        DaemonResponse::ControllerStatus(status) => Ok(status),
+        DaemonResponse::BasicAck { successful: false } => {
+            bail!("Failed to read the controller status from the brain")
+        }
+        response => bail!("Unexpected response from daemon: {:?}", response),
+    }
+}
+
+fn battery(percent: u8) -> String {
+    let filled = (percent as usize * BATTERY_BAR_WIDTH / 100).min(BATTERY_BAR_WIDTH);
+    format!(
+        "[{}{}] {:>3}%",
+        "█".repeat(filled),
+        "░".repeat(BATTERY_BAR_WIDTH - filled),
+        percent
+    )
+}
+
+fn describe(status: &ControllerStatus) -> String {
+    if !status.connected {
+        return "No controller is connected to the brain".to_string();
+    }
+
+    let link = if status.tethered { "cable" } else { "radio" };
+    let mut line = format!("Controller ({link}): {}", battery(status.battery_percent));
+    if status.partner_connected {
+        line += &format!(" | Partner: {}", battery(status.partner_battery_percent));
+    }
+    line
+}
+
+/// Polls the controller status until interrupted.
+///
+/// Changes are shown on a single live line when stdout is a terminal,
+/// or streamed as one JSON object per change with `json`.
+/// With `record`, each change is also appended to the file with a timestamp.
+pub async fn monitor(route: u8, json: bool, record: Option<PathBuf>) -> anyhow::Result<()> {
+    let mut record = record
+        .map(|path| {
+            File::create(&path)
+                .with_context(|| format!("Failed to create recording file {}", path.display()))
+        })
+        .transpose()?;
+    let live = !json && stdout().is_terminal();
+    let mut socket = BufReader::new(connect_to_socket().await?);
+
+    let start = Instant::now();
+    let mut last_status = None;
+    let mut last_line: Option<Instant> = None;
+    let mut interval = interval(POLL_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let status = controller_status(&mut socket, route).await?;
+        let changed = last_status != Some(status);
+        last_status = Some(status);
+
+        if changed {
+            if let Some(file) = &mut record {
+                let entry = json!({
+                    "elapsed_ms": start.elapsed().as_millis() as u64,
+                    "status": status,
+                });
+                writeln!(file, "{}", entry)?;
+            }
+        }
+
+        if json {
+            if changed {
+                println!("{}", serde_json::to_string(&status)?);
+            }
+        } else if live {
+            if changed {
+                print!("\r\x1b[2K{}", describe(&status));
+                stdout().flush()?;
+            }
+        } else if last_line.is_none_or(|time| time.elapsed() >= PLAIN_LINE_INTERVAL) {
+            println!("{}", describe(&status));
+            last_line = Some(Instant::now());
+        }
+    }
+}
@@ -0,0 +1,22 @@
+use anyhow::bail;
+use tokio::{io::BufReader, net::UnixStream, select, signal::ctrl_c};
+use v5d_interface::{get_response, send_command, DaemonCommand, DaemonResponse};
+
+/// Prints the daemon's own log records as it emits them, until it exits or Ctrl+C is pressed.
+pub async fn logs(socket: &mut BufReader<UnixStream>, level: log::Level) -> anyhow::Result<()> {
+    send_command(socket, DaemonCommand::StreamLogs { level }).await?;
+    loop {
+        let response = select! {
+            response = get_response(socket) => response,
+            _ = ctrl_c() => return Ok(()),
+        };
+        match response {
+            Ok(DaemonResponse::Log(record)) => {
+                println!("{:<5} [{}] {}", record.level, record.target, record.message)
+            }
+            Ok(response) => bail!("Unexpected response from daemon: {:?}", response),
+            // Ends with the daemon saying it is shutting down, or just closing the stream
+            Err(_) => return Ok(()),
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! `v5ctl deploy`: upload a program, run it, and put the old one back if it crashes.
+
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use log::{error, info, warn};
+use tokio::{io::BufReader, net::UnixStream, time::sleep};
+use v5d_interface::{
+    connect_to_socket, get_response, read_user, send_command_to, upload_steps, AfterFileUpload,
+    DaemonCommand, DaemonResponse, ProgramData, ProgramUpload, Slot, MAX_PROGRAM_NAME_LEN,
+};
+
+use super::{
+    terminal::PANIC_MARKER,
+    upload::{check_layout, fit_to_brain, run_upload, ProgramIcon},
+};
+
+/// How often the program is checked while it is being watched.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// How long the program may take to show up as running after the upload.
+const STARTUP_GRACE: Duration = Duration::from_secs(2);
+
+/// Sends one command over `socket` and returns the reply.
+async fn exchange(
+    socket: &mut BufReader<UnixStream>,
+    route: u8,
+    command: DaemonCommand,
+) -> anyhow::Result<DaemonResponse> {
+    send_command_to(socket, route, command).await?;
+    Ok(get_response(socket).await?)
+}
+
+/// Sends one command on its own connection and returns the reply.
+async fn request(route: u8, command: DaemonCommand) -> anyhow::Result<DaemonResponse> {
+    exchange(
+        &mut BufReader::new(connect_to_socket().await?),
+        route,
+        command,
+    )
+    .await
+}
+
+async fn backup(route: u8, slot: Slot) -> anyhow::Result<Option<ProgramUpload>> {
+    match request(route, DaemonCommand::ReadProgram { slot }).await? {
+        DaemonResponse::Program(result) => Ok(result?),
+        DaemonResponse::BasicAck { successful: false } => {
+            bail!("The daemon couldn't read slot {}", slot)
+        }
+        response => bail!("Unexpected response from daemon: {:?}", response),
+    }
+}
+
+/// How the program behaved while it was watched.
+enum Outcome {
+    /// Still running at the end of the window.
+    Survived,
+    /// Stopped after printing a panic message.
+    Crashed,
+    /// Stopped without printing a panic message.
+    Exited,
+    /// Never showed up as running.
+    DidNotStart,
+}
+
+/// Watches the program in `slot` for `window`, reading its output to tell crashes from exits.
+///
+/// Output can only be read over a wired connection. Without it, every stop is an exit.
+async fn watch(route: u8, slot: Slot, window: Duration) -> anyhow::Result<Outcome> {
+    // Polled often, so keep one connection rather than opening one per poll
+    let mut socket = BufReader::new(connect_to_socket().await?);
+    let start = Instant::now();
+    let mut started = false;
+    let mut output = Vec::new();
+    while start.elapsed() < window {
+        if let Ok(data) = read_user(&mut socket, route, 1024).await {
+            output.extend(data);
+        }
+        let running = match exchange(&mut socket, route, DaemonCommand::Uptime).await? {
+            DaemonResponse::Uptime(uptime) => uptime.program.map(|program| program.slot),
+            DaemonResponse::BasicAck { successful: false } => {
+                bail!("Failed to read the brain's status")
+            }
+            response => bail!("Unexpected response from daemon: {:?}", response),
+        };
+
+        if running == Some(slot.get()) {
+            started = true;
+        } else if started {
+            // Whatever it printed on the way out is still in the FIFO
+            if let Ok(data) = read_user(&mut socket, route, 1024).await {
+                output.extend(data);
+            }
+            // A program that stops after printing a panic crashed; one that stops without it
+            // exited on its own
+            let panicked = output
+                .windows(PANIC_MARKER.len())
+                .any(|window| window == PANIC_MARKER);
+            return Ok(if panicked {
+                let output = String::from_utf8_lossy(&output);
+                error!("The program crashed:\n{}", output.trim_end());
+                Outcome::Crashed
+            } else {
+                Outcome::Exited
+            });
+        } else if start.elapsed() > STARTUP_GRACE {
+            return Ok(Outcome::DidNotStart);
+        }
+        sleep(WATCH_POLL_INTERVAL).await;
+    }
+    Ok(Outcome::Survived)
+}
+
+/// Puts `backup` back in `slot`, or empties the slot if there was nothing to back up.
+async fn roll_back(route: u8, slot: Slot, backup: Option<ProgramUpload>) -> anyhow::Result<()> {
+    let Some(backup) = backup else {
+        info!("[rollback] Slot {} was empty before, erasing it", slot);
+        return match request(route, DaemonCommand::EraseSlot { slot }).await? {
+            DaemonResponse::TransferComplete(result) => Ok(result?),
+            response => bail!("Unexpected response from daemon: {:?}", response),
+        };
+    };
+
+    info!("[rollback] Restoring {:?} to slot {}", backup.name, slot);
+    let steps = upload_steps(&backup.data);
+    let socket = BufReader::new(connect_to_socket().await?);
+    run_upload(socket, route, backup, &steps, None).await??;
+    Ok(())
+}
+
+/// Uploads `bin` to `slot` and runs it, watching it for `window`.
+///
+/// With `rollback`, the slot's current program is downloaded first and put back if the new one
+/// crashes or doesn't start. A program that exits on its own isn't rolled back.
+pub async fn deploy(
+    socket: BufReader<UnixStream>,
+    route: u8,
+    bin: &Path,
+    slot: Slot,
+    name: Option<String>,
+    window: Duration,
+    rollback: bool,
+) -> anyhow::Result<()> {
+    let data = ProgramData::Monolith(
+        std::fs::read(bin).with_context(|| format!("reading {}", bin.display()))?,
+    );
+    check_layout(&data)?;
+    // A name given on purpose has to fit, but the file's can be shortened
+    let name = match name {
+        Some(name) => fit_to_brain("name", name, MAX_PROGRAM_NAME_LEN, false)?,
+        None => {
+            let stem = bin.file_stem().unwrap().to_string_lossy().to_string();
+            fit_to_brain("name", stem, MAX_PROGRAM_NAME_LEN, true)?
+        }
+    };
+    let upload = ProgramUpload {
+        name,
+        description: "Uploaded with v5d".to_string(),
+        icon: format!("USER{:03}x.bmp", ProgramIcon::default() as u16),
+        program_type: "Unknown".to_string(),
+        slot,
+        compression: true,
+        after_upload: AfterFileUpload::RunProgram,
+        data,
+        replace: true,
+        overwrite_protected: false,
+    };
+
+    let backup = if rollback {
+        info!("[backup] Reading the program in slot {}", slot);
+        let backup = backup(route, slot)
+            .await
+            .context("Couldn't back up the slot")?;
+        match &backup {
+            Some(program) => info!("[backup] Saved {:?}", program.name),
+            None => info!("[backup] Slot {} is empty", slot),
+        }
+        Some(backup)
+    } else {
+        None
+    };
+
+    info!("[upload] Uploading {} to slot {}", bin.display(), slot);
+    let steps = upload_steps(&upload.data);
+    if let Err(err) = run_upload(socket, route, upload, &steps, None).await? {
+        if err.slot_protected {
+            bail!("Deploy failed: slot {} is protected on this brain", slot);
+        }
+        // Replacing erases the slot first, so a failed upload leaves it empty or partial
+        if let Some(backup) = backup {
+            warn!("[upload] Failed: {}", err);
+            roll_back(route, slot, backup).await?;
+            bail!("Deploy failed: the upload didn't finish. The previous program was restored");
+        }
+        bail!("Deploy failed: {}", err);
+    }
+
+    info!("[watch] Watching the program for {}s", window.as_secs());
+    let failure = match watch(route, slot, window).await? {
+        Outcome::Survived => {
+            info!("[watch] The program is still running. Deployed");
+            return Ok(());
+        }
+        Outcome::Exited => {
+            info!("[watch] The program exited on its own, which isn't a crash. Deployed");
+            return Ok(());
+        }
+        Outcome::Crashed => "the program crashed",
+        Outcome::DidNotStart => "the program didn't start",
+    };
+    match backup {
+        Some(backup) => {
+            roll_back(route, slot, backup).await?;
+            bail!(
+                "Deploy failed: {}. The previous program was restored",
+                failure
+            )
+        }
+        None => bail!("Deploy failed: {}", failure),
+    }
+}
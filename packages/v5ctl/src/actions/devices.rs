@@ -0,0 +1,173 @@
+use std::{collections::HashSet, time::Duration};
+
+use anyhow::{bail, Context};
+use indicatif::ProgressBar;
+use log::{error, info};
+use tokio::{
+    io::BufReader,
+    net::UnixStream,
+    time::{sleep, timeout},
+};
+use v5d_interface::{
+    connect_to_socket, get_response, send_command, send_command_to, ConnectedDevice, DaemonCommand,
+    DaemonResponse, DeviceConnectionType,
+};
+
+/// How often the daemon is asked for its devices while waiting for one to appear.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Describes how `v5ctl terminal` reaches a device over the given connection.
+fn terminal_support(connection_type: DeviceConnectionType) -> &'static str {
+    match connection_type {
+        DeviceConnectionType::Wired => "terminal: wired",
+        // User I/O is tunneled through the radio one FIFO packet at a time.
+        DeviceConnectionType::Controller => "terminal: wireless, ~1KB/s",
+        DeviceConnectionType::Bluetooth => "no terminal",
+    }
+}
+
+pub async fn devices(socket: &mut BufReader<UnixStream>) -> anyhow::Result<()> {
+    send_command(socket, DaemonCommand::ListDevices).await?;
+    match get_response(socket).await? {
+        DaemonResponse::Devices(devices) => {
+            if devices.is_empty() {
+                info!("The daemon is not connected to any devices");
+            }
+            for device in devices {
+                info!(
+                    "{}: {:?} ({}; {})",
+                    device.route,
+                    device.connection_type,
+                    device.id,
+                    terminal_support(device.connection_type)
+                );
+            }
+        }
+        _ => error!("Unexpected response from daemon"),
+    }
+
+    Ok(())
+}
+
+/// Returns the daemon's devices, or `None` if it isn't running.
+async fn list_devices() -> Option<Vec<ConnectedDevice>> {
+    let mut socket = BufReader::new(connect_to_socket().await.ok()?);
+    send_command(&mut socket, DaemonCommand::ListDevices)
+        .await
+        .ok()?;
+    match get_response(&mut socket).await {
+        Ok(DaemonResponse::Devices(devices)) => Some(devices),
+        _ => None,
+    }
+}
+
+/// Returns the routes of every device the daemon is connected to.
+pub async fn routes() -> anyhow::Result<Vec<u8>> {
+    let devices = list_devices()
+        .await
+        .context("Couldn't list the daemon's devices")?;
+    Ok(devices.iter().map(|device| device.route).collect())
+}
+
+/// Returns whether the daemon is running and has a device bound to the route.
+async fn has_device(route: u8) -> bool {
+    list_devices()
+        .await
+        .is_some_and(|devices| devices.iter().any(|device| device.route == route))
+}
+
+/// Waits until the daemon is connected to a device on the route.
+///
+/// Also covers the daemon itself not having started yet.
+pub async fn wait_for_device(route: u8, wait_time: Duration) -> anyhow::Result<()> {
+    if has_device(route).await {
+        return Ok(());
+    }
+
+    let spinner = ProgressBar::new_spinner().with_message("Waiting for a brain...");
+    spinner.enable_steady_tick(Duration::from_millis(100));
+    let found = timeout(wait_time, async {
+        while !has_device(route).await {
+            sleep(WAIT_POLL_INTERVAL).await;
+        }
+    })
+    .await;
+    spinner.finish_and_clear();
+
+    if found.is_err() {
+        bail!(
+            "Timed out after {}s waiting for a brain on route {}",
+            wait_time.as_secs(),
+            route
+        );
+    }
+    Ok(())
+}
+
+/// Waits for the daemon to connect to a device it wasn't connected to when this started,
+/// and returns the device's route.
+///
+/// Only a device with the given id counts, if there is one. A device that disconnects
+/// while waiting counts again when it comes back.
+pub async fn wait_for_new_device(
+    id: Option<&str>,
+    deadline: Option<Duration>,
+) -> anyhow::Result<u8> {
+    let mut known: HashSet<String> = list_devices()
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .map(|device| device.id)
+        .collect();
+
+    let message = match id {
+        Some(id) => format!("Waiting for {} to connect...", id),
+        None => "Waiting for a brain to connect...".to_string(),
+    };
+    let spinner = ProgressBar::new_spinner().with_message(message);
+    spinner.enable_steady_tick(Duration::from_millis(100));
+    let wait = async {
+        loop {
+            sleep(WAIT_POLL_INTERVAL).await;
+            let devices = list_devices().await.unwrap_or_default();
+            let new = devices
+                .iter()
+                .find(|device| !known.contains(&device.id) && id.is_none_or(|id| device.id == id));
+            if let Some(device) = new {
+                return device.clone();
+            }
+            known.retain(|known| devices.iter().any(|device| device.id == *known));
+        }
+    };
+    let found = match deadline {
+        Some(deadline) => timeout(deadline, wait).await.ok(),
+        None => Some(wait.await),
+    };
+    spinner.finish_and_clear();
+
+    let Some(device) = found else {
+        bail!(
+            "Timed out after {}s waiting for a brain to connect",
+            deadline.unwrap_or_default().as_secs()
+        );
+    };
+    info!("{} connected on route {}", device.id, device.route);
+    Ok(device.route)
+}
+
+/// Asks the daemon to move a Bluetooth device onto the brain's USB cable.
+pub async fn switch_to_usb(socket: &mut BufReader<UnixStream>, route: u8) -> anyhow::Result<()> {
+    send_command_to(socket, route, DaemonCommand::SwitchToUsb).await?;
+    match get_response(socket).await? {
+        DaemonResponse::BasicAck { successful: true } => {
+            info!("Device {} is now connected over USB", route);
+            Ok(())
+        }
+        DaemonResponse::BasicAck { successful: false } => bail!(
+            "Couldn't switch device {} to USB. Is the brain plugged in? \
+             `v5ctl errors` shows why",
+            route
+        ),
+        response => bail!("Unexpected response from daemon: {:?}", response),
+    }
+}
@@ -0,0 +1,238 @@
+//! `v5ctl env`: the settings v5ctl would run with, and where each one came from.
+
+use std::env;
+
+use clap::{parser::ValueSource, ArgMatches};
+use serde_json::json;
+
+use super::{interaction::non_interactive_reason, progress::unicode_unsupported_reason};
+use crate::args::Args;
+
+const DEFAULT: &str = "built-in default";
+
+/// One resolved setting.
+struct Setting {
+    name: &'static str,
+    value: String,
+    source: String,
+}
+
+/// Where a global option's value came from.
+fn arg_source(matches: &ArgMatches, id: &str, flag: &str) -> String {
+    match matches.value_source(id) {
+        Some(ValueSource::CommandLine) => format!("{flag} flag"),
+        Some(ValueSource::EnvVariable) => "environment variable".to_string(),
+        _ => DEFAULT.to_string(),
+    }
+}
+
+fn resolve(matches: &ArgMatches, args: &Args) -> Vec<Setting> {
+    let mut settings = Vec::new();
+    let mut set = |name, value: String, source: String| {
+        settings.push(Setting {
+            name,
+            value,
+            source,
+        })
+    };
+
+    // `socket_path()` panics without a runtime directory, so check for one first
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(_) => set(
+            "socket",
+            v5d_interface::socket_path().display().to_string(),
+            "XDG_RUNTIME_DIR environment variable".to_string(),
+        ),
+        None => set(
+            "socket",
+            "none".to_string(),
+            "XDG_RUNTIME_DIR isn't set, so v5ctl can't find the daemon".to_string(),
+        ),
+    }
+
+    match &args.on_connect {
+        Some(id) => set(
+            "device",
+            "the next brain to connect".to_string(),
+            if id.is_empty() {
+                "--on-connect flag".to_string()
+            } else {
+                format!("--on-connect flag, waiting for {id}")
+            },
+        ),
+        None => set(
+            "device",
+            args.device.to_string(),
+            arg_source(matches, "device", "--device"),
+        ),
+    }
+    set(
+        "connect timeout",
+        format!("{}s", args.connect_timeout),
+        arg_source(matches, "connect_timeout", "--connect-timeout"),
+    );
+    set(
+        "wait for device",
+        args.wait_for_device
+            .map_or_else(|| "no".to_string(), |secs| format!("up to {secs}s")),
+        arg_source(matches, "wait_for_device", "--wait-for-device"),
+    );
+    set(
+        "log level",
+        if args.verbose { "debug" } else { "info" }.to_string(),
+        arg_source(matches, "verbose", "--verbose"),
+    );
+    let (progress_bars, source) = match (args.ascii, unicode_unsupported_reason()) {
+        (true, _) => ("ascii", "--ascii flag".to_string()),
+        (false, Some(reason)) => ("ascii", reason),
+        (false, None) => ("unicode", "the locale is UTF-8".to_string()),
+    };
+    set("progress bars", progress_bars.to_string(), source);
+    let (prompts, source) = match non_interactive_reason(args.non_interactive) {
+        Some(reason) => ("fail", reason.to_string()),
+        None => ("ask", DEFAULT.to_string()),
+    };
+    set("prompts", prompts.to_string(), source);
+    set(
+        "command timeout",
+        args.timeout
+            .map_or_else(|| "none".to_string(), |secs| format!("{secs}s")),
+        arg_source(matches, "timeout", "--timeout"),
+    );
+
+    // Upload defaults, which each upload's own flags override
+    for (name, value) in [
+        ("upload compression", "on"),
+        ("upload after-upload", "show-screen"),
+        ("upload icon", "question-mark"),
+        ("upload description", "Uploaded with v5d"),
+        ("upload program type", "Unknown"),
+    ] {
+        set(name, value.to_string(), DEFAULT.to_string());
+    }
+    settings
+}
+
+/// Prints the settings v5ctl resolved for this run, and the source of each.
+pub fn env(matches: &ArgMatches, args: &Args, json: bool) {
+    let settings = resolve(matches, args);
+    if json {
+        let settings = settings
+            .iter()
+            .map(|setting| {
+                json!({
+                    "name": setting.name,
+                    "value": setting.value,
+                    "source": setting.source,
+                })
+            })
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::to_string_pretty(&settings).unwrap());
+        return;
+    }
+
+    let width = settings.iter().map(|setting| setting.name.len()).max();
+    for setting in settings {
+        println!(
+            "{:width$}  {}  ({})",
+            setting.name,
+            setting.value,
+            setting.source,
+            width = width.unwrap_or_default()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, FromArgMatches};
+
+    use super::*;
+
+    /// The value and source of every setting, for `v5ctl` run with `args`.
+    fn resolve_for(args: &[&str]) -> Vec<(&'static str, String, String)> {
+        let matches = Args::command()
+            .try_get_matches_from([&["v5ctl"], args].concat())
+            .unwrap();
+        let args = Args::from_arg_matches(&matches).unwrap();
+        resolve(&matches, &args)
+            .into_iter()
+            .map(|setting| (setting.name, setting.value, setting.source))
+            .collect()
+    }
+
+    fn setting<'a>(settings: &'a [(&str, String, String)], name: &str) -> (&'a str, &'a str) {
+        let (_, value, source) = settings
+            .iter()
+            .find(|(setting, ..)| *setting == name)
+            .unwrap_or_else(|| panic!("no {name} setting"));
+        (value, source)
+    }
+
+    #[test]
+    fn unset_options_come_from_the_defaults() {
+        let settings = resolve_for(&["env"]);
+        assert_eq!(setting(&settings, "device"), ("0", DEFAULT));
+        assert_eq!(setting(&settings, "connect timeout").1, DEFAULT);
+        assert_eq!(setting(&settings, "wait for device"), ("no", DEFAULT));
+        assert_eq!(setting(&settings, "log level"), ("info", DEFAULT));
+        assert_eq!(setting(&settings, "command timeout"), ("none", DEFAULT));
+        assert_eq!(
+            setting(&settings, "upload after-upload"),
+            ("show-screen", DEFAULT)
+        );
+    }
+
+    #[test]
+    fn flags_name_themselves_wherever_they_are_given() {
+        let settings = resolve_for(&[
+            "--device",
+            "2",
+            "--timeout",
+            "30",
+            "env",
+            "--connect-timeout",
+            "1.5",
+            "--wait-for-device",
+            "--verbose",
+            "--ascii",
+            "--non-interactive",
+        ]);
+        assert_eq!(setting(&settings, "device"), ("2", "--device flag"));
+        assert_eq!(
+            setting(&settings, "command timeout"),
+            ("30s", "--timeout flag")
+        );
+        assert_eq!(
+            setting(&settings, "connect timeout"),
+            ("1.5s", "--connect-timeout flag")
+        );
+        assert_eq!(
+            setting(&settings, "wait for device"),
+            ("up to 30s", "--wait-for-device flag")
+        );
+        assert_eq!(setting(&settings, "log level"), ("debug", "--verbose flag"));
+        assert_eq!(
+            setting(&settings, "progress bars"),
+            ("ascii", "--ascii flag")
+        );
+        assert_eq!(
+            setting(&settings, "prompts"),
+            ("fail", "--non-interactive flag")
+        );
+    }
+
+    #[test]
+    fn on_connect_overrides_the_device_flag() {
+        let settings = resolve_for(&["--device", "2", "env", "--on-connect"]);
+        assert_eq!(
+            setting(&settings, "device"),
+            ("the next brain to connect", "--on-connect flag")
+        );
+        let settings = resolve_for(&["--on-connect", "0x1234", "env"]);
+        assert_eq!(
+            setting(&settings, "device").1,
+            "--on-connect flag, waiting for 0x1234"
+        );
+    }
+}
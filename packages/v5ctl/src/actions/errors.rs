@@ -0,0 +1,33 @@
+use std::time::SystemTime;
+
+use anyhow::bail;
+use log::info;
+use tokio::{io::BufReader, net::UnixStream};
+use v5d_interface::{get_response, send_command, DaemonCommand, DaemonResponse};
+
+use super::uptime::format_duration;
+
+/// Prints up to `limit` of the daemon's most recent errors.
+pub async fn errors(socket: &mut BufReader<UnixStream>, limit: usize) -> anyhow::Result<()> {
+    send_command(socket, DaemonCommand::RecentErrors { limit: Some(limit) }).await?;
+    let errors = match get_response(socket).await? {
+        DaemonResponse::RecentErrors(errors) => errors,
+        response => bail!("Unexpected response from daemon: {:?}", response),
+    };
+
+    if errors.is_empty() {
+        info!("The daemon hasn't logged any errors");
+    }
+    let now = SystemTime::now();
+    for error in errors {
+        let ago = now.duration_since(error.time).unwrap_or_default();
+        println!(
+            "{} ago  [{}] {}",
+            format_duration(ago),
+            error.target,
+            error.message
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,35 @@
+use anyhow::bail;
+use log::info;
+use tokio::{io::BufReader, net::UnixStream};
+use v5d_interface::{
+    get_response, send_command_to, CompetitionMode, DaemonCommand, DaemonResponse,
+    FieldControlStatus,
+};
+
+pub async fn field(socket: &mut BufReader<UnixStream>, route: u8) -> anyhow::Result<()> {
+    send_command_to(socket, route, DaemonCommand::FieldControlStatus).await?;
+    let status = match get_response(socket).await? {
+        DaemonResponse::FieldControlStatus(Some(status)) => status,
+        DaemonResponse::FieldControlStatus(None) => {
+            bail!("The brain didn't report its field control state")
+        }
+        DaemonResponse::BasicAck { successful: false } => {
+            bail!("Failed to read the brain's status")
+        }
+        response => bail!("Unexpected response from daemon: {:?}", response),
+    };
+
+    match status {
+        FieldControlStatus::Disconnected => info!("No field control connected"),
+        FieldControlStatus::Connected(mode) => info!(
+            "Field control connected, robot is {}",
+            match mode {
+                CompetitionMode::Disabled => "disabled",
+                CompetitionMode::Autonomous => "in autonomous",
+                CompetitionMode::Driver => "in driver control",
+            }
+        ),
+    }
+
+    Ok(())
+}
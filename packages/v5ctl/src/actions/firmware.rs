@@ -0,0 +1,23 @@
+use anyhow::bail;
+use log::info;
+use tokio::{io::BufReader, net::UnixStream};
+use v5d_interface::{get_response, send_command_to, DaemonCommand, DaemonResponse};
+
+pub async fn firmware(socket: &mut BufReader<UnixStream>, route: u8) -> anyhow::Result<()> {
+    send_command_to(socket, route, DaemonCommand::FirmwareStatus).await?;
+    let status = match get_response(socket).await? {
+        DaemonResponse::FirmwareStatus(status) => status,
+        DaemonResponse::BasicAck { successful: false } => {
+            bail!("Failed to read the brain's status")
+        }
+        response => bail!("Unexpected response from daemon: {:?}", response),
+    };
+
+    info!("VEXos {}", status.active);
+    match status.golden {
+        Some(golden) => info!("Golden image: VEXos {}", golden),
+        None => info!("The brain didn't report its golden image version"),
+    }
+
+    Ok(())
+}
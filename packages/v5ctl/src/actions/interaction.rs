@@ -0,0 +1,58 @@
+//! Questions v5ctl asks the user, and what happens when nobody is there to answer them.
+//!
+//! Every prompt goes through here so that `--non-interactive` turns each one into an error
+//! naming the flag that answers it, instead of a job that hangs until it's killed.
+
+use std::{
+    io::{stdin, stdout, IsTerminal, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::bail;
+use rustyline::DefaultEditor;
+
+static NON_INTERACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Disables prompts if `non_interactive` is set or stdin isn't a terminal.
+pub fn set_non_interactive(non_interactive: bool) {
+    let non_interactive = non_interactive || !stdin().is_terminal();
+    NON_INTERACTIVE.store(non_interactive, Ordering::Relaxed);
+}
+
+/// Returns whether prompts are disabled, and why.
+pub(crate) fn non_interactive_reason(flag: bool) -> Option<&'static str> {
+    if flag {
+        Some("--non-interactive flag")
+    } else if !stdin().is_terminal() {
+        Some("stdin isn't a terminal")
+    } else {
+        None
+    }
+}
+
+fn check_interactive(question: &str, flag: &str) -> anyhow::Result<()> {
+    if NON_INTERACTIVE.load(Ordering::Relaxed) {
+        bail!(
+            "v5ctl is running non-interactively, so it can't ask \"{question}\". \
+             Pass {flag} to answer it"
+        );
+    }
+    Ok(())
+}
+
+/// Asks a yes or no question, defaulting to no. `flag` is the option that answers yes.
+pub(crate) fn confirm(question: &str, flag: &str) -> anyhow::Result<bool> {
+    check_interactive(question, flag)?;
+    print!("{question} [y/N] ");
+    stdout().flush()?;
+    let mut answer = String::new();
+    stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Reads a line of input. `flag` is the option that gives it on the command line instead.
+pub(crate) fn ask(prompt: &str, flag: &str) -> anyhow::Result<String> {
+    check_interactive(prompt, flag)?;
+    let mut editor = DefaultEditor::new()?;
+    Ok(editor.readline(prompt)?)
+}
@@ -0,0 +1,153 @@
+use clap::{Arg, ArgAction, Command};
+use serde_json::{json, Value};
+
+/// Bumped whenever the JSON layout changes in a way that could break consumers.
+const FORMAT_VERSION: u32 = 1;
+
+/// What a subcommand has to reach before it can run.
+fn requirement(path: &[&str]) -> &'static str {
+    match path {
+        ["introspect" | "env" | "clean-temp"] => "none",
+        ["devices" | "errors" | "daemon" | "stop-daemon" | "reconnect" | "jobs", ..] => "daemon",
+        _ => "device",
+    }
+}
+
+fn describe_arg(arg: &Arg) -> Value {
+    let takes_value = arg.get_action().takes_values();
+    json!({
+        "id": arg.get_id().as_str(),
+        "long": arg.get_long(),
+        "short": arg.get_short(),
+        "help": arg.get_help().map(ToString::to_string),
+        "positional": arg.is_positional(),
+        "required": arg.is_required_set(),
+        "global": arg.is_global_set(),
+        "kind": match arg.get_action() {
+            ArgAction::SetTrue | ArgAction::SetFalse => "flag",
+            ArgAction::Count => "count",
+            _ if takes_value => "value",
+            _ => "other",
+        },
+        "value_names": arg.get_value_names().map(|names| {
+            names.iter().map(ToString::to_string).collect::<Vec<_>>()
+        }),
+        "default_values": arg
+            .get_default_values()
+            .iter()
+            .map(|value| value.to_string_lossy())
+            .collect::<Vec<_>>(),
+        "possible_values": arg
+            .get_possible_values()
+            .iter()
+            .filter(|value| !value.is_hide_set())
+            .map(|value| value.get_name())
+            .collect::<Vec<_>>(),
+    })
+}
+
+fn describe_command<'a>(command: &'a Command, path: &mut Vec<&'a str>) -> Value {
+    let args = command
+        .get_arguments()
+        .filter(|arg| !arg.is_hide_set() && !arg.is_global_set())
+        .filter(|arg| !matches!(arg.get_action(), ArgAction::Help | ArgAction::Version))
+        .map(describe_arg)
+        .collect::<Vec<_>>();
+    let subcommands = command
+        .get_subcommands()
+        .filter(|subcommand| subcommand.get_name() != "help")
+        .map(|subcommand| {
+            path.push(subcommand.get_name());
+            let description = describe_command(subcommand, path);
+            path.pop();
+            description
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "name": command.get_name(),
+        "aliases": command.get_visible_aliases().collect::<Vec<_>>(),
+        "about": command.get_about().map(ToString::to_string),
+        "requires": requirement(path),
+        "args": args,
+        "subcommands": subcommands,
+    })
+}
+
+/// Prints a description of every subcommand, generated from the CLI definition itself.
+pub fn introspect(mut command: Command, json: bool) {
+    command.build();
+    if !json {
+        let commands = command
+            .get_subcommands()
+            .filter(|subcommand| subcommand.get_name() != "help");
+        for subcommand in commands {
+            println!(
+                "{:<16} {}",
+                subcommand.get_name(),
+                subcommand
+                    .get_about()
+                    .map(ToString::to_string)
+                    .unwrap_or_default()
+            );
+        }
+        return;
+    }
+
+    println!("{}", describe(&command));
+}
+
+/// The JSON description of `command` and everything under it. `command` must be built.
+fn describe(command: &Command) -> Value {
+    let global_args = command
+        .get_arguments()
+        .filter(|arg| arg.is_global_set())
+        .map(describe_arg)
+        .collect::<Vec<_>>();
+    let commands = command
+        .get_subcommands()
+        .filter(|subcommand| subcommand.get_name() != "help")
+        .map(|subcommand| describe_command(subcommand, &mut vec![subcommand.get_name()]))
+        .collect::<Vec<_>>();
+    json!({
+        "format_version": FORMAT_VERSION,
+        "name": command.get_name(),
+        "version": command.get_version(),
+        "global_args": global_args,
+        "commands": commands,
+    })
+}
+
+// The snapshot is of a normal build, without the debug commands
+#[cfg(all(test, not(feature = "debug")))]
+mod tests {
+    use std::{env, fs, path::Path};
+
+    use clap::CommandFactory;
+
+    use super::*;
+    use crate::args::Args;
+
+    /// Where the reviewed description is kept, relative to the package.
+    const SNAPSHOT: &str = "src/actions/introspect.snapshot.json";
+
+    // Changing any command changes this description, which the VS Code extension reads, so
+    // the change shows up in review. Run with UPDATE_SNAPSHOTS=1 to accept it
+    #[test]
+    fn the_description_matches_the_snapshot() {
+        let mut command = Args::command();
+        command.build();
+        let description = serde_json::to_string_pretty(&describe(&command)).unwrap() + "\n";
+
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(SNAPSHOT);
+        if env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            fs::write(&path, &description).unwrap();
+        }
+        let snapshot = fs::read_to_string(&path).unwrap_or_default();
+        assert!(
+            snapshot == description,
+            "`v5ctl introspect --json` changed; review {SNAPSHOT} after running the tests \
+             with UPDATE_SNAPSHOTS=1"
+        );
+    }
+}
@@ -0,0 +1,89 @@
+use anyhow::bail;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{error, info};
+use tokio::{io::BufReader, net::UnixStream};
+use v5d_interface::{
+    get_response, send_command, DaemonCommand, DaemonResponse, JobId, TransferError,
+};
+
+use super::progress::progress_chars;
+
+fn report_result(id: JobId, result: &Result<(), TransferError>) {
+    match result {
+        Ok(()) => info!("Job {} finished successfully", id),
+        Err(err) => error!("Job {} failed: {}", id, err),
+    }
+}
+
+pub async fn status(socket: &mut BufReader<UnixStream>, id: JobId) -> anyhow::Result<()> {
+    send_command(socket, DaemonCommand::JobStatus(id)).await?;
+    let status = match get_response(socket).await? {
+        DaemonResponse::JobStatus(Some(status)) => status,
+        DaemonResponse::JobStatus(None) => bail!("There is no job with id {}", id),
+        response => bail!("Unexpected response from daemon: {:?}", response),
+    };
+
+    info!("Job {} is uploading to route {}", id, status.route);
+    for section in status.progress {
+        info!(
+            "{:4} {:>7.2}% ({}/{} bytes)",
+            section.step,
+            section.percent(),
+            section.sent,
+            section.total
+        );
+    }
+    match status.result {
+        Some(result) => report_result(id, &result),
+        None => info!("Job {} is still running", id),
+    }
+
+    Ok(())
+}
+
+pub async fn attach(socket: &mut BufReader<UnixStream>, id: JobId) -> anyhow::Result<()> {
+    send_command(socket, DaemonCommand::AttachJob(id)).await?;
+
+    let progress = ProgressBar::new(10000).with_style(
+        ProgressStyle::with_template("{msg:4} {percent_precise:>7}% {bar:40.green}")
+            .unwrap()
+            .progress_chars(progress_chars()),
+    );
+    loop {
+        let response = tokio::select! {
+            response = get_response(socket) => response?,
+            _ = tokio::signal::ctrl_c() => {
+                progress.abandon();
+                info!("Detached from job {}. It will keep running", id);
+                return Ok(());
+            }
+        };
+        match response {
+            DaemonResponse::TransferProgress(section) => {
+                progress.set_message(section.step.to_string());
+                progress.set_length(section.total.into());
+                progress.set_position(section.sent.into());
+            }
+            DaemonResponse::TransferComplete(result) => {
+                progress.finish();
+                report_result(id, &result);
+                return Ok(());
+            }
+            DaemonResponse::BasicAck { successful: false } => {
+                bail!("There is no job with id {}", id)
+            }
+            response => bail!("Unexpected response from daemon: {:?}", response),
+        }
+    }
+}
+
+pub async fn cancel(socket: &mut BufReader<UnixStream>, id: JobId) -> anyhow::Result<()> {
+    send_command(socket, DaemonCommand::CancelJob(id)).await?;
+    match get_response(socket).await? {
+        DaemonResponse::BasicAck { successful: true } => info!("Cancelling job {}", id),
+        DaemonResponse::BasicAck { successful: false } => bail!("There is no job with id {}", id),
+        response => bail!("Unexpected response from daemon: {:?}", response),
+    }
+
+    Ok(())
+}
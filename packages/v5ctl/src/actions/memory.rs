@@ -0,0 +1,53 @@
+use anyhow::bail;
+use tokio::{io::BufReader, net::UnixStream};
+use v5d_interface::{get_response, send_command_to, DaemonCommand, DaemonResponse};
+
+/// Parses a memory address, in hex if it starts with `0x`.
+pub fn parse_address(address: &str) -> Result<u32, String> {
+    match address.strip_prefix("0x").or(address.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => address.parse(),
+    }
+    .map_err(|err| err.to_string())
+}
+
+/// Prints bytes 16 to a line, with their addresses and an ASCII column.
+fn hexdump(address: u32, data: &[u8]) {
+    for (i, line) in data.chunks(16).enumerate() {
+        let hex = line
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let ascii = line
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect::<String>();
+        println!("{:08x}  {hex:<47}  |{ascii}|", address as usize + i * 16);
+    }
+}
+
+/// Reads and hexdumps a range of the brain's memory.
+pub async fn read_memory(
+    socket: &mut BufReader<UnixStream>,
+    route: u8,
+    address: u32,
+    length: u16,
+) -> anyhow::Result<()> {
+    send_command_to(socket, route, DaemonCommand::ReadMemory { address, length }).await?;
+    match get_response(socket).await? {
+        DaemonResponse::Memory(data) => hexdump(address, &data),
+        DaemonResponse::BasicAck { successful: false } => {
+            bail!("The brain refused to read {length} bytes at {address:#010x}")
+        }
+        response => bail!("Unexpected response from daemon: {:?}", response),
+    }
+
+    Ok(())
+}
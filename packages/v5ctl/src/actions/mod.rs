@@ -1,5 +1,41 @@
+pub mod boot_state;
+pub mod cargo;
+pub mod controller;
+pub mod daemon;
+pub mod deploy;
+pub mod devices;
+pub mod env;
+pub mod errors;
+pub mod field;
+pub mod firmware;
+pub mod interaction;
+pub mod introspect;
+pub mod jobs;
+#[cfg(feature = "debug")]
+pub mod memory;
 pub mod pair;
+pub mod ping;
+pub mod progress;
+pub mod project;
+pub mod protect;
+pub mod selftest;
+pub mod settings;
+pub mod snapshot;
+pub mod temp;
+pub mod terminal;
 pub mod upload;
+pub mod uptime;
 
+pub use boot_state::boot_state;
+pub use deploy::deploy;
+pub use devices::{devices, switch_to_usb, wait_for_device};
+pub use errors::errors;
+pub use field::field;
+pub use firmware::firmware;
 pub use pair::pair;
+pub use ping::ping_brain;
+pub use settings::kv;
+pub use snapshot::snapshot;
+pub use terminal::terminal;
 pub use upload::upload;
+pub use uptime::uptime;
@@ -1,10 +1,31 @@
+use anyhow::Context;
 use log::{error, info};
-use rustyline::DefaultEditor;
 use tokio::{io::BufReader, net::UnixStream};
-use v5d_interface::{get_response, send_command, DaemonCommand, DaemonResponse};
+use v5d_interface::{get_response, send_command_to, DaemonCommand, DaemonResponse};
 
-pub async fn pair(socket: &mut BufReader<UnixStream>) -> anyhow::Result<()> {
-    send_command(socket, DaemonCommand::RequestPair).await?;
+use super::interaction::ask;
+
+/// Parses the four-digit pairing pin the brain shows.
+fn parse_pin(pin: &str) -> anyhow::Result<[u8; 4]> {
+    let digits = pin
+        .trim()
+        .chars()
+        .map(|digit| digit.to_digit(10).map(|digit| digit as u8))
+        .collect::<Option<Vec<_>>>()
+        .context("The pin must only contain digits")?;
+    digits
+        .try_into()
+        .ok()
+        .context("The pin must be four digits long")
+}
+
+/// Pairs with the brain, asking for the pin it shows unless `pin` is given.
+pub async fn pair(
+    socket: &mut BufReader<UnixStream>,
+    route: u8,
+    pin: Option<String>,
+) -> anyhow::Result<()> {
+    send_command_to(socket, route, DaemonCommand::RequestPair).await?;
     let response = get_response(socket).await?;
     match response {
         DaemonResponse::BasicAck { successful } => {
@@ -21,24 +42,18 @@ pub async fn pair(socket: &mut BufReader<UnixStream>) -> anyhow::Result<()> {
         }
     }
 
-    info!("Enter the pairing pin shown on the brain:");
-    let mut editor = DefaultEditor::new().unwrap();
-    let pin = editor.readline("Enter PIN: >> ").unwrap();
-
-    let mut chars = pin.chars();
+    let pin = match pin {
+        Some(pin) => pin,
+        None => {
+            info!("Enter the pairing pin shown on the brain:");
+            ask("Enter PIN: >> ", "--pin")?
+        }
+    };
+    let pin = parse_pin(&pin)?;
 
     let mut socket = BufReader::new(v5d_interface::connect_to_socket().await?);
 
-    send_command(
-        &mut socket,
-        DaemonCommand::PairingPin([
-            chars.next().unwrap().to_digit(10).unwrap() as u8,
-            chars.next().unwrap().to_digit(10).unwrap() as u8,
-            chars.next().unwrap().to_digit(10).unwrap() as u8,
-            chars.next().unwrap().to_digit(10).unwrap() as u8,
-        ]),
-    )
-    .await?;
+    send_command_to(&mut socket, route, DaemonCommand::PairingPin(pin)).await?;
     let response = get_response(&mut socket).await?;
     match response {
         DaemonResponse::BasicAck { successful } => {
@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use anyhow::bail;
+use log::info;
+use tokio::{io::BufReader, net::UnixStream, select, signal::ctrl_c, time::sleep};
+use v5d_interface::{
+    connect_to_socket, get_response, send_command_to, DaemonCommand, DaemonResponse,
+};
+
+/// How long to wait between pings.
+const PING_INTERVAL: Duration = Duration::from_millis(500);
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Pings the brain `count` times through the daemon and summarizes the round-trip times,
+/// like `ping`. Ctrl+C stops early and still prints the summary.
+pub async fn ping_brain(
+    socket: BufReader<UnixStream>,
+    route: u8,
+    count: u32,
+) -> anyhow::Result<()> {
+    let mut sent = 0;
+    let mut rtts = Vec::new();
+    let pings = async {
+        let mut socket = Some(socket);
+        for seq in 1..=count {
+            if seq > 1 {
+                sleep(PING_INTERVAL).await;
+            }
+            // The daemon serves one request per connection
+            let mut socket = match socket.take() {
+                Some(socket) => socket,
+                None => BufReader::new(connect_to_socket().await?),
+            };
+            send_command_to(&mut socket, route, DaemonCommand::PingBrain).await?;
+            sent += 1;
+            match get_response(&mut socket).await? {
+                DaemonResponse::BrainPing(Some(rtt)) => {
+                    info!(
+                        "Reply from device {route}: seq={seq} time={:.1}ms",
+                        millis(rtt)
+                    );
+                    rtts.push(rtt);
+                }
+                DaemonResponse::BrainPing(None) => info!("No reply from device {route}: seq={seq}"),
+                DaemonResponse::BasicAck { successful: false } => {
+                    bail!("The daemon couldn't ping device {route}")
+                }
+                response => bail!("Unexpected response from daemon: {:?}", response),
+            }
+        }
+        Ok(())
+    };
+    select! {
+        result = pings => result?,
+        _ = ctrl_c() => {}
+    }
+
+    let received = rtts.len();
+    let loss = if sent == 0 {
+        0.0
+    } else {
+        (sent - received) as f64 / sent as f64 * 100.0
+    };
+    info!("{sent} sent, {received} received, {loss:.0}% loss");
+    if let (Some(min), Some(max)) = (rtts.iter().min(), rtts.iter().max()) {
+        let avg = rtts.iter().sum::<Duration>() / received as u32;
+        info!(
+            "Round trip min/avg/max = {:.1}/{:.1}/{:.1} ms",
+            millis(*min),
+            millis(avg),
+            millis(*max)
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,448 @@
+use std::{
+    collections::HashMap,
+    env, fmt,
+    fs::File,
+    io::{self, stderr, Write},
+    os::fd::{AsFd, FromRawFd, RawFd},
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use clap::ValueEnum;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use log::{info, warn};
+use serde_json::{json, Value};
+use v5d_interface::{ProgressRenderer, SectionProgress, TransferError, UploadStep};
+
+const UNICODE_PROGRESS_CHARS: &str = "⣿⣦⣀";
+const ASCII_PROGRESS_CHARS: &str = "#>-";
+
+static ASCII: AtomicBool = AtomicBool::new(false);
+
+/// Returns why the terminal likely can't draw the braille progress characters, or `None`
+/// if it can.
+///
+/// They need a UTF-8 locale, and a terminal other than the Linux console, whose font
+/// lacks them.
+pub(crate) fn unicode_unsupported_reason() -> Option<String> {
+    if let Ok(term @ ("dumb" | "linux")) = env::var("TERM").as_deref() {
+        return Some(format!("TERM={term}"));
+    }
+    // The first of these that is set decides the locale
+    let Some((name, locale)) = ["LC_ALL", "LC_CTYPE", "LANG"].into_iter().find_map(|name| {
+        let value = env::var(name).ok().filter(|value| !value.is_empty())?;
+        Some((name, value))
+    }) else {
+        return Some("no locale is set".to_string());
+    };
+    let lowercase = locale.to_ascii_lowercase();
+    if lowercase.contains("utf-8") || lowercase.contains("utf8") {
+        None
+    } else {
+        Some(format!("{name}={locale} isn't UTF-8"))
+    }
+}
+
+/// Picks the progress bar characters: plain ASCII if `ascii` is set or the terminal doesn't
+/// look like it can draw anything else.
+pub fn set_ascii(ascii: bool) {
+    let ascii = ascii || unicode_unsupported_reason().is_some();
+    ASCII.store(ascii, Ordering::Relaxed);
+}
+
+/// The characters progress bars are drawn with.
+pub(crate) fn progress_chars() -> &'static str {
+    if ASCII.load(Ordering::Relaxed) {
+        ASCII_PROGRESS_CHARS
+    } else {
+        UNICODE_PROGRESS_CHARS
+    }
+}
+
+/// Bar colour for each section.
+fn bar_color(step: UploadStep) -> &'static str {
+    match step {
+        UploadStep::Ini => "green",
+        UploadStep::Cold => "blue",
+        UploadStep::Monolith | UploadStep::Hot => "red",
+    }
+}
+
+/// Draws a progress bar per section on the terminal.
+#[derive(Default)]
+pub struct TerminalRenderer {
+    multi_progress: MultiProgress,
+    bars: HashMap<UploadStep, ProgressBar>,
+}
+impl ProgressRenderer for TerminalRenderer {
+    fn started(&mut self, steps: &[UploadStep]) {
+        for &step in steps {
+            let bar = self
+                .multi_progress
+                .add(ProgressBar::new(10000))
+                .with_style(
+                    ProgressStyle::with_template(&format!(
+                        "{{msg:4}} {{percent_precise:>7}}% {{bar:40.{}}} {{prefix}}",
+                        bar_color(step)
+                    ))
+                    .unwrap()
+                    .progress_chars(progress_chars()),
+                )
+                .with_message(step.to_string());
+            bar.tick();
+            self.bars.insert(step, bar);
+        }
+    }
+
+    fn progress(&mut self, progress: SectionProgress) {
+        if let Some(bar) = self.bars.get(&progress.step) {
+            bar.set_length(progress.total.into());
+            bar.set_position(progress.sent.into());
+            bar.set_prefix(format!("{:.2?}", bar.elapsed()));
+        }
+    }
+
+    fn step_started(&mut self, step: UploadStep) {
+        if let Some(bar) = self.bars.get(&step) {
+            bar.reset_elapsed();
+        }
+    }
+
+    fn finished(&mut self, _result: &Result<(), TransferError>) {
+        for bar in self.bars.values() {
+            bar.finish();
+        }
+    }
+}
+
+/// Logs a line as each section starts and finishes, for when there's no terminal to draw on.
+#[derive(Default)]
+pub struct LineRenderer;
+impl ProgressRenderer for LineRenderer {
+    fn started(&mut self, steps: &[UploadStep]) {
+        let steps = steps.iter().map(ToString::to_string).collect::<Vec<_>>();
+        info!("Uploading sections: {}", steps.join(", "));
+    }
+
+    fn step_started(&mut self, step: UploadStep) {
+        info!("Sending {}...", step);
+    }
+
+    fn progress(&mut self, _progress: SectionProgress) {}
+
+    fn step_finished(&mut self, step: UploadStep, elapsed: Duration) {
+        info!("Sent {} in {:.2?}", step, elapsed);
+    }
+
+    fn finished(&mut self, _result: &Result<(), TransferError>) {}
+}
+
+/// Writes progress as newline-delimited JSON, for editors and other tools to parse.
+///
+/// Each line is one event, with an `event` field saying which:
+///
+/// - `{"event":"started","steps":["Ini","Monolith"]}`: the sections to send, in order
+/// - `{"event":"step_started","step":"Ini"}`
+/// - `{"event":"progress","step":"Ini","sent":512,"total":1024}`: bytes of the section
+///   sent so far, after any compression
+/// - `{"event":"step_finished","step":"Ini","elapsed_ms":120}`
+/// - `{"event":"finished","error":null}`: `error` is `null` on success, or else an object
+///   with `message`, the failed `step` if known, and `program_running`
+///
+/// Writing stops, with a warning, if the destination can't be written to.
+pub struct JsonRenderer {
+    out: Option<File>,
+}
+impl JsonRenderer {
+    pub fn new(out: File) -> Self {
+        Self { out: Some(out) }
+    }
+
+    fn emit(&mut self, event: Value) {
+        let Some(out) = &mut self.out else {
+            return;
+        };
+        if let Err(err) = writeln!(out, "{event}").and_then(|()| out.flush()) {
+            warn!("Stopped writing JSON progress: {err}");
+            self.out = None;
+        }
+    }
+}
+impl ProgressRenderer for JsonRenderer {
+    fn started(&mut self, steps: &[UploadStep]) {
+        self.emit(json!({ "event": "started", "steps": steps }));
+    }
+
+    fn step_started(&mut self, step: UploadStep) {
+        self.emit(json!({ "event": "step_started", "step": step }));
+    }
+
+    fn progress(&mut self, progress: SectionProgress) {
+        let mut event = json!(progress);
+        event["event"] = "progress".into();
+        self.emit(event);
+    }
+
+    fn step_finished(&mut self, step: UploadStep, elapsed: Duration) {
+        self.emit(json!({
+            "event": "step_finished",
+            "step": step,
+            "elapsed_ms": elapsed.as_millis() as u64,
+        }));
+    }
+
+    fn finished(&mut self, result: &Result<(), TransferError>) {
+        self.emit(json!({ "event": "finished", "error": result.as_ref().err() }));
+    }
+}
+
+/// Writes progress as one plain line per record, for shell scripts and build tools to parse.
+///
+/// Fields are separated by single spaces:
+///
+/// - `PROGRESS <section> sending <bytes> <total>`: bytes of the section sent so far, after
+///   any compression. Written at most every [`COMPACT_PROGRESS_INTERVAL`] per section
+/// - `PROGRESS <section> done <total> <total>`: the section was sent completely
+/// - `FINISHED ok`, or `FINISHED failed <message>`: always the last record
+///
+/// Sections are named as in the progress bars: `INI`, `BIN`, `COLD` or `HOT`.
+pub struct CompactRenderer {
+    out: Option<File>,
+    last: Option<(SectionProgress, Instant)>,
+}
+impl CompactRenderer {
+    pub fn new(out: File) -> Self {
+        Self {
+            out: Some(out),
+            last: None,
+        }
+    }
+
+    fn emit(&mut self, record: fmt::Arguments) {
+        let Some(out) = &mut self.out else {
+            return;
+        };
+        if let Err(err) = writeln!(out, "{record}").and_then(|()| out.flush()) {
+            warn!("Stopped writing progress records: {err}");
+            self.out = None;
+        }
+    }
+}
+impl ProgressRenderer for CompactRenderer {
+    fn started(&mut self, _steps: &[UploadStep]) {}
+
+    fn step_started(&mut self, _step: UploadStep) {
+        self.last = None;
+    }
+
+    fn progress(&mut self, progress: SectionProgress) {
+        let due = self
+            .last
+            .is_none_or(|(_, written)| written.elapsed() >= COMPACT_PROGRESS_INTERVAL);
+        if due {
+            self.emit(format_args!(
+                "PROGRESS {} sending {} {}",
+                progress.step, progress.sent, progress.total
+            ));
+            self.last = Some((progress, Instant::now()));
+        } else if let Some((last, _)) = &mut self.last {
+            *last = progress;
+        }
+    }
+
+    fn step_finished(&mut self, step: UploadStep, _elapsed: Duration) {
+        let total = self.last.map_or(0, |(progress, _)| progress.total);
+        self.emit(format_args!("PROGRESS {step} done {total} {total}"));
+    }
+
+    fn finished(&mut self, result: &Result<(), TransferError>) {
+        match result {
+            Ok(()) => self.emit(format_args!("FINISHED ok")),
+            Err(err) => {
+                // Keep the record on one line whatever the message holds
+                let message = err.message.split_whitespace().collect::<Vec<_>>().join(" ");
+                self.emit(format_args!("FINISHED failed {message}"));
+            }
+        }
+    }
+}
+
+/// The least time between two `sending` records of [`CompactRenderer`] for one section.
+pub const COMPACT_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How progress written for other programs is formatted.
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProgressFormat {
+    /// Newline-delimited JSON events
+    #[default]
+    Json,
+    /// One `PROGRESS ...` or `FINISHED ...` line per record
+    Compact,
+}
+
+/// Where `--progress-fd` writes progress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressFd {
+    /// Compact records on stderr, in place of the progress bars.
+    StderrCompact,
+    /// An already-open file descriptor.
+    Fd(RawFd),
+}
+impl FromStr for ProgressFd {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "stderr-compact" {
+            return Ok(Self::StderrCompact);
+        }
+        s.parse()
+            .map(Self::Fd)
+            .map_err(|_| format!("{s:?} isn't a file descriptor or `stderr-compact`"))
+    }
+}
+
+/// Takes ownership of an inherited file descriptor to write progress to.
+///
+/// Fails if the descriptor isn't open, or isn't open for writing.
+pub fn open_progress_fd(fd: RawFd) -> anyhow::Result<File> {
+    // SAFETY: F_GETFL only reads the descriptor's flags, and fails if it isn't open
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        bail!(
+            "--progress-fd {} isn't an open file descriptor: {}",
+            fd,
+            io::Error::last_os_error()
+        );
+    }
+    if flags & libc::O_ACCMODE == libc::O_RDONLY {
+        bail!("--progress-fd {} is only open for reading", fd);
+    }
+    // SAFETY: the caller opened this descriptor for us and nothing else uses it
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// A destination for progress written for other programs, and its format.
+pub struct ProgressOutput {
+    out: File,
+    format: ProgressFormat,
+    /// Whether this takes the place of the progress bars, because it shares their stream.
+    pub replaces_bars: bool,
+}
+impl ProgressOutput {
+    pub fn new(out: File, format: ProgressFormat) -> Self {
+        Self {
+            out,
+            format,
+            replaces_bars: false,
+        }
+    }
+
+    /// Compact records on stderr.
+    pub fn stderr_compact() -> io::Result<Self> {
+        Ok(Self {
+            out: stderr().as_fd().try_clone_to_owned()?.into(),
+            format: ProgressFormat::Compact,
+            replaces_bars: true,
+        })
+    }
+
+    pub fn renderer(&self) -> io::Result<Box<dyn ProgressRenderer>> {
+        let out = self.out.try_clone()?;
+        Ok(match self.format {
+            ProgressFormat::Json => Box::new(JsonRenderer::new(out)),
+            ProgressFormat::Compact => Box::new(CompactRenderer::new(out)),
+        })
+    }
+}
+
+/// Sends every event to two renderers, like progress bars alongside [`JsonRenderer`].
+pub struct TeeRenderer(pub Box<dyn ProgressRenderer>, pub Box<dyn ProgressRenderer>);
+impl ProgressRenderer for TeeRenderer {
+    fn started(&mut self, steps: &[UploadStep]) {
+        self.0.started(steps);
+        self.1.started(steps);
+    }
+
+    fn step_started(&mut self, step: UploadStep) {
+        self.0.step_started(step);
+        self.1.step_started(step);
+    }
+
+    fn progress(&mut self, progress: SectionProgress) {
+        self.0.progress(progress);
+        self.1.progress(progress);
+    }
+
+    fn step_finished(&mut self, step: UploadStep, elapsed: Duration) {
+        self.0.step_finished(step, elapsed);
+        self.1.step_finished(step, elapsed);
+    }
+
+    fn finished(&mut self, result: &Result<(), TransferError>) {
+        self.0.finished(result);
+        self.1.finished(result);
+    }
+}
+
+/// Draws one device's upload as a single line among others, for uploads to several brains.
+///
+/// The bar shows the section being sent. Without a terminal, lines are logged instead, each
+/// marked with the device's route.
+pub struct DeviceRenderer {
+    route: u8,
+    bar: Option<ProgressBar>,
+}
+impl DeviceRenderer {
+    /// Adds a bar for the device to `multi_progress`, or logs lines if it's `None`.
+    pub fn new(route: u8, multi_progress: Option<&MultiProgress>) -> Self {
+        let bar = multi_progress.map(|multi_progress| {
+            multi_progress.add(ProgressBar::new(10000)).with_style(
+                ProgressStyle::with_template(
+                    "device {prefix:>3} {msg:8} {percent_precise:>7}% {bar:40.cyan}",
+                )
+                .unwrap()
+                .progress_chars(progress_chars()),
+            )
+        });
+        if let Some(bar) = &bar {
+            bar.set_prefix(route.to_string());
+            bar.set_message("waiting");
+            bar.tick();
+        }
+        Self { route, bar }
+    }
+}
+impl ProgressRenderer for DeviceRenderer {
+    fn started(&mut self, _steps: &[UploadStep]) {}
+
+    fn step_started(&mut self, step: UploadStep) {
+        match &self.bar {
+            Some(bar) => {
+                bar.set_message(step.to_string());
+                bar.set_position(0);
+            }
+            None => info!("[device {}] Sending {}...", self.route, step),
+        }
+    }
+
+    fn progress(&mut self, progress: SectionProgress) {
+        if let Some(bar) = &self.bar {
+            bar.set_length(progress.total.into());
+            bar.set_position(progress.sent.into());
+        }
+    }
+
+    fn step_finished(&mut self, step: UploadStep, elapsed: Duration) {
+        if self.bar.is_none() {
+            info!("[device {}] Sent {} in {:.2?}", self.route, step, elapsed);
+        }
+    }
+
+    fn finished(&mut self, result: &Result<(), TransferError>) {
+        if let Some(bar) = &self.bar {
+            bar.finish_with_message(if result.is_ok() { "done" } else { "failed" });
+        }
+    }
+}
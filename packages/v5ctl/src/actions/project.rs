@@ -0,0 +1,104 @@
+//! Reading upload settings from a program descriptor, for `upload --from-project`.
+//!
+//! The descriptor is the INI file VEXcode keeps with a project, which is also what the
+//! brain stores next to each program:
+//!
+//! ```ini
+//! [project]
+//! ide = Rust
+//!
+//! [program]
+//! name = My Program
+//! slot = 0
+//! icon = USER029x.bmp
+//! description = Drives the robot
+//! ```
+//!
+//! Only these fields are read. `slot` is zero-based, like the brain's own INI files, and
+//! `ide` becomes the program type. Other sections and keys are ignored, and every field
+//! is optional.
+
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use clap::ValueEnum;
+use v5d_interface::Slot;
+
+use super::upload::ProgramIcon;
+
+/// Upload settings read from a program descriptor.
+#[derive(Debug, Default)]
+pub struct ProjectDescriptor {
+    pub name: Option<String>,
+    pub slot: Option<Slot>,
+    pub icon: Option<ProgramIcon>,
+    pub description: Option<String>,
+    pub program_type: Option<String>,
+}
+
+/// Parses an icon file name like `USER029x.bmp`.
+fn parse_icon(file: &str) -> Option<ProgramIcon> {
+    let number = file.strip_prefix("USER")?.strip_suffix("x.bmp")?;
+    let number = number.parse::<u16>().ok()?;
+    ProgramIcon::value_variants()
+        .iter()
+        .find(|icon| **icon as u16 == number)
+        .copied()
+}
+
+/// Reads the descriptor at `path`.
+pub fn read_descriptor(path: &Path) -> anyhow::Result<ProjectDescriptor> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read program descriptor {}", path.display()))?;
+    parse_descriptor(&text).with_context(|| format!("{} is malformed", path.display()))
+}
+
+fn parse_descriptor(text: &str) -> anyhow::Result<ProjectDescriptor> {
+    let mut descriptor = ProjectDescriptor::default();
+    let mut section = String::new();
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with([';', '#']) {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[') {
+            let Some(name) = name.strip_suffix(']') else {
+                bail!("line {line_number}: unclosed section header");
+            };
+            section = name.trim().to_ascii_lowercase();
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            bail!("line {line_number}: expected `key = value`");
+        };
+        let key = key.trim().to_ascii_lowercase();
+        let value = value.trim().trim_matches('"').to_string();
+
+        match (section.as_str(), key.as_str()) {
+            ("program", "name") => descriptor.name = Some(value),
+            ("program", "description") => descriptor.description = Some(value),
+            ("program", "slot") => {
+                let slot = value
+                    .parse::<u8>()
+                    .ok()
+                    .and_then(|slot| Slot::try_from(slot.checked_add(1)?).ok())
+                    .with_context(|| {
+                        format!(
+                            "line {line_number}: slot {value:?} isn't a slot from 0 to {}",
+                            Slot::MAX - 1
+                        )
+                    })?;
+                descriptor.slot = Some(slot);
+            }
+            ("program", "icon") => {
+                let icon = parse_icon(&value)
+                    .with_context(|| format!("line {line_number}: unknown icon {value:?}"))?;
+                descriptor.icon = Some(icon);
+            }
+            ("project", "ide") => descriptor.program_type = Some(value),
+            _ => {}
+        }
+    }
+    Ok(descriptor)
+}
@@ -0,0 +1,64 @@
+use anyhow::bail;
+use log::info;
+use tokio::{io::BufReader, net::UnixStream};
+use v5d_interface::{get_response, send_command_to, DaemonCommand, DaemonResponse, Slot};
+
+async fn set_protection(
+    socket: &mut BufReader<UnixStream>,
+    route: u8,
+    slot: Slot,
+    protected: bool,
+) -> anyhow::Result<()> {
+    send_command_to(
+        socket,
+        route,
+        DaemonCommand::SetSlotProtection { slot, protected },
+    )
+    .await?;
+    match get_response(socket).await? {
+        DaemonResponse::BasicAck { successful: true } => Ok(()),
+        DaemonResponse::BasicAck { successful: false } => {
+            bail!("The daemon couldn't change the protection of slot {}", slot)
+        }
+        response => bail!("Unexpected response from daemon: {:?}", response),
+    }
+}
+
+/// Protects `slot`, or lists the protected slots if it's `None`.
+pub async fn protect(
+    socket: &mut BufReader<UnixStream>,
+    route: u8,
+    slot: Option<Slot>,
+) -> anyhow::Result<()> {
+    if let Some(slot) = slot {
+        set_protection(socket, route, slot, true).await?;
+        info!("Slot {} is now protected from uploads", slot);
+        return Ok(());
+    }
+
+    send_command_to(socket, route, DaemonCommand::ProtectedSlots).await?;
+    match get_response(socket).await? {
+        DaemonResponse::ProtectedSlots(slots) if slots.is_empty() => {
+            info!("No slots are protected on this brain")
+        }
+        DaemonResponse::ProtectedSlots(slots) => {
+            let slots = slots.iter().map(ToString::to_string).collect::<Vec<_>>();
+            info!("Protected slots: {}", slots.join(", "));
+        }
+        DaemonResponse::BasicAck { successful: false } => {
+            bail!("The daemon couldn't read the protected slots")
+        }
+        response => bail!("Unexpected response from daemon: {:?}", response),
+    }
+    Ok(())
+}
+
+pub async fn unprotect(
+    socket: &mut BufReader<UnixStream>,
+    route: u8,
+    slot: Slot,
+) -> anyhow::Result<()> {
+    set_protection(socket, route, slot, false).await?;
+    info!("Slot {} is no longer protected", slot);
+    Ok(())
+}
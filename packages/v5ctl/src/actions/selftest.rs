@@ -0,0 +1,182 @@
+use std::{
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use log::{error, info};
+use tokio::{io::BufReader, net::UnixStream, signal::ctrl_c, time::sleep};
+use v5d_interface::{
+    connect_to_socket, get_response, read_user, send_command_to, upload_steps, write_user,
+    AfterFileUpload, DaemonCommand, DaemonResponse, ProgramData, ProgramUpload, Slot, Transfer,
+};
+
+use super::{interaction::confirm, progress::TerminalRenderer, upload::ProgramIcon};
+
+/// How long each stage may take before it counts as failed.
+const STAGE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the brain is polled while waiting on a stage.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Sends one command on its own connection and returns the reply.
+async fn request(route: u8, command: DaemonCommand) -> anyhow::Result<DaemonResponse> {
+    let mut socket = BufReader::new(connect_to_socket().await?);
+    send_command_to(&mut socket, route, command).await?;
+    Ok(get_response(&mut socket).await?)
+}
+
+/// Returns the slot of the program running on the brain.
+async fn running_slot(route: u8) -> anyhow::Result<Option<u8>> {
+    match request(route, DaemonCommand::Uptime).await? {
+        DaemonResponse::Uptime(uptime) => Ok(uptime.program.map(|program| program.slot)),
+        response => bail!("Unexpected response from daemon: {:?}", response),
+    }
+}
+
+/// Polls until the running program is `expected`, or the stage times out.
+async fn wait_for_running(route: u8, expected: Option<u8>) -> anyhow::Result<()> {
+    let start = Instant::now();
+    while running_slot(route).await? != expected {
+        if start.elapsed() > STAGE_TIMEOUT {
+            bail!("timed out after {}s", STAGE_TIMEOUT.as_secs());
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+    Ok(())
+}
+
+async fn upload_stage(
+    socket: BufReader<UnixStream>,
+    route: u8,
+    slot: Slot,
+    program: &Path,
+) -> anyhow::Result<()> {
+    let data = ProgramData::Monolith(
+        std::fs::read(program).with_context(|| format!("reading {}", program.display()))?,
+    );
+    let steps = upload_steps(&data);
+    let upload = ProgramUpload {
+        name: "v5ctl self-test".to_string(),
+        description: "Echoes its input. Safe to delete".to_string(),
+        icon: format!("USER{:03}x.bmp", ProgramIcon::default() as u16),
+        program_type: "Self-test".to_string(),
+        slot,
+        compression: true,
+        after_upload: AfterFileUpload::RunProgram,
+        data,
+        replace: true,
+        overwrite_protected: false,
+    };
+    let transfer = Transfer::start(socket, route, DaemonCommand::UploadProgram(upload)).await?;
+    let cancel = async {
+        let _ = ctrl_c().await;
+    };
+    transfer
+        .run(&steps, &mut TerminalRenderer::default(), cancel)
+        .await?;
+    Ok(())
+}
+
+/// Writes a unique line to the program and waits for it to be echoed back.
+async fn echo_stage(route: u8) -> anyhow::Result<()> {
+    let nonce = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let pattern = format!("v5ctl-selftest-{:x}\n", nonce);
+
+    let mut socket = BufReader::new(connect_to_socket().await?);
+    let mut remaining = pattern.as_bytes();
+    while !remaining.is_empty() {
+        let written = write_user(&mut socket, route, remaining.to_vec()).await?;
+        remaining = &remaining[written..];
+    }
+
+    // The program may print other things too, so look for the pattern anywhere in its output
+    let start = Instant::now();
+    let mut output = Vec::new();
+    loop {
+        output.extend(read_user(&mut socket, route, 1024).await?);
+        if output
+            .windows(pattern.len())
+            .any(|window| window == pattern.as_bytes())
+        {
+            return Ok(());
+        }
+        if start.elapsed() > STAGE_TIMEOUT {
+            bail!(
+                "the program didn't echo {:?} within {}s (it printed {:?})",
+                pattern.trim_end(),
+                STAGE_TIMEOUT.as_secs(),
+                String::from_utf8_lossy(&output)
+            );
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+async fn stop_stage(route: u8) -> anyhow::Result<()> {
+    match request(route, DaemonCommand::StopProgram).await? {
+        DaemonResponse::BasicAck { successful: true } => {}
+        DaemonResponse::BasicAck { successful: false } => bail!("the brain refused to stop it"),
+        response => bail!("Unexpected response from daemon: {:?}", response),
+    }
+    wait_for_running(route, None).await
+}
+
+async fn remove_stage(route: u8, slot: Slot) -> anyhow::Result<()> {
+    match request(route, DaemonCommand::EraseSlot { slot }).await? {
+        DaemonResponse::TransferComplete(result) => Ok(result?),
+        response => bail!("Unexpected response from daemon: {:?}", response),
+    }
+}
+
+/// Logs whether a stage passed, passing on its error so later stages are skipped.
+fn report(stage: &str, result: anyhow::Result<()>) -> anyhow::Result<()> {
+    match result {
+        Ok(()) => {
+            info!("[pass] {}", stage);
+            Ok(())
+        }
+        Err(err) => {
+            error!("[FAIL] {}: {:#}", stage, err);
+            bail!("Device self-test failed at the {} stage", stage)
+        }
+    }
+}
+
+/// Checks upload, running, user I/O and stopping end to end with a known-good program.
+///
+/// `program` must be a monolith binary that echoes each byte it reads on stdin back to stdout.
+pub async fn selftest_device(
+    socket: BufReader<UnixStream>,
+    route: u8,
+    slot: Slot,
+    program: &Path,
+    keep: bool,
+    yes: bool,
+) -> anyhow::Result<()> {
+    if !yes {
+        let question = format!(
+            "This overwrites slot {} on device {}{}. Continue?",
+            slot,
+            route,
+            if keep { "" } else { " and then deletes it" }
+        );
+        if !confirm(&question, "--yes")? {
+            info!("Cancelled");
+            return Ok(());
+        }
+    }
+
+    report("upload", upload_stage(socket, route, slot, program).await)?;
+    report("run", wait_for_running(route, Some(slot.get())).await)?;
+    report("echo", echo_stage(route).await)?;
+    report("stop", stop_stage(route).await)?;
+    if !keep {
+        report("remove", remove_stage(route, slot).await)?;
+    }
+
+    info!("Device self-test passed. Upload, run, terminal and stop all work");
+    Ok(())
+}
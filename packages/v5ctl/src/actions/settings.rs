@@ -0,0 +1,72 @@
+use log::{error, info, warn};
+use tokio::{io::BufReader, net::UnixStream};
+use v5d_interface::{get_response, send_command_to, DaemonCommand, DaemonResponse};
+
+/// Reads a key-value setting from the brain.
+/// Returns `None` if the brain doesn't support the setting.
+pub async fn read_setting(
+    socket: &mut BufReader<UnixStream>,
+    route: u8,
+    key: &str,
+) -> anyhow::Result<Option<String>> {
+    send_command_to(
+        socket,
+        route,
+        DaemonCommand::ReadKeyValue {
+            key: key.to_string(),
+        },
+    )
+    .await?;
+    match get_response(socket).await? {
+        DaemonResponse::KeyValue(value) => Ok(value),
+        DaemonResponse::BasicAck { successful: false } => {
+            anyhow::bail!("Failed to read setting {:?}", key)
+        }
+        _ => anyhow::bail!("Unexpected response from daemon"),
+    }
+}
+
+/// Writes a key-value setting to the brain.
+/// Returns `false` if the brain rejected the setting.
+pub async fn write_setting(
+    socket: &mut BufReader<UnixStream>,
+    route: u8,
+    key: &str,
+    value: String,
+) -> anyhow::Result<bool> {
+    send_command_to(
+        socket,
+        route,
+        DaemonCommand::WriteKeyValue {
+            key: key.to_string(),
+            value,
+        },
+    )
+    .await?;
+    match get_response(socket).await? {
+        DaemonResponse::BasicAck { successful } => Ok(successful),
+        _ => anyhow::bail!("Unexpected response from daemon"),
+    }
+}
+
+pub async fn kv(
+    socket: &mut BufReader<UnixStream>,
+    route: u8,
+    key: String,
+    value: Option<String>,
+) -> anyhow::Result<()> {
+    if let Some(value) = value {
+        if write_setting(socket, route, &key, value).await? {
+            info!("Updated {}", key);
+        } else {
+            error!("The brain rejected the value for {}", key);
+        }
+    } else {
+        match read_setting(socket, route, &key).await? {
+            Some(value) => info!("{} = {:?}", key, value),
+            None => warn!("{} is unsupported by this brain", key),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,148 @@
+//! `v5ctl snapshot`: everything the read-only commands report, as one JSON document.
+
+use std::{
+    path::Path,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::bail;
+use log::info;
+use serde_json::{json, Value};
+use tokio::io::BufReader;
+use v5d_interface::{
+    connect_to_socket, get_response, send_command, send_command_to, DaemonCommand, DaemonResponse,
+};
+
+use super::temp::{write_atomically, write_new_atomically};
+
+/// Sends one command on its own connection. `route` is `None` for commands to the daemon.
+async fn query(route: Option<u8>, command: DaemonCommand) -> anyhow::Result<DaemonResponse> {
+    let mut socket = BufReader::new(connect_to_socket().await?);
+    match route {
+        Some(route) => send_command_to(&mut socket, route, command).await?,
+        None => send_command(&mut socket, command).await?,
+    }
+    match get_response(&mut socket).await? {
+        DaemonResponse::BasicAck { successful: false } => bail!("The daemon reported a failure"),
+        response => Ok(response),
+    }
+}
+
+/// A field that couldn't be read, in place of its value.
+fn error(err: impl std::fmt::Display) -> Value {
+    json!({ "error": err.to_string() })
+}
+
+/// A field read from the daemon, or the error reading it.
+fn field(
+    response: anyhow::Result<DaemonResponse>,
+    read: impl FnOnce(DaemonResponse) -> Option<Value>,
+) -> Value {
+    match response {
+        Ok(response) => {
+            let unexpected = format!("Unexpected response from daemon: {response:?}");
+            read(response).unwrap_or_else(|| error(unexpected))
+        }
+        Err(err) => error(err),
+    }
+}
+
+/// Reads everything the read-only commands report about the device on `route`.
+///
+/// A query that fails leaves an `{"error": ...}` object in its field instead of failing
+/// the snapshot.
+async fn take_snapshot(route: u8) -> Value {
+    let started = Instant::now();
+    let taken_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let device = field(query(None, DaemonCommand::ListDevices).await, |response| {
+        let DaemonResponse::Devices(devices) = response else {
+            return None;
+        };
+        Some(match devices.iter().find(|device| device.route == route) {
+            Some(device) => json!(device),
+            None => error(format!("No device is bound to route {route}")),
+        })
+    });
+    let uptime = field(
+        query(Some(route), DaemonCommand::Uptime).await,
+        |response| match response {
+            DaemonResponse::Uptime(uptime) => Some(json!(uptime)),
+            _ => None,
+        },
+    );
+    let ping = field(
+        query(Some(route), DaemonCommand::PingBrain).await,
+        |response| match response {
+            DaemonResponse::BrainPing(rtt) => {
+                Some(json!(rtt.map(|rtt| rtt.as_secs_f64() * 1000.0)))
+            }
+            _ => None,
+        },
+    );
+    let controllers = field(
+        query(Some(route), DaemonCommand::ControllerStatus).await,
+        |response| match response {
+            DaemonResponse::ControllerStatus(status) => Some(json!(status)),
+            _ => None,
+        },
+    );
+    let field_control = field(
+        query(Some(route), DaemonCommand::FieldControlStatus).await,
+        |response| match response {
+            DaemonResponse::FieldControlStatus(status) => Some(json!(status)),
+            _ => None,
+        },
+    );
+    let boot_state = field(
+        query(Some(route), DaemonCommand::BootState).await,
+        |response| match response {
+            DaemonResponse::BootState(state) => Some(json!(state)),
+            _ => None,
+        },
+    );
+    let firmware = field(
+        query(Some(route), DaemonCommand::FirmwareStatus).await,
+        |response| match response {
+            DaemonResponse::FirmwareStatus(status) => Some(json!(status)),
+            _ => None,
+        },
+    );
+
+    json!({
+        "v5ctl_version": env!("CARGO_PKG_VERSION"),
+        "taken_at": taken_at,
+        "duration_ms": started.elapsed().as_millis() as u64,
+        "route": route,
+        "device": device,
+        "uptime": uptime,
+        "ping_ms": ping,
+        "controllers": controllers,
+        "field_control": field_control,
+        "boot_state": boot_state,
+        "firmware": firmware,
+    })
+}
+
+/// Writes a snapshot of the device on `route` to `output`, or to stdout.
+///
+/// An existing output file is only replaced with `force`.
+pub async fn snapshot(route: u8, output: Option<&Path>, force: bool) -> anyhow::Result<()> {
+    let snapshot = serde_json::to_string_pretty(&take_snapshot(route).await)?;
+    match output {
+        Some(path) => {
+            let contents = (snapshot + "\n").into_bytes();
+            if force {
+                write_atomically(path, &contents)?;
+            } else {
+                write_new_atomically(path, &contents)?;
+            }
+            info!("Wrote the snapshot to {}", path.display());
+        }
+        None => println!("{snapshot}"),
+    }
+    Ok(())
+}
@@ -0,0 +1,181 @@
+//! Writing output files so an interrupted command never leaves a partial one behind.
+//!
+//! Files are written to `<path>.v5tmp-<pid>` and renamed into place once complete. If v5ctl
+//! dies first, the temporary file is left behind with the PID that wrote it, which
+//! `v5ctl clean-temp` uses to tell abandoned files from ones still being written.
+
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use log::{info, warn};
+
+use super::interaction::confirm;
+
+const TEMP_MARKER: &str = ".v5tmp-";
+
+/// How old an abandoned temporary file must be before it is cleaned up.
+const STALE_AFTER: Duration = Duration::from_secs(10 * 60);
+
+/// Writes `contents` to `path`, replacing it only once the whole file is written.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    write_through_temp(path, contents, true)
+}
+
+/// Like [`write_atomically`], but fails if `path` already exists, even if another process
+/// creates it while this one is writing.
+pub fn write_new_atomically(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    write_through_temp(path, contents, false)
+}
+
+fn write_through_temp(path: &Path, contents: &[u8], replace: bool) -> anyhow::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!("{TEMP_MARKER}{}", process::id()));
+    let temp = PathBuf::from(temp);
+
+    let written = (|| -> io::Result<()> {
+        let mut file = File::create(&temp)?;
+        file.write_all(contents)?;
+        // On disk before the rename, so a crash can't leave `path` naming a partial file
+        file.sync_all()?;
+        if replace {
+            fs::rename(&temp, path)
+        } else {
+            // Unlike a rename, linking never replaces what's there
+            fs::hard_link(&temp, path)
+        }
+    })();
+    if !replace || written.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    match written {
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            bail!("{} already exists", path.display())
+        }
+        written => written.with_context(|| format!("Failed to write {}", path.display())),
+    }
+}
+
+/// Returns whether a process with this PID is running.
+fn is_running(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+/// Finds temporary files in `dir` whose writer has exited and that haven't changed lately.
+fn find_stale(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut stale = Vec::new();
+    let entries = fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some((_, pid)) = name.to_str().and_then(|name| name.rsplit_once(TEMP_MARKER)) else {
+            continue;
+        };
+        let Ok(pid) = pid.parse::<u32>() else {
+            continue;
+        };
+        let age = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok());
+        if !is_running(pid) && age.is_some_and(|age| age > STALE_AFTER) {
+            stale.push(entry.path());
+        }
+    }
+    stale.sort();
+    Ok(stale)
+}
+
+/// Removes the temporary files interrupted commands left in `dir`, asking first unless `yes`.
+pub fn clean_temp(dir: &Path, yes: bool) -> anyhow::Result<()> {
+    let stale = find_stale(dir)?;
+    if stale.is_empty() {
+        info!("No leftover temporary files in {}", dir.display());
+        return Ok(());
+    }
+    for path in &stale {
+        info!("Leftover: {}", path.display());
+    }
+    if !yes && !confirm(&format!("Remove {} file(s)?", stale.len()), "--yes")? {
+        info!("Cancelled");
+        return Ok(());
+    }
+
+    let mut removed = 0;
+    for path in &stale {
+        match fs::remove_file(path) {
+            Ok(()) => removed += 1,
+            Err(err) => warn!("Couldn't remove {}: {}", path.display(), err),
+        }
+    }
+    info!("Removed {} file(s)", removed);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{process::Command, time::SystemTime};
+
+    use super::*;
+
+    /// A fresh directory for one test's files.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("v5ctl-temp-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// The PID of a process that has exited.
+    fn dead_pid() -> u32 {
+        let mut child = Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        child.id()
+    }
+
+    /// Leaves a temporary file as a v5ctl with `pid` would if it died `age` into writing it.
+    fn leave_temp(dir: &Path, name: &str, pid: u32, age: Duration) -> PathBuf {
+        let path = dir.join(format!("{name}{TEMP_MARKER}{pid}"));
+        let file = File::create(&path).unwrap();
+        file.set_modified(SystemTime::now() - age).unwrap();
+        path
+    }
+
+    #[test]
+    fn only_old_files_of_dead_writers_are_cleaned_up() {
+        let dir = test_dir("crash");
+        let old = STALE_AFTER * 2;
+        let abandoned = leave_temp(&dir, "snapshot.json", dead_pid(), old);
+        let recent = leave_temp(&dir, "recent.json", dead_pid(), Duration::ZERO);
+        let still_writing = leave_temp(&dir, "writing.json", process::id(), old);
+        fs::write(dir.join("snapshot.json"), "{}").unwrap();
+
+        assert_eq!(find_stale(&dir).unwrap(), std::slice::from_ref(&abandoned));
+        clean_temp(&dir, true).unwrap();
+        assert!(!abandoned.exists());
+        assert!(recent.exists() && still_writing.exists());
+        assert!(dir.join("snapshot.json").exists());
+    }
+
+    #[test]
+    fn writes_replace_only_when_asked_to() {
+        let dir = test_dir("clobber");
+        let path = dir.join("snapshot.json");
+
+        write_new_atomically(&path, b"first").unwrap();
+        let err = write_new_atomically(&path, b"second").unwrap_err();
+        assert!(err.to_string().contains("already exists"), "{err}");
+        assert_eq!(fs::read(&path).unwrap(), b"first");
+
+        write_atomically(&path, b"third").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"third");
+        // Neither leaves its temporary file behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    }
+}
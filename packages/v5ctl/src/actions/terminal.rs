@@ -0,0 +1,368 @@
+use std::{
+    io::{self, stdout, IsTerminal, Stdout, Write},
+    mem::MaybeUninit,
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use log::info;
+use tokio::{
+    io::{copy, stdin, AsyncReadExt, AsyncWriteExt, BufReader, Stdin},
+    signal::ctrl_c,
+    time::sleep,
+};
+use v5d_interface::{connect_to_socket, read_user, UserIo};
+
+/// What a Rust program prints when it panics.
+///
+/// The brain gives programs a single output stream, so this is the only way to tell a panic
+/// apart from normal output.
+pub(crate) const PANIC_MARKER: &[u8] = b"panicked at";
+
+/// How long to wait before polling the program's output again when there was none.
+const EMPTY_READ_BACKOFF: Duration = Duration::from_millis(10);
+/// How long the program must stay quiet after panicking before its output is normal again.
+const PANIC_QUIET_PERIOD: Duration = Duration::from_secs(1);
+
+/// How `v5ctl terminal` shows the program's output and sends it input.
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TerminalMode {
+    /// Pass output through unchanged and send each key as it's pressed, for programs that
+    /// draw their own interface
+    Raw,
+    /// Print output a whole line at a time
+    #[default]
+    Line,
+    /// Like `line`, but also show a partial line, like a prompt, once the program pauses.
+    /// Input is echoed and can be edited with Backspace until Enter sends it
+    Cooked,
+}
+
+/// Writes the program's output to stdout, highlighting panics.
+///
+/// Except in raw mode, output from a line containing [`PANIC_MARKER`] until the program
+/// goes quiet is drawn in red, or prefixed with `[panic]` when stdout isn't a terminal.
+/// Complete lines are classified as they arrive. A partial line is held until its newline,
+/// or in cooked mode until the program stops sending more.
+struct ProgramOutput<W: Write = Stdout> {
+    out: W,
+    mode: TerminalMode,
+    color: bool,
+    pending: Vec<u8>,
+    panicking: bool,
+    at_line_start: bool,
+    quiet_since: Option<Instant>,
+}
+impl ProgramOutput {
+    fn new(mode: TerminalMode) -> Self {
+        Self::with_writer(stdout(), mode, stdout().is_terminal())
+    }
+}
+impl<W: Write> ProgramOutput<W> {
+    fn with_writer(out: W, mode: TerminalMode, color: bool) -> Self {
+        Self {
+            out,
+            mode,
+            color,
+            pending: Vec::new(),
+            panicking: false,
+            at_line_start: true,
+            quiet_since: None,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) -> io::Result<()> {
+        self.quiet_since = None;
+        if self.mode == TerminalMode::Raw {
+            self.out.write_all(data)?;
+            return self.out.flush();
+        }
+        self.pending.extend_from_slice(data);
+        while let Some(end) = self.pending.iter().position(|&byte| byte == b'\n') {
+            let line = self.pending.drain(..=end).collect::<Vec<_>>();
+            self.write(&line)?;
+        }
+        Ok(())
+    }
+
+    /// Called when a read returned nothing.
+    fn quiet(&mut self) -> io::Result<()> {
+        if self.mode == TerminalMode::Cooked {
+            self.write_pending()?;
+        }
+        let quiet_since = *self.quiet_since.get_or_insert_with(Instant::now);
+        if self.panicking && quiet_since.elapsed() > PANIC_QUIET_PERIOD {
+            self.panicking = false;
+        }
+        Ok(())
+    }
+
+    /// Writes what's left of a partial line.
+    fn write_pending(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let partial = std::mem::take(&mut self.pending);
+        self.write(&partial)
+    }
+
+    fn write(&mut self, text: &[u8]) -> io::Result<()> {
+        if text
+            .windows(PANIC_MARKER.len())
+            .any(|window| window == PANIC_MARKER)
+        {
+            self.panicking = true;
+        }
+
+        let out = &mut self.out;
+        match (self.panicking, self.color) {
+            (false, _) => out.write_all(text)?,
+            (true, true) => {
+                let (text, newline) = match text.strip_suffix(b"\n") {
+                    Some(text) => (text, "\n"),
+                    None => (text, ""),
+                };
+                out.write_all(b"\x1b[1;31m")?;
+                out.write_all(text)?;
+                write!(out, "\x1b[0m{newline}")?;
+            }
+            (true, false) => {
+                if self.at_line_start {
+                    out.write_all(b"[panic] ")?;
+                }
+                out.write_all(text)?;
+            }
+        }
+        self.at_line_start = text.ends_with(b"\n");
+        out.flush()
+    }
+}
+
+/// Echoes typed input and lets it be edited until Enter, for cooked mode.
+///
+/// The editing is done here rather than by the terminal, so it behaves the same whatever
+/// erase character the terminal is set up with.
+struct LineEditor<W: Write = Stdout> {
+    echo: W,
+    line: Vec<u8>,
+    after_cr: bool,
+}
+impl<W: Write> LineEditor<W> {
+    fn new(echo: W) -> Self {
+        Self {
+            echo,
+            line: Vec::new(),
+            after_cr: false,
+        }
+    }
+
+    /// Handles typed bytes, returning the lines they finished, each ending in `\n`.
+    fn push(&mut self, typed: &[u8]) -> io::Result<Vec<u8>> {
+        let mut finished = Vec::new();
+        for &byte in typed {
+            // Enter may arrive as CR, LF or CRLF depending on the terminal
+            let after_cr = std::mem::replace(&mut self.after_cr, byte == b'\r');
+            match byte {
+                b'\n' if after_cr => {}
+                b'\r' | b'\n' => {
+                    self.line.push(b'\n');
+                    finished.append(&mut self.line);
+                    self.echo.write_all(b"\r\n")?;
+                }
+                // DEL is what most terminals send for Backspace
+                0x7F | 0x08 => {
+                    if self.line.is_empty() {
+                        continue;
+                    }
+                    // Remove a whole UTF-8 character, continuation bytes first
+                    while self.line.pop().is_some_and(|byte| byte & 0xC0 == 0x80) {}
+                    self.echo.write_all(b"\x08 \x08")?;
+                }
+                _ => {
+                    self.line.push(byte);
+                    self.echo.write_all(&[byte])?;
+                }
+            }
+        }
+        self.echo.flush()?;
+        Ok(finished)
+    }
+}
+
+/// Sends stdin to the program until stdin closes, through `editor` if there is one.
+async fn send_input(
+    stdin: &mut Stdin,
+    program_in: &mut UserIo,
+    editor: Option<LineEditor>,
+) -> io::Result<()> {
+    let Some(mut editor) = editor else {
+        copy(stdin, program_in).await?;
+        return Ok(());
+    };
+    let mut typed = [0; 256];
+    loop {
+        let len = stdin.read(&mut typed).await?;
+        if len == 0 {
+            return Ok(());
+        }
+        let lines = editor.push(&typed[..len])?;
+        program_in.write_all(&lines).await?;
+    }
+}
+
+/// Changes the settings of the terminal on stdin, restoring them when dropped.
+struct TtySettings {
+    original: libc::termios,
+}
+impl TtySettings {
+    /// Sets up stdin's terminal for `mode`. Returns `None` if nothing needs changing.
+    fn apply(mode: TerminalMode) -> io::Result<Option<Self>> {
+        if mode == TerminalMode::Line || !std::io::stdin().is_terminal() {
+            return Ok(None);
+        }
+        let mut original = MaybeUninit::uninit();
+        // SAFETY: stdin is a terminal, and tcgetattr fills in the whole struct on success
+        let original = unsafe {
+            if libc::tcgetattr(libc::STDIN_FILENO, original.as_mut_ptr()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            original.assume_init()
+        };
+
+        // Keys are sent as they're pressed, or in cooked mode edited by `LineEditor`.
+        // Signals stay on, so Ctrl+C still closes the terminal
+        let mut settings = original;
+        settings.c_lflag &= !(libc::ICANON | libc::ECHO);
+        settings.c_cc[libc::VMIN] = 1;
+        settings.c_cc[libc::VTIME] = 0;
+        set_tty(&settings)?;
+        Ok(Some(Self { original }))
+    }
+}
+impl Drop for TtySettings {
+    fn drop(&mut self) {
+        let _ = set_tty(&self.original);
+    }
+}
+
+fn set_tty(settings: &libc::termios) -> io::Result<()> {
+    // SAFETY: `settings` is a valid termios struct
+    if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, settings) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Connects the user program's stdio to this terminal until either side closes or Ctrl+C
+/// is pressed.
+pub async fn terminal(route: u8, mode: TerminalMode) -> anyhow::Result<()> {
+    info!("Connected to user program. Press Ctrl+C to exit.");
+
+    let tty = TtySettings::apply(mode)?;
+    let editor = (mode == TerminalMode::Cooked && tty.is_some()).then(|| LineEditor::new(stdout()));
+    let mut output = ProgramOutput::new(mode);
+    // One connection each way for the whole session, instead of one per poll
+    let mut socket = BufReader::new(connect_to_socket().await?);
+    let mut program_in = UserIo::connect(route).await?;
+    let program_out = async {
+        loop {
+            let data = read_user(&mut socket, route, 1024).await?;
+            if data.is_empty() {
+                output.quiet()?;
+                sleep(EMPTY_READ_BACKOFF).await;
+            } else {
+                output.push(&data)?;
+            }
+        }
+    };
+    let mut stdin = stdin();
+    // Returning instead of being killed by Ctrl+C puts the terminal's settings back
+    tokio::select! {
+        result = program_out => return result,
+        result = send_input(&mut stdin, &mut program_in, editor) => {
+            result?;
+        }
+        _ = ctrl_c() => {}
+    };
+    output.write_pending()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(mode: TerminalMode) -> ProgramOutput<Vec<u8>> {
+        ProgramOutput::with_writer(Vec::new(), mode, false)
+    }
+
+    #[test]
+    fn line_mode_holds_partial_lines() {
+        let mut output = output(TerminalMode::Line);
+        output.push(b"hel").unwrap();
+        output.quiet().unwrap();
+        assert_eq!(output.out, b"");
+        output.push(b"lo\nwor").unwrap();
+        assert_eq!(output.out, b"hello\n");
+        output.push(b"ld\n").unwrap();
+        assert_eq!(output.out, b"hello\nworld\n");
+    }
+
+    #[test]
+    fn cooked_mode_shows_partial_lines_once_quiet() {
+        let mut output = output(TerminalMode::Cooked);
+        output.push(b"name? ").unwrap();
+        assert_eq!(output.out, b"");
+        output.quiet().unwrap();
+        assert_eq!(output.out, b"name? ");
+        output.push(b"ok\n").unwrap();
+        assert_eq!(output.out, b"name? ok\n");
+    }
+
+    #[test]
+    fn crlf_lines_are_kept_whole() {
+        let mut output = output(TerminalMode::Line);
+        output.push(b"one\r").unwrap();
+        assert_eq!(output.out, b"");
+        output.push(b"\ntwo\r\n").unwrap();
+        assert_eq!(output.out, b"one\r\ntwo\r\n");
+    }
+
+    #[test]
+    fn panics_are_marked_until_the_program_goes_quiet() {
+        let mut output = output(TerminalMode::Line);
+        output
+            .push(b"ok\npanicked at src/main.rs:1:1\nmore\n")
+            .unwrap();
+        assert_eq!(
+            output.out,
+            b"ok\n[panic] panicked at src/main.rs:1:1\n[panic] more\n"
+        );
+    }
+
+    #[test]
+    fn raw_mode_passes_output_through() {
+        let mut output = output(TerminalMode::Raw);
+        output.push(b"\x1b[2Jpartial").unwrap();
+        assert_eq!(output.out, b"\x1b[2Jpartial");
+    }
+
+    #[test]
+    fn cooked_input_is_sent_a_line_at_a_time() {
+        let mut editor = LineEditor::new(Vec::new());
+        assert_eq!(editor.push(b"abc").unwrap(), b"");
+        assert_eq!(editor.push(b"\rde").unwrap(), b"abc\n");
+        assert_eq!(editor.push(b"\r\nf\n").unwrap(), b"de\nf\n");
+        assert_eq!(editor.echo, b"abc\r\nde\r\nf\r\n");
+    }
+
+    #[test]
+    fn backspace_edits_cooked_input() {
+        let mut editor = LineEditor::new(Vec::new());
+        assert_eq!(editor.push(b"\x7fab\x7fc\r").unwrap(), b"ac\n");
+        assert_eq!(editor.echo, b"ab\x08 \x08c\r\n");
+        // A multibyte character is removed whole
+        assert_eq!(editor.push("xé\x08\r".as_bytes()).unwrap(), b"x\n");
+    }
+}
@@ -1,19 +1,45 @@
-use std::{path::PathBuf, time::Instant};
+use std::{
+    io::{stderr, stdout, IsTerminal, Write},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
+use anyhow::{bail, Context};
 use clap::ValueEnum;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use log::{error, info};
-use tokio::{io::BufReader, net::UnixStream};
+use indicatif::MultiProgress;
+use log::{debug, error, info, warn};
+use tokio::{
+    io::BufReader,
+    net::UnixStream,
+    signal::ctrl_c,
+    task::{spawn_local, LocalSet},
+    time::sleep,
+};
 use v5d_interface::{
-    get_response, send_command, AfterFileUpload, DaemonCommand, DaemonResponse, ProgramData,
-    UploadStep,
+    connect_to_socket, get_response, layout::ProgramRegion, read_user, send_command_to,
+    upload_steps, AfterFileUpload, DaemonCommand, DaemonResponse, ProgramData, ProgramUpload,
+    ProgressRenderer, Slot, Transfer, TransferError, UploadStep, MAX_PROGRAM_DESCRIPTION_LEN,
+    MAX_PROGRAM_NAME_LEN, MAX_PROGRAM_TYPE_LEN,
+};
+
+use super::{
+    cargo::{self, CargoArtifact},
+    progress::{DeviceRenderer, LineRenderer, ProgressOutput, TeeRenderer, TerminalRenderer},
 };
 
-#[derive(ValueEnum, Debug, Clone, Copy, Default)]
+/// What the brain does once an upload finishes.
+///
+/// Each variant maps to its own exit action. The firmware has no action that both
+/// starts the program and shows its run screen, so `run` is the only one that starts it.
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub enum AfterUpload {
-    #[default]
+    /// Stay on whatever screen the brain was showing. The program doesn't start
     None,
+    /// Start the program immediately
     Run,
+    /// Open the program's run screen, where it can be started from the brain. The program doesn't start
+    #[default]
+    #[value(alias = "run-screen")]
     ShowScreen,
 }
 impl From<AfterUpload> for AfterFileUpload {
@@ -26,7 +52,7 @@ impl From<AfterUpload> for AfterFileUpload {
     }
 }
 
-#[derive(Default, Debug, ValueEnum, Clone, Copy)]
+#[derive(Default, Debug, ValueEnum, Clone, Copy, PartialEq, Eq)]
 #[repr(u16)]
 pub enum ProgramIcon {
     VexCodingStudio = 0,
@@ -60,80 +86,55 @@ pub enum ProgramIcon {
     VexcodeCpp = 926,
 }
 
-const PROGRESS_CHARS: &str = "⣿⣦⣀";
-
-pub async fn upload(
-    socket: &mut BufReader<UnixStream>,
-    monolith: Option<PathBuf>,
-    hot: Option<PathBuf>,
-    cold: Option<PathBuf>,
-    slot: u8,
-    name: Option<String>,
-    description: Option<String>,
-    icon: ProgramIcon,
-    program_type: Option<String>,
-    uncompressed: bool,
-    after_upload: AfterUpload,
-) -> anyhow::Result<()> {
-    let multi_progress = MultiProgress::new();
-
-    let ini_progress = multi_progress
-        .add(ProgressBar::new(10000))
-        .with_style(
-            ProgressStyle::with_template("{msg:4} {percent_precise:>7}% {bar:40.green} {prefix}")
-                .unwrap()
-                .progress_chars(PROGRESS_CHARS),
-        )
-        .with_message("INI");
-
-    let cold_progress = if cold.is_some() {
-        let bar = multi_progress
-            .add(ProgressBar::new(10000))
-            .with_style(
-                ProgressStyle::with_template(
-                    "{msg:4} {percent_precise:>7}% {bar:40.blue} {prefix}",
-                )
-                .unwrap()
-                .progress_chars(PROGRESS_CHARS),
-            )
-            .with_message("COLD");
-
-        Some(bar)
-    } else {
-        None
+/// Makes sure every binary fits in the memory region the brain will load it into.
+pub(super) fn check_layout(data: &ProgramData) -> anyhow::Result<()> {
+    let sections = match data {
+        ProgramData::Monolith(monolith) => {
+            vec![("Monolith", ProgramRegion::MONOLITH, Some(monolith))]
+        }
+        ProgramData::HotCold { hot, cold } => vec![
+            ("Hot", ProgramRegion::HOT, hot.as_ref()),
+            ("Cold", ProgramRegion::COLD, cold.as_ref()),
+        ],
     };
 
-    let hot_progress = if hot.is_some() {
-        let bar = multi_progress
-            .add(ProgressBar::new(10000))
-            .with_style(
-                ProgressStyle::with_template("{msg:4} {percent_precise:>7}% {bar:40.red} {prefix}")
-                    .unwrap()
-                    .progress_chars(PROGRESS_CHARS),
-            )
-            .with_message("HOT");
-
-        Some(bar)
-    } else {
-        None
-    };
+    for (section, region, binary) in sections {
+        let Some(binary) = binary else {
+            continue;
+        };
+        debug!(
+            "{} binary is {} bytes, loading into {}",
+            section,
+            binary.len(),
+            region
+        );
+        region
+            .check_fits(binary.len())
+            .with_context(|| format!("{} binary is too large", section))?;
+    }
 
-    let monolith_progress = if monolith.is_some() {
-        let bar = multi_progress
-            .add(ProgressBar::new(10000))
-            .with_style(
-                ProgressStyle::with_template("{msg:4} {percent_precise:>7}% {bar:40.red} {prefix}")
-                    .unwrap()
-                    .progress_chars(PROGRESS_CHARS),
-            )
-            .with_message("BIN");
+    Ok(())
+}
 
-        Some(bar)
-    } else {
-        None
-    };
+/// Where the program to upload comes from.
+pub enum ProgramSource {
+    /// Bins given on the command line.
+    Files {
+        monolith: Option<PathBuf>,
+        hot: Option<PathBuf>,
+        cold: Option<PathBuf>,
+    },
+    /// A monolith built by cargo.
+    Cargo(CargoArtifact),
+}
 
-    let (fallback_name, data) = match (monolith, cold, hot) {
+/// Reads the bins given on the command line, along with a name for the program.
+fn read_bins(
+    monolith: Option<PathBuf>,
+    hot: Option<PathBuf>,
+    cold: Option<PathBuf>,
+) -> anyhow::Result<(String, ProgramData)> {
+    Ok(match (monolith, hot, cold) {
         (Some(monolith), None, None) => (
             monolith.file_stem().unwrap().to_string_lossy().to_string(),
             ProgramData::Monolith(std::fs::read(monolith)?),
@@ -160,97 +161,696 @@ pub async fn upload(
             },
         ),
         _ => unreachable!(),
+    })
+}
+
+/// What to do when another slot already holds a program with the name being uploaded.
+///
+/// The brain's program list would show both with the same name, with nothing to tell
+/// them apart.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicateName {
+    /// Fail, naming the slot that has the name.
+    #[default]
+    Fail,
+    /// Add " (2)", or the next free number, to the name.
+    Rename,
+    /// Upload anyway, with a warning.
+    Allow,
+}
+impl DuplicateName {
+    pub fn from_flags(auto_rename: bool, allow_duplicate_name: bool) -> Self {
+        match (auto_rename, allow_duplicate_name) {
+            (true, _) => Self::Rename,
+            (false, true) => Self::Allow,
+            (false, false) => Self::Fail,
+        }
+    }
+}
+
+/// How to upload a program, as chosen on the command line.
+///
+/// Unset strings and icons fall back to the cargo package's metadata, then to defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UploadOptions {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub icon: Option<ProgramIcon>,
+    pub program_type: Option<String>,
+    pub uncompressed: bool,
+    pub after_upload: AfterUpload,
+    /// Leave the upload to a daemon job.
+    pub background: bool,
+    /// Seconds the program must keep running for after the upload.
+    pub smoke_test: Option<u64>,
+    /// Seconds to print the program's output for after the upload.
+    pub tail_after_run: Option<u64>,
+    /// Erase the slot's other files first.
+    pub replace: bool,
+    pub truncate: bool,
+    pub force_stop: bool,
+    /// Upload even if the slot is protected.
+    pub force: bool,
+    pub duplicates: DuplicateName,
+}
+
+/// Returns the names of the programs on the device, by slot.
+async fn program_names(route: u8) -> anyhow::Result<Vec<(Slot, String)>> {
+    let mut socket = BufReader::new(connect_to_socket().await?);
+    send_command_to(&mut socket, route, DaemonCommand::ProgramNames).await?;
+    match get_response(&mut socket).await? {
+        DaemonResponse::ProgramNames(Ok(names)) => Ok(names),
+        DaemonResponse::ProgramNames(Err(err)) => bail!(
+            "Couldn't read the programs on device {}: {}",
+            route,
+            err.message
+        ),
+        DaemonResponse::BasicAck { successful: false } => {
+            bail!("The daemon couldn't read the programs on device {}", route)
+        }
+        response => bail!("Unexpected response from daemon: {:?}", response),
+    }
+}
+
+/// Checks `name` against the programs in the other slots of every device in `routes`,
+/// returning the name to upload as.
+async fn check_duplicate_name(
+    routes: &[u8],
+    slot: Slot,
+    name: String,
+    duplicates: DuplicateName,
+) -> anyhow::Result<String> {
+    let mut taken = Vec::new();
+    for &route in routes {
+        for (other, other_name) in program_names(route).await? {
+            if other != slot {
+                taken.push((route, other, other_name));
+            }
+        }
+    }
+    resolve_duplicate_name(&taken, name, duplicates)
+}
+
+/// Returns the name to upload as, given the programs already in other slots by device, slot
+/// and name.
+fn resolve_duplicate_name(
+    taken: &[(u8, Slot, String)],
+    name: String,
+    duplicates: DuplicateName,
+) -> anyhow::Result<String> {
+    let Some((route, other, _)) = taken.iter().find(|(_, _, taken)| *taken == name) else {
+        return Ok(name);
     };
 
-    let description = description.unwrap_or_else(|| "Uploaded with v5d".to_string());
-    let program_type = program_type.unwrap_or_else(|| "Unknown".to_string());
-    let command = DaemonCommand::UploadProgram {
-        name: name.unwrap_or(fallback_name),
+    match duplicates {
+        DuplicateName::Fail => bail!(
+            "Slot {} on device {} already holds a program named {:?}. Pass --auto-rename to \
+             upload it under a new name, or --allow-duplicate-name to upload it anyway",
+            other,
+            route,
+            name
+        ),
+        DuplicateName::Allow => {
+            warn!(
+                "Slot {} on device {} already holds a program named {:?}",
+                other, route, name
+            );
+            Ok(name)
+        }
+        DuplicateName::Rename => {
+            let renamed = (2..)
+                .map(|n| {
+                    let suffix = format!(" ({n})");
+                    let base_len = MAX_PROGRAM_NAME_LEN.saturating_sub(suffix.chars().count());
+                    name.chars().take(base_len).collect::<String>() + &suffix
+                })
+                .find(|candidate| taken.iter().all(|(_, _, taken)| taken != candidate))
+                .expect("only eight slots can be taken");
+            info!(
+                "Slot {} on device {} already holds a program named {:?}, uploading as {:?}",
+                other, route, name, renamed
+            );
+            Ok(renamed)
+        }
+    }
+}
+
+/// Checks that a string shown on the brain fits, shortening it if `truncate` is set.
+///
+/// Without `truncate`, a value that doesn't fit is an error, rather than something the brain
+/// cuts short or the upload fails on part way.
+pub(super) fn fit_to_brain(
+    field: &str,
+    value: String,
+    max_len: usize,
+    truncate: bool,
+) -> anyhow::Result<String> {
+    let len = value.chars().count();
+    if len <= max_len {
+        return Ok(value);
+    }
+    if !truncate {
+        bail!(
+            "Program {} too long (max {} chars, {:?} has {}). Pass --truncate to shorten it",
+            field,
+            max_len,
+            value,
+            len
+        );
+    }
+    let shortened: String = value.chars().take(max_len).collect();
+    warn!("Shortened program {} to {:?}", field, shortened);
+    Ok(shortened)
+}
+
+/// Uploads `source` to `slot`, on every device in `devices` at once if given.
+pub async fn upload(
+    socket: BufReader<UnixStream>,
+    route: u8,
+    source: ProgramSource,
+    slot: Slot,
+    options: UploadOptions,
+    progress_output: Option<ProgressOutput>,
+    devices: Option<Vec<u8>>,
+) -> anyhow::Result<()> {
+    let UploadOptions {
+        name,
         description,
-        icon: format!("USER{:03}x.bmp", icon as u16),
+        icon,
+        program_type,
+        uncompressed,
+        after_upload,
+        background,
+        smoke_test,
+        tail_after_run,
+        replace,
+        truncate,
+        force_stop,
+        force,
+        duplicates,
+    } = options;
+    if smoke_test.is_some() && !matches!(after_upload, AfterUpload::Run) {
+        bail!("--smoke-test needs the program to run after uploading (--after-upload run)");
+    }
+    if tail_after_run.is_some() && !matches!(after_upload, AfterUpload::Run) {
+        bail!("--tail-after-run needs the program to run after uploading (--after-upload run)");
+    }
+
+    let mut icon = icon;
+    let mut description = description;
+    let (fallback_name, data) = match source {
+        ProgramSource::Files {
+            monolith,
+            hot,
+            cold,
+        } => read_bins(monolith, hot, cold)?,
+        ProgramSource::Cargo(artifact) => {
+            let elf = std::fs::read(&artifact.path)
+                .with_context(|| format!("reading {}", artifact.path.display()))?;
+            icon = icon.or(artifact.icon);
+            description = description.or(artifact.description);
+            (
+                artifact.name,
+                ProgramData::Monolith(cargo::elf_to_bin(&elf)?),
+            )
+        }
+    };
+    check_layout(&data)?;
+
+    let name = fit_to_brain(
+        "name",
+        name.unwrap_or(fallback_name),
+        MAX_PROGRAM_NAME_LEN,
+        truncate,
+    )?;
+    let routes = devices.clone().unwrap_or_else(|| vec![route]);
+    let name = check_duplicate_name(&routes, slot, name, duplicates).await?;
+    let description = fit_to_brain(
+        "description",
+        description.unwrap_or_else(|| "Uploaded with v5d".to_string()),
+        MAX_PROGRAM_DESCRIPTION_LEN,
+        truncate,
+    )?;
+    let program_type = fit_to_brain(
+        "type",
+        program_type.unwrap_or_else(|| "Unknown".to_string()),
+        MAX_PROGRAM_TYPE_LEN,
+        truncate,
+    )?;
+    let upload = ProgramUpload {
+        name,
+        description,
+        icon: format!("USER{:03}x.bmp", icon.unwrap_or_default() as u16),
         program_type,
         slot,
         compression: !uncompressed,
         after_upload: after_upload.into(),
         data,
+        replace,
+        overwrite_protected: force,
     };
-    send_command(socket, command).await?;
-
-    let mut prev_step = UploadStep::Ini;
-    let mut start = Instant::now();
-
-    ini_progress.tick();
-    if let Some(ref monolith_progress) = monolith_progress {
-        monolith_progress.tick();
-    }
-    if let Some(ref cold_progress) = cold_progress {
-        cold_progress.tick();
-    }
-    if let Some(ref hot_progress) = hot_progress {
-        hot_progress.tick();
-    }
-
-    loop {
-        let response = get_response(socket).await?;
-
-        match response {
-            DaemonResponse::TransferProgress { percent, step } => {
-                if prev_step != step {
-                    start = Instant::now();
-                }
-
-                let elapsed = start.elapsed();
-                let elapsed_format = format!("{:.2?}", elapsed);
-                let position = (percent * 100.0) as u64;
-
-                match step {
-                    UploadStep::Ini => {
-                        ini_progress.set_position(position);
-                        ini_progress.set_prefix(elapsed_format);
-                    }
-                    UploadStep::Monolith => {
-                        if let Some(ref monolith_progress) = monolith_progress {
-                            monolith_progress.set_position(position);
-                            monolith_progress.set_prefix(elapsed_format);
-                        }
-                    }
-                    UploadStep::Cold => {
-                        if let Some(ref cold_progress) = cold_progress {
-                            cold_progress.set_position(position);
-                            cold_progress.set_prefix(elapsed_format);
-                        }
-                    }
-                    UploadStep::Hot => {
-                        if let Some(ref hot_progress) = hot_progress {
-                            hot_progress.set_position(position);
-                            hot_progress.set_prefix(elapsed_format);
-                        }
-                    }
-                }
-
-                prev_step = step;
+
+    if let Some(routes) = devices {
+        return upload_to_devices(&routes, upload, force_stop).await;
+    }
+
+    if background {
+        let mut socket = socket;
+        send_command_to(
+            &mut socket,
+            route,
+            DaemonCommand::UploadProgramInBackground(upload),
+        )
+        .await?;
+        match get_response(&mut socket).await? {
+            DaemonResponse::JobStarted(id) => {
+                info!("Started upload as job {}", id);
+                info!("Follow it with `v5ctl jobs attach {}`", id);
             }
-            DaemonResponse::TransferComplete(res) => {
-                ini_progress.finish();
-                if let Some(ref monolith_progress) = monolith_progress {
-                    monolith_progress.finish();
-                }
-                if let Some(ref cold_progress) = cold_progress {
-                    cold_progress.finish();
-                }
-                if let Some(ref hot_progress) = hot_progress {
-                    hot_progress.finish();
-                }
-                if let Err(err) = res {
-                    error!("Failed to upload program: {}", err);
-                } else {
-                    info!("Successfully uploaded program!");
-                }
+            DaemonResponse::BasicAck { successful: false } => {
+                bail!("The daemon could not start the upload")
+            }
+            response => bail!("Unexpected response from daemon: {:?}", response),
+        }
+        return Ok(());
+    }
+
+    let steps = upload_steps(&upload.data);
+    let retry = force_stop.then(|| upload.clone());
+    let mut result = run_upload(socket, route, upload, &steps, progress_output.as_ref()).await?;
+    if let (Err(err), Some(upload)) = (&result, retry) {
+        if err.program_running {
+            info!("The brain refused the upload because a program is running. Stopping it...");
+            stop_program(route).await?;
+            let socket = BufReader::new(connect_to_socket().await?);
+            result = run_upload(socket, route, upload, &steps, progress_output.as_ref()).await?;
+        }
+    }
+
+    if let Err(err) = result {
+        match err.step {
+            Some(step) => error!("Upload failed during the {} section: {}", step, err.message),
+            None => error!("Failed to upload program: {}", err.message),
+        }
+        if err.program_running && !force_stop {
+            error!("A program is running on the brain. Pass --force-stop to stop it and retry");
+        }
+        if err.slot_protected {
+            error!(
+                "Pass --force to upload anyway, or lift the protection with `v5ctl unprotect {}`",
+                slot
+            );
+        }
+        if smoke_test.is_some() {
+            bail!("Smoke test failed: the program wasn't uploaded");
+        }
+    } else {
+        info!("Successfully uploaded program!");
+        if let Some(secs) = tail_after_run {
+            tail_output(route, Duration::from_secs(secs)).await?;
+        }
+        if let Some(secs) = smoke_test {
+            run_smoke_test(route, slot, Duration::from_secs(secs)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends `upload` and draws its progress until it finishes or Ctrl+C cancels it.
+///
+/// Progress is also written to `progress_output`, if given.
+pub(super) async fn run_upload(
+    socket: BufReader<UnixStream>,
+    route: u8,
+    upload: ProgramUpload,
+    steps: &[UploadStep],
+    progress_output: Option<&ProgressOutput>,
+) -> anyhow::Result<Result<(), TransferError>> {
+    let bars = stderr().is_terminal() && !progress_output.is_some_and(|out| out.replaces_bars);
+    let mut renderer: Box<dyn ProgressRenderer> = if bars {
+        Box::new(TerminalRenderer::default())
+    } else {
+        Box::new(LineRenderer)
+    };
+    if let Some(out) = progress_output {
+        renderer = Box::new(TeeRenderer(renderer, out.renderer()?));
+    }
+    let transfer = Transfer::start(socket, route, DaemonCommand::UploadProgram(upload)).await?;
+    let cancel = async {
+        let _ = ctrl_c().await;
+        info!("Cancelling upload...");
+    };
+    Ok(transfer.run(steps, renderer.as_mut(), cancel).await)
+}
+
+/// Uploads to one of several devices, stopping its program and retrying if `force_stop` is set.
+async fn upload_to_device(
+    route: u8,
+    upload: ProgramUpload,
+    steps: &[UploadStep],
+    mut renderer: DeviceRenderer,
+    force_stop: bool,
+) -> anyhow::Result<()> {
+    let cancel = || async {
+        let _ = ctrl_c().await;
+    };
+    let socket = BufReader::new(connect_to_socket().await?);
+    let retry = force_stop.then(|| upload.clone());
+    let transfer = Transfer::start(socket, route, DaemonCommand::UploadProgram(upload)).await?;
+    let mut result = transfer.run(steps, &mut renderer, cancel()).await;
+    if let (Err(err), Some(upload)) = (&result, retry) {
+        if err.program_running {
+            stop_program(route).await?;
+            let socket = BufReader::new(connect_to_socket().await?);
+            let transfer =
+                Transfer::start(socket, route, DaemonCommand::UploadProgram(upload)).await?;
+            result = transfer.run(steps, &mut renderer, cancel()).await;
+        }
+    }
+    Ok(result?)
+}
+
+/// Uploads the same program to every device in `routes` at once.
+///
+/// A failure on one device doesn't stop the others. Each device's result is reported at the
+/// end, and the command fails if any upload did.
+async fn upload_to_devices(
+    routes: &[u8],
+    upload: ProgramUpload,
+    force_stop: bool,
+) -> anyhow::Result<()> {
+    if routes.is_empty() {
+        bail!("The daemon isn't connected to any devices");
+    }
+    info!("Uploading {:?} to {} devices...", upload.name, routes.len());
+    let steps = upload_steps(&upload.data);
+    let multi_progress = stderr().is_terminal().then(MultiProgress::new);
+
+    // Progress renderers aren't `Send`, so the uploads share this thread
+    let local = LocalSet::new();
+    let results = local
+        .run_until(async {
+            let uploads = routes
+                .iter()
+                .map(|&route| {
+                    let renderer = DeviceRenderer::new(route, multi_progress.as_ref());
+                    let upload = upload.clone();
+                    let steps = steps.clone();
+                    let task = spawn_local(async move {
+                        upload_to_device(route, upload, &steps, renderer, force_stop).await
+                    });
+                    (route, task)
+                })
+                .collect::<Vec<_>>();
+            let mut results = Vec::new();
+            for (route, task) in uploads {
+                results.push((route, task.await?));
+            }
+            anyhow::Ok(results)
+        })
+        .await?;
+
+    let mut failed = 0;
+    for (route, result) in &results {
+        match result {
+            Ok(()) => info!("Device {}: uploaded", route),
+            Err(err) => {
+                error!("Device {}: {}", route, err);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!(
+            "The upload failed on {} of {} devices",
+            failed,
+            results.len()
+        );
+    }
+    info!("Uploaded to all {} devices", results.len());
+    Ok(())
+}
+
+/// Stops whatever program is running on the brain.
+pub(super) async fn stop_program(route: u8) -> anyhow::Result<()> {
+    let mut socket = BufReader::new(connect_to_socket().await?);
+    send_command_to(&mut socket, route, DaemonCommand::StopProgram).await?;
+    match get_response(&mut socket).await? {
+        DaemonResponse::BasicAck { successful: true } => Ok(()),
+        DaemonResponse::BasicAck { successful: false } => bail!("Failed to stop the program"),
+        response => bail!("Unexpected response from daemon: {:?}", response),
+    }
+}
+
+/// How often the brain is checked during a smoke test.
+const SMOKE_TEST_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// How long a program may take to show up as running after an upload.
+const SMOKE_TEST_STARTUP_GRACE: Duration = Duration::from_secs(2);
+
+/// Checks that the program in `slot` starts and keeps running for `duration`.
+async fn run_smoke_test(route: u8, slot: Slot, duration: Duration) -> anyhow::Result<()> {
+    info!("Watching the program for {}s...", duration.as_secs());
+    let start = Instant::now();
+    let mut started = false;
+    while start.elapsed() < duration {
+        let mut socket = BufReader::new(connect_to_socket().await?);
+        send_command_to(&mut socket, route, DaemonCommand::Uptime).await?;
+        let running = match get_response(&mut socket).await? {
+            DaemonResponse::Uptime(uptime) => uptime.program.map(|program| program.slot),
+            DaemonResponse::BasicAck { successful: false } => {
+                bail!("Failed to read the brain's status")
+            }
+            response => bail!("Unexpected response from daemon: {:?}", response),
+        };
+
+        if running == Some(slot.get()) {
+            started = true;
+        } else if started {
+            bail!(
+                "Smoke test failed: the program stopped after {:.1}s",
+                start.elapsed().as_secs_f32()
+            );
+        } else if start.elapsed() > SMOKE_TEST_STARTUP_GRACE {
+            bail!("Smoke test failed: the program didn't start");
+        }
+        sleep(SMOKE_TEST_POLL_INTERVAL).await;
+    }
+
+    info!("Smoke test passed: the program is still running");
+    Ok(())
+}
+
+/// How long to wait before reading the program's output again when there was none.
+const TAIL_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Prints the output of the program that was just started for `duration`, between markers
+/// that set it apart from the log.
+///
+/// The daemon doesn't read the program's output on its own, so anything printed before the
+/// first read waits in the brain's FIFO (or the serial port's buffer) until this drains it.
+async fn tail_output(route: u8, duration: Duration) -> anyhow::Result<()> {
+    info!("Program output for the next {}s:", duration.as_secs());
+    let mut socket = BufReader::new(connect_to_socket().await?);
+    let mut out = stdout();
+    writeln!(out, "----- program output -----")?;
+    let start = Instant::now();
+    let mut last = None;
+    while start.elapsed() < duration {
+        let data = match read_user(&mut socket, route, 1024).await {
+            Ok(data) => data,
+            Err(err) => {
+                warn!("Stopped reading the program's output: {}", err);
                 break;
             }
-            _ => panic!("Unexpected response from daemon"),
+        };
+        if data.is_empty() {
+            sleep(TAIL_POLL_INTERVAL).await;
+            continue;
+        }
+        out.write_all(&data)?;
+        out.flush()?;
+        last = data.last().copied();
+    }
+    match last {
+        None => writeln!(out, "(no output)")?,
+        Some(b'\n') => {}
+        Some(_) => writeln!(out)?,
+    }
+    writeln!(out, "----- end of program output -----")?;
+    Ok(())
+}
+
+/// Applies an after-upload action to a program that is already on the brain.
+pub async fn apply_exit_action(
+    socket: &mut BufReader<UnixStream>,
+    route: u8,
+    slot: Slot,
+    action: AfterUpload,
+    force_stop: bool,
+) -> anyhow::Result<()> {
+    let command = || DaemonCommand::ApplyExitAction {
+        slot,
+        action: action.into(),
+    };
+    send_command_to(socket, route, command()).await?;
+    let mut response = get_response(socket).await?;
+    if let DaemonResponse::TransferComplete(Err(err)) = &response {
+        if err.program_running && force_stop {
+            info!("A program is running. Stopping it and trying again...");
+            stop_program(route).await?;
+            let mut socket = BufReader::new(connect_to_socket().await?);
+            send_command_to(&mut socket, route, command()).await?;
+            response = get_response(&mut socket).await?;
+        }
+    }
+    match response {
+        DaemonResponse::TransferComplete(Ok(())) => info!("Applied {:?} to slot {}", action, slot),
+        DaemonResponse::TransferComplete(Err(err)) if err.program_running && !force_stop => {
+            bail!("{}. Pass --force-stop to stop it and retry", err)
+        }
+        DaemonResponse::TransferComplete(Err(err)) => bail!(err),
+        DaemonResponse::BasicAck { successful: false } => {
+            bail!("The daemon could not update slot {}", slot)
         }
+        response => bail!("Unexpected response from daemon: {:?}", response),
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::ValueEnum;
+    use v5d_interface::AfterFileUpload;
+
+    use v5d_interface::{Slot, MAX_PROGRAM_NAME_LEN};
+
+    use super::{fit_to_brain, resolve_duplicate_name, AfterUpload, DuplicateName};
+
+    // Each choice means something different, so none may share an exit action
+    #[test]
+    fn after_upload_choices_map_to_their_own_exit_actions() {
+        let mapped = AfterUpload::value_variants()
+            .iter()
+            .map(|&after| (after, AfterFileUpload::from(after)))
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            mapped[..],
+            [
+                (AfterUpload::None, AfterFileUpload::DoNothing),
+                (AfterUpload::Run, AfterFileUpload::RunProgram),
+                (AfterUpload::ShowScreen, AfterFileUpload::ShowRunScreen),
+            ]
+        ));
+        assert!(matches!(AfterUpload::default(), AfterUpload::ShowScreen));
+        assert!(matches!(
+            AfterUpload::from_str("run-screen", false),
+            Ok(AfterUpload::ShowScreen)
+        ));
+    }
+
+    #[test]
+    fn values_up_to_the_limit_are_kept() {
+        for truncate in [false, true] {
+            assert_eq!(
+                fit_to_brain("name", "abc".into(), 4, truncate).unwrap(),
+                "abc"
+            );
+            assert_eq!(
+                fit_to_brain("name", "abcd".into(), 4, truncate).unwrap(),
+                "abcd"
+            );
+        }
+    }
+
+    #[test]
+    fn longer_values_are_refused_unless_truncating() {
+        let err = fit_to_brain("name", "abcde".into(), 4, false).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Program name too long (max 4 chars"));
+        assert_eq!(
+            fit_to_brain("name", "abcde".into(), 4, true).unwrap(),
+            "abcd"
+        );
+    }
+
+    #[test]
+    fn limits_count_characters_not_bytes() {
+        // Each of these is several bytes in UTF-8
+        assert_eq!(fit_to_brain("name", "ñü€".into(), 4, false).unwrap(), "ñü€");
+        assert_eq!(
+            fit_to_brain("name", "ñü€🦀".into(), 4, false).unwrap(),
+            "ñü€🦀"
+        );
+        assert!(fit_to_brain("name", "ñü€🦀é".into(), 4, false).is_err());
+        assert_eq!(
+            fit_to_brain("name", "ñü€🦀é".into(), 4, true).unwrap(),
+            "ñü€🦀"
+        );
+    }
+
+    /// Programs named `names` in slots 1 onwards of device 0.
+    fn taken(names: &[&str]) -> Vec<(u8, Slot, String)> {
+        names
+            .iter()
+            .zip(1..)
+            .map(|(name, slot)| (0, Slot::try_from(slot).unwrap(), name.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn a_free_name_is_kept_whatever_the_mode() {
+        let taken = taken(&["drive", "auton"]);
+        for mode in [
+            DuplicateName::Fail,
+            DuplicateName::Allow,
+            DuplicateName::Rename,
+        ] {
+            let name = resolve_duplicate_name(&taken, "skills".to_owned(), mode).unwrap();
+            assert_eq!(name, "skills");
+        }
+    }
+
+    #[test]
+    fn a_duplicate_fails_naming_its_slot_or_is_allowed() {
+        let taken = taken(&["drive", "auton"]);
+        let err = resolve_duplicate_name(&taken, "auton".to_owned(), DuplicateName::Fail)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Slot 2 on device 0"), "{err}");
+        assert!(err.contains("--auto-rename"), "{err}");
+
+        let name = resolve_duplicate_name(&taken, "auton".to_owned(), DuplicateName::Allow);
+        assert_eq!(name.unwrap(), "auton");
+    }
+
+    #[test]
+    fn renaming_picks_the_next_free_number() {
+        let name = resolve_duplicate_name(
+            &taken(&["drive"]),
+            "drive".to_owned(),
+            DuplicateName::Rename,
+        );
+        assert_eq!(name.unwrap(), "drive (2)");
+
+        let taken = taken(&["drive", "drive (2)", "drive (3)"]);
+        let name = resolve_duplicate_name(&taken, "drive".to_owned(), DuplicateName::Rename);
+        assert_eq!(name.unwrap(), "drive (4)");
+    }
+
+    #[test]
+    fn renaming_a_full_length_name_shortens_it_to_fit() {
+        let long = "a".repeat(MAX_PROGRAM_NAME_LEN);
+        let renamed =
+            resolve_duplicate_name(&taken(&[&long]), long.clone(), DuplicateName::Rename).unwrap();
+        assert_eq!(renamed.chars().count(), MAX_PROGRAM_NAME_LEN);
+        assert_eq!(renamed, "a".repeat(MAX_PROGRAM_NAME_LEN - 4) + " (2)");
+
+        // The shortened " (2)" is taken too
+        let taken = taken(&[&long, &renamed]);
+        let renamed = resolve_duplicate_name(&taken, long, DuplicateName::Rename).unwrap();
+        assert_eq!(renamed, "a".repeat(MAX_PROGRAM_NAME_LEN - 4) + " (3)");
+    }
+}
@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+use log::{error, info};
+use tokio::{io::BufReader, net::UnixStream};
+use v5d_interface::{get_response, send_command_to, DaemonCommand, DaemonResponse};
+
+/// Formats a duration as `[Hh ][Mm ]Ss`.
+pub(super) fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{hours}h {minutes}m {secs}s")
+    } else if minutes > 0 {
+        format!("{minutes}m {secs}s")
+    } else {
+        format!("{secs}s")
+    }
+}
+
+pub async fn uptime(socket: &mut BufReader<UnixStream>, route: u8) -> anyhow::Result<()> {
+    send_command_to(socket, route, DaemonCommand::Uptime).await?;
+    match get_response(socket).await? {
+        DaemonResponse::Uptime(uptime) => {
+            // No status packet carries the brain's own power-on time
+            info!("Brain uptime: unavailable (the brain doesn't report it)");
+            info!(
+                "Connection time: {} (since v5d connected to the brain)",
+                format_duration(uptime.connected_for)
+            );
+            match uptime.program {
+                None => info!("No program is running"),
+                Some(program) => match program.running_for {
+                    Some(running_for) => info!(
+                        "Program in slot {} has been running for {}",
+                        program.slot,
+                        format_duration(running_for)
+                    ),
+                    None => info!(
+                        "Program in slot {} is running (started outside of v5d, so its run time is unknown)",
+                        program.slot
+                    ),
+                },
+            }
+        }
+        DaemonResponse::BasicAck { successful: false } => {
+            error!("Failed to read the brain's status");
+        }
+        _ => error!("Unexpected response from daemon"),
+    }
+
+    Ok(())
+}
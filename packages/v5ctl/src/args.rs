@@ -0,0 +1,839 @@
+//! The command line, as clap parses it.
+//!
+//! These types have no side effects, so anything that needs the command tree, like
+//! `introspect`, can use them without running anything.
+
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use v5d_interface::{JobId, Slot};
+
+use crate::actions::{
+    progress::{ProgressFd, ProgressFormat},
+    terminal::TerminalMode,
+    upload::{AfterUpload, DuplicateName, ProgramIcon, UploadOptions},
+};
+
+/// Size of the V5 brain's screen in pixels.
+const SCREEN_WIDTH: u16 = 480;
+const SCREEN_HEIGHT: u16 = 272;
+
+#[derive(Parser)]
+#[command(version, about = "A CLI for interacting with the V5 Daemon (v5d)")]
+pub struct Args {
+    #[clap(subcommand)]
+    pub action: Action,
+
+    /// The route of the device to send commands to
+    #[arg(long, short = 'D', global = true, default_value_t = 0)]
+    pub device: u8,
+
+    /// Wait up to this many seconds for the daemon to connect to the device before running
+    #[arg(
+        long,
+        global = true,
+        value_name = "TIMEOUT",
+        num_args = 0..=1,
+        default_missing_value = "30"
+    )]
+    pub wait_for_device: Option<u64>,
+
+    /// Wait for a brain to connect to the daemon, then run the command on it and exit.
+    /// Brains already connected don't count. If an id from `v5ctl devices` is given,
+    /// only that brain does
+    #[arg(
+        long,
+        global = true,
+        value_name = "ID",
+        num_args = 0..=1,
+        default_missing_value = "",
+        conflicts_with = "wait_for_device"
+    )]
+    pub on_connect: Option<String>,
+
+    /// Give up on --on-connect after this many seconds
+    #[arg(long, global = true, value_name = "SECS", requires = "on_connect")]
+    pub on_connect_timeout: Option<u64>,
+
+    /// Give up connecting to the daemon after this many seconds
+    #[arg(
+        long,
+        global = true,
+        value_name = "SECS",
+        default_value_t = v5d_interface::DEFAULT_CONNECT_TIMEOUT.as_secs_f64()
+    )]
+    pub connect_timeout: f64,
+
+    /// Print debug logs
+    #[arg(long, short = 'v', global = true)]
+    pub verbose: bool,
+
+    /// Draw progress bars with plain ASCII characters. This is the default when the
+    /// locale isn't UTF-8
+    #[arg(long, global = true)]
+    pub ascii: bool,
+
+    /// Fail instead of asking a question, naming the flag that answers it. This is the
+    /// default when stdin isn't a terminal
+    #[arg(long, global = true)]
+    pub non_interactive: bool,
+
+    /// Give up on the command after this many seconds. An upload in progress is cancelled
+    /// on the brain, as with Ctrl+C
+    #[arg(long, global = true, value_name = "SECS")]
+    pub timeout: Option<f64>,
+
+    /// Print what a command that changes the brain or daemon would do, without connecting.
+    /// Read-only commands run as usual
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+}
+
+/// Upload options that only make sense for a single device.
+const PARALLEL_CONFLICTS: [&str; 5] = [
+    "background",
+    "smoke_test",
+    "tail_after_run",
+    "progress_fd",
+    "progress_file",
+];
+
+// Parsed once per run, so the size of the biggest variant doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand)]
+pub enum Action {
+    /// Taps the brain's screen
+    MockTap {
+        /// Pixels from the left edge of the screen
+        #[arg(value_parser = clap::value_parser!(u16).range(..SCREEN_WIDTH as i64))]
+        x: u16,
+        /// Pixels from the top edge of the screen
+        #[arg(value_parser = clap::value_parser!(u16).range(..SCREEN_HEIGHT as i64))]
+        y: u16,
+    },
+    /// Uploads a user program to the brain
+    #[command(name = "upload", visible_alias = "u")]
+    UploadProgram {
+        /// Path to the monolith bin to upload
+        #[arg(required_unless_present_any = ["hot", "cold", "from_cargo"], conflicts_with_all = ["hot", "cold", "from_cargo"])]
+        monolith: Option<PathBuf>,
+
+        /// Path to the hot bin to upload
+        #[arg(long, required_unless_present_any = ["cold", "monolith", "from_cargo"], conflicts_with_all = ["monolith", "from_cargo"])]
+        hot: Option<PathBuf>,
+
+        /// Path to the cold bin to upload
+        #[arg(long, required_unless_present_any = ["hot", "monolith", "from_cargo"], conflicts_with_all = ["monolith", "from_cargo"])]
+        cold: Option<PathBuf>,
+
+        /// Upload the program the cargo project in this directory builds.
+        /// Its name, description and `[package.metadata.v5]` icon are the defaults
+        #[arg(long)]
+        from_cargo: bool,
+
+        /// Upload cargo's release build instead of the debug one
+        #[arg(long, requires = "from_cargo")]
+        release: bool,
+
+        /// The binary target to upload, if the project has several
+        #[arg(long, requires = "from_cargo")]
+        bin: Option<String>,
+
+        /// Read `cargo metadata --format-version 1` output from this file instead of running cargo
+        #[arg(long, value_name = "PATH", requires = "from_cargo")]
+        metadata_path: Option<PathBuf>,
+
+        /// Read the name, slot, icon, description and type from this VEXcode-style INI
+        /// program descriptor. Flags given on the command line take precedence
+        #[arg(long, value_name = "PATH")]
+        from_project: Option<PathBuf>,
+
+        /// The slot to upload to
+        #[arg(long, short, required_unless_present = "from_project")]
+        slot: Option<Slot>,
+
+        /// The name of the program
+        #[arg(short, long)]
+        name: Option<String>,
+
+        /// The description of the program
+        #[arg(short, long)]
+        description: Option<String>,
+
+        /// The icon to appear on the program. Defaults to question-mark
+        #[arg(short, long)]
+        icon: Option<ProgramIcon>,
+
+        /// The text to appear in the program type box
+        #[arg(short = 't', long)]
+        program_type: Option<String>,
+
+        /// Whether or not the program should be compressed before uploading
+        #[arg(short, long)]
+        uncompressed: bool,
+
+        /// Action to perform after uploading the program
+        #[arg(short, long, default_value = "show-screen")]
+        after_upload: AfterUpload,
+
+        /// Let the daemon finish the upload in the background and print its job id
+        #[arg(long)]
+        background: bool,
+
+        /// After running the program, fail unless it is still running this many seconds later
+        #[arg(long, value_name = "SECS", conflicts_with = "background")]
+        smoke_test: Option<u64>,
+
+        /// After running the program, print what it outputs for this many seconds
+        #[arg(
+            long,
+            value_name = "SECS",
+            num_args = 0..=1,
+            default_missing_value = "3",
+            conflicts_with = "background"
+        )]
+        tail_after_run: Option<u64>,
+
+        /// Erase the slot's existing files before uploading
+        #[arg(long)]
+        replace: bool,
+
+        /// Shorten a name, description or program type that is too long for the brain.
+        /// Without this, a value that doesn't fit is an error
+        #[arg(long)]
+        truncate: bool,
+
+        /// If the brain refuses the upload because a program is running, stop it and retry
+        #[arg(long, conflicts_with = "background")]
+        force_stop: bool,
+
+        /// Upload even if the slot is protected
+        #[arg(long)]
+        force: bool,
+
+        /// If another slot already holds a program with this name, add " (2)" or the next
+        /// free number to it
+        #[arg(long, conflicts_with = "allow_duplicate_name")]
+        auto_rename: bool,
+
+        /// Upload even if another slot already holds a program with this name
+        #[arg(long)]
+        allow_duplicate_name: bool,
+
+        /// Also write progress to this already-open file descriptor, in --progress-format.
+        /// `stderr-compact` writes compact records to stderr instead of drawing progress bars
+        #[arg(long, value_name = "FD", conflicts_with_all = ["background", "progress_file"])]
+        progress_fd: Option<ProgressFd>,
+
+        /// Also write progress to this file, in --progress-format, replacing it
+        #[arg(long, value_name = "PATH", conflicts_with = "background")]
+        progress_file: Option<PathBuf>,
+
+        /// The format of --progress-fd and --progress-file: JSON lines with `started`,
+        /// `step_started`, `progress`, `step_finished` and `finished` events, or compact
+        /// `PROGRESS <section> <phase> <bytes> <total>` lines ending with a `FINISHED` line
+        #[arg(long, default_value = "json")]
+        progress_format: ProgressFormat,
+
+        /// Upload to these devices at once, by route, instead of the one given by --device
+        #[arg(
+            long,
+            value_name = "ROUTES",
+            value_delimiter = ',',
+            conflicts_with_all = PARALLEL_CONFLICTS
+        )]
+        devices: Vec<u8>,
+
+        /// Upload to every device the daemon is connected to at once
+        #[arg(long, conflicts_with = "devices", conflicts_with_all = PARALLEL_CONFLICTS)]
+        all: bool,
+    },
+    /// Erases a slot and uploads a monolith bin to it, leaving nothing of the old program behind
+    ///
+    /// The brain can't swap files atomically. If this is interrupted, the slot is left
+    /// empty or with a partial program, never a mix of the old and new ones.
+    Replace {
+        /// The slot to replace
+        slot: Slot,
+
+        /// Path to the monolith bin to upload
+        bin: PathBuf,
+
+        /// The name of the program
+        #[arg(short, long)]
+        name: Option<String>,
+
+        /// Action to perform after uploading the program
+        #[arg(short, long, default_value = "show-screen")]
+        after_upload: AfterUpload,
+
+        /// Shorten a name that is too long for the brain, instead of failing
+        #[arg(long)]
+        truncate: bool,
+
+        /// If the brain refuses the upload because a program is running, stop it and retry
+        #[arg(long)]
+        force_stop: bool,
+
+        /// Replace the program even if the slot is protected
+        #[arg(long)]
+        force: bool,
+
+        /// If another slot already holds a program with this name, add " (2)" or the next
+        /// free number to it
+        #[arg(long, conflicts_with = "allow_duplicate_name")]
+        auto_rename: bool,
+
+        /// Replace the program even if another slot holds one with the same name
+        #[arg(long)]
+        allow_duplicate_name: bool,
+    },
+    /// Runs the action that would follow an upload for a program already on the brain
+    ///
+    /// The action happens now: the program's INI is uploaded again, unchanged, and the
+    /// brain carries out the action as that upload finishes. Nothing is stored for later.
+    #[command(name = "apply-exit-action")]
+    ApplyExit {
+        /// The slot the program is in
+        slot: Slot,
+        action: AfterUpload,
+
+        /// If the brain refuses because a program is running, stop it and retry
+        #[arg(long)]
+        force_stop: bool,
+    },
+    Pair {
+        /// The pin the brain shows, instead of asking for it
+        #[arg(long)]
+        pin: Option<String>,
+    },
+    /// Moves a device connected over Bluetooth onto its USB cable, once it's plugged in
+    #[command(name = "switch-to-usb")]
+    SwitchToUsb,
+    /// Reads or writes a raw setting in the brain's key-value store
+    Kv {
+        /// The setting to access
+        key: String,
+
+        /// The value to write. If omitted, the current value is read
+        value: Option<String>,
+    },
+    /// Opens a terminal connected to the user program's stdio
+    #[command(visible_alias = "t")]
+    Terminal {
+        /// How to show the program's output and send it input
+        #[arg(long, default_value = "line")]
+        mode: TerminalMode,
+    },
+    /// Checks that uploading, running, the terminal and stopping work, using a known-good program
+    ///
+    /// The program must echo everything it reads on stdin back to stdout.
+    SelftestDevice {
+        /// The slot to test with. Its current program is overwritten
+        #[arg(long, short)]
+        slot: Slot,
+
+        /// Path to the echo program's monolith bin
+        #[arg(long)]
+        program: PathBuf,
+
+        /// Leave the program on the brain afterwards instead of deleting it
+        #[arg(long)]
+        keep: bool,
+
+        /// Don't ask before overwriting the slot
+        #[arg(long, short)]
+        yes: bool,
+    },
+    /// Uploads a program, runs it, and watches it for a crash
+    ///
+    /// A crash is a program that stops after printing a panic message, or one that
+    /// doesn't start. A program that exits on its own counts as deployed.
+    Deploy {
+        /// Path to the monolith bin to upload
+        bin: PathBuf,
+
+        /// The slot to upload to
+        #[arg(long, short)]
+        slot: Slot,
+
+        /// The program's name. Defaults to the file name, shortened to fit
+        #[arg(long, short)]
+        name: Option<String>,
+
+        /// Back up the slot's current program first, and put it back if the new one crashes
+        #[arg(long)]
+        rollback: bool,
+
+        /// How many seconds to watch the program for
+        #[arg(long, default_value_t = 5)]
+        window: u64,
+    },
+    /// Protects a slot on the brain from uploads, or lists the protected slots
+    ///
+    /// Uploads to a protected slot fail unless given --force. Protection belongs to the
+    /// brain, so it follows the brain to other routes and connections.
+    Protect {
+        /// The slot to protect. If omitted, the protected slots are listed
+        slot: Option<Slot>,
+    },
+    /// Lets uploads to a protected slot through again
+    Unprotect {
+        slot: Slot,
+    },
+    /// Lists the devices the daemon is connected to
+    Devices,
+    /// Shows the errors the daemon logged most recently, oldest first
+    Errors {
+        /// How many errors to show
+        #[arg(long, short = 'n', default_value_t = 20)]
+        limit: usize,
+    },
+    /// Shows how long v5d has been connected to the device, and its running program
+    ///
+    /// The brain does not report its own power-on time, so brain uptime is shown as
+    /// unavailable. The connection time is measured from when the daemon connected.
+    Uptime,
+    /// Measures the round-trip time of the link to the brain, like `ping`
+    PingBrain {
+        /// How many pings to send
+        #[arg(long, short = 'c', default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..))]
+        count: u32,
+    },
+    /// Prints everything the read-only commands report about the device as one JSON
+    /// document, for bug reports
+    ///
+    /// A query that fails is recorded in its field as `{"error": ...}`.
+    Snapshot {
+        /// Write the snapshot to this file instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+
+        /// Replace the output file if it already exists
+        #[arg(long, requires = "output")]
+        force: bool,
+    },
+    /// Shows whether a field controller is connected and the robot's competition mode
+    Field,
+    /// Shows whether the brain booted normally or into a bootloader
+    BootState,
+    /// Shows the brain's firmware version, and that of the golden image it recovers with
+    Firmware,
+    /// Inspects the controllers linked to the brain
+    Controller {
+        #[command(subcommand)]
+        action: ControllerAction,
+    },
+    /// Inspects and controls background uploads
+    Jobs {
+        #[command(subcommand)]
+        action: JobsAction,
+    },
+    /// Hexdumps a range of the brain's memory
+    ///
+    /// Reading memory the firmware doesn't expect to be read, such as hardware registers,
+    /// can crash the brain or the running program.
+    #[cfg(feature = "debug")]
+    Mem {
+        /// The address to start at, in hex if prefixed with 0x
+        #[arg(value_parser = crate::actions::memory::parse_address)]
+        address: u32,
+        /// How many bytes to read
+        #[arg(value_parser = clap::value_parser!(u16).range(1..=v5d_interface::MAX_MEMORY_READ as i64))]
+        length: u16,
+    },
+    /// Inspects the daemon itself
+    Daemon {
+        #[command(subcommand)]
+        action: DaemonAction,
+    },
+    StopDaemon,
+    Reconnect,
+    /// Describes every subcommand and its arguments, for tools that drive v5ctl
+    Introspect {
+        /// Print a machine-readable description
+        #[arg(long)]
+        json: bool,
+    },
+    /// Removes the temporary files that interrupted commands left behind
+    ///
+    /// Only files whose writer has exited and that haven't changed for 10 minutes are removed.
+    CleanTemp {
+        /// The directory to clean
+        #[arg(default_value = ".")]
+        dir: PathBuf,
+
+        /// Don't ask before removing them
+        #[arg(long, short)]
+        yes: bool,
+    },
+    /// Prints the settings v5ctl would run with and where each one came from
+    Env {
+        /// Print the settings as a JSON array
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ControllerAction {
+    /// Shows the controller's link and battery status as it changes
+    Monitor {
+        /// Print one JSON object per change instead of a live status line
+        #[arg(long)]
+        json: bool,
+
+        /// Also log each change with a timestamp to this file
+        #[arg(long)]
+        record: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum JobsAction {
+    /// Shows how far a job has got
+    Status { id: JobId },
+    /// Follows a job's progress until it finishes. Pressing Ctrl+C leaves the job running
+    Attach { id: JobId },
+    /// Stops a job
+    Cancel { id: JobId },
+}
+
+#[derive(Subcommand)]
+pub enum DaemonAction {
+    /// Prints the daemon's log as it is written, with the module each line came from
+    Logs {
+        /// The least severe level to print
+        #[arg(long, default_value = "info")]
+        level: log::Level,
+    },
+}
+
+/// The name clap uses for a value, as it would be typed on the command line.
+fn value_name(value: impl ValueEnum) -> String {
+    value
+        .to_possible_value()
+        .map(|value| value.get_name().to_owned())
+        .unwrap_or_default()
+}
+
+impl Action {
+    /// The options an upload or replace was given, or `None` for other actions.
+    ///
+    /// The program descriptor's defaults aren't applied, since reading it has side effects.
+    pub fn upload_options(&self) -> Option<UploadOptions> {
+        let options = match self {
+            Action::UploadProgram {
+                icon,
+                description,
+                name,
+                program_type,
+                uncompressed,
+                after_upload,
+                background,
+                smoke_test,
+                tail_after_run,
+                replace,
+                truncate,
+                force_stop,
+                force,
+                auto_rename,
+                allow_duplicate_name,
+                ..
+            } => UploadOptions {
+                name: name.clone(),
+                description: description.clone(),
+                icon: *icon,
+                program_type: program_type.clone(),
+                uncompressed: *uncompressed,
+                after_upload: *after_upload,
+                background: *background,
+                smoke_test: *smoke_test,
+                tail_after_run: *tail_after_run,
+                replace: *replace,
+                truncate: *truncate,
+                force_stop: *force_stop,
+                force: *force,
+                duplicates: DuplicateName::from_flags(*auto_rename, *allow_duplicate_name),
+            },
+            Action::Replace {
+                name,
+                after_upload,
+                truncate,
+                force_stop,
+                force,
+                auto_rename,
+                allow_duplicate_name,
+                ..
+            } => UploadOptions {
+                name: name.clone(),
+                after_upload: *after_upload,
+                replace: true,
+                truncate: *truncate,
+                force_stop: *force_stop,
+                force: *force,
+                duplicates: DuplicateName::from_flags(*auto_rename, *allow_duplicate_name),
+                ..Default::default()
+            },
+            _ => return None,
+        };
+        Some(options)
+    }
+
+    /// Describes what the action would change, or `None` if it only reads state.
+    pub fn dry_run_preview(&self, route: u8) -> Option<Vec<String>> {
+        let preview = match self {
+            Action::MockTap { x, y } => {
+                vec![format!("Tap the screen of device {route} at ({x}, {y})")]
+            }
+            Action::UploadProgram {
+                monolith,
+                hot,
+                cold,
+                slot,
+                name,
+                uncompressed,
+                after_upload,
+                background,
+                tail_after_run,
+                replace,
+                devices,
+                all,
+                from_cargo,
+                release,
+                bin,
+                from_project,
+                ..
+            } => {
+                let slot = slot.map_or_else(
+                    || "the project's slot".to_owned(),
+                    |slot| format!("slot {slot}"),
+                );
+                let mut preview = Vec::new();
+                if let Some(path) = from_project {
+                    preview.push(format!("Read upload settings from {}", path.display()));
+                }
+                if *replace {
+                    preview.push(format!("Erase the files in {slot} on device {route}"));
+                }
+                preview.push(format!(
+                    "Upload {} to {slot} on device {route}{}",
+                    name.as_deref()
+                        .map_or_else(|| "the program".to_owned(), |name| format!("\"{name}\"")),
+                    if *uncompressed { "" } else { ", compressed" },
+                ));
+                for (label, path) in [("monolith", monolith), ("hot", hot), ("cold", cold)] {
+                    if let Some(path) = path {
+                        preview.push(format!("Read the {label} bin from {}", path.display()));
+                    }
+                }
+                if *from_cargo {
+                    preview.push(format!(
+                        "Read the {} build of the cargo project{}",
+                        if *release { "release" } else { "debug" },
+                        bin.as_deref()
+                            .map_or_else(String::new, |bin| format!("'s {bin} binary")),
+                    ));
+                }
+                preview.push(format!("Then {}", value_name(*after_upload)));
+                if let Some(secs) = tail_after_run {
+                    preview.push(format!("Print the program's output for {secs}s"));
+                }
+                if *background {
+                    preview.push("Leave the upload to a daemon job".to_owned());
+                }
+                if *all {
+                    preview.push("Do this on every connected device at once".to_owned());
+                } else if !devices.is_empty() {
+                    let routes = devices
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    preview.push(format!("Do this on devices {routes} at once"));
+                }
+                preview
+            }
+            Action::Replace {
+                slot,
+                bin,
+                after_upload,
+                ..
+            } => vec![
+                format!("Erase the files in slot {slot} on device {route}"),
+                format!("Upload {} to slot {slot}", bin.display()),
+                format!("Then {}", value_name(*after_upload)),
+            ],
+            Action::ApplyExit { slot, action, .. } => vec![format!(
+                "Run the {} action for slot {slot} on device {route}",
+                value_name(*action)
+            )],
+            Action::Kv {
+                key,
+                value: Some(value),
+            } => vec![format!("Set {key} to \"{value}\" on device {route}")],
+            Action::SelftestDevice {
+                slot,
+                program,
+                keep,
+                ..
+            } => {
+                let mut preview = vec![
+                    format!(
+                        "Upload {} to slot {slot} on device {route} and run it",
+                        program.display()
+                    ),
+                    "Write a test line to it and check it is echoed back".to_owned(),
+                    "Stop the program".to_owned(),
+                ];
+                if !keep {
+                    preview.push(format!("Erase slot {slot}"));
+                }
+                preview
+            }
+            Action::Deploy {
+                bin,
+                slot,
+                rollback,
+                window,
+                ..
+            } => {
+                let mut preview = Vec::new();
+                if *rollback {
+                    preview.push(format!("Back up the program in slot {slot}"));
+                }
+                preview.push(format!(
+                    "Upload {} to slot {slot} on device {route} and run it",
+                    bin.display()
+                ));
+                preview.push(format!("Watch it for {window}s"));
+                if *rollback {
+                    preview.push("Restore the backup if it crashes".to_owned());
+                }
+                preview
+            }
+            Action::Protect { slot: Some(slot) } => {
+                vec![format!(
+                    "Protect slot {slot} on device {route} from uploads"
+                )]
+            }
+            Action::Unprotect { slot } => {
+                vec![format!(
+                    "Lift the protection of slot {slot} on device {route}"
+                )]
+            }
+            Action::Pair { .. } => vec![format!("Start pairing with device {route}")],
+            Action::SwitchToUsb => vec![format!(
+                "Move device {route} from Bluetooth to a USB connection to the same brain"
+            )],
+            Action::Jobs {
+                action: JobsAction::Cancel { id },
+            } => vec![format!("Cancel job {id}")],
+            Action::StopDaemon => vec!["Stop the daemon".to_owned()],
+            Action::Reconnect => vec!["Drop and reconnect every device".to_owned()],
+            _ => return None,
+        };
+        Some(preview)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{error::ErrorKind, Parser};
+
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, clap::Error> {
+        Args::try_parse_from([&["v5ctl"], args].concat())
+    }
+
+    #[test]
+    fn taps_anywhere_on_the_screen_are_accepted() {
+        for (x, y) in [("0", "0"), ("479", "271"), ("240", "136")] {
+            let args = parse(&["mock-tap", x, y]).unwrap();
+            let Action::MockTap {
+                x: parsed_x,
+                y: parsed_y,
+            } = args.action
+            else {
+                panic!("parsed as another command");
+            };
+            assert_eq!(
+                (parsed_x.to_string(), parsed_y.to_string()),
+                (x.into(), y.into())
+            );
+        }
+    }
+
+    #[test]
+    fn taps_off_the_screen_are_rejected() {
+        for (x, y) in [("480", "0"), ("0", "272"), ("4800", "272")] {
+            let err = parse(&["mock-tap", x, y]).err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::ValueValidation, "({x}, {y})");
+        }
+    }
+
+    #[test]
+    fn upload_flags_become_upload_options() {
+        let args = parse(&[
+            "upload",
+            "program.bin",
+            "--slot",
+            "3",
+            "--name",
+            "clawbot",
+            "--icon",
+            "cool-x",
+            "--after-upload",
+            "run",
+            "--tail-after-run",
+            "--uncompressed",
+            "--truncate",
+            "--force-stop",
+            "--auto-rename",
+        ])
+        .unwrap();
+        let expected = UploadOptions {
+            name: Some("clawbot".to_owned()),
+            icon: Some(ProgramIcon::CoolX),
+            uncompressed: true,
+            after_upload: AfterUpload::Run,
+            tail_after_run: Some(3),
+            truncate: true,
+            force_stop: true,
+            duplicates: DuplicateName::Rename,
+            ..Default::default()
+        };
+        assert_eq!(args.action.upload_options(), Some(expected));
+    }
+
+    #[test]
+    fn replacing_is_uploading_with_replace() {
+        let upload = parse(&[
+            "upload",
+            "program.bin",
+            "--slot",
+            "3",
+            "--replace",
+            "--force",
+            "--allow-duplicate-name",
+        ])
+        .unwrap();
+        let replace = parse(&[
+            "replace",
+            "3",
+            "program.bin",
+            "--force",
+            "--allow-duplicate-name",
+        ])
+        .unwrap();
+        let options = replace.action.upload_options().unwrap();
+        assert!(options.replace && options.force);
+        assert_eq!(options.duplicates, DuplicateName::Allow);
+        assert_eq!(upload.action.upload_options(), Some(options));
+        assert_eq!(
+            parse(&["ping-brain"]).unwrap().action.upload_options(),
+            None
+        );
+    }
+}
@@ -1,132 +1,89 @@
-use std::path::PathBuf;
+use std::time::Duration;
 
-use actions::upload::{AfterUpload, ProgramIcon};
-use clap::{Parser, Subcommand};
+use anyhow::{anyhow, Context};
+use args::{Action, Args};
+use clap::{CommandFactory, FromArgMatches};
 use log::info;
-use tokio::io::BufReader;
-use v5d_interface::{get_response, send_command, DaemonCommand};
+use run::run_action;
+use tokio::{io::BufReader, net::UnixStream, time::timeout};
 
 pub mod actions;
-
-#[derive(Parser)]
-#[command(version, about = "A CLI for interacting with the V5 Daemon (v5d)")]
-struct Args {
-    #[clap(subcommand)]
-    action: Action,
-}
-
-#[derive(Subcommand)]
-enum Action {
-    MockTap {
-        x: u16,
-        y: u16,
-    },
-    /// Uploads a user program to the brain
-    #[command(name = "upload", visible_alias = "u")]
-    UploadProgram {
-        /// Path to the monolith bin to upload
-        #[arg(required_unless_present_any = ["hot", "cold"], conflicts_with_all = ["hot", "cold"])]
-        monolith: Option<PathBuf>,
-
-        /// Path to the hot bin to upload
-        #[arg(long, required_unless_present_any = ["cold", "monolith"], conflicts_with = "monolith")]
-        hot: Option<PathBuf>,
-
-        /// Path to the cold bin to upload
-        #[arg(long, required_unless_present_any = ["hot", "monolith"], conflicts_with = "monolith")]
-        cold: Option<PathBuf>,
-
-        /// The slot to upload to
-        #[arg(long, short)]
-        slot: u8,
-
-        /// The name of the program
-        #[arg(short, long)]
-        name: Option<String>,
-
-        /// The description of the program
-        #[arg(short, long)]
-        description: Option<String>,
-
-        /// The icon to appear on the program
-        #[arg(short, long, default_value = "question-mark")]
-        icon: ProgramIcon,
-
-        /// The text to appear in the program type box
-        #[arg(short = 't', long)]
-        program_type: Option<String>,
-
-        /// Whether or not the program should be compressed before uploading
-        #[arg(short, long)]
-        uncompressed: bool,
-
-        /// Action to perform after uploading the program
-        #[arg(short, long, default_value = "show-screen")]
-        after_upload: AfterUpload,
-    },
-    Pair,
-    StopDaemon,
-    Reconnect,
+mod args;
+mod run;
+
+/// Opens a connection to the daemon.
+///
+/// This happens after arguments are parsed and logging is set up, and only for actions
+/// that use it, so `--help` and argument errors never wait on the socket.
+async fn connect(limit: Duration) -> anyhow::Result<BufReader<UnixStream>> {
+    let socket = v5d_interface::connect_to_socket_within(limit)
+        .await
+        .context("Failed to connect to v5d! Is it running?")?;
+    Ok(BufReader::new(socket))
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+    // The matches also say where each value came from, for `env`
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
     let _ = simplelog::TermLogger::init(
-        log::LevelFilter::Info,
+        if args.verbose {
+            log::LevelFilter::Debug
+        } else {
+            log::LevelFilter::Info
+        },
         Default::default(),
         simplelog::TerminalMode::Mixed,
         simplelog::ColorChoice::Auto,
     );
+    actions::progress::set_ascii(args.ascii);
+    actions::interaction::set_non_interactive(args.non_interactive);
 
-    let mut sock = BufReader::new(
-        v5d_interface::connect_to_socket()
-            .await
-            .expect("Failed to connect to v5d! Is it running?"),
-    );
-    match args.action {
-        Action::MockTap { x, y } => {
-            send_command(&mut sock, DaemonCommand::MockTap { x, y }).await?;
-            let response = get_response(&mut sock).await?;
-            info!("Received response: {:?}", response);
-        }
-        Action::UploadProgram {
-            slot,
-            icon,
-            description,
-            name,
-            program_type,
-            monolith,
-            hot,
-            cold,
-            uncompressed,
-            after_upload,
-        } => {
-            actions::upload(
-                &mut sock,
-                monolith,
-                hot,
-                cold,
-                slot,
-                name,
-                description,
-                icon,
-                program_type,
-                uncompressed,
-                after_upload,
-            )
-            .await?;
-        }
-        Action::StopDaemon => {
-            send_command(&mut sock, DaemonCommand::Shutdown).await?;
-        }
-        Action::Reconnect => {
-            send_command(&mut sock, DaemonCommand::Reconnect).await?;
-        }
-        Action::Pair => {
-            actions::pair(&mut sock).await?;
+    if let Action::Introspect { json } = args.action {
+        actions::introspect::introspect(Args::command(), json);
+        return Ok(());
+    }
+    if let Action::Env { json } = args.action {
+        actions::env::env(&matches, &args, json);
+        return Ok(());
+    }
+    if let Action::CleanTemp { dir, yes } = &args.action {
+        return actions::temp::clean_temp(dir, *yes);
+    }
+
+    if args.dry_run {
+        if let Some(preview) = args.action.dry_run_preview(args.device) {
+            for line in preview {
+                info!("[dry-run] {line}");
+            }
+            return Ok(());
         }
     }
 
-    anyhow::Ok(())
+    if let Some(wait_time) = args.wait_for_device {
+        actions::wait_for_device(args.device, Duration::from_secs(wait_time)).await?;
+    }
+    if let Some(id) = &args.on_connect {
+        let id = Some(id.as_str()).filter(|id| !id.is_empty());
+        let deadline = args.on_connect_timeout.map(Duration::from_secs);
+        args.device = actions::devices::wait_for_new_device(id, deadline).await?;
+    }
+
+    let connect_timeout = Duration::try_from_secs_f64(args.connect_timeout)
+        .context("--connect-timeout must be a positive number of seconds")?;
+    let action_timeout = args
+        .timeout
+        .map(Duration::try_from_secs_f64)
+        .transpose()
+        .context("--timeout must be a positive number of seconds")?;
+    let action = run_action(args.action, args.device, connect_timeout);
+    match action_timeout {
+        // Dropping the action closes its connections, which makes the daemon cancel
+        // whatever it was doing for them
+        Some(limit) => timeout(limit, action)
+            .await
+            .map_err(|_| anyhow!("The command didn't finish within {}s", limit.as_secs_f64()))?,
+        None => action.await,
+    }
 }
@@ -0,0 +1,240 @@
+//! Running a parsed action against the daemon.
+
+use std::{fs::File, time::Duration};
+
+use anyhow::Context;
+use log::info;
+use v5d_interface::{get_response, send_command, send_command_to, DaemonCommand};
+
+use crate::{
+    actions::{
+        self,
+        progress::{open_progress_fd, ProgressFd, ProgressOutput},
+        upload::ProgramSource,
+    },
+    args::{Action, ControllerAction, DaemonAction, JobsAction},
+};
+
+/// Runs `action` on the device bound to `route`.
+///
+/// Only actions that talk to the daemon connect to it, waiting up to `connect_timeout`.
+/// Actions that open their own connections don't leave an unused one behind, which the
+/// daemon would log as a failed request.
+pub async fn run_action(
+    action: Action,
+    route: u8,
+    connect_timeout: Duration,
+) -> anyhow::Result<()> {
+    let connect = || crate::connect(connect_timeout);
+    let upload_options = action.upload_options();
+    match action {
+        Action::MockTap { x, y } => {
+            let mut sock = connect().await?;
+            send_command_to(&mut sock, route, DaemonCommand::MockTap { x, y }).await?;
+            let response = get_response(&mut sock).await?;
+            info!("Received response: {:?}", response);
+        }
+        Action::UploadProgram {
+            slot,
+            monolith,
+            hot,
+            cold,
+            from_cargo,
+            release,
+            bin,
+            metadata_path,
+            from_project,
+            progress_fd,
+            progress_file,
+            progress_format,
+            devices,
+            all,
+            ..
+        } => {
+            let mut options = upload_options.expect("uploads have upload options");
+            let progress_output = match (progress_fd, progress_file) {
+                (Some(ProgressFd::StderrCompact), _) => Some(ProgressOutput::stderr_compact()?),
+                (Some(ProgressFd::Fd(fd)), _) => {
+                    Some(ProgressOutput::new(open_progress_fd(fd)?, progress_format))
+                }
+                (None, Some(path)) => {
+                    let file = File::create(&path).with_context(|| {
+                        format!("Failed to create progress file {}", path.display())
+                    })?;
+                    Some(ProgressOutput::new(file, progress_format))
+                }
+                (None, None) => None,
+            };
+            let devices = if all {
+                Some(actions::devices::routes().await?)
+            } else {
+                (!devices.is_empty()).then_some(devices)
+            };
+            let project = match from_project {
+                Some(path) => actions::project::read_descriptor(&path)?,
+                None => Default::default(),
+            };
+            let slot = slot
+                .or(project.slot)
+                .context("The program descriptor has no slot. Pick one with --slot")?;
+            options.name = options.name.or(project.name);
+            options.description = options.description.or(project.description);
+            options.icon = options.icon.or(project.icon);
+            options.program_type = options.program_type.or(project.program_type);
+            let source = if from_cargo {
+                let metadata = actions::cargo::load_metadata(metadata_path.as_deref())?;
+                ProgramSource::Cargo(actions::cargo::find_artifact(
+                    &metadata,
+                    bin.as_deref(),
+                    release,
+                )?)
+            } else {
+                ProgramSource::Files {
+                    monolith,
+                    hot,
+                    cold,
+                }
+            };
+            actions::upload(
+                connect().await?,
+                route,
+                source,
+                slot,
+                options,
+                progress_output,
+                devices,
+            )
+            .await?;
+        }
+        Action::Replace { slot, bin, .. } => {
+            let options = upload_options.expect("replacing is an upload");
+            actions::upload(
+                connect().await?,
+                route,
+                ProgramSource::Files {
+                    monolith: Some(bin),
+                    hot: None,
+                    cold: None,
+                },
+                slot,
+                options,
+                None,
+                None,
+            )
+            .await?;
+        }
+        Action::Daemon { action } => match action {
+            DaemonAction::Logs { level } => {
+                actions::daemon::logs(&mut connect().await?, level).await?
+            }
+        },
+        Action::StopDaemon => {
+            send_command(&mut connect().await?, DaemonCommand::Shutdown).await?;
+        }
+        Action::Reconnect => {
+            send_command(&mut connect().await?, DaemonCommand::Reconnect).await?;
+        }
+        Action::ApplyExit {
+            slot,
+            action,
+            force_stop,
+        } => {
+            actions::upload::apply_exit_action(
+                &mut connect().await?,
+                route,
+                slot,
+                action,
+                force_stop,
+            )
+            .await?;
+        }
+        Action::Pair { pin } => {
+            actions::pair(&mut connect().await?, route, pin).await?;
+        }
+        Action::SwitchToUsb => {
+            actions::switch_to_usb(&mut connect().await?, route).await?;
+        }
+        Action::Kv { key, value } => {
+            actions::kv(&mut connect().await?, route, key, value).await?;
+        }
+        Action::Terminal { mode } => {
+            actions::terminal(route, mode).await?;
+        }
+        Action::SelftestDevice {
+            slot,
+            program,
+            keep,
+            yes,
+        } => {
+            actions::selftest::selftest_device(connect().await?, route, slot, &program, keep, yes)
+                .await?;
+        }
+        Action::Deploy {
+            bin,
+            slot,
+            name,
+            rollback,
+            window,
+        } => {
+            actions::deploy(
+                connect().await?,
+                route,
+                &bin,
+                slot,
+                name,
+                Duration::from_secs(window),
+                rollback,
+            )
+            .await?;
+        }
+        Action::Protect { slot } => {
+            actions::protect::protect(&mut connect().await?, route, slot).await?;
+        }
+        Action::Unprotect { slot } => {
+            actions::protect::unprotect(&mut connect().await?, route, slot).await?;
+        }
+        Action::Devices => {
+            actions::devices(&mut connect().await?).await?;
+        }
+        Action::Errors { limit } => {
+            actions::errors(&mut connect().await?, limit).await?;
+        }
+        Action::Uptime => {
+            actions::uptime(&mut connect().await?, route).await?;
+        }
+        Action::PingBrain { count } => {
+            actions::ping_brain(connect().await?, route, count).await?;
+        }
+        Action::Jobs { action } => match action {
+            JobsAction::Status { id } => actions::jobs::status(&mut connect().await?, id).await?,
+            JobsAction::Attach { id } => actions::jobs::attach(&mut connect().await?, id).await?,
+            JobsAction::Cancel { id } => actions::jobs::cancel(&mut connect().await?, id).await?,
+        },
+        Action::Snapshot { output, force } => {
+            actions::snapshot(route, output.as_deref(), force).await?;
+        }
+        Action::Field => {
+            actions::field(&mut connect().await?, route).await?;
+        }
+        Action::BootState => {
+            actions::boot_state(&mut connect().await?, route).await?;
+        }
+        Action::Firmware => {
+            actions::firmware(&mut connect().await?, route).await?;
+        }
+        Action::Controller {
+            action: ControllerAction::Monitor { json, record },
+        } => {
+            actions::controller::monitor(route, json, record).await?;
+        }
+        Action::Introspect { .. } | Action::Env { .. } | Action::CleanTemp { .. } => {
+            unreachable!("introspect, env and clean-temp run without connecting")
+        }
+        #[cfg(feature = "debug")]
+        Action::Mem { address, length } => {
+            actions::memory::read_memory(&mut connect().await?, route, address, length).await?;
+        }
+    }
+
+    anyhow::Ok(())
+}
@@ -0,0 +1,133 @@
+//! A fake daemon to run v5ctl against, answering each command however a test says.
+
+// Each test crate uses only some of these
+#![allow(dead_code)]
+
+use std::{
+    path::PathBuf,
+    process::{Command, Output},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+use v5d_interface::{DaemonCommand, DaemonRequest, DaemonResponse};
+
+/// Answers a command the fake daemon was sent.
+///
+/// Answering with [`DaemonResponse::TransferProgress`] leaves the transfer running, so the
+/// client waits for more until it gives up.
+pub type Answer = Box<dyn FnMut(DaemonCommand) -> DaemonResponse + Send>;
+
+/// Answers a command the fake daemon was sent with several responses, in order, such as the
+/// progress of a transfer followed by its result.
+pub type Answers = Box<dyn FnMut(DaemonCommand) -> Vec<DaemonResponse> + Send>;
+
+/// A daemon listening in a runtime directory of its own, which v5ctl is pointed at.
+pub struct FakeDaemon {
+    pub runtime_dir: PathBuf,
+    received: Arc<Mutex<Vec<String>>>,
+    abandoned: Arc<AtomicUsize>,
+    serving: tokio::task::JoinHandle<()>,
+}
+
+impl FakeDaemon {
+    /// Starts a daemon that answers every command with `answer`. `name` keeps each test's
+    /// directory apart.
+    pub fn start(name: &str, mut answer: Answer) -> Self {
+        Self::start_with_answers(name, Box::new(move |command| vec![answer(command)]))
+    }
+
+    /// Starts a daemon that answers every command with each of the responses `answers`
+    /// gives.
+    pub fn start_with_answers(name: &str, answers: Answers) -> Self {
+        let runtime_dir = std::env::temp_dir().join(format!("v5ctl-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&runtime_dir);
+        std::fs::create_dir_all(&runtime_dir).unwrap();
+
+        let listener = UnixListener::bind(runtime_dir.join("v5d.sock")).unwrap();
+        let received = Arc::<Mutex<Vec<String>>>::default();
+        let abandoned = Arc::<AtomicUsize>::default();
+        let answers = Arc::new(Mutex::new(answers));
+        let serving = tokio::spawn({
+            let (received, abandoned) = (received.clone(), abandoned.clone());
+            async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let client = serve(stream, received.clone(), answers.clone());
+                    let abandoned = abandoned.clone();
+                    tokio::spawn(async move {
+                        if client.await {
+                            abandoned.fetch_add(1, Ordering::Relaxed);
+                        }
+                    });
+                }
+            }
+        });
+        Self {
+            runtime_dir,
+            received,
+            abandoned,
+            serving,
+        }
+    }
+
+    /// Runs v5ctl with `args` until it exits.
+    pub async fn run(&self, args: &[&str]) -> Output {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_v5ctl"));
+        command.args(args).env("XDG_RUNTIME_DIR", &self.runtime_dir);
+        tokio::task::spawn_blocking(move || command.output().unwrap())
+            .await
+            .unwrap()
+    }
+
+    /// The names of the commands received so far, in order.
+    pub fn received(&self) -> Vec<String> {
+        self.received.lock().unwrap().clone()
+    }
+
+    /// How many clients hung up while a transfer was still running, which is how the real
+    /// daemon knows to abort it.
+    pub fn abandoned_transfers(&self) -> usize {
+        self.abandoned.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for FakeDaemon {
+    fn drop(&mut self) {
+        self.serving.abort();
+        let _ = std::fs::remove_dir_all(&self.runtime_dir);
+    }
+}
+
+/// Answers a client until it hangs up, returning whether it left a transfer running.
+async fn serve(
+    stream: UnixStream,
+    received: Arc<Mutex<Vec<String>>>,
+    answers: Arc<Mutex<Answers>>,
+) -> bool {
+    let mut stream = BufReader::new(stream);
+    let mut line = String::new();
+    let mut transferring = false;
+    while stream.read_line(&mut line).await.unwrap() > 0 {
+        let request: DaemonRequest = serde_json::from_str(&line).unwrap();
+        line.clear();
+        let name = format!("{:?}", request.command);
+        let name = name.split([' ', '(']).next().unwrap().to_string();
+        received.lock().unwrap().push(name);
+
+        let responses = (answers.lock().unwrap())(request.command);
+        transferring = matches!(responses.last(), Some(DaemonResponse::TransferProgress(_)));
+        for response in responses {
+            let mut response = serde_json::to_string(&response).unwrap();
+            response.push('\n');
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    }
+    transferring
+}
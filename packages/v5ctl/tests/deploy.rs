@@ -0,0 +1,106 @@
+//! Runs `v5ctl deploy --rollback` against a fake daemon whose program runs briefly, then stops.
+
+mod common;
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use common::FakeDaemon;
+use v5d_interface::{
+    AfterFileUpload, DaemonCommand, DaemonResponse, ProgramData, ProgramUpload, RunningProgram,
+    Slot, UptimeInfo,
+};
+
+/// The program in slot 1 before the deploy.
+fn old_program() -> ProgramUpload {
+    ProgramUpload {
+        name: "old".to_owned(),
+        description: "Uploaded with v5d".to_owned(),
+        icon: "USER902x.bmp".to_owned(),
+        program_type: "Unknown".to_owned(),
+        slot: Slot::try_from(1).unwrap(),
+        compression: true,
+        after_upload: AfterFileUpload::DoNothing,
+        data: ProgramData::Monolith(vec![0xCD; 1000]),
+        replace: false,
+        overwrite_protected: false,
+    }
+}
+
+/// A daemon whose brain runs the uploaded program for two status polls, after which it stops
+/// having printed `output`. The names of the uploaded programs are recorded in `uploads`.
+fn deploy_daemon(name: &str, output: &'static str, uploads: Arc<Mutex<Vec<String>>>) -> FakeDaemon {
+    let mut polls_left: u32 = 0;
+    let daemon = FakeDaemon::start(
+        name,
+        Box::new(move |command| match command {
+            DaemonCommand::ReadProgram { .. } => DaemonResponse::Program(Ok(Some(old_program()))),
+            DaemonCommand::UploadProgram(upload) => {
+                uploads.lock().unwrap().push(upload.name);
+                polls_left = 2;
+                DaemonResponse::TransferComplete(Ok(()))
+            }
+            DaemonCommand::Uptime => {
+                let program = (polls_left > 0).then_some(RunningProgram {
+                    slot: 1,
+                    running_for: None,
+                });
+                polls_left = polls_left.saturating_sub(1);
+                DaemonResponse::Uptime(UptimeInfo {
+                    connected_for: Duration::from_secs(60),
+                    program,
+                })
+            }
+            // The output is only read once the program has stopped
+            DaemonCommand::ReadUser { .. } if polls_left == 0 => {
+                DaemonResponse::UserRead(output.as_bytes().to_vec())
+            }
+            DaemonCommand::ReadUser { .. } => DaemonResponse::UserRead(vec![]),
+            command => panic!("unexpected command {command:?}"),
+        }),
+    );
+    std::fs::write(daemon.runtime_dir.join("new.bin"), [0xAB; 1000]).unwrap();
+    daemon
+}
+
+async fn deploy(daemon: &FakeDaemon) -> std::process::Output {
+    let bin = daemon.runtime_dir.join("new.bin");
+    daemon
+        .run(&["deploy", bin.to_str().unwrap(), "--slot", "1", "--rollback"])
+        .await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_crash_restores_the_previous_program() {
+    let uploads = Arc::<Mutex<Vec<String>>>::default();
+    let daemon = deploy_daemon(
+        "deploy-crash",
+        "panicked at src/main.rs:12:5:\nexplicit panic\n",
+        uploads.clone(),
+    );
+    let output = deploy(&daemon).await;
+    assert!(!output.status.success());
+    assert_eq!(*uploads.lock().unwrap(), ["new", "old"]);
+    assert_eq!(daemon.received()[0], "ReadProgram");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Deploy failed: the program crashed. The previous program was restored"),
+        "{stderr}"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_clean_exit_is_not_rolled_back() {
+    let uploads = Arc::<Mutex<Vec<String>>>::default();
+    let daemon = deploy_daemon("deploy-exit", "done\n", uploads.clone());
+    let output = deploy(&daemon).await;
+    assert!(output.status.success());
+    assert_eq!(*uploads.lock().unwrap(), ["new"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("exited on its own, which isn't a crash"),
+        "{stdout}"
+    );
+}
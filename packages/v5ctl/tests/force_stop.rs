@@ -0,0 +1,71 @@
+//! Runs `v5ctl upload` against a fake daemon whose brain refuses uploads while a program runs.
+
+mod common;
+
+use common::FakeDaemon;
+use v5d_interface::{DaemonCommand, DaemonResponse, TransferError};
+
+/// A daemon whose brain runs a program until it's told to stop.
+fn busy_daemon(name: &str) -> FakeDaemon {
+    let mut running = true;
+    let daemon = FakeDaemon::start(
+        name,
+        Box::new(move |command| match command {
+            DaemonCommand::ProgramNames => DaemonResponse::ProgramNames(Ok(vec![])),
+            DaemonCommand::StopProgram => {
+                running = false;
+                DaemonResponse::BasicAck { successful: true }
+            }
+            DaemonCommand::UploadProgram(_) if running => {
+                DaemonResponse::TransferComplete(Err(TransferError {
+                    program_running: true,
+                    ..TransferError::new("The brain refused the transfer")
+                }))
+            }
+            DaemonCommand::UploadProgram(_) => DaemonResponse::TransferComplete(Ok(())),
+            command => panic!("unexpected command {command:?}"),
+        }),
+    );
+    std::fs::write(daemon.runtime_dir.join("program.bin"), [0xAB; 1000]).unwrap();
+    daemon
+}
+
+async fn upload(daemon: &FakeDaemon, args: &[&str]) -> std::process::Output {
+    let program = daemon.runtime_dir.join("program.bin");
+    let args = [&["upload", "--slot", "1", program.to_str().unwrap()], args].concat();
+    daemon.run(&args).await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_busy_brain_is_explained() {
+    let daemon = busy_daemon("busy");
+    let output = upload(&daemon, &[]).await;
+    assert_eq!(daemon.received(), ["ProgramNames", "UploadProgram"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("A program is running on the brain. Pass --force-stop"),
+        "{stderr}"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn force_stop_stops_the_program_and_retries() {
+    let daemon = busy_daemon("force-stop");
+    let output = upload(&daemon, &["--force-stop"]).await;
+    assert!(output.status.success());
+    assert_eq!(
+        daemon.received(),
+        [
+            "ProgramNames",
+            "UploadProgram",
+            "StopProgram",
+            "UploadProgram"
+        ]
+    );
+    // Info is logged to stdout, and errors to stderr
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("Successfully uploaded program!"),
+        "{stdout}"
+    );
+}
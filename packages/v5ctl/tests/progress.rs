@@ -0,0 +1,117 @@
+//! Runs `v5ctl upload --progress-fd stderr-compact` against a fake daemon, parsing the compact
+//! progress records the way a wrapping script would.
+
+mod common;
+
+use common::FakeDaemon;
+use v5d_interface::{DaemonCommand, DaemonResponse, SectionProgress, TransferError, UploadStep};
+
+/// A daemon that sends an INI and a BIN section in chunks, then ends the transfer with
+/// `result`.
+fn uploading_daemon(name: &str, result: Result<(), TransferError>) -> FakeDaemon {
+    let daemon = FakeDaemon::start_with_answers(
+        name,
+        Box::new(move |command| match command {
+            DaemonCommand::ProgramNames => vec![DaemonResponse::ProgramNames(Ok(vec![]))],
+            DaemonCommand::UploadProgram(_) => {
+                let sections = [(UploadStep::Ini, 200), (UploadStep::Monolith, 4000)];
+                let mut responses: Vec<_> = sections
+                    .into_iter()
+                    .flat_map(|(step, total)| {
+                        (1..=4).map(move |chunk| {
+                            DaemonResponse::TransferProgress(SectionProgress {
+                                step,
+                                sent: total * chunk / 4,
+                                total,
+                            })
+                        })
+                    })
+                    .collect();
+                responses.push(DaemonResponse::TransferComplete(result.clone()));
+                responses
+            }
+            command => panic!("unexpected command {command:?}"),
+        }),
+    );
+    std::fs::write(daemon.runtime_dir.join("program.bin"), [0xAB; 4000]).unwrap();
+    daemon
+}
+
+/// The records in `stderr`, split into fields. Log lines are skipped, as a script would.
+fn records(stderr: &[u8]) -> Vec<Vec<String>> {
+    String::from_utf8_lossy(stderr)
+        .lines()
+        .filter(|line| line.starts_with("PROGRESS ") || line.starts_with("FINISHED "))
+        .map(|line| line.split(' ').map(str::to_owned).collect())
+        .collect()
+}
+
+async fn upload(daemon: &FakeDaemon) -> std::process::Output {
+    let program = daemon.runtime_dir.join("program.bin");
+    daemon
+        .run(&[
+            "upload",
+            "--slot",
+            "1",
+            program.to_str().unwrap(),
+            "--progress-fd",
+            "stderr-compact",
+        ])
+        .await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn an_upload_writes_well_formed_records() {
+    let daemon = uploading_daemon("compact-ok", Ok(()));
+    let output = upload(&daemon).await;
+    assert!(output.status.success());
+    let records = records(&output.stderr);
+
+    let (finished, progress) = records.split_last().expect("no records were written");
+    assert_eq!(finished, &["FINISHED", "ok"]);
+    for record in progress {
+        let [tag, section, phase, bytes, total] = &record[..] else {
+            panic!("malformed record {record:?}");
+        };
+        assert_eq!(tag, "PROGRESS");
+        assert!(["INI", "BIN"].contains(&section.as_str()), "{record:?}");
+        assert!(["sending", "done"].contains(&phase.as_str()), "{record:?}");
+        let (bytes, total) = (bytes.parse::<u32>().unwrap(), total.parse::<u32>().unwrap());
+        assert!(bytes <= total, "{record:?}");
+    }
+
+    // Every section ends with a `done` record for all of its bytes
+    let done: Vec<_> = progress
+        .iter()
+        .filter(|record| record[2] == "done")
+        .map(|record| record[1..].join(" "))
+        .collect();
+    assert_eq!(done, ["INI done 200 200", "BIN done 4000 4000"]);
+
+    // Logs stay on stdout, in human form
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("Successfully uploaded program!"),
+        "{stdout}"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_failed_upload_ends_with_its_error_on_one_line() {
+    let daemon = uploading_daemon(
+        "compact-failed",
+        Err(TransferError::new("The brain refused\nthe transfer")),
+    );
+    let output = upload(&daemon).await;
+    let records = records(&output.stderr);
+    assert_eq!(
+        records.last().unwrap().join(" "),
+        "FINISHED failed The brain refused the transfer"
+    );
+    // The error is still logged in full alongside the records
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("The brain refused\nthe transfer"),
+        "{stderr}"
+    );
+}
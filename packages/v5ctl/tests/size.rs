@@ -0,0 +1,48 @@
+//! Keeps the release v5ctl binary small, since the VS Code extension bundles one per platform.
+//!
+//! Building in release mode takes minutes, so this is ignored by default. CI runs it with
+//! `cargo test -p v5ctl --test size -- --ignored --nocapture`, which also prints the size of
+//! each feature combination. When this was written, the default and `debug` builds were both
+//! 3.0MB.
+
+use std::{path::Path, process::Command};
+
+/// The most the default build may weigh. Raise it deliberately, not to make CI pass.
+const BUDGET: u64 = 4 * 1024 * 1024;
+
+/// Builds v5ctl in release mode with `features`, returning the binary's size in bytes.
+fn release_size(features: &[&str]) -> u64 {
+    let name = if features.is_empty() {
+        "default".to_string()
+    } else {
+        features.join("-")
+    };
+    // A target directory of its own, so this doesn't wait on or disturb the test build
+    let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("size-{name}"));
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "--release", "-p", "v5ctl", "--features"])
+        .arg(features.join(","))
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .status()
+        .unwrap();
+    assert!(status.success(), "the {name} release build failed");
+
+    let binary = target_dir
+        .join("release")
+        .join(format!("v5ctl{}", std::env::consts::EXE_SUFFIX));
+    let size = binary.metadata().unwrap().len();
+    println!("v5ctl ({name}): {:.1}MB", size as f64 / 1024.0 / 1024.0);
+    size
+}
+
+#[test]
+#[ignore = "builds v5ctl in release mode"]
+fn release_binary_stays_within_budget() {
+    let size = release_size(&[]);
+    release_size(&["debug"]);
+    assert!(
+        size <= BUDGET,
+        "the release v5ctl is {size} bytes, over the budget of {BUDGET}"
+    );
+}
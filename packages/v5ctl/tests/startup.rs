@@ -0,0 +1,49 @@
+//! Keeps commands that never reach the daemon fast to start.
+//!
+//! The budget is far above what these take on a developer machine, so only a real regression
+//! (like dialing the daemon or loading data before parsing arguments) trips it on a slow CI
+//! runner.
+
+use std::{
+    process::{Command, Output},
+    time::{Duration, Instant},
+};
+
+const BUDGET: Duration = Duration::from_secs(2);
+
+/// Runs v5ctl with `args` a few times, returning its output and the fastest run's time.
+fn time_v5ctl(args: &[&str]) -> (Output, Duration) {
+    // No daemon can be running here, so waiting on one would show up as a slow start
+    let runtime_dir = std::env::temp_dir().join(format!("v5ctl-startup-{}", std::process::id()));
+    std::fs::create_dir_all(&runtime_dir).unwrap();
+
+    let mut fastest = Duration::MAX;
+    let mut output = None;
+    for _ in 0..3 {
+        let started = Instant::now();
+        let run = Command::new(env!("CARGO_BIN_EXE_v5ctl"))
+            .args(args)
+            .env("XDG_RUNTIME_DIR", &runtime_dir)
+            .output()
+            .unwrap();
+        fastest = fastest.min(started.elapsed());
+        output = Some(run);
+    }
+    let _ = std::fs::remove_dir(&runtime_dir);
+    (output.unwrap(), fastest)
+}
+
+#[test]
+fn help_starts_quickly() {
+    let (output, took) = time_v5ctl(&["--help"]);
+    assert!(output.status.success());
+    assert!(took < BUDGET, "`v5ctl --help` took {took:?}");
+}
+
+#[test]
+fn argument_errors_start_quickly() {
+    let (output, took) = time_v5ctl(&["upload", "--no-such-flag"]);
+    // clap's exit code for usage errors
+    assert_eq!(output.status.code(), Some(2));
+    assert!(took < BUDGET, "an argument error took {took:?}");
+}
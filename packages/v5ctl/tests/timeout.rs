@@ -0,0 +1,56 @@
+//! Runs `v5ctl --timeout` against a fake daemon whose upload never finishes.
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::FakeDaemon;
+use v5d_interface::{DaemonCommand, DaemonResponse, SectionProgress, UploadStep};
+
+// When the timeout fires mid-upload, v5ctl hangs up on the daemon, which aborts the transfer
+// on the brain
+#[tokio::test(flavor = "multi_thread")]
+async fn the_timeout_abandons_a_stalled_upload() {
+    let daemon = FakeDaemon::start(
+        "timeout",
+        Box::new(|command| match command {
+            DaemonCommand::ProgramNames => DaemonResponse::ProgramNames(Ok(vec![])),
+            DaemonCommand::UploadProgram(_) => DaemonResponse::TransferProgress(SectionProgress {
+                step: UploadStep::Monolith,
+                sent: 4096,
+                total: 40_960,
+            }),
+            command => panic!("unexpected command {command:?}"),
+        }),
+    );
+    let program = daemon.runtime_dir.join("program.bin");
+    std::fs::write(&program, [0xAB; 1000]).unwrap();
+
+    let started = Instant::now();
+    let output = daemon
+        .run(&[
+            "--timeout",
+            "0.5",
+            "upload",
+            "--slot",
+            "1",
+            program.to_str().unwrap(),
+        ])
+        .await;
+    assert!(started.elapsed() < Duration::from_secs(10));
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("The command didn't finish within 0.5s"),
+        "{stderr}"
+    );
+    // The daemon only notices the hang-up after v5ctl exits
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while daemon.abandoned_transfers() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("v5ctl never hung up on the upload");
+    assert_eq!(daemon.received(), ["ProgramNames", "UploadProgram"]);
+}
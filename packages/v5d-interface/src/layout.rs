@@ -0,0 +1,112 @@
+//! Where user program binaries live in the brain's memory.
+//!
+//! These addresses are fixed rather than queried from the brain: no packet reports the
+//! memory layout, and vex-v5-serial's upload command always loads binaries at these addresses
+//! whatever the brain would say. They match the `COLD_MEMORY` and `HOT_MEMORY` regions in
+//! PROS's linker scripts.
+
+use std::fmt;
+
+use vex_v5_serial::commands::file::COLD_START;
+
+/// Load address of the hot binary in a hot/cold upload.
+///
+/// vex-v5-serial writes this address inline instead of exporting it, so it's repeated here.
+pub const HOT_START: u32 = 0x07800000;
+
+/// End of the memory region available to user programs.
+pub const USER_MEMORY_END: u32 = 0x08000000;
+
+/// A region of memory that a program binary is loaded into.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ProgramRegion {
+    pub load_address: u32,
+    /// The largest binary (in bytes, before compression) that fits in this region.
+    pub max_size: u32,
+}
+
+impl ProgramRegion {
+    /// Monolith binaries may use all of user memory.
+    pub const MONOLITH: Self = Self::new(COLD_START, USER_MEMORY_END);
+    /// Cold binaries must end before the hot binary starts.
+    pub const COLD: Self = Self::new(COLD_START, HOT_START);
+    pub const HOT: Self = Self::new(HOT_START, USER_MEMORY_END);
+
+    const fn new(start: u32, end: u32) -> Self {
+        Self {
+            load_address: start,
+            max_size: end - start,
+        }
+    }
+
+    /// Checks that a binary of the given size fits in this region.
+    pub fn check_fits(&self, size: usize) -> Result<(), BinaryTooLarge> {
+        if size > self.max_size as usize {
+            return Err(BinaryTooLarge {
+                region: *self,
+                size,
+            });
+        }
+        Ok(())
+    }
+}
+impl fmt::Display for ProgramRegion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#010x}..{:#010x} ({} bytes)",
+            self.load_address,
+            self.load_address + self.max_size,
+            self.max_size
+        )
+    }
+}
+
+/// A binary is too large for the memory region it would be loaded into.
+#[derive(Debug, Clone, Copy)]
+pub struct BinaryTooLarge {
+    pub region: ProgramRegion,
+    pub size: usize,
+}
+impl fmt::Display for BinaryTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "binary is {} bytes, but at most {} bytes fit at {:#010x}",
+            self.size, self.region.max_size, self.region.load_address
+        )
+    }
+}
+impl std::error::Error for BinaryTooLarge {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binaries_fit_up_to_the_end_of_their_region() {
+        for region in [
+            ProgramRegion::MONOLITH,
+            ProgramRegion::COLD,
+            ProgramRegion::HOT,
+        ] {
+            let end = region.max_size as usize;
+            assert!(region.check_fits(end - 1).is_ok(), "{region}");
+            assert!(region.check_fits(end).is_ok(), "{region}");
+            let err = region.check_fits(end + 1).unwrap_err();
+            assert_eq!(err.size, end + 1);
+            assert_eq!(err.region, region);
+        }
+    }
+
+    #[test]
+    fn cold_binaries_end_where_hot_ones_start() {
+        let cold = ProgramRegion::COLD;
+        assert_eq!(
+            cold.load_address + cold.max_size,
+            ProgramRegion::HOT.load_address
+        );
+        let hot = ProgramRegion::HOT;
+        assert_eq!(hot.load_address + hot.max_size, USER_MEMORY_END);
+    }
+}
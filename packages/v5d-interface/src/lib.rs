@@ -1,13 +1,26 @@
-use std::{io, path::PathBuf};
+use std::{
+    fmt,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+pub mod layout;
+mod transfer;
+mod user_io;
 
-use log::{debug, info};
+use log::debug;
 use serde::{Deserialize, Serialize};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt},
     net::UnixStream,
 };
-use vex_v5_serial::packets::file::FileExitAction;
+use vex_v5_serial::{connection::ConnectionType, packets::file::FileExitAction};
 
+pub use transfer::{ProgressRenderer, Transfer, TransferEvent};
+pub use user_io::{read_user, write_user, UserIo};
 pub use vex_v5_serial::commands::file::ProgramData;
 
 pub fn socket_path() -> PathBuf {
@@ -16,33 +29,87 @@ pub fn socket_path() -> PathBuf {
         .join("v5d.sock")
 }
 
+/// How long connecting to the daemon may take before giving up, unless told otherwise.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub async fn connect_to_socket() -> io::Result<UnixStream> {
-    let path = socket_path();
-    debug!("Connecting to UNIX socket at {:?}", path);
+    connect_to_socket_within(DEFAULT_CONNECT_TIMEOUT).await
+}
 
-    let socket = UnixStream::connect(&path).await?;
+/// Connects to the daemon, failing with [`io::ErrorKind::TimedOut`] if that takes longer
+/// than `limit`, so a stuck socket can't hang a client forever.
+pub async fn connect_to_socket_within(limit: Duration) -> io::Result<UnixStream> {
+    connect_to_path_within(&socket_path(), limit).await
+}
 
-    info!("Connected to UNIX socket at {:?}", path);
+async fn connect_to_path_within(path: &Path, limit: Duration) -> io::Result<UnixStream> {
+    debug!("Connecting to UNIX socket at {:?}", path);
+    let socket = within(limit, UnixStream::connect(path)).await?;
+    debug!("Connected to UNIX socket at {:?}", path);
     Ok(socket)
 }
 
-pub async fn send_command(
-    stream: &mut BufReader<UnixStream>,
+/// Waits for `connect`, failing with [`io::ErrorKind::TimedOut`] after `limit`.
+async fn within<T>(limit: Duration, connect: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    tokio::time::timeout(limit, connect).await.map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("connecting took longer than {:.1}s", limit.as_secs_f64()),
+        )
+    })?
+}
+
+/// A connection to the daemon.
+///
+/// Clients normally use a buffered [`UnixStream`] from [`connect_to_socket`],
+/// but any buffered, bidirectional byte stream works.
+///
+/// A connection can carry any number of requests, one at a time: send the next request only
+/// once the last one's response has arrived. Clients that poll should reuse one connection.
+pub trait DaemonStream: AsyncBufRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncBufRead + AsyncWrite + Unpin + Send> DaemonStream for T {}
+
+/// Sends a command to the daemon, targeting the first connected device.
+pub async fn send_command(stream: &mut impl DaemonStream, cmd: DaemonCommand) -> io::Result<()> {
+    send_command_to(stream, 0, cmd).await
+}
+
+/// Sends a command to the daemon, targeting the device bound to the given route.
+pub async fn send_command_to(
+    stream: &mut impl DaemonStream,
+    route: u8,
     cmd: DaemonCommand,
 ) -> io::Result<()> {
-    let mut content = serde_json::to_string(&cmd)?;
+    let request = DaemonRequest {
+        route,
+        command: cmd,
+    };
+    let mut content = serde_json::to_string(&request)?;
     content.push('\n');
     stream.write_all(content.as_bytes()).await?;
     Ok(())
 }
-pub async fn get_response(stream: &mut BufReader<UnixStream>) -> io::Result<DaemonResponse> {
+pub async fn get_response(stream: &mut impl DaemonStream) -> io::Result<DaemonResponse> {
     let mut response = String::new();
     stream.read_line(&mut response).await?;
-    let responses = serde_json::from_str(&response)?;
-    Ok(responses)
+    match serde_json::from_str(&response)? {
+        DaemonResponse::InternalError { message, incident } => Err(io::Error::other(format!(
+            "The daemon hit an internal error: {message}. This is a bug in v5d; please report it \
+             with incident number {incident} and the daemon's log"
+        ))),
+        DaemonResponse::ShuttingDown => Err(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "The daemon is shutting down",
+        )),
+        response => Ok(response),
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+fn unexpected_response(response: DaemonResponse) -> io::Error {
+    io::Error::other(format!("Unexpected response from daemon: {:?}", response))
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
 pub enum AfterFileUpload {
     DoNothing,
     RunProgram,
@@ -60,13 +127,231 @@ impl From<AfterFileUpload> for FileExitAction {
     }
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum UploadStep {
     Ini,
     Monolith,
     Cold,
     Hot,
 }
+impl fmt::Display for UploadStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Self::Ini => "INI",
+            Self::Monolith => "BIN",
+            Self::Cold => "COLD",
+            Self::Hot => "HOT",
+        })
+    }
+}
+
+/// The sections an upload of `data` sends, in order. The INI always comes first.
+pub fn upload_steps(data: &ProgramData) -> Vec<UploadStep> {
+    let mut steps = vec![UploadStep::Ini];
+    match data {
+        ProgramData::Monolith(_) => steps.push(UploadStep::Monolith),
+        ProgramData::HotCold { hot, cold } => {
+            if cold.is_some() {
+                steps.push(UploadStep::Cold);
+            }
+            if hot.is_some() {
+                steps.push(UploadStep::Hot);
+            }
+        }
+    }
+    steps
+}
+
+/// A user program slot, numbered 1 to 8 as on the brain's screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub struct Slot(u8);
+impl Slot {
+    pub const MAX: u8 = 8;
+
+    pub fn get(self) -> u8 {
+        self.0
+    }
+
+    /// Every slot, in order.
+    pub fn all() -> impl Iterator<Item = Slot> {
+        (1..=Self::MAX).map(Self)
+    }
+
+    /// The number the brain's own file names use, which starts at 0.
+    pub fn to_zero_based(self) -> u8 {
+        self.0 - 1
+    }
+
+    /// The name the slot's files start with, like `slot0` for slot 1.
+    pub fn file_stem(self) -> String {
+        format!("slot{}", self.to_zero_based())
+    }
+}
+impl TryFrom<u8> for Slot {
+    type Error = String;
+
+    fn try_from(slot: u8) -> Result<Self, Self::Error> {
+        if (1..=Self::MAX).contains(&slot) {
+            Ok(Self(slot))
+        } else {
+            Err(format!(
+                "there is no slot {slot}, slots go from 1 to {}",
+                Self::MAX
+            ))
+        }
+    }
+}
+impl From<Slot> for u8 {
+    fn from(slot: Slot) -> Self {
+        slot.0
+    }
+}
+impl FromStr for Slot {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse::<u8>()
+            .map_err(|_| format!("{s:?} isn't a slot number"))?
+            .try_into()
+    }
+}
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// How much of one section of an upload has been sent.
+///
+/// Sizes are of the data as sent to the brain, so they are compressed sizes
+/// when the upload is compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SectionProgress {
+    pub step: UploadStep,
+    /// Bytes of the section sent so far.
+    pub sent: u32,
+    /// The section's size. It is known before the section starts sending and never changes.
+    pub total: u32,
+}
+impl SectionProgress {
+    pub fn percent(&self) -> f32 {
+        if self.total == 0 {
+            100.0
+        } else {
+            self.sent as f32 / self.total as f32 * 100.0
+        }
+    }
+
+    /// Whether every byte of the section has been sent.
+    pub fn is_complete(&self) -> bool {
+        self.sent >= self.total
+    }
+}
+
+/// Why a transfer failed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferError {
+    /// The section of an upload that was being transferred when it failed.
+    pub step: Option<UploadStep>,
+    pub message: String,
+    /// The brain refused the transfer while a user program was running, which it does
+    /// for file operations. Stopping the program and trying again may work.
+    #[serde(default)]
+    pub program_running: bool,
+    /// The upload was refused because its slot is protected on the brain.
+    #[serde(default)]
+    pub slot_protected: bool,
+}
+impl TransferError {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            step: None,
+            message: message.into(),
+            program_running: false,
+            slot_protected: false,
+        }
+    }
+}
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.step {
+            Some(step) => write!(f, "{} (during the {} section)", self.message, step),
+            None => write!(f, "{}", self.message),
+        }?;
+        if self.program_running {
+            write!(f, ". A program is running on the brain; stop it first")?;
+        }
+        Ok(())
+    }
+}
+impl std::error::Error for TransferError {}
+
+// v5ctl refuses program strings over these limits unless told to shorten them.
+
+/// The longest program name the brain is thought to accept, in characters.
+pub const MAX_PROGRAM_NAME_LEN: usize = 32;
+/// The longest program description the brain is thought to accept, in characters.
+pub const MAX_PROGRAM_DESCRIPTION_LEN: usize = 255;
+/// The longest program type the brain is thought to accept, in characters.
+pub const MAX_PROGRAM_TYPE_LEN: usize = 32;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProgramUpload {
+    pub name: String,
+    pub description: String,
+    pub icon: String,
+    pub program_type: String,
+    pub slot: Slot,
+    pub compression: bool,
+    pub after_upload: AfterFileUpload,
+    pub data: ProgramData,
+    /// Erase every file the slot already has before uploading, so nothing from the old
+    /// program is left behind.
+    ///
+    /// The brain can't rename files, so this isn't atomic: if the upload is interrupted,
+    /// the slot is left empty or with a partial program rather than the old one.
+    #[serde(default)]
+    pub replace: bool,
+    /// Upload even if the slot is protected. See [`DaemonCommand::SetSlotProtection`].
+    #[serde(default)]
+    pub overwrite_protected: bool,
+}
+// `ProgramData` isn't `Clone`
+impl Clone for ProgramUpload {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            description: self.description.clone(),
+            icon: self.icon.clone(),
+            program_type: self.program_type.clone(),
+            slot: self.slot,
+            compression: self.compression,
+            after_upload: self.after_upload,
+            data: match &self.data {
+                ProgramData::Monolith(data) => ProgramData::Monolith(data.clone()),
+                ProgramData::HotCold { hot, cold } => ProgramData::HotCold {
+                    hot: hot.clone(),
+                    cold: cold.clone(),
+                },
+            },
+            replace: self.replace,
+            overwrite_protected: self.overwrite_protected,
+        }
+    }
+}
+
+/// A log record from the daemon itself.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogRecord {
+    pub level: log::Level,
+    /// The module that logged the record.
+    pub target: String,
+    pub message: String,
+}
+
+/// Identifies a background job for as long as the daemon runs.
+pub type JobId = u32;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum DaemonCommand {
@@ -74,26 +359,414 @@ pub enum DaemonCommand {
         x: u16,
         y: u16,
     },
-    UploadProgram {
-        name: String,
-        description: String,
-        icon: String,
-        program_type: String,
-        // 1-indexed slot
-        slot: u8,
-        compression: bool,
-        after_upload: AfterFileUpload,
-        data: ProgramData,
+    UploadProgram(ProgramUpload),
+    /// Starts an upload that keeps running after the client disconnects.
+    UploadProgramInBackground(ProgramUpload),
+    /// Reports on a background job without following it.
+    JobStatus(JobId),
+    /// Streams a background job's progress, starting with what has already happened.
+    ///
+    /// Disconnecting leaves the job running.
+    AttachJob(JobId),
+    CancelJob(JobId),
+    /// Finishes a transfer of an existing program's files with the given action,
+    /// as if it had just been uploaded with it.
+    ///
+    /// Responds with [`DaemonResponse::TransferComplete`].
+    ApplyExitAction {
+        slot: Slot,
+        action: AfterFileUpload,
+    },
+    /// Stops whatever program is running on the brain.
+    StopProgram,
+    /// Streams the daemon's own log records at `level` or more severe as [`DaemonResponse::Log`],
+    /// until the client disconnects.
+    StreamLogs {
+        level: log::Level,
+    },
+    /// Deletes the program in a slot.
+    ///
+    /// Responds with [`DaemonResponse::TransferComplete`].
+    EraseSlot {
+        slot: Slot,
+    },
+    /// Downloads the program in a slot, in a form that can be uploaded again to restore it.
+    ///
+    /// Responds with [`DaemonResponse::Program`].
+    ReadProgram {
+        slot: Slot,
+    },
+    /// Reads the name of the program in every occupied slot.
+    ///
+    /// Responds with [`DaemonResponse::ProgramNames`].
+    ProgramNames,
+    ReadKeyValue {
+        key: String,
+    },
+    WriteKeyValue {
+        key: String,
+        value: String,
+    },
+    /// Reads up to `max_len` bytes of user program output.
+    ReadUser {
+        max_len: u16,
+    },
+    /// Writes bytes to the user program's stdin.
+    WriteUser(Vec<u8>),
+    Uptime,
+    /// Times one cheap request to the brain, to measure the link's round-trip time.
+    ///
+    /// Responds with [`DaemonResponse::BrainPing`].
+    PingBrain,
+    ControllerStatus,
+    FieldControlStatus,
+    /// Responds with [`DaemonResponse::BootState`].
+    BootState,
+    /// Responds with [`DaemonResponse::FirmwareStatus`].
+    FirmwareStatus,
+    /// Reads raw bytes from the brain's memory. At most [`MAX_MEMORY_READ`] bytes are read at once.
+    ///
+    /// This is for debugging vexide's runtime. Reading memory that the firmware doesn't expect
+    /// to be read, like hardware registers with read side effects, can crash the brain or
+    /// the running program.
+    #[cfg(feature = "debug")]
+    ReadMemory {
+        address: u32,
+        length: u16,
     },
     Shutdown,
+    ListDevices,
     RequestPair,
     PairingPin([u8; 4]),
     Reconnect,
+    /// Lists the errors the daemon logged most recently, oldest first. Only the last
+    /// `limit` are sent, if given.
+    ///
+    /// Responds with [`DaemonResponse::RecentErrors`].
+    RecentErrors {
+        limit: Option<usize>,
+    },
+    /// Moves a device connected over Bluetooth onto a USB connection to the same brain.
+    ///
+    /// Commands already running on the device finish over Bluetooth first. The device keeps
+    /// its route, and the Bluetooth connection is closed.
+    SwitchToUsb,
+    /// Lists the protected slots of the brain on the route.
+    ///
+    /// Responds with [`DaemonResponse::ProtectedSlots`].
+    ProtectedSlots,
+    /// Protects a slot from uploads, or lifts the protection.
+    ///
+    /// Protection belongs to the brain, not the route, and is kept across daemon restarts.
+    /// Uploads to a protected slot fail unless they set
+    /// [`ProgramUpload::overwrite_protected`].
+    SetSlotProtection {
+        slot: Slot,
+        protected: bool,
+    },
 }
 
+/// The envelope every client request is sent in.
 #[derive(Debug, Serialize, Deserialize)]
+pub struct DaemonRequest {
+    /// Identifies which of the daemon's device connections the command is routed to.
+    ///
+    /// Routes are assigned in the order devices are first connected, starting at 0,
+    /// and a device that reconnects keeps its route.
+    /// Clients that predate routing send a bare [`DaemonCommand`], which the daemon treats as route 0.
+    #[serde(default)]
+    pub route: u8,
+    pub command: DaemonCommand,
+}
+
+/// Anything the daemon accepts on its socket.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum IncomingRequest {
+    Routed(DaemonRequest),
+    Legacy(DaemonCommand),
+}
+impl From<IncomingRequest> for DaemonRequest {
+    fn from(value: IncomingRequest) -> Self {
+        match value {
+            IncomingRequest::Routed(request) => request,
+            IncomingRequest::Legacy(command) => DaemonRequest { route: 0, command },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum DaemonResponse {
-    BasicAck { successful: bool },
-    TransferProgress { percent: f32, step: UploadStep },
-    TransferComplete(Result<(), String>),
+    BasicAck {
+        successful: bool,
+    },
+    TransferProgress(SectionProgress),
+    TransferComplete(Result<(), TransferError>),
+    Devices(Vec<ConnectedDevice>),
+    /// The value of a key-value setting, or `None` if the brain doesn't support the key.
+    KeyValue(Option<String>),
+    /// User program output. Empty if the program hasn't written anything since the last read.
+    UserRead(Vec<u8>),
+    UserWritten(usize),
+    Uptime(UptimeInfo),
+    /// How long the brain took to reply, or `None` if the reply never came.
+    BrainPing(Option<Duration>),
+    ControllerStatus(ControllerStatus),
+    /// The brain's field control state, or `None` if the brain didn't report it.
+    FieldControlStatus(Option<FieldControlStatus>),
+    /// The mode the brain booted into, or `None` if the brain didn't report it.
+    BootState(Option<BootState>),
+    FirmwareStatus(FirmwareStatus),
+    JobStarted(JobId),
+    /// The state of a background job, or `None` if there is no job with the requested id.
+    JobStatus(Option<JobStatus>),
+    RecentErrors(Vec<ErrorRecord>),
+    Log(LogRecord),
+    /// The program read by [`DaemonCommand::ReadProgram`], or `None` if the slot is empty.
+    ///
+    /// Its data is exactly what the brain stores, so it is uploaded uncompressed.
+    Program(Result<Option<ProgramUpload>, TransferError>),
+    /// The names read by [`DaemonCommand::ProgramNames`], by slot.
+    ProgramNames(Result<Vec<(Slot, String)>, TransferError>),
+    /// Bytes read by [`DaemonCommand::ReadMemory`].
+    #[cfg(feature = "debug")]
+    Memory(Vec<u8>),
+    /// The protected slots of a brain, in order.
+    ProtectedSlots(Vec<Slot>),
+    /// The daemon panicked while handling the request. This is the last response on the
+    /// connection.
+    ///
+    /// `incident` also appears in the daemon's log next to the panic. [`get_response`]
+    /// returns this as an error.
+    InternalError {
+        message: String,
+        incident: u64,
+    },
+    /// The daemon is shutting down. This is the last response on the connection, sent in
+    /// place of the next response the client is waiting for.
+    ///
+    /// [`get_response`] returns this as an error.
+    ShuttingDown,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum DeviceConnectionType {
+    Wired,
+    Controller,
+    Bluetooth,
+}
+impl From<ConnectionType> for DeviceConnectionType {
+    fn from(value: ConnectionType) -> Self {
+        match value {
+            ConnectionType::Wired => DeviceConnectionType::Wired,
+            ConnectionType::Controller => DeviceConnectionType::Controller,
+            ConnectionType::Bluetooth => DeviceConnectionType::Bluetooth,
+        }
+    }
+}
+
+/// A device connection held by the daemon.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectedDevice {
+    /// The route the device is bound to. It stays the same when the device reconnects.
+    pub route: u8,
+    /// A description of where the device is connected, such as its serial port.
+    pub id: String,
+    pub connection_type: DeviceConnectionType,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobStatus {
+    /// The route of the device the job is transferring to.
+    pub route: u8,
+    /// The latest progress of every step the job has started.
+    pub progress: Vec<SectionProgress>,
+    /// How the job ended, or `None` if it's still running.
+    pub result: Option<Result<(), TransferError>>,
+}
+
+/// An error the daemon logged.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorRecord {
+    pub time: SystemTime,
+    /// The module that logged the error.
+    pub target: String,
+    /// The error message. Digits are masked in messages that mention a pin or token.
+    pub message: String,
+}
+
+/// How long a device has been connected and what it is running.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UptimeInfo {
+    /// How long the daemon has been connected to the device.
+    pub connected_for: Duration,
+    /// The program currently running on the device, if any.
+    pub program: Option<RunningProgram>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunningProgram {
+    /// The slot the program is running from.
+    ///
+    /// Slots 1-8 are user programs; built-in programs use higher numbers.
+    pub slot: u8,
+    /// How long the program has been running.
+    /// Only known if the daemon started the program.
+    pub running_for: Option<Duration>,
+}
+
+/// The state of the controllers linked to a brain.
+///
+/// Battery levels are only reported in steps of 8%.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ControllerStatus {
+    /// Whether a controller is connected, either over the radio or a cable.
+    pub connected: bool,
+    /// Whether the controller is connected with a cable.
+    pub tethered: bool,
+    pub partner_connected: bool,
+    pub battery_percent: u8,
+    pub partner_battery_percent: u8,
+}
+
+/// The most memory [`DaemonCommand::ReadMemory`] reads in one command.
+#[cfg(feature = "debug")]
+pub const MAX_MEMORY_READ: u16 = 4096;
+
+/// What the brain's field control port is connected to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FieldControlStatus {
+    /// Neither a field controller nor a competition switch is connected.
+    Disconnected,
+    Connected(CompetitionMode),
+}
+
+/// The mode a field controller puts the robot in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CompetitionMode {
+    Disabled,
+    Autonomous,
+    Driver,
+}
+
+/// The mode a brain booted into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BootState {
+    /// VEXos started normally.
+    Normal,
+    /// The brain is in the RAM bootloader, as during a firmware update.
+    RamBootloader,
+    /// The brain is in the ROM bootloader, which is used to recover a brain whose
+    /// firmware won't start.
+    RomBootloader,
+    /// A combination of boot flags v5d doesn't recognize, as the raw status flags.
+    Unknown(u16),
+}
+
+/// A firmware version, as the brain reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FirmwareVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub build: u8,
+    pub beta: u8,
+}
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.build)?;
+        if self.beta != 0 {
+            write!(f, "-b{}", self.beta)?;
+        }
+        Ok(())
+    }
+}
+
+/// The firmware a brain runs, and the copy it keeps to recover with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FirmwareStatus {
+    /// The VEXos version the brain is running.
+    pub active: FirmwareVersion,
+    /// The version of the golden image, the firmware the brain falls back to if an update
+    /// fails. `None` if the brain's status didn't include it.
+    pub golden: Option<FirmwareVersion>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_actions_map_to_the_matching_firmware_actions() {
+        for (after, action) in [
+            (AfterFileUpload::DoNothing, FileExitAction::DoNothing),
+            (AfterFileUpload::RunProgram, FileExitAction::RunProgram),
+            (
+                AfterFileUpload::ShowRunScreen,
+                FileExitAction::ShowRunScreen,
+            ),
+            (AfterFileUpload::Halt, FileExitAction::Halt),
+        ] {
+            assert_eq!(FileExitAction::from(after) as u8, action as u8);
+        }
+    }
+
+    #[test]
+    fn firmware_versions_show_their_beta_only_if_they_have_one() {
+        let mut version = FirmwareVersion {
+            major: 1,
+            minor: 1,
+            build: 4,
+            beta: 0,
+        };
+        assert_eq!(version.to_string(), "1.1.4");
+        version.beta = 19;
+        assert_eq!(version.to_string(), "1.1.4-b19");
+    }
+
+    // A listener that never accepts doesn't stall connecting, since the kernel completes the
+    // connection and queues it, so a stalled connect is stood in for by one that never ends
+    #[tokio::test]
+    async fn connecting_gives_up_after_the_limit() {
+        let path = std::env::temp_dir().join(format!("v5d-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let limit = Duration::from_millis(100);
+        connect_to_path_within(&path, limit).await.unwrap();
+
+        let stalled = within(limit, std::future::pending::<io::Result<UnixStream>>());
+        let started = std::time::Instant::now();
+        let err = stalled.await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < limit * 10);
+
+        drop(listener);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn slots_go_from_1_to_8() {
+        assert!(Slot::try_from(0).is_err());
+        assert_eq!(Slot::try_from(1).unwrap().get(), 1);
+        assert_eq!(Slot::try_from(8).unwrap().get(), 8);
+        assert!(Slot::try_from(9).is_err());
+        assert!("0".parse::<Slot>().is_err());
+        assert_eq!("8".parse::<Slot>(), Ok(Slot(8)));
+        assert!("one".parse::<Slot>().is_err());
+        assert_eq!(Slot::all().count(), 8);
+    }
+
+    #[test]
+    fn slots_are_zero_based_on_the_brain() {
+        assert_eq!(Slot(1).to_zero_based(), 0);
+        assert_eq!(Slot(8).to_zero_based(), 7);
+        assert_eq!(Slot(1).file_stem(), "slot0");
+    }
+
+    #[test]
+    fn slots_are_checked_when_deserialized() {
+        assert_eq!(serde_json::to_string(&Slot(3)).unwrap(), "3");
+        assert_eq!(serde_json::from_str::<Slot>("3").unwrap(), Slot(3));
+        assert!(serde_json::from_str::<Slot>("0").is_err());
+        assert!(serde_json::from_str::<Slot>("9").is_err());
+    }
 }
@@ -0,0 +1,279 @@
+use std::{future::Future, io, pin::pin, time::Duration};
+
+use tokio::{
+    io::{AsyncWriteExt, BufReader},
+    net::UnixStream,
+    select,
+    time::Instant,
+};
+
+use crate::{
+    get_response, send_command_to, unexpected_response, DaemonCommand, DaemonResponse,
+    DaemonStream, SectionProgress, TransferError, UploadStep,
+};
+
+/// Something that happened during a file transfer.
+#[derive(Debug, Clone)]
+pub enum TransferEvent {
+    Progress(SectionProgress),
+    /// The transfer finished, failed or was cancelled. No more events follow.
+    Complete(Result<(), TransferError>),
+}
+
+/// Displays the progress of a transfer driven by [`Transfer::run`].
+///
+/// Calls arrive in order: [`started`](Self::started) once, then for each section
+/// [`step_started`](Self::step_started), any number of [`progress`](Self::progress) ticks and,
+/// if the section completed, [`step_finished`](Self::step_finished). [`finished`](Self::finished)
+/// always comes last, including when the transfer fails before sending anything.
+pub trait ProgressRenderer {
+    /// The transfer was requested. `steps` lists the sections it will send, in order.
+    fn started(&mut self, steps: &[UploadStep]);
+    /// A section started sending.
+    fn step_started(&mut self, step: UploadStep) {
+        let _ = step;
+    }
+    /// More of a section was sent.
+    fn progress(&mut self, progress: SectionProgress);
+    /// A section was sent completely, taking `elapsed`.
+    fn step_finished(&mut self, step: UploadStep, elapsed: Duration) {
+        let _ = (step, elapsed);
+    }
+    /// The transfer ended, successfully or not.
+    fn finished(&mut self, result: &Result<(), TransferError>);
+}
+
+/// A file transfer that the daemon is performing.
+///
+/// The transfer can be cancelled with [`Transfer::cancel`] or by dropping it.
+/// Either way, the daemon stops sending chunks and closes the transfer on the brain.
+/// Transfers can't be paused, since the daemon holds the device for as long as one is open;
+/// cancel and start again instead.
+pub struct Transfer<S: DaemonStream = BufReader<UnixStream>> {
+    stream: S,
+    cancelled: bool,
+}
+impl<S: DaemonStream> Transfer<S> {
+    /// Asks the daemon to start a transfer on the device at `route`.
+    pub async fn start(mut stream: S, route: u8, command: DaemonCommand) -> io::Result<Self> {
+        send_command_to(&mut stream, route, command).await?;
+        Ok(Self {
+            stream,
+            cancelled: false,
+        })
+    }
+
+    /// Waits for the next event from the daemon.
+    pub async fn next_event(&mut self) -> io::Result<TransferEvent> {
+        match get_response(&mut self.stream).await? {
+            DaemonResponse::TransferProgress(progress) => Ok(TransferEvent::Progress(progress)),
+            DaemonResponse::TransferComplete(result) => Ok(TransferEvent::Complete(result)),
+            DaemonResponse::BasicAck { successful: false } => Ok(TransferEvent::Complete(Err(
+                TransferError::new("The daemon could not start the transfer"),
+            ))),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    /// Follows the transfer to the end, reporting it to `renderer`.
+    ///
+    /// `steps` are the sections the transfer sends, as given by [`crate::upload_steps`].
+    /// The transfer is cancelled if `cancel` completes first. Losing the connection to the
+    /// daemon is reported as a failed transfer.
+    pub async fn run(
+        mut self,
+        steps: &[UploadStep],
+        renderer: &mut dyn ProgressRenderer,
+        cancel: impl Future<Output = ()>,
+    ) -> Result<(), TransferError> {
+        renderer.started(steps);
+
+        let mut cancel = pin!(cancel);
+        let mut current: Option<(UploadStep, Instant)> = None;
+        let result = loop {
+            let event = select! {
+                event = self.next_event() => event,
+                _ = &mut cancel, if !self.cancelled => {
+                    match self.cancel().await {
+                        Ok(()) => continue,
+                        Err(err) => Err(err),
+                    }
+                }
+            };
+
+            match event {
+                Ok(TransferEvent::Progress(progress)) => {
+                    let step = progress.step;
+                    if current.map(|(current, _)| current) != Some(step) {
+                        if let Some((previous, started)) = current {
+                            renderer.step_finished(previous, started.elapsed());
+                        }
+                        renderer.step_started(step);
+                        current = Some((step, Instant::now()));
+                    }
+                    renderer.progress(progress);
+                }
+                Ok(TransferEvent::Complete(result)) => break result,
+                Err(err) => {
+                    break Err(TransferError::new(format!(
+                        "Lost contact with the daemon: {}",
+                        err
+                    )))
+                }
+            }
+        };
+
+        if let (Ok(()), Some((step, started))) = (&result, current) {
+            renderer.step_finished(step, started.elapsed());
+        }
+        renderer.finished(&result);
+        result
+    }
+
+    /// Asks the daemon to stop the transfer.
+    ///
+    /// Keep calling [`Transfer::next_event`] afterwards to find out how the transfer ended.
+    pub async fn cancel(&mut self) -> io::Result<()> {
+        if !self.cancelled {
+            // The daemon treats the end of our half of the stream as a cancellation
+            self.stream.shutdown().await?;
+            self.cancelled = true;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncBufReadExt, DuplexStream};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Call {
+        Started(Vec<UploadStep>),
+        StepStarted(UploadStep),
+        Progress(UploadStep, u32),
+        StepFinished(UploadStep),
+        Finished(bool),
+    }
+
+    /// Records what it's told to display.
+    #[derive(Default)]
+    struct Recorder(Vec<Call>);
+    impl ProgressRenderer for Recorder {
+        fn started(&mut self, steps: &[UploadStep]) {
+            self.0.push(Call::Started(steps.to_vec()));
+        }
+        fn step_started(&mut self, step: UploadStep) {
+            self.0.push(Call::StepStarted(step));
+        }
+        fn progress(&mut self, progress: SectionProgress) {
+            self.0.push(Call::Progress(progress.step, progress.sent));
+        }
+        fn step_finished(&mut self, step: UploadStep, _elapsed: Duration) {
+            self.0.push(Call::StepFinished(step));
+        }
+        fn finished(&mut self, result: &Result<(), TransferError>) {
+            self.0.push(Call::Finished(result.is_ok()));
+        }
+    }
+
+    fn progress(step: UploadStep, sent: u32) -> DaemonResponse {
+        DaemonResponse::TransferProgress(SectionProgress {
+            step,
+            sent,
+            total: 100,
+        })
+    }
+
+    /// Runs a transfer against a daemon that reads the request, then sends `responses` and
+    /// hangs up.
+    async fn run(responses: Vec<DaemonResponse>) -> Vec<Call> {
+        let (client, daemon) = duplex(4096);
+        tokio::spawn(async move {
+            let mut daemon = BufReader::new(daemon);
+            let mut request = String::new();
+            daemon.read_line(&mut request).await.unwrap();
+            for response in responses {
+                respond(&mut daemon, response).await;
+            }
+        });
+
+        let command = DaemonCommand::PingBrain;
+        let transfer = Transfer::start(BufReader::new(client), 0, command)
+            .await
+            .unwrap();
+        let mut recorder = Recorder::default();
+        let steps = [UploadStep::Ini, UploadStep::Monolith];
+        let _ = transfer
+            .run(&steps, &mut recorder, std::future::pending())
+            .await;
+        recorder.0
+    }
+
+    async fn respond(stream: &mut BufReader<DuplexStream>, response: DaemonResponse) {
+        let mut content = serde_json::to_string(&response).unwrap();
+        content.push('\n');
+        stream.write_all(content.as_bytes()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_transfer_reports_each_section() {
+        let calls = run(vec![
+            progress(UploadStep::Ini, 100),
+            progress(UploadStep::Monolith, 40),
+            progress(UploadStep::Monolith, 100),
+            DaemonResponse::TransferComplete(Ok(())),
+        ])
+        .await;
+        assert_eq!(
+            calls,
+            [
+                Call::Started(vec![UploadStep::Ini, UploadStep::Monolith]),
+                Call::StepStarted(UploadStep::Ini),
+                Call::Progress(UploadStep::Ini, 100),
+                Call::StepFinished(UploadStep::Ini),
+                Call::StepStarted(UploadStep::Monolith),
+                Call::Progress(UploadStep::Monolith, 40),
+                Call::Progress(UploadStep::Monolith, 100),
+                Call::StepFinished(UploadStep::Monolith),
+                Call::Finished(true),
+            ]
+        );
+    }
+
+    // A failing section isn't reported as finished, and the renderer still hears how the
+    // transfer ended
+    #[tokio::test]
+    async fn a_failed_transfer_still_finishes() {
+        let failed = DaemonResponse::TransferComplete(Err(TransferError::new("refused")));
+        let calls = run(vec![progress(UploadStep::Ini, 30), failed]).await;
+        assert_eq!(
+            calls,
+            [
+                Call::Started(vec![UploadStep::Ini, UploadStep::Monolith]),
+                Call::StepStarted(UploadStep::Ini),
+                Call::Progress(UploadStep::Ini, 30),
+                Call::Finished(false),
+            ]
+        );
+    }
+
+    // Whether the daemon refuses, fails the transfer or hangs up
+    #[tokio::test]
+    async fn a_transfer_failing_before_it_sends_anything_still_finishes() {
+        let refused = DaemonResponse::BasicAck { successful: false };
+        let failed = DaemonResponse::TransferComplete(Err(TransferError::new("no device")));
+        for responses in [vec![refused], vec![failed], vec![]] {
+            let calls = run(responses).await;
+            assert_eq!(
+                calls,
+                [
+                    Call::Started(vec![UploadStep::Ini, UploadStep::Monolith]),
+                    Call::Finished(false),
+                ]
+            );
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, BufReader, ReadBuf},
+    net::UnixStream,
+    time::sleep,
+};
+
+use crate::{
+    connect_to_socket, get_response, send_command_to, unexpected_response, DaemonCommand,
+    DaemonResponse, DaemonStream,
+};
+
+/// How long to wait before polling the FIFO again after it returned no data.
+const EMPTY_READ_BACKOFF: Duration = Duration::from_millis(10);
+
+/// A command in flight, which hands its connection back when it's done.
+type Exchange<S, T> = Pin<Box<dyn Future<Output = io::Result<(S, T)>> + Send>>;
+
+/// Reads up to `max_len` bytes of output from the user program running on a device.
+///
+/// An empty result means that the program hasn't written anything since the last read.
+/// Callers that poll should keep reusing `stream` rather than connecting for every read.
+pub async fn read_user(
+    stream: &mut impl DaemonStream,
+    route: u8,
+    max_len: u16,
+) -> io::Result<Vec<u8>> {
+    send_command_to(stream, route, DaemonCommand::ReadUser { max_len }).await?;
+    match get_response(stream).await? {
+        DaemonResponse::UserRead(data) => Ok(data),
+        response => Err(unexpected_response(response)),
+    }
+}
+
+/// Writes to the stdin of the user program running on a device.
+pub async fn write_user(
+    stream: &mut impl DaemonStream,
+    route: u8,
+    data: Vec<u8>,
+) -> io::Result<usize> {
+    send_command_to(stream, route, DaemonCommand::WriteUser(data)).await?;
+    match get_response(stream).await? {
+        DaemonResponse::UserWritten(written) => Ok(written),
+        response => Err(unexpected_response(response)),
+    }
+}
+
+/// The stdio of a user program, exposed through tokio's [`AsyncRead`] and [`AsyncWrite`] traits.
+///
+/// Reads and writes each hold their own connection to the daemon for as long as the adapter
+/// lives, so neither waits on the other.
+/// When the program has no output available, reads wait and try again instead of reporting EOF.
+pub struct UserIo<S: DaemonStream + 'static = BufReader<UnixStream>> {
+    route: u8,
+    reader: Option<S>,
+    writer: Option<S>,
+    read: Option<Exchange<S, Vec<u8>>>,
+    write: Option<Exchange<S, usize>>,
+}
+impl UserIo {
+    /// Connects to the daemon for the user program running on the device bound to `route`.
+    pub async fn connect(route: u8) -> io::Result<Self> {
+        let reader = BufReader::new(connect_to_socket().await?);
+        let writer = BufReader::new(connect_to_socket().await?);
+        Ok(Self::new(route, reader, writer))
+    }
+}
+impl<S: DaemonStream + 'static> UserIo<S> {
+    /// Creates an adapter that sends reads over `reader` and writes over `writer`.
+    pub fn new(route: u8, reader: S, writer: S) -> Self {
+        Self {
+            route,
+            reader: Some(reader),
+            writer: Some(writer),
+            read: None,
+            write: None,
+        }
+    }
+}
+
+/// The error for using a connection that an earlier failed command took down with it.
+fn connection_lost() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotConnected,
+        "the connection to the daemon was lost",
+    )
+}
+
+impl<S: DaemonStream + 'static> AsyncRead for UserIo<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            let route = this.route;
+            let max_len = buf.remaining().min(u16::MAX as usize) as u16;
+            let read = match &mut this.read {
+                Some(read) => read,
+                None => {
+                    let mut stream = this.reader.take().ok_or_else(connection_lost)?;
+                    this.read.insert(Box::pin(async move {
+                        let data = read_user(&mut stream, route, max_len).await?;
+                        Ok((stream, data))
+                    }))
+                }
+            };
+
+            let (mut stream, data) = match read.as_mut().poll(cx) {
+                Poll::Ready(result) => {
+                    this.read = None;
+                    result?
+                }
+                Poll::Pending => return Poll::Pending,
+            };
+
+            if data.is_empty() {
+                // No data isn't EOF, so back off and poll the FIFO again.
+                this.read = Some(Box::pin(async move {
+                    sleep(EMPTY_READ_BACKOFF).await;
+                    let data = read_user(&mut stream, route, max_len).await?;
+                    Ok((stream, data))
+                }));
+                continue;
+            }
+            this.reader = Some(stream);
+
+            let len = data.len().min(buf.remaining());
+            buf.put_slice(&data[..len]);
+            return Poll::Ready(Ok(()));
+        }
+    }
+}
+impl<S: DaemonStream + 'static> AsyncWrite for UserIo<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let route = this.route;
+        // If a previous write is still in flight, it was started with the same buffer.
+        let write = match &mut this.write {
+            Some(write) => write,
+            None => {
+                let mut stream = this.writer.take().ok_or_else(connection_lost)?;
+                let data = buf.to_vec();
+                this.write.insert(Box::pin(async move {
+                    let written = write_user(&mut stream, route, data).await?;
+                    Ok((stream, written))
+                }))
+            }
+        };
+
+        match write.as_mut().poll(cx) {
+            Poll::Ready(result) => {
+                this.write = None;
+                let (stream, written) = result?;
+                this.writer = Some(stream);
+                Poll::Ready(Ok(written))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::{duplex, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use super::*;
+    use crate::DaemonRequest;
+
+    /// What the fake program has been sent and not yet echoed.
+    type Echoed = Arc<Mutex<Vec<u8>>>;
+
+    /// Answers writes like a daemon whose program echoes its input.
+    async fn answer_writes(mut stream: BufReader<DuplexStream>, echoed: Echoed) {
+        while let Some(command) = next_command(&mut stream).await {
+            let DaemonCommand::WriteUser(data) = command else {
+                panic!("unexpected command on the write connection: {command:?}");
+            };
+            echoed.lock().unwrap().extend(&data);
+            respond(&mut stream, DaemonResponse::UserWritten(data.len())).await;
+        }
+    }
+
+    /// Answers reads with the echoed input, reporting no output for the first few reads after
+    /// each answer with data.
+    async fn answer_reads(mut stream: BufReader<DuplexStream>, echoed: Echoed) {
+        let mut empty_reads = 0;
+        while let Some(command) = next_command(&mut stream).await {
+            let DaemonCommand::ReadUser { max_len } = command else {
+                panic!("unexpected command on the read connection: {command:?}");
+            };
+            let data = {
+                let mut echoed = echoed.lock().unwrap();
+                if empty_reads < 3 || echoed.is_empty() {
+                    empty_reads += 1;
+                    Vec::new()
+                } else {
+                    empty_reads = 0;
+                    let len = echoed.len().min(max_len as usize);
+                    echoed.drain(..len).collect()
+                }
+            };
+            respond(&mut stream, DaemonResponse::UserRead(data)).await;
+        }
+    }
+
+    async fn next_command(stream: &mut BufReader<DuplexStream>) -> Option<DaemonCommand> {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await.unwrap() == 0 {
+            return None;
+        }
+        Some(
+            serde_json::from_str::<DaemonRequest>(&line)
+                .unwrap()
+                .command,
+        )
+    }
+
+    async fn respond(stream: &mut BufReader<DuplexStream>, response: DaemonResponse) {
+        let mut content = serde_json::to_string(&response).unwrap();
+        content.push('\n');
+        stream.write_all(content.as_bytes()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn writes_are_read_back_over_held_connections() {
+        let (reader, daemon_reader) = duplex(1024);
+        let (writer, daemon_writer) = duplex(1024);
+        let echoed = Echoed::default();
+        tokio::spawn(answer_reads(BufReader::new(daemon_reader), echoed.clone()));
+        tokio::spawn(answer_writes(BufReader::new(daemon_writer), echoed));
+        let mut io = UserIo::new(0, BufReader::new(reader), BufReader::new(writer));
+
+        for line in ["hello\n", "partial", " line\n"] {
+            io.write_all(line.as_bytes()).await.unwrap();
+        }
+        let mut echoed = [0; 19];
+        io.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"hello\npartial line\n");
+    }
+}
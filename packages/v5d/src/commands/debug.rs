@@ -0,0 +1,93 @@
+use std::time::Duration;
+
+use vex_v5_serial::{
+    commands::Command,
+    connection::Connection,
+    packets::file::{
+        ExitFileTransferPacket, ExitFileTransferReplyPacket, FileDownloadTarget, FileExitAction,
+        FileInitAction, FileInitOption, FileVendor, InitFileTransferPacket,
+        InitFileTransferPayload, InitFileTransferReplyPacket, ReadFilePacket, ReadFilePayload,
+        ReadFileReplyPacket,
+    },
+    string::FixedLengthString,
+    version::Version,
+};
+
+/// Reads raw bytes from the brain's memory through a file transfer targeting DDR.
+///
+/// Reads are 4-byte aligned on the wire, so the surrounding words are read and trimmed.
+/// An address the brain won't read is NACKed.
+#[derive(Debug)]
+pub struct ReadMemory {
+    pub address: u32,
+    pub length: u16,
+}
+impl Command for ReadMemory {
+    type Output = Vec<u8>;
+
+    async fn execute<C: Connection + ?Sized>(
+        &mut self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        let start = self.address & !3;
+        let end = self.address.saturating_add(u32::from(self.length) + 3) & !3;
+
+        connection
+            .packet_handshake::<InitFileTransferReplyPacket>(
+                Duration::from_millis(500),
+                5,
+                InitFileTransferPacket::new(InitFileTransferPayload {
+                    operation: FileInitAction::Read,
+                    target: FileDownloadTarget::Ddr,
+                    vendor: FileVendor::Sys,
+                    options: FileInitOption::None,
+                    write_file_size: end - start,
+                    load_address: start,
+                    write_file_crc: 0,
+                    file_extension: FixedLengthString::new("bin".to_owned())?,
+                    timestamp: 0,
+                    version: Version {
+                        major: 1,
+                        minor: 0,
+                        build: 0,
+                        beta: 0,
+                    },
+                    file_name: FixedLengthString::new("memory".to_owned())?,
+                }),
+            )
+            .await?
+            .try_into_inner()?;
+
+        let read = connection
+            .packet_handshake::<ReadFileReplyPacket>(
+                Duration::from_millis(500),
+                5,
+                ReadFilePacket::new(ReadFilePayload {
+                    address: start,
+                    size: (end - start) as u16,
+                }),
+            )
+            .await?
+            .payload
+            .unwrap();
+
+        // Close the transfer whether or not the read succeeded. The brain NACKs this if it
+        // never opened one.
+        let _ = connection
+            .packet_handshake::<ExitFileTransferReplyPacket>(
+                Duration::from_millis(500),
+                1,
+                ExitFileTransferPacket::new(FileExitAction::DoNothing),
+            )
+            .await?
+            .try_into_inner();
+
+        let data = read?.1.into_inner();
+        let offset = (self.address - start) as usize;
+        Ok(data
+            .into_iter()
+            .skip(offset)
+            .take(self.length.into())
+            .collect())
+    }
+}
@@ -0,0 +1,134 @@
+use std::time::Duration;
+
+use vex_v5_serial::{
+    commands::Command,
+    connection::Connection,
+    packets::file::{
+        EraseFilePacket, EraseFilePayload, EraseFileReplyPacket, ExitFileTransferPacket,
+        ExitFileTransferReplyPacket, FileExitAction, FileLoadAction, FileVendor,
+        GetFileMetadataPacket, GetFileMetadataPayload, GetFileMetadataReplyPacket,
+        GetFileMetadataReplyPayload, LoadFileActionPacket, LoadFileActionPayload,
+        LoadFileActionReplyPacket,
+    },
+    string::FixedLengthString,
+};
+
+/// Ends whatever file transfer the brain currently has open without running anything.
+///
+/// Used to clean up after a transfer fails part way through, so the brain isn't left
+/// waiting for chunks that will never arrive.
+#[derive(Debug)]
+pub struct AbortFileTransfer;
+impl Command for AbortFileTransfer {
+    type Output = ();
+
+    async fn execute<C: Connection + ?Sized>(
+        &mut self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        // The brain NACKs this if no transfer is open, which is fine.
+        let _ = connection
+            .packet_handshake::<ExitFileTransferReplyPacket>(
+                Duration::from_millis(500),
+                1,
+                ExitFileTransferPacket::new(FileExitAction::DoNothing),
+            )
+            .await?
+            .try_into_inner();
+
+        Ok(())
+    }
+}
+
+/// Reads the metadata of a file owned by `vendor`. User programs are [`FileVendor::User`].
+///
+/// Returns `None` if the brain reports no metadata. Depending on the firmware,
+/// a missing file may instead be NACKed.
+#[derive(Debug)]
+pub struct GetFileMetadata {
+    pub file_name: FixedLengthString<23>,
+    pub vendor: FileVendor,
+}
+impl Command for GetFileMetadata {
+    type Output = Option<GetFileMetadataReplyPayload>;
+
+    async fn execute<C: Connection + ?Sized>(
+        &mut self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        let metadata = connection
+            .packet_handshake::<GetFileMetadataReplyPacket>(
+                Duration::from_millis(500),
+                5,
+                GetFileMetadataPacket::new(GetFileMetadataPayload {
+                    vendor: self.vendor,
+                    option: 0,
+                    file_name: self.file_name.clone(),
+                }),
+            )
+            .await?
+            .try_into_inner()?;
+
+        Ok(metadata)
+    }
+}
+
+/// Deletes a file owned by `vendor`.
+///
+/// The brain NACKs this if the file doesn't exist, so check with [`GetFileMetadata`] first.
+#[derive(Debug)]
+pub struct EraseFile {
+    pub file_name: FixedLengthString<23>,
+    pub vendor: FileVendor,
+}
+impl Command for EraseFile {
+    type Output = ();
+
+    async fn execute<C: Connection + ?Sized>(
+        &mut self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        connection
+            .packet_handshake::<EraseFileReplyPacket>(
+                Duration::from_millis(500),
+                5,
+                EraseFilePacket::new(EraseFilePayload {
+                    vendor: self.vendor,
+                    option: 128,
+                    file_name: self.file_name.clone(),
+                }),
+            )
+            .await?
+            .try_into_inner()?;
+
+        Ok(())
+    }
+}
+
+/// Stops the user program running on the brain. Does nothing if none is running.
+#[derive(Debug)]
+pub struct StopProgram;
+impl Command for StopProgram {
+    type Output = ();
+
+    async fn execute<C: Connection + ?Sized>(
+        &mut self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        connection
+            .packet_handshake::<LoadFileActionReplyPacket>(
+                Duration::from_millis(500),
+                5,
+                LoadFileActionPacket::new(LoadFileActionPayload {
+                    vendor: FileVendor::User,
+                    action: FileLoadAction::Stop,
+                    // Stopping applies to the running program, whatever its file
+                    file_name: FixedLengthString::new(String::new())?,
+                }),
+            )
+            .await?
+            .try_into_inner()?;
+
+        Ok(())
+    }
+}
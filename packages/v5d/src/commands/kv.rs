@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+use vex_v5_serial::{
+    commands::Command,
+    connection::Connection,
+    packets::kv::{
+        ReadKeyValuePacket, ReadKeyValueReplyPacket, WriteKeyValuePacket, WriteKeyValuePayload,
+        WriteKeyValueReplyPacket,
+    },
+    string::{FixedLengthString, VarLengthString},
+};
+
+/// Reads a value from the brain's global key-value store.
+#[derive(Debug)]
+pub struct ReadKeyValue {
+    pub key: String,
+}
+impl Command for ReadKeyValue {
+    type Output = String;
+
+    async fn execute<C: Connection + ?Sized>(
+        &mut self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        let value = connection
+            .packet_handshake::<ReadKeyValueReplyPacket>(
+                Duration::from_millis(100),
+                5,
+                ReadKeyValuePacket::new(FixedLengthString::new(self.key.clone())?),
+            )
+            .await?
+            .try_into_inner()?;
+
+        // The reply is padded with nul bytes up to its maximum length
+        Ok(value.0.trim_end_matches('\0').to_string())
+    }
+}
+
+/// Writes a value to the brain's global key-value store.
+#[derive(Debug)]
+pub struct WriteKeyValue {
+    pub key: String,
+    pub value: String,
+}
+impl Command for WriteKeyValue {
+    type Output = ();
+
+    async fn execute<C: Connection + ?Sized>(
+        &mut self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        // The write payload isn't `Clone`, so we can't use `packet_handshake` here.
+        connection
+            .send_packet(WriteKeyValuePacket::new(WriteKeyValuePayload {
+                key: VarLengthString::new(self.key.clone())?,
+                value: VarLengthString::new(self.value.clone())?,
+            }))
+            .await?;
+        connection
+            .receive_packet::<WriteKeyValueReplyPacket>(Duration::from_millis(100))
+            .await?
+            .try_into_inner()?;
+
+        Ok(())
+    }
+}
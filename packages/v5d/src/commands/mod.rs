@@ -0,0 +1,128 @@
+//! Brain commands that aren't provided by `vex-v5-serial`.
+//!
+//! # Cancel safety
+//!
+//! Every command here is a single packet handshake (or a write followed by its reply),
+//! so dropping one part way through can at most leave a stale reply in the connection's
+//! incoming packet buffer. Replies are matched by packet type and expire after a couple
+//! of seconds, so the next command on the connection is unaffected.
+//!
+//! Multi-packet commands like `UploadFile` come from `vex-v5-serial` and leave the brain's
+//! file transfer open if they're dropped part way. [`file::AbortFileTransfer`] closes it
+//! again. The tests below drop commands at every await point over a mock connection to
+//! check both.
+
+#[cfg(feature = "debug")]
+pub mod debug;
+pub mod file;
+pub mod kv;
+pub mod system;
+pub mod user;
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+
+    use vex_v5_serial::{
+        commands::{
+            file::{UploadFile, COLD_START},
+            Command,
+        },
+        connection::{serial::SerialError, Connection},
+        packets::file::{FileExitAction, FileVendor},
+        string::FixedLengthString,
+    };
+
+    use super::{
+        file::{AbortFileTransfer, EraseFile, StopProgram},
+        kv::WriteKeyValue,
+    };
+    use crate::testing::MockConnection;
+
+    /// Polls `command` up to `polls` times, then drops it. Returns its result if it finished.
+    fn run_partially<C: Command>(
+        command: C,
+        connection: &mut MockConnection,
+        polls: usize,
+    ) -> Option<Result<C::Output, SerialError>> {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut execution = pin!(connection.execute_command(command));
+        (0..polls).find_map(|_| match execution.as_mut().poll(&mut cx) {
+            Poll::Ready(result) => Some(result),
+            Poll::Pending => None,
+        })
+    }
+
+    fn run<C: Command>(
+        command: C,
+        connection: &mut MockConnection,
+    ) -> Result<C::Output, SerialError> {
+        run_partially(command, connection, 10_000).expect("the command never finished")
+    }
+
+    /// Drops a command at each of its await points in turn, checking that the connection can
+    /// still close the brain's file transfer afterwards.
+    ///
+    /// Returns the connections left behind, one per drop point.
+    fn drop_at_every_await<C: Command>(command: impl Fn() -> C) -> Vec<MockConnection> {
+        let mut connections = Vec::new();
+        for polls in 0.. {
+            let mut connection = MockConnection::default();
+            let finished = run_partially(command(), &mut connection, polls).is_some();
+            run(AbortFileTransfer, &mut connection).unwrap();
+            assert!(!connection.transfer_open());
+            connections.push(connection);
+            if finished {
+                return connections;
+            }
+        }
+        unreachable!()
+    }
+
+    fn file_name() -> FixedLengthString<23> {
+        FixedLengthString::new("slot_1.bin".to_string()).unwrap()
+    }
+
+    fn upload(data: Vec<u8>) -> UploadFile<'static> {
+        UploadFile {
+            filename: file_name(),
+            filetype: FixedLengthString::new("bin".to_string()).unwrap(),
+            vendor: Some(FileVendor::User),
+            data,
+            target: None,
+            load_addr: COLD_START,
+            linked_file: None,
+            after_upload: FileExitAction::DoNothing,
+            progress_callback: None,
+        }
+    }
+
+    #[test]
+    fn commands_can_be_dropped_at_any_await() {
+        drop_at_every_await(|| AbortFileTransfer);
+        drop_at_every_await(|| StopProgram);
+        drop_at_every_await(|| EraseFile {
+            file_name: file_name(),
+            vendor: FileVendor::User,
+        });
+        drop_at_every_await(|| WriteKeyValue {
+            key: "teamnumber".to_string(),
+            value: "1234A".to_string(),
+        });
+    }
+
+    #[test]
+    fn aborting_closes_an_upload_dropped_at_any_await() {
+        // Three chunks, so the upload is dropped between chunks as well as around them
+        let connections = drop_at_every_await(|| upload(vec![0; 10_000]));
+        assert!(connections.len() > 10, "the upload never yielded");
+        for mut connection in connections {
+            run(upload(vec![0; 10_000]), &mut connection).unwrap();
+            assert!(!connection.transfer_open());
+        }
+    }
+}
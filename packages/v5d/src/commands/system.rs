@@ -0,0 +1,236 @@
+use std::time::{Duration, Instant};
+
+use v5d_interface::{
+    BootState, CompetitionMode, ControllerStatus, FieldControlStatus, FirmwareStatus,
+    FirmwareVersion,
+};
+use vex_v5_serial::{
+    commands::Command,
+    connection::Connection,
+    packets::system::{
+        GetSystemFlagsPacket, GetSystemFlagsReplyPacket, GetSystemStatusPacket,
+        GetSystemStatusReplyPacket, GetSystemVersionPacket, GetSystemVersionReplyPacket,
+        SystemFlags, SystemStatus,
+    },
+    version::Version,
+};
+
+/// Reads the brain's system flags, which include the currently running program.
+#[derive(Debug)]
+pub struct GetSystemFlags;
+impl Command for GetSystemFlags {
+    type Output = SystemFlags;
+
+    async fn execute<C: Connection + ?Sized>(
+        &mut self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        let flags = connection
+            .packet_handshake::<GetSystemFlagsReplyPacket>(
+                Duration::from_millis(100),
+                5,
+                GetSystemFlagsPacket::new(()),
+            )
+            .await?
+            .try_into_inner()?;
+
+        Ok(flags)
+    }
+}
+
+/// Times one system version query, about the cheapest request the brain answers.
+///
+/// The query is sent once, so a lost packet shows up as an error instead of a slow reply.
+#[derive(Debug)]
+pub struct PingBrain {
+    pub timeout: Duration,
+}
+impl Command for PingBrain {
+    type Output = Duration;
+
+    async fn execute<C: Connection + ?Sized>(
+        &mut self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        let start = Instant::now();
+        connection
+            .packet_handshake::<GetSystemVersionReplyPacket>(
+                self.timeout,
+                1,
+                GetSystemVersionPacket::new(()),
+            )
+            .await?;
+
+        Ok(start.elapsed())
+    }
+}
+
+// Bits of `SystemFlags::flags`. The protocol docs number them from the most significant bit.
+const RADIO_CONNECTED: u32 = 1 << (32 - 22);
+const PARTNER_CONNECTED: u32 = 1 << (32 - 19);
+const CONTROLLER_TETHERED: u32 = 1 << (32 - 24);
+
+/// Reads the link state and battery levels of the brain's controllers.
+#[derive(Debug)]
+pub struct GetControllerStatus;
+impl Command for GetControllerStatus {
+    type Output = ControllerStatus;
+
+    async fn execute<C: Connection + ?Sized>(
+        &mut self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        let flags = connection.execute_command(GetSystemFlags).await?;
+        let tethered = flags.flags & CONTROLLER_TETHERED != 0;
+
+        Ok(ControllerStatus {
+            connected: tethered || flags.flags & RADIO_CONNECTED != 0,
+            tethered,
+            partner_connected: flags.flags & PARTNER_CONNECTED != 0,
+            battery_percent: (flags.byte_1 & 0x0F) * 8,
+            partner_battery_percent: (flags.byte_2 & 0x0F) * 8,
+        })
+    }
+}
+
+/// Reads the brain's firmware versions and status details.
+#[derive(Debug)]
+pub struct GetSystemStatus;
+impl Command for GetSystemStatus {
+    type Output = SystemStatus;
+
+    async fn execute<C: Connection + ?Sized>(
+        &mut self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        let status = connection
+            .packet_handshake::<GetSystemStatusReplyPacket>(
+                Duration::from_millis(100),
+                5,
+                GetSystemStatusPacket::new(()),
+            )
+            .await?
+            .try_into_inner()?;
+
+        Ok(status)
+    }
+}
+
+// Bits of `SystemDetails::flags_2`, also numbered from the most significant bit.
+const AUTONOMOUS: u16 = 1 << (16 - 2);
+const DISABLED: u16 = 1 << (16 - 3);
+const FIELD_CONTROL_CONNECTED: u16 = 1 << (16 - 4);
+
+/// Reads whether a field controller is connected and what mode it puts the robot in.
+///
+/// Returns `None` if the brain's status doesn't include the field control flags.
+#[derive(Debug)]
+pub struct GetFieldControlStatus;
+impl Command for GetFieldControlStatus {
+    type Output = Option<FieldControlStatus>;
+
+    async fn execute<C: Connection + ?Sized>(
+        &mut self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        let status = connection.execute_command(GetSystemStatus).await?;
+        Ok(status.details.map(|details| {
+            let flags = details.flags_2;
+            if flags & FIELD_CONTROL_CONNECTED == 0 {
+                FieldControlStatus::Disconnected
+            } else if flags & DISABLED != 0 {
+                FieldControlStatus::Connected(CompetitionMode::Disabled)
+            } else if flags & AUTONOMOUS != 0 {
+                FieldControlStatus::Connected(CompetitionMode::Autonomous)
+            } else {
+                FieldControlStatus::Connected(CompetitionMode::Driver)
+            }
+        }))
+    }
+}
+
+// Bits of `SystemDetails::flags_3`, numbered like those of `flags_2`.
+const RAM_BOOTLOADER: u16 = 1 << (16 - 14);
+const ROM_BOOTLOADER: u16 = 1 << (16 - 15);
+
+/// Reads which mode the brain booted into.
+///
+/// Returns `None` if the brain's status doesn't include the boot flags.
+#[derive(Debug)]
+pub struct GetBootState;
+impl Command for GetBootState {
+    type Output = Option<BootState>;
+
+    async fn execute<C: Connection + ?Sized>(
+        &mut self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        let status = connection.execute_command(GetSystemStatus).await?;
+        Ok(status.details.map(|details| boot_state(details.flags_3)))
+    }
+}
+
+/// Decodes the boot mode from `SystemDetails::flags_3`, whose other bits are settings.
+fn boot_state(flags: u16) -> BootState {
+    match (flags & RAM_BOOTLOADER != 0, flags & ROM_BOOTLOADER != 0) {
+        (false, false) => BootState::Normal,
+        (true, false) => BootState::RamBootloader,
+        (false, true) => BootState::RomBootloader,
+        (true, true) => BootState::Unknown(flags),
+    }
+}
+
+fn firmware_version(version: Version) -> FirmwareVersion {
+    FirmwareVersion {
+        major: version.major,
+        minor: version.minor,
+        build: version.build,
+        beta: version.beta,
+    }
+}
+
+/// Reads the version of the firmware the brain is running, and of its golden image.
+#[derive(Debug)]
+pub struct GetFirmwareStatus;
+impl Command for GetFirmwareStatus {
+    type Output = FirmwareStatus;
+
+    async fn execute<C: Connection + ?Sized>(
+        &mut self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        let status = connection.execute_command(GetSystemStatus).await?;
+        Ok(FirmwareStatus {
+            active: firmware_version(status.system_version),
+            golden: status
+                .details
+                .map(|details| firmware_version(details.golden_version)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boot_flags_decode_to_the_mode_they_mean() {
+        assert_eq!(boot_state(0), BootState::Normal);
+        assert_eq!(boot_state(RAM_BOOTLOADER), BootState::RamBootloader);
+        assert_eq!(boot_state(ROM_BOOTLOADER), BootState::RomBootloader);
+        // Both at once isn't a known mode, so the flags are kept for the report
+        let both = RAM_BOOTLOADER | ROM_BOOTLOADER;
+        assert_eq!(boot_state(both), BootState::Unknown(both));
+    }
+
+    #[test]
+    fn settings_in_the_boot_flags_are_ignored() {
+        // The language index, the white theme and the event brain bits
+        let settings = 0xF000 | (1 << (16 - 6)) | 1;
+        assert_eq!(boot_state(settings), BootState::Normal);
+        assert_eq!(
+            boot_state(settings | ROM_BOOTLOADER),
+            BootState::RomBootloader
+        );
+    }
+}
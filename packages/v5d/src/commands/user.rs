@@ -0,0 +1,47 @@
+use std::time::Duration;
+
+use vex_v5_serial::{
+    commands::Command,
+    connection::Connection,
+    packets::controller::{UserFifoPacket, UserFifoPayload, UserFifoReplyPacket},
+    string::VarLengthString,
+};
+
+/// The user FIFO channel carrying the program's stdio.
+const STDIO_CHANNEL: u8 = 1;
+
+/// Exchanges data with the user program's stdio through the brain's system channel.
+///
+/// This is how user I/O reaches the brain over a controller's radio link, where
+/// there is no user port. Unlike `vex-v5-serial`'s fallback, this makes exactly one
+/// round trip and doesn't wait for output to appear. Firmware without FIFO tunneling NACKs it.
+#[derive(Debug)]
+pub struct UserFifo {
+    pub write: Option<VarLengthString<224>>,
+}
+impl Command for UserFifo {
+    /// Output the program wrote since the last exchange.
+    type Output = Vec<u8>;
+
+    async fn execute<C: Connection + ?Sized>(
+        &mut self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        let reply = connection
+            .packet_handshake::<UserFifoReplyPacket>(
+                Duration::from_millis(250),
+                1,
+                UserFifoPacket::new(UserFifoPayload {
+                    channel: STDIO_CHANNEL,
+                    write: self.write.take(),
+                }),
+            )
+            .await?
+            .try_into_inner()?;
+
+        Ok(reply
+            .data
+            .map(|data| data.0.into_bytes())
+            .unwrap_or_default())
+    }
+}
@@ -1,61 +1,333 @@
-use std::time::Duration;
+use std::{fmt, future::Future, num::NonZeroU32, pin::pin, time::Duration};
 
-use log::{info, warn};
+use btleplug::{
+    api::{Manager as _, Peripheral as _},
+    platform::Manager,
+};
+use log::{debug, info, warn};
 use tokio::{select, time::sleep};
 use vex_v5_serial::connection::{
     bluetooth,
     generic::{GenericConnection, GenericError},
-    serial,
+    serial::SerialDevice,
+    Connection,
+};
+
+use crate::{
+    commands::system::GetSystemStatus,
+    daemon::DaemonError,
+    device::DeviceId,
+    discovery::{self, PortFilter},
 };
 
-use crate::daemon::DaemonError;
+/// How long to wait before the second attempt at connecting to a Bluetooth device.
+/// Each later attempt waits twice as long as the one before.
+const BLUETOOTH_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Which Bluetooth brains the daemon connects to.
+#[derive(Debug, Clone, Default)]
+pub enum BluetoothSelection {
+    /// The first brain found in range.
+    #[default]
+    First,
+    /// The brains with these peripheral ids, as `v5ctl devices` shows them.
+    Ids(Vec<String>),
+    /// Every brain in range.
+    All,
+}
+impl BluetoothSelection {
+    fn includes(&self, id: &str) -> bool {
+        match self {
+            Self::First | Self::All => true,
+            Self::Ids(ids) => ids
+                .iter()
+                .any(|wanted| wanted.strip_prefix("bluetooth:").unwrap_or(wanted) == id),
+        }
+    }
+}
+
+/// Limits on how hard the daemon tries to reach devices.
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    /// Give up after this many failed searches for serial devices, or Bluetooth scans,
+    /// or never if `None`.
+    pub max_connect_attempts: Option<NonZeroU32>,
+    /// How many times to try connecting to each Bluetooth device that was found.
+    pub bluetooth_connect_attempts: NonZeroU32,
+    /// Which Bluetooth brains to connect to.
+    pub bluetooth: BluetoothSelection,
+    /// Which serial ports may be opened.
+    pub ports: PortFilter,
+}
 
-async fn bluetooth_connection() -> Result<GenericConnection, DaemonError> {
-    // Scan for 10 seconds
-    let devices = bluetooth::find_devices(Duration::from_secs(10), None)
+/// Counts the Bluetooth adapters available to scan with.
+pub async fn bluetooth_adapter_count() -> Result<usize, DaemonError> {
+    let adapters = async { Manager::new().await?.adapters().await }
         .await
-        .map_err(Into::<GenericError>::into)?;
-    // Open a connection to the first device
-    let connection = devices[0]
-        .connect()
+        .map_err(|err| GenericError::from(bluetooth::BluetoothError::from(err)))?;
+    Ok(adapters.len())
+}
+
+/// Connects to a Bluetooth device, retrying with backoff since the first attempt often fails.
+async fn connect_bluetooth(
+    device: &bluetooth::BluetoothDevice,
+    attempts: NonZeroU32,
+) -> Result<bluetooth::BluetoothConnection, GenericError> {
+    retry_with_backoff("Bluetooth", attempts, BLUETOOTH_RETRY_BACKOFF, || {
+        device.connect()
+    })
+    .await
+    .map_err(Into::into)
+}
+
+/// Calls `connect` until it succeeds or has been tried `attempts` times, waiting `backoff`
+/// after the first failure and twice as long after each one after that.
+async fn retry_with_backoff<T, E: fmt::Display, F: Future<Output = Result<T, E>>>(
+    transport: &str,
+    attempts: NonZeroU32,
+    mut backoff: Duration,
+    mut connect: impl FnMut() -> F,
+) -> Result<T, E> {
+    let mut attempt = 1;
+    loop {
+        info!(
+            "Connecting over {} (attempt {} of {})",
+            transport, attempt, attempts
+        );
+        match connect().await {
+            Ok(connection) => return Ok(connection),
+            Err(err) if attempt < attempts.get() => {
+                warn!(
+                    "{} connection failed: {}. Retrying in {}ms...",
+                    transport,
+                    err,
+                    backoff.as_millis()
+                );
+                sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Scans for Bluetooth brains and connects to the selected ones.
+///
+/// Scans again until one connects, or until `max_scans` scans have come up empty.
+async fn bluetooth_connections(
+    max_scans: Option<NonZeroU32>,
+    attempts: NonZeroU32,
+    selection: &BluetoothSelection,
+) -> Result<Vec<(DeviceId, GenericConnection)>, DaemonError> {
+    let mut scans = 0;
+    loop {
+        scans += 1;
+        let connections = scan_bluetooth(attempts, selection).await?;
+        if !connections.is_empty() {
+            info!(
+                "Connected to {} Brain(s) over Bluetooth!",
+                connections.len()
+            );
+            return Ok(connections);
+        }
+
+        if max_scans.is_some_and(|max| scans >= max.get()) {
+            warn!("No Bluetooth brains connected after {} scan(s)", scans);
+            return Err(DaemonError::NoDevices);
+        }
+        warn!("No Bluetooth brains connected. Scanning again...");
+    }
+}
+
+/// Runs one Bluetooth scan, connecting to the selected brains it finds.
+async fn scan_bluetooth(
+    attempts: NonZeroU32,
+    selection: &BluetoothSelection,
+) -> Result<Vec<(DeviceId, GenericConnection)>, DaemonError> {
+    // Scan for 10 seconds, or until the first brain turns up if that's the only one wanted
+    let max_devices = matches!(selection, BluetoothSelection::First).then_some(1);
+    let devices = bluetooth::find_devices(Duration::from_secs(10), max_devices)
         .await
         .map_err(Into::<GenericError>::into)?;
-    info!("Connected to the Brain over Bluetooth!");
-    Ok(connection.into())
+    // A brain that won't connect shouldn't keep the others from being used
+    let mut connections = Vec::new();
+    for device in devices {
+        let id = device.0.id().to_string();
+        if !selection.includes(&id) {
+            continue;
+        }
+        let id = DeviceId::Bluetooth(id);
+        match connect_bluetooth(&device, attempts).await {
+            Ok(connection) => connections.push((id, connection.into())),
+            Err(err) => warn!("Couldn't connect to {}: {}", id, err),
+        }
+    }
+    Ok(connections)
+}
+
+/// Opens every serial device that was found, skipping any that can't be opened.
+fn open_serial_devices(devices: Vec<SerialDevice>) -> Vec<(DeviceId, GenericConnection)> {
+    let mut connections = Vec::with_capacity(devices.len());
+    for device in devices {
+        let id = DeviceId::Serial(device.system_port());
+        match device.connect(Duration::from_secs(2)) {
+            Ok(connection) => connections.push((id, connection.into())),
+            // Usually the port is busy, like when another program has it open
+            Err(err) => warn!("Couldn't open {}: {}", id, err),
+        }
+    }
+    connections
 }
 
-async fn serial_connection() -> Result<GenericConnection, DaemonError> {
+async fn serial_connections(
+    max_attempts: Option<NonZeroU32>,
+    ports: &PortFilter,
+) -> Result<Vec<(DeviceId, GenericConnection)>, DaemonError> {
+    let mut attempts = 0;
     loop {
+        attempts += 1;
         // Find all connected serial devices
-        let mut devices = serial::find_devices()
-            .map_err(Into::<GenericError>::into)?
-            .into_iter();
-        // Open a connection to the first device
-        let Some(device) = devices.next() else {
-            warn!("No serial devices found. Retrying in 1s...");
-            sleep(Duration::from_millis(1000)).await;
+        let devices = discovery::find_devices(ports).map_err(Into::<GenericError>::into)?;
+        let found = devices.len();
+        let connections = open_serial_devices(devices);
+        if !connections.is_empty() {
+            info!("Connected to {} Brain(s) over serial!", connections.len());
+            return Ok(connections);
+        }
+
+        let problem = if found == 0 {
+            "No serial devices found".to_string()
+        } else {
+            format!(
+                "None of the {} serial device(s) found could be opened",
+                found
+            )
+        };
+        if max_attempts.is_some_and(|max| attempts >= max.get()) {
+            warn!("{} after {} attempt(s)", problem, attempts);
+            return Err(DaemonError::NoDevices);
+        }
+        warn!("{}. Retrying in 1s...", problem);
+        sleep(Duration::from_millis(1000)).await;
+    }
+}
+
+/// Reads the brain's unique ID, which is the same whichever way the brain is connected.
+pub async fn brain_id(connection: &mut GenericConnection) -> Result<Option<u32>, GenericError> {
+    let status = connection.execute_command(GetSystemStatus).await?;
+    Ok(status.details.map(|details| details.unique_id))
+}
+
+/// Looks for a USB connection to the brain with the given unique ID.
+///
+/// Ports in `bound` are already in use and aren't opened.
+pub async fn find_usb_connection(
+    brain: u32,
+    ports: &PortFilter,
+    bound: &[DeviceId],
+) -> Result<Option<(DeviceId, GenericConnection)>, DaemonError> {
+    let devices = discovery::find_devices(ports).map_err(Into::<GenericError>::into)?;
+    for device in devices {
+        if !matches!(device, SerialDevice::Brain { .. }) {
             continue;
+        }
+        let id = DeviceId::Serial(device.system_port());
+        if bound.contains(&id) {
+            continue;
+        }
+        let mut connection: GenericConnection = match device.connect(Duration::from_secs(2)) {
+            Ok(connection) => connection.into(),
+            Err(err) => {
+                warn!("Couldn't open {}: {}", id, err);
+                continue;
+            }
         };
-        let connection = device
-            .connect(Duration::from_secs(2))
-            .map_err(Into::<GenericError>::into)?;
-        info!("Connected to the Brain over serial!");
-        return Ok(connection.into());
+        match brain_id(&mut connection).await {
+            Ok(Some(id_over_usb)) if id_over_usb == brain => return Ok(Some((id, connection))),
+            Ok(_) => debug!("{} is a different brain", id),
+            Err(err) => warn!("Couldn't identify the brain on {}: {}", id, err),
+        }
     }
+    Ok(None)
 }
 
-pub async fn setup_connection(
+/// Connects to every device reachable with the given connection type.
+///
+/// Devices are searched for until one is found, or the options' limit is reached.
+/// With [`Auto`](super::ConnectionType::Auto), the first way to find a device wins,
+/// and the search only fails once both have given up.
+pub async fn setup_connections(
     connection_type: super::ConnectionType,
-) -> Result<GenericConnection, DaemonError> {
+    options: &ConnectOptions,
+) -> Result<Vec<(DeviceId, GenericConnection)>, DaemonError> {
+    let bluetooth = bluetooth_connections(
+        options.max_connect_attempts,
+        options.bluetooth_connect_attempts,
+        &options.bluetooth,
+    );
+    let serial = serial_connections(options.max_connect_attempts, &options.ports);
     match connection_type {
-        super::ConnectionType::Bluetooth => bluetooth_connection().await,
-        super::ConnectionType::Serial => serial_connection().await,
+        super::ConnectionType::Bluetooth => bluetooth.await,
+        super::ConnectionType::Serial => serial.await,
         super::ConnectionType::Auto => {
-            // Race the two connection methods
+            // Race the two connection methods, falling back to the other if one gives up
+            let (mut bluetooth, mut serial) = (pin!(bluetooth), pin!(serial));
             select! {
-                con = bluetooth_connection() => con,
-                con = serial_connection() => con,
+                con = &mut bluetooth => match con {
+                    Ok(connections) => Ok(connections),
+                    Err(err) => {
+                        warn!("Giving up on Bluetooth: {}. Still searching over serial", err);
+                        serial.await
+                    }
+                },
+                con = &mut serial => match con {
+                    Ok(connections) => Ok(connections),
+                    Err(err) => {
+                        warn!("Giving up on serial: {}. Still searching over Bluetooth", err);
+                        bluetooth.await
+                    }
+                },
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_connect_that_fails_once_is_retried() {
+        let mut calls = 0;
+        let connected = retry_with_backoff(
+            "test",
+            NonZeroU32::new(3).unwrap(),
+            Duration::from_millis(1),
+            || {
+                calls += 1;
+                let result = if calls == 1 { Err("busy") } else { Ok(calls) };
+                async move { result }
+            },
+        )
+        .await;
+        assert_eq!(connected, Ok(2));
+    }
+
+    #[tokio::test]
+    async fn connecting_gives_up_after_the_last_attempt() {
+        let mut calls = 0;
+        let connected: Result<(), _> = retry_with_backoff(
+            "test",
+            NonZeroU32::new(2).unwrap(),
+            Duration::from_millis(1),
+            || {
+                calls += 1;
+                async { Err("busy") }
+            },
+        )
+        .await;
+        assert_eq!(connected, Err("busy"));
+        assert_eq!(calls, 2);
+    }
+}
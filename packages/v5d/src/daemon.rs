@@ -1,20 +1,64 @@
-use std::{io, sync::Arc};
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    io::{self, Write},
+    os::unix::net,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 
-use log::{debug, error, info, trace};
+use flate2::{Compression, GzBuilder};
+use log::{debug, error, info, trace, warn};
 use thiserror::Error;
 use tokio::{
     io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    join,
     net::{UnixListener, UnixStream},
-    spawn,
-    sync::{mpsc::Sender, Mutex},
+    select, spawn,
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc::{self, Receiver, Sender},
+        watch, Mutex, Notify, RwLock,
+    },
+    time::{sleep, timeout},
+};
+use v5d_interface::{
+    upload_steps, AfterFileUpload, ConnectedDevice, DaemonCommand, DaemonRequest, DaemonResponse,
+    DeviceConnectionType, IncomingRequest, JobId, LogRecord, ProgramData, ProgramUpload,
+    RunningProgram, SectionProgress, Slot, TransferError, UploadStep, UptimeInfo,
 };
-use v5d_interface::{DaemonCommand, DaemonResponse, UploadStep};
-use vex_v5_serial::connection::{
-    generic::{GenericConnection, GenericError},
-    Connection,
+use vex_v5_serial::{
+    commands::file::{DownloadFile, Program, ProgramIniConfig, Project, UploadFile},
+    connection::serial::SerialError,
+    connection::{
+        generic::{GenericConnection, GenericError},
+        Connection,
+    },
+    packets::{cdc2::Cdc2Ack, file::FileVendor},
+    string::{FixedLengthString, VarLengthString},
 };
 
-use crate::{connection::setup_connection, setup_socket, ConnectionType};
+use crate::{
+    commands::{
+        file::{AbortFileTransfer, EraseFile, GetFileMetadata, StopProgram},
+        kv::{ReadKeyValue, WriteKeyValue},
+        system::{
+            GetBootState, GetControllerStatus, GetFieldControlStatus, GetFirmwareStatus,
+            GetSystemFlags, PingBrain,
+        },
+        user::UserFifo,
+    },
+    connection::{brain_id, find_usb_connection, setup_connections, ConnectOptions},
+    device::{Device, DeviceId, LaunchedProgram, RouteTable},
+    jobs::{Job, Jobs},
+    protection::Protection,
+    recent_errors, remove_socket, setup_socket, ConnectionType,
+};
 
 #[derive(Debug, Error)]
 pub enum DaemonError {
@@ -24,141 +68,1298 @@ pub enum DaemonError {
     Serde(#[from] serde_json::Error),
     #[error("IO error: {0}")]
     Io(#[from] io::Error),
+    #[error("No devices were found")]
+    NoDevices,
+    #[error("No device is bound to route {0}")]
+    UnknownRoute(u8),
+    #[error("{0} is not supported over Bluetooth")]
+    UnsupportedOverBluetooth(&'static str),
+    #[error("There is no job with id {0}")]
+    UnknownJob(JobId),
+    #[error("This brain's firmware doesn't support user I/O through the controller")]
+    UserFifoUnsupported,
+    #[error("User input sent through the controller must be valid UTF-8")]
+    NonUtf8UserInput,
+    #[error("The device on route {0} isn't connected over Bluetooth")]
+    NotBluetooth(u8),
+    #[error("The brain didn't report its unique ID, so its USB connection can't be found")]
+    UnknownBrainId,
+    #[error("No USB connection to the same brain was found")]
+    NoUsbConnection,
+    #[error("Every route is taken, so {0} can't be given one")]
+    OutOfRoutes(String),
+}
+
+/// The most user input sent to the brain in one FIFO packet.
+const USER_FIFO_CHUNK: usize = 224;
+
+/// Exchanges one packet with the user FIFO, as used on controller links.
+///
+/// Firmware that can't tunnel the FIFO either NACKs the packet or never answers it.
+async fn exchange_user_fifo(
+    connection: &mut GenericConnection,
+    write: Option<VarLengthString<USER_FIFO_CHUNK>>,
+) -> Result<Vec<u8>, DaemonError> {
+    match connection.execute_command(UserFifo { write }).await {
+        Ok(read) => Ok(read),
+        Err(GenericError::Nack(_) | GenericError::SerialError(SerialError::Timeout)) => {
+            Err(DaemonError::UserFifoUnsupported)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Takes the longest prefix of `data` that fits in one FIFO packet without splitting a character.
+fn user_fifo_chunk(data: &[u8]) -> Result<&str, DaemonError> {
+    let data = &data[..data.len().min(USER_FIFO_CHUNK)];
+    match std::str::from_utf8(data) {
+        Ok(chunk) => Ok(chunk),
+        // A character cut off by the packet size limit goes out with the next write.
+        Err(err) if err.error_len().is_none() && err.valid_up_to() > 0 => {
+            Ok(std::str::from_utf8(&data[..err.valid_up_to()]).unwrap())
+        }
+        Err(_) => Err(DaemonError::NonUtf8UserInput),
+    }
+}
+
+/// How long a user FIFO read may hold a device before reporting that there's no data.
+const USER_READ_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// How long a brain ping waits for its reply before counting it as lost.
+const BRAIN_PING_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often the daemon checks whether the host slept.
+const RESUME_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How far the wall clock must get ahead of the monotonic clock between checks to count as
+/// the host having slept. The monotonic clock stops while the host is suspended.
+const RESUME_CLOCK_JUMP: Duration = Duration::from_secs(10);
+
+/// How long shutting down waits for connected clients to be told about it.
+const SHUTDOWN_NOTICE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The vendor whose files client commands work with. No command lets clients pick another.
+const CLIENT_VENDOR: FileVendor = FileVendor::User;
+
+/// How long the host slept between two readings of the clocks, given how much time each says
+/// passed, or `None` if it didn't.
+///
+/// The wall clock is `None` if it went backwards, such as when it was set.
+fn time_asleep(monotonic: Duration, wall: Option<Duration>) -> Option<Duration> {
+    let slept = wall.unwrap_or_default().saturating_sub(monotonic);
+    (slept > RESUME_CLOCK_JUMP).then_some(slept)
+}
+
+/// A device, locked for the duration of each command routed to it.
+type DeviceHandle = Arc<Mutex<Device>>;
+
+/// A device bound to a route.
+///
+/// Its identity is kept outside the device's lock, so listing devices doesn't wait for
+/// commands that hold a connection for a long time, like uploads.
+struct BoundDevice {
+    id: DeviceId,
+    connection_type: DeviceConnectionType,
+    device: DeviceHandle,
+}
+
+/// Binds each connection to its device's route.
+fn into_routes(
+    route_table: &mut RouteTable,
+    connections: Vec<(DeviceId, GenericConnection)>,
+) -> Result<BTreeMap<u8, BoundDevice>, DaemonError> {
+    connections
+        .into_iter()
+        .map(|(id, connection)| {
+            let route = route_table.route(&id)?;
+            let bound = BoundDevice {
+                id,
+                connection_type: connection.connection_type().into(),
+                device: Arc::new(Mutex::new(Device::new(connection))),
+            };
+            Ok((route, bound))
+        })
+        .collect()
+}
+
+/// Reads the client's next request, or `None` once the client has closed its side of the
+/// connection.
+pub async fn read_request(
+    stream: &mut BufReader<UnixStream>,
+) -> Result<Option<DaemonRequest>, DaemonError> {
+    let mut content = String::new();
+    if stream.read_line(&mut content).await? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(
+        serde_json::from_str::<IncomingRequest>(&content)?.into(),
+    ))
+}
+
+pub async fn write_response(
+    stream: &mut BufReader<UnixStream>,
+    response: &DaemonResponse,
+) -> Result<(), DaemonError> {
+    let mut content = serde_json::to_string(response)?;
+    content.push('\n');
+    stream.write_all(content.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Sends a transfer's progress to the client that requested it.
+///
+/// `cancel` is notified if the client closes its end of the stream or stops listening.
+async fn forward_transfer_events(
+    stream: Arc<Mutex<BufReader<UnixStream>>>,
+    mut events: Receiver<DaemonResponse>,
+    cancel: Arc<Notify>,
+) {
+    let mut stream = stream.lock().await;
+    let mut client_gone = false;
+    loop {
+        select! {
+            event = events.recv() => {
+                let Some(event) = event else { break };
+                if !client_gone && write_response(&mut stream, &event).await.is_err() {
+                    client_gone = true;
+                    cancel.notify_one();
+                }
+            }
+            readable = stream.get_ref().readable(), if !client_gone => {
+                // Clients don't send anything after their request,
+                // so this is either the end of the stream or an error.
+                let mut buf = [0; 64];
+                let closed = match stream.get_ref().try_read(&mut buf) {
+                    Ok(0) => true,
+                    Ok(_) => false,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => false,
+                    Err(_) => true,
+                };
+                if readable.is_err() || closed {
+                    client_gone = true;
+                    cancel.notify_one();
+                }
+            }
+        }
+    }
+}
+
+/// Describes a failed file operation, noting whether a running program is why the brain
+/// refused it.
+///
+/// The brain rejects file operations it won't do while a program runs with the same bare
+/// NACK it uses for other refusals, so the running program is checked for separately.
+async fn refusal(
+    connection: &mut GenericConnection,
+    err: &GenericError,
+    message: String,
+) -> TransferError {
+    let mut error = TransferError::new(message);
+    if matches!(err, GenericError::Nack(Cdc2Ack::Nack)) {
+        match connection.execute_command(GetSystemFlags).await {
+            Ok(flags) => error.program_running = flags.current_program != 0,
+            Err(err) => debug!("Couldn't check for a running program: {}", err),
+        }
+    }
+    error
+}
+
+/// Erases every file an upload to `slot` writes, among `vendor`'s files.
+///
+/// The INI goes first so that, if this is interrupted, the brain stops listing the
+/// program before any of its binaries are missing.
+async fn erase_slot(
+    connection: &mut GenericConnection,
+    vendor: FileVendor,
+    slot: Slot,
+) -> Result<(), TransferError> {
+    let base_name = slot.file_stem();
+    for file_name in [
+        format!("{base_name}.ini"),
+        format!("{base_name}.bin"),
+        format!("{base_name}_lib.bin"),
+    ] {
+        let file_name =
+            FixedLengthString::new(file_name).map_err(|err| TransferError::new(err.to_string()))?;
+        let exists = match connection
+            .execute_command(GetFileMetadata {
+                file_name: file_name.clone(),
+                vendor,
+            })
+            .await
+        {
+            Ok(metadata) => metadata.is_some(),
+            Err(GenericError::Nack(_)) => false,
+            Err(err) => {
+                return Err(TransferError::new(format!(
+                    "Failed to read file metadata: {}",
+                    err
+                )))
+            }
+        };
+        if exists {
+            debug!("Erasing {}", file_name);
+            let result = connection
+                .execute_command(EraseFile { file_name, vendor })
+                .await;
+            if let Err(err) = result {
+                let message = format!("Failed to erase slot {}: {}", slot, err);
+                return Err(refusal(connection, &err, message).await);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Works out which section an upload failed in from the last progress it reported.
+fn failed_section(
+    sections: &[UploadStep],
+    last_progress: Option<SectionProgress>,
+) -> Option<UploadStep> {
+    match last_progress {
+        None => sections.first().copied(),
+        Some(progress) if !progress.is_complete() => Some(progress.step),
+        // The section's data was all sent, so closing it or starting the next one failed.
+        // Blame the next section unless this was the last.
+        Some(progress) => sections
+            .iter()
+            .skip_while(|section| **section != progress.step)
+            .nth(1)
+            .copied()
+            .or(Some(progress.step)),
+    }
+}
+
+/// Gzips a section. The brain notices and decompresses it itself.
+fn compress(data: &mut Vec<u8>) {
+    let mut encoder = GzBuilder::new().write(Vec::new(), Compression::default());
+    // Writing to a Vec can't fail
+    encoder.write_all(data).unwrap();
+    *data = encoder.finish().unwrap();
+}
+
+/// The size of each section of `upload` as it will be sent, so after any compression.
+///
+/// The INI is built the same way the upload command builds it.
+fn section_sizes(upload: &ProgramUpload) -> HashMap<UploadStep, u32> {
+    let ini = ProgramIniConfig {
+        program: Program {
+            description: upload.description.clone(),
+            icon: upload.icon.clone(),
+            iconalt: String::new(),
+            slot: upload.slot.to_zero_based(),
+            name: upload.name.clone(),
+        },
+        project: Project {
+            ide: upload.program_type.clone(),
+        },
+    };
+    let ini_size = serde_ini::to_vec(&ini).map_or(0, |ini| ini.len());
+
+    let mut sizes = HashMap::from([(UploadStep::Ini, ini_size as u32)]);
+    match &upload.data {
+        ProgramData::Monolith(data) => {
+            sizes.insert(UploadStep::Monolith, data.len() as u32);
+        }
+        ProgramData::HotCold { hot, cold } => {
+            if let Some(cold) = cold {
+                sizes.insert(UploadStep::Cold, cold.len() as u32);
+            }
+            if let Some(hot) = hot {
+                sizes.insert(UploadStep::Hot, hot.len() as u32);
+            }
+        }
+    }
+    sizes
+}
+
+/// Uploads a slot's INI file among `vendor`'s files again, unchanged, finishing the transfer
+/// with `action`.
+///
+/// The brain applies an exit action when a transfer ends instead of storing it with the program,
+/// so this is how an action is applied to a program that is already on the brain.
+async fn rewrite_program_ini(
+    connection: &mut GenericConnection,
+    vendor: FileVendor,
+    slot: Slot,
+    action: AfterFileUpload,
+) -> Result<(), TransferError> {
+    let file_name = FixedLengthString::new(format!("{}.ini", slot.file_stem()))
+        .map_err(|err| TransferError::new(err.to_string()))?;
+    let file_type = FixedLengthString::new("ini".to_string())
+        .map_err(|err| TransferError::new(err.to_string()))?;
+
+    let metadata = match connection
+        .execute_command(GetFileMetadata {
+            file_name: file_name.clone(),
+            vendor,
+        })
+        .await
+    {
+        Ok(Some(metadata)) => metadata,
+        Ok(None) | Err(GenericError::Nack(_)) => {
+            return Err(TransferError::new(format!("Slot {} is empty", slot)))
+        }
+        Err(err) => {
+            return Err(TransferError::new(format!(
+                "Failed to read program metadata: {}",
+                err
+            )))
+        }
+    };
+
+    // Reuse the current INI so the program keeps its name, icon and description
+    let result = connection
+        .execute_command(DownloadFile {
+            filename: file_name.clone(),
+            filetype: file_type.clone(),
+            size: metadata.size,
+            vendor,
+            target: None,
+            load_addr: metadata.load_address,
+            progress_callback: None,
+        })
+        .await;
+    let mut ini = match result {
+        Ok(ini) => ini,
+        Err(err) => {
+            let message = format!("Failed to read program INI: {}", err);
+            return Err(refusal(connection, &err, message).await);
+        }
+    };
+    ini.truncate(metadata.size as usize);
+
+    let result = connection
+        .execute_command(UploadFile {
+            filename: file_name,
+            filetype: file_type,
+            vendor: Some(vendor),
+            data: ini,
+            target: None,
+            load_addr: metadata.load_address,
+            linked_file: None,
+            after_upload: action.into(),
+            progress_callback: None,
+        })
+        .await;
+    if let Err(err) = result {
+        let error = refusal(
+            connection,
+            &err,
+            format!("Failed to write program INI: {}", err),
+        )
+        .await;
+        if let Err(err) = connection.execute_command(AbortFileTransfer).await {
+            warn!("Failed to abort file transfer: {}", err);
+        }
+        return Err(error);
+    }
+    Ok(())
+}
+
+/// Downloads one of `vendor`'s files, or returns `None` if it doesn't exist.
+async fn download_file(
+    connection: &mut GenericConnection,
+    vendor: FileVendor,
+    file_name: String,
+    file_type: &str,
+) -> Result<Option<Vec<u8>>, TransferError> {
+    let file_name =
+        FixedLengthString::new(file_name).map_err(|err| TransferError::new(err.to_string()))?;
+    let file_type = FixedLengthString::new(file_type.to_string())
+        .map_err(|err| TransferError::new(err.to_string()))?;
+    let metadata = match connection
+        .execute_command(GetFileMetadata {
+            file_name: file_name.clone(),
+            vendor,
+        })
+        .await
+    {
+        Ok(Some(metadata)) => metadata,
+        Ok(None) | Err(GenericError::Nack(_)) => return Ok(None),
+        Err(err) => {
+            return Err(TransferError::new(format!(
+                "Failed to read file metadata: {}",
+                err
+            )))
+        }
+    };
+
+    debug!("Downloading {}", file_name);
+    let result = connection
+        .execute_command(DownloadFile {
+            filename: file_name.clone(),
+            filetype: file_type,
+            size: metadata.size,
+            vendor,
+            target: None,
+            load_addr: metadata.load_address,
+            progress_callback: None,
+        })
+        .await;
+    match result {
+        Ok(mut data) => {
+            data.truncate(metadata.size as usize);
+            Ok(Some(data))
+        }
+        Err(err) => {
+            let message = format!("Failed to download {}: {}", file_name, err);
+            Err(refusal(connection, &err, message).await)
+        }
+    }
+}
+
+/// Reads back the program in `slot` as the upload that would recreate it.
+///
+/// Only monolith programs can be read, since the library doesn't say how a hot/cold
+/// program's files would be linked again.
+async fn read_program(
+    connection: &mut GenericConnection,
+    vendor: FileVendor,
+    slot: Slot,
+) -> Result<Option<ProgramUpload>, TransferError> {
+    let base_name = slot.file_stem();
+    let Some(ini) = download_file(connection, vendor, format!("{base_name}.ini"), "ini").await?
+    else {
+        return Ok(None);
+    };
+    let ini: ProgramIniConfig = serde_ini::from_str(&String::from_utf8_lossy(&ini))
+        .map_err(|err| TransferError::new(format!("Slot {}'s INI is malformed: {}", slot, err)))?;
+
+    let lib = download_file(connection, vendor, format!("{base_name}_lib.bin"), "bin").await?;
+    if lib.is_some() {
+        return Err(TransferError::new(format!(
+            "Slot {} holds a hot/cold program, which can't be read back",
+            slot
+        )));
+    }
+    let program = download_file(connection, vendor, format!("{base_name}.bin"), "bin")
+        .await?
+        .ok_or_else(|| TransferError::new(format!("Slot {} has an INI but no program", slot)))?;
+
+    Ok(Some(ProgramUpload {
+        name: ini.program.name,
+        description: ini.program.description,
+        icon: ini.program.icon,
+        program_type: ini.project.ide,
+        slot,
+        // Stored programs stay compressed if they were uploaded that way
+        compression: false,
+        after_upload: AfterFileUpload::DoNothing,
+        data: ProgramData::Monolith(program),
+        replace: true,
+        overwrite_protected: false,
+    }))
+}
+
+/// Reads the name of the program in each occupied slot from its INI.
+///
+/// A slot whose INI can't be parsed is skipped, since its name can't be known.
+async fn read_program_names(
+    connection: &mut GenericConnection,
+    vendor: FileVendor,
+) -> Result<Vec<(Slot, String)>, TransferError> {
+    let mut names = Vec::new();
+    for slot in Slot::all() {
+        let file_name = format!("{}.ini", slot.file_stem());
+        let Some(ini) = download_file(connection, vendor, file_name, "ini").await? else {
+            continue;
+        };
+        match serde_ini::from_str::<ProgramIniConfig>(&String::from_utf8_lossy(&ini)) {
+            Ok(ini) => names.push((slot, ini.program.name)),
+            Err(err) => warn!("Slot {}'s INI is malformed: {}", slot, err),
+        }
+    }
+    Ok(names)
+}
+
+/// Splits a client's socket into two handles to the same connection.
+fn duplicate(stream: UnixStream) -> io::Result<(UnixStream, net::UnixStream)> {
+    let stream = stream.into_std()?;
+    let copy = stream.try_clone()?;
+    Ok((UnixStream::from_std(stream)?, copy))
+}
+
+/// The message a panic was raised with.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Waits for in-flight commands to finish, then closes every device connection.
+async fn close_devices(devices: &mut BTreeMap<u8, BoundDevice>) {
+    for bound in devices.values() {
+        drop(bound.device.lock().await);
+    }
+    devices.clear();
 }
 
 pub struct Daemon {
     socket: UnixListener,
-    brain_connection: Mutex<GenericConnection>,
+    socket_path: PathBuf,
+    devices: RwLock<BTreeMap<u8, BoundDevice>>,
+    jobs: Jobs,
+    /// Remembers the route of every device seen, so reconnecting devices keep their route.
+    route_table: Mutex<RouteTable>,
     connection_type: ConnectionType,
+    connect_options: ConnectOptions,
+    /// The daemon's own log records, for clients streaming them.
+    logs: broadcast::Sender<LogRecord>,
+    /// Notified when the daemon should shut down.
+    shutdown: Arc<Notify>,
+    /// Set once the daemon is shutting down, so connected clients can be told.
+    /// Each client connection holds a receiver until it closes.
+    closing: watch::Sender<bool>,
+    /// Slots protected from uploads.
+    protection: Mutex<Protection>,
+    /// Numbers the panics caught while handling clients, so reports can be matched to the log.
+    incidents: AtomicU64,
 }
 impl Daemon {
-    pub async fn new(connection_type: ConnectionType) -> Result<Self, DaemonError> {
+    /// Connects to devices, and listens for clients on the socket at `socket_path`.
+    pub async fn new(
+        socket_path: PathBuf,
+        connection_type: ConnectionType,
+        connect_options: ConnectOptions,
+        logs: broadcast::Sender<LogRecord>,
+        shutdown: Arc<Notify>,
+    ) -> Result<Self, DaemonError> {
+        let socket = setup_socket(&socket_path)?;
+        let mut route_table = RouteTable::default();
+        let devices = async {
+            let connections = setup_connections(connection_type, &connect_options).await?;
+            into_routes(&mut route_table, connections)
+        }
+        .await
+        .inspect_err(|_| remove_socket(&socket_path))?;
         Ok(Self {
-            socket: setup_socket()?,
-            brain_connection: Mutex::new(setup_connection(connection_type).await?),
+            socket,
+            socket_path,
+            devices: RwLock::new(devices),
+            route_table: Mutex::new(route_table),
+            jobs: Jobs::default(),
             connection_type,
+            connect_options,
+            logs,
+            shutdown,
+            closing: watch::channel(false).0,
+            protection: Mutex::new(Protection::load()),
+            incidents: AtomicU64::new(1),
         })
     }
 
+    /// Returns the connection bound to the given route.
+    async fn device(&self, route: u8) -> Result<DeviceHandle, DaemonError> {
+        self.devices
+            .read()
+            .await
+            .get(&route)
+            .map(|bound| bound.device.clone())
+            .ok_or(DaemonError::UnknownRoute(route))
+    }
+
+    /// Serves clients until the daemon is told to shut down.
     pub async fn run(self) {
         let this = Arc::new(self);
+        let resume_watch = spawn(this.clone().watch_for_resume());
         loop {
-            match this.socket.accept().await {
+            let accepted = select! {
+                accepted = this.socket.accept() => accepted,
+                _ = this.shutdown.notified() => break,
+            };
+            match accepted {
                 Ok((stream, _addr)) => {
-                    let this = this.clone();
-                    spawn(async move {
-                        if let Err(e) = this.handle_connection(BufReader::new(stream)).await {
-                            error!("Failed to handle connection: {}", e);
-                        }
-                    });
+                    spawn(this.clone().serve_client(stream));
                 }
                 Err(e) => {
                     error!("Failed to accept connection: {}", e);
                 }
             }
         }
+
+        resume_watch.abort();
+        this.shut_down().await;
+    }
+
+    /// Notices when the host wakes from sleep, and checks the devices then.
+    ///
+    /// Serial ports and Bluetooth links usually don't survive a suspend, but nothing reports
+    /// that until the next command fails.
+    async fn watch_for_resume(self: Arc<Self>) {
+        loop {
+            let (monotonic, wall) = (Instant::now(), SystemTime::now());
+            sleep(RESUME_CHECK_INTERVAL).await;
+            if let Some(slept) = time_asleep(monotonic.elapsed(), wall.elapsed().ok()) {
+                info!("The host woke from about {}s of sleep", slept.as_secs());
+                if let Err(err) = self.revalidate_devices().await {
+                    error!("Failed to reconnect devices after waking: {}", err);
+                }
+            }
+        }
+    }
+
+    /// Pings every device, and reconnects them all if any doesn't answer.
+    async fn revalidate_devices(&self) -> Result<(), DaemonError> {
+        if self.unresponsive_routes().await.is_empty() {
+            info!("Every device still answers");
+            return Ok(());
+        }
+        self.reconnect().await
+    }
+
+    /// Pings every device, returning the routes of those that didn't answer.
+    async fn unresponsive_routes(&self) -> Vec<u8> {
+        let mut dead = Vec::new();
+        for (route, bound) in self.devices.read().await.iter() {
+            let ping = PingBrain {
+                timeout: BRAIN_PING_TIMEOUT,
+            };
+            if let Err(err) = bound
+                .device
+                .lock()
+                .await
+                .connection
+                .execute_command(ping)
+                .await
+            {
+                warn!(
+                    "Device {} on route {} stopped answering: {}",
+                    bound.id, route, err
+                );
+                dead.push(*route);
+            }
+        }
+        dead
+    }
+
+    /// Drops every device connection and connects to whatever can be found again.
+    async fn reconnect(&self) -> Result<(), DaemonError> {
+        let mut devices = self.devices.write().await;
+        // Release the old connections (and their ports) before reopening them
+        close_devices(&mut devices).await;
+        let connections = setup_connections(self.connection_type, &self.connect_options).await?;
+        *devices = into_routes(&mut *self.route_table.lock().await, connections)?;
+        info!("Reconnected to {} device(s)", devices.len());
+        Ok(())
+    }
+
+    /// Shuts down in an order that leaves neither a stale socket nor a device mid-command.
+    async fn shut_down(&self) {
+        info!("Shutting down...");
+        // New clients can't connect once the socket file is gone
+        remove_socket(&self.socket_path);
+        // Clients waiting for their next response are told now. The rest are told once
+        // their command finishes, which closing the devices waits for.
+        self.closing.send_replace(true);
+
+        close_devices(&mut *self.devices.write().await).await;
+        if timeout(SHUTDOWN_NOTICE_TIMEOUT, self.closing.closed())
+            .await
+            .is_err()
+        {
+            warn!("Not every client could be told that the daemon is shutting down");
+        }
+
+        info!("Shutdown complete!");
+    }
+
+    /// Fails if `slot` is protected on the connected brain.
+    async fn check_protection(
+        &self,
+        connection: &mut GenericConnection,
+        slot: Slot,
+    ) -> Result<(), TransferError> {
+        let protection = self.protection.lock().await;
+        // Skip asking the brain for its ID when nothing could match it
+        if protection.is_empty() {
+            return Ok(());
+        }
+        let brain = brain_id(connection)
+            .await
+            .map_err(|err| TransferError::new(format!("Couldn't identify the brain: {err}")))?;
+        match brain {
+            Some(brain) if protection.is_protected(brain, slot) => Err(TransferError {
+                slot_protected: true,
+                ..TransferError::new(format!("Slot {slot} is protected on this brain"))
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Uploads a program among `vendor`'s files, sending progress to `events`, until it
+    /// finishes or `cancel` is notified.
+    ///
+    /// vex-v5-serial's program upload always writes user files, so other vendors are refused
+    /// rather than only having their old files erased.
+    async fn upload_program(
+        &self,
+        route: u8,
+        vendor: FileVendor,
+        mut upload: ProgramUpload,
+        events: Sender<DaemonResponse>,
+        cancel: Arc<Notify>,
+    ) -> Result<(), TransferError> {
+        let device = self
+            .device(route)
+            .await
+            .map_err(|err| TransferError::new(err.to_string()))?;
+        if !matches!(vendor, FileVendor::User) {
+            return Err(TransferError::new(format!(
+                "Programs can only be uploaded as user files, not {:?}",
+                vendor
+            )));
+        }
+        let runs_program = upload.after_upload == AfterFileUpload::RunProgram;
+        let sections = upload_steps(&upload.data);
+
+        // Compress here rather than in the upload command, so every section's final size
+        // is known before it starts sending and progress never counts against the wrong total
+        if upload.compression {
+            match &mut upload.data {
+                ProgramData::Monolith(data) => compress(data),
+                ProgramData::HotCold { hot, cold } => {
+                    [cold, hot].into_iter().flatten().for_each(compress)
+                }
+            }
+        }
+        let sizes = section_sizes(&upload);
+        let last_progress = Arc::new(std::sync::Mutex::new(None));
+
+        fn generate_callback(
+            step: UploadStep,
+            total: u32,
+            sender: Sender<DaemonResponse>,
+            last_progress: Arc<std::sync::Mutex<Option<SectionProgress>>>,
+        ) -> Box<dyn FnMut(f32) + Send> {
+            Box::new(move |percent| {
+                let progress = SectionProgress {
+                    step,
+                    sent: (total as f32 * percent / 100.0).round() as u32,
+                    total,
+                };
+                *last_progress.lock().unwrap() = Some(progress);
+                tokio::task::block_in_place(|| {
+                    let response = DaemonResponse::TransferProgress(progress);
+                    trace!("CALLBACK: {:?}", response);
+                    // The receiver only goes away once the upload is over
+                    let _ = sender.blocking_send(response);
+                });
+            })
+        }
+        let callback = |step, events| {
+            let total = sizes.get(&step).copied().unwrap_or_default();
+            Some(generate_callback(
+                step,
+                total,
+                events,
+                last_progress.clone(),
+            ))
+        };
+
+        let command = vex_v5_serial::commands::file::UploadProgram {
+            name: upload.name,
+            program_type: upload.program_type,
+            description: upload.description,
+            icon: upload.icon,
+            slot: upload.slot.to_zero_based(),
+            compress_program: false,
+            after_upload: upload.after_upload.into(),
+            data: upload.data,
+            ini_callback: callback(UploadStep::Ini, events.clone()),
+            monolith_callback: callback(UploadStep::Monolith, events.clone()),
+            cold_callback: callback(UploadStep::Cold, events.clone()),
+            hot_callback: callback(UploadStep::Hot, events),
+        };
+
+        let mut device = device.lock().await;
+        if !upload.overwrite_protected {
+            self.check_protection(&mut device.connection, upload.slot)
+                .await?;
+        }
+        if upload.replace {
+            info!("Erasing slot {} before uploading", upload.slot);
+            erase_slot(&mut device.connection, vendor, upload.slot).await?;
+        }
+        // Stopping the upload between packets is the same as it failing part way
+        let result = select! {
+            result = device.connection.execute_command(command) => Some(result),
+            _ = cancel.notified() => None,
+        };
+        let result = match result {
+            Some(Ok(())) => Ok(()),
+            Some(Err(err)) => Err(refusal(&mut device.connection, &err, err.to_string()).await),
+            None => {
+                info!("Upload cancelled");
+                Err(TransferError::new("Upload cancelled"))
+            }
+        };
+        let result = result.map_err(|err| TransferError {
+            step: failed_section(&sections, *last_progress.lock().unwrap()),
+            ..err
+        });
+        if result.is_err() {
+            // Don't leave the brain in the middle of a transfer
+            if let Err(err) = device.connection.execute_command(AbortFileTransfer).await {
+                warn!("Failed to abort file transfer: {}", err);
+            }
+        } else if runs_program {
+            device.launched_program = Some(LaunchedProgram {
+                slot: upload.slot,
+                started_at: Instant::now(),
+            });
+        }
+        result
     }
 
     async fn perform_command(
         self: Arc<Self>,
-        command: DaemonCommand,
+        request: DaemonRequest,
         stream: Arc<Mutex<BufReader<UnixStream>>>,
     ) -> Result<Option<DaemonResponse>, DaemonError> {
+        let DaemonRequest { route, command } = request;
         let response = match command {
             DaemonCommand::MockTap { x, y } => {
-                self.brain_connection
+                self.device(route)
+                    .await?
                     .lock()
                     .await
+                    .connection
                     .execute_command(vex_v5_serial::commands::screen::MockTap { x, y })
                     .await?;
                 Some(DaemonResponse::BasicAck { successful: true })
             }
-            DaemonCommand::UploadProgram {
-                name,
-                description,
-                icon,
-                slot,
-                compression,
-                after_upload,
-                data,
-                program_type,
-            } => {
-                let (response_sender, mut response_receiver) =
-                    tokio::sync::mpsc::channel::<DaemonResponse>(1000);
-                let response_sender = Arc::new(Mutex::new(response_sender));
+            DaemonCommand::UploadProgram(upload) => {
+                let (events, receiver) = mpsc::channel(1000);
+                let cancel = Arc::new(Notify::new());
+                spawn(forward_transfer_events(stream, receiver, cancel.clone()));
 
+                let result = self
+                    .upload_program(route, CLIENT_VENDOR, upload, events, cancel)
+                    .await;
+                Some(DaemonResponse::TransferComplete(result))
+            }
+            DaemonCommand::UploadProgramInBackground(upload) => {
+                // Fail now rather than in a job nobody is watching
+                self.device(route).await?;
+
+                let job = Arc::new(Job::new(route));
+                let id = self.jobs.add(job.clone()).await;
+                info!("Starting background upload as job {}", id);
                 spawn(async move {
-                    let mut stream = stream.lock().await;
-                    while let Some(response) = response_receiver.recv().await {
-                        let mut content = serde_json::to_string(&response).unwrap();
-                        content.push('\n');
-                        let content_bytes = content.as_bytes();
-                        stream.write_all(content_bytes).await.unwrap();
-                        stream.flush().await.unwrap();
-                    }
+                    let (events, mut receiver) = mpsc::channel(1000);
+                    let record = async {
+                        while let Some(event) = receiver.recv().await {
+                            job.record(event).await;
+                        }
+                    };
+                    let (result, ()) = join!(
+                        self.upload_program(
+                            route,
+                            CLIENT_VENDOR,
+                            upload,
+                            events,
+                            job.cancel.clone()
+                        ),
+                        record
+                    );
+                    job.record(DaemonResponse::TransferComplete(result)).await;
                 });
+                Some(DaemonResponse::JobStarted(id))
+            }
+            DaemonCommand::JobStatus(id) => {
+                let status = match self.jobs.get(id).await {
+                    Some(job) => Some(job.status().await),
+                    None => None,
+                };
+                Some(DaemonResponse::JobStatus(status))
+            }
+            DaemonCommand::AttachJob(id) => {
+                let job = self.jobs.get(id).await.ok_or(DaemonError::UnknownJob(id))?;
+                let (catch_up, updates) = job.attach().await;
+                let mut stream = stream.lock().await;
+                for event in catch_up {
+                    write_response(&mut stream, &event).await?;
+                }
+                if let Some(mut updates) = updates {
+                    loop {
+                        match updates.recv().await {
+                            Ok(event) => {
+                                write_response(&mut stream, &event).await?;
+                                if matches!(event, DaemonResponse::TransferComplete(_)) {
+                                    break;
+                                }
+                            }
+                            // Skipped progress is superseded by the next event anyway
+                            Err(RecvError::Lagged(_)) => continue,
+                            Err(RecvError::Closed) => break,
+                        }
+                    }
+                }
+                None
+            }
+            DaemonCommand::CancelJob(id) => {
+                let job = self.jobs.get(id).await.ok_or(DaemonError::UnknownJob(id))?;
+                job.cancel.notify_one();
+                Some(DaemonResponse::BasicAck { successful: true })
+            }
+            DaemonCommand::ApplyExitAction { slot, action } => {
+                let device = self.device(route).await?;
+                let mut device = device.lock().await;
+                let result =
+                    rewrite_program_ini(&mut device.connection, CLIENT_VENDOR, slot, action).await;
+                if result.is_ok() && action == AfterFileUpload::RunProgram {
+                    device.launched_program = Some(LaunchedProgram {
+                        slot,
+                        started_at: Instant::now(),
+                    });
+                }
+                Some(DaemonResponse::TransferComplete(result))
+            }
+            DaemonCommand::StopProgram => {
+                let device = self.device(route).await?;
+                let mut device = device.lock().await;
+                device.connection.execute_command(StopProgram).await?;
+                device.launched_program = None;
+                Some(DaemonResponse::BasicAck { successful: true })
+            }
+            DaemonCommand::EraseSlot { slot } => {
+                let device = self.device(route).await?;
+                let mut device = device.lock().await;
+                let result = erase_slot(&mut device.connection, CLIENT_VENDOR, slot).await;
+                if result.is_ok() && device.launched_program.is_some_and(|p| p.slot == slot) {
+                    device.launched_program = None;
+                }
+                Some(DaemonResponse::TransferComplete(result))
+            }
+            DaemonCommand::ReadProgram { slot } => {
+                let device = self.device(route).await?;
+                let mut device = device.lock().await;
+                let result = read_program(&mut device.connection, CLIENT_VENDOR, slot).await;
+                Some(DaemonResponse::Program(result))
+            }
+            DaemonCommand::ProgramNames => {
+                let device = self.device(route).await?;
+                let mut device = device.lock().await;
+                let result = read_program_names(&mut device.connection, CLIENT_VENDOR).await;
+                Some(DaemonResponse::ProgramNames(result))
+            }
+            DaemonCommand::ReadKeyValue { key } => {
+                let result = self
+                    .device(route)
+                    .await?
+                    .lock()
+                    .await
+                    .connection
+                    .execute_command(ReadKeyValue { key })
+                    .await;
+                Some(DaemonResponse::KeyValue(match result {
+                    Ok(value) => Some(value),
+                    // The brain rejects keys it doesn't know about
+                    Err(GenericError::Nack(_)) => None,
+                    Err(err) => return Err(err.into()),
+                }))
+            }
+            DaemonCommand::WriteKeyValue { key, value } => {
+                let result = self
+                    .device(route)
+                    .await?
+                    .lock()
+                    .await
+                    .connection
+                    .execute_command(WriteKeyValue { key, value })
+                    .await;
+                Some(match result {
+                    Ok(()) => DaemonResponse::BasicAck { successful: true },
+                    Err(GenericError::Nack(_)) => DaemonResponse::BasicAck { successful: false },
+                    Err(err) => return Err(err.into()),
+                })
+            }
+            DaemonCommand::ReadUser { max_len } => {
+                let device = self.device(route).await?;
+                let mut device = device.lock().await;
+                if device.connection.is_bluetooth() {
+                    return Err(DaemonError::UnsupportedOverBluetooth("Reading user output"));
+                }
 
-                fn generate_callback(
-                    step: UploadStep,
-                    sender: Arc<Mutex<Sender<DaemonResponse>>>,
-                ) -> Box<dyn FnMut(f32) + Send> {
-                    Box::new(move |percent| {
-                        let sender = sender.clone();
-                        tokio::task::block_in_place(move || {
-                            let response = DaemonResponse::TransferProgress { percent, step };
-                            let sender = sender.blocking_lock();
-                            trace!("CALLBACK: {:?}", response);
-                            sender.blocking_send(response).unwrap();
-                        });
-                    })
+                if device.connection.is_controller() {
+                    let mut read = std::mem::take(&mut device.pending_user_output);
+                    if read.is_empty() {
+                        read = exchange_user_fifo(&mut device.connection, None).await?;
+                    }
+                    // The brain hands over everything it has buffered, which may exceed `max_len`.
+                    if read.len() > max_len as usize {
+                        device.pending_user_output = read.split_off(max_len as usize);
+                    }
+                    return Ok(Some(DaemonResponse::UserRead(read)));
                 }
+                let connection = &mut device.connection;
 
-                let command = vex_v5_serial::commands::file::UploadProgram {
-                    name,
-                    program_type,
-                    description,
-                    icon,
-                    slot: slot - 1,
-                    compress_program: compression,
-                    after_upload: after_upload.into(),
-                    data,
-                    ini_callback: Some(generate_callback(UploadStep::Ini, response_sender.clone())),
-                    monolith_callback: Some(generate_callback(
-                        UploadStep::Monolith,
-                        response_sender.clone(),
-                    )),
-                    cold_callback: Some(generate_callback(
-                        UploadStep::Cold,
-                        response_sender.clone(),
-                    )),
-                    hot_callback: Some(generate_callback(UploadStep::Hot, response_sender.clone())),
+                let mut buf = vec![0; max_len as usize];
+                // Wired reads block until the program writes something, so don't hog the device.
+                let read = match timeout(USER_READ_TIMEOUT, connection.read_user(&mut buf)).await {
+                    Ok(read) => read?,
+                    Err(_) => 0,
                 };
+                buf.truncate(read);
+                Some(DaemonResponse::UserRead(buf))
+            }
+            DaemonCommand::WriteUser(data) => {
+                let device = self.device(route).await?;
+                let connection = &mut device.lock().await.connection;
+                if connection.is_bluetooth() {
+                    return Err(DaemonError::UnsupportedOverBluetooth("Writing user input"));
+                }
+                if connection.is_controller() {
+                    // The radio is slow, so send a single packet and let the client write the
+                    // rest in later commands rather than holding the device for the whole buffer.
+                    let chunk = user_fifo_chunk(&data)?;
+                    let written = chunk.len();
+                    let write =
+                        VarLengthString::new(chunk.to_owned()).map_err(GenericError::from)?;
+                    exchange_user_fifo(connection, Some(write)).await?;
+                    return Ok(Some(DaemonResponse::UserWritten(written)));
+                }
 
-                Some(DaemonResponse::TransferComplete(
-                    match self
-                        .brain_connection
-                        .lock()
-                        .await
-                        .execute_command(command)
+                let written = connection.write_user(&data).await?;
+                Some(DaemonResponse::UserWritten(written))
+            }
+            DaemonCommand::Uptime => {
+                let device = self.device(route).await?;
+                let mut device = device.lock().await;
+                let flags = device.connection.execute_command(GetSystemFlags).await?;
+
+                let program = match flags.current_program {
+                    0 => None,
+                    slot => Some(RunningProgram {
+                        slot,
+                        running_for: device
+                            .launched_program
+                            .filter(|program| program.slot.get() == slot)
+                            .map(|program| program.started_at.elapsed()),
+                    }),
+                };
+                if program.is_none() {
+                    // Whatever we launched has since exited
+                    device.launched_program = None;
+                }
+
+                Some(DaemonResponse::Uptime(UptimeInfo {
+                    connected_for: device.connected_at.elapsed(),
+                    program,
+                }))
+            }
+            DaemonCommand::PingBrain => {
+                let device = self.device(route).await?;
+                let mut device = device.lock().await;
+                let ping = PingBrain {
+                    timeout: BRAIN_PING_TIMEOUT,
+                };
+                let rtt = match device.connection.execute_command(ping).await {
+                    Ok(rtt) => Some(rtt),
+                    Err(err) => {
+                        debug!("Brain ping lost: {}", err);
+                        None
+                    }
+                };
+                Some(DaemonResponse::BrainPing(rtt))
+            }
+            DaemonCommand::ControllerStatus => {
+                let device = self.device(route).await?;
+                let status = device
+                    .lock()
+                    .await
+                    .connection
+                    .execute_command(GetControllerStatus)
+                    .await?;
+                Some(DaemonResponse::ControllerStatus(status))
+            }
+            DaemonCommand::FieldControlStatus => {
+                let device = self.device(route).await?;
+                let status = device
+                    .lock()
+                    .await
+                    .connection
+                    .execute_command(GetFieldControlStatus)
+                    .await?;
+                Some(DaemonResponse::FieldControlStatus(status))
+            }
+            DaemonCommand::BootState => {
+                let device = self.device(route).await?;
+                let state = device
+                    .lock()
+                    .await
+                    .connection
+                    .execute_command(GetBootState)
+                    .await?;
+                Some(DaemonResponse::BootState(state))
+            }
+            DaemonCommand::FirmwareStatus => {
+                let device = self.device(route).await?;
+                let versions = device
+                    .lock()
+                    .await
+                    .connection
+                    .execute_command(GetFirmwareStatus)
+                    .await?;
+                Some(DaemonResponse::FirmwareStatus(versions))
+            }
+            #[cfg(feature = "debug")]
+            DaemonCommand::ReadMemory { address, length } => {
+                let device = self.device(route).await?;
+                let mut device = device.lock().await;
+                let length = length.min(v5d_interface::MAX_MEMORY_READ);
+                warn!("Reading {length} bytes of brain memory at {address:#010x}");
+                Some(
+                    match device
+                        .connection
+                        .execute_command(crate::commands::debug::ReadMemory { address, length })
                         .await
                     {
-                        Ok(_) => Ok(()),
-                        Err(err) => Err(format!("Failed to upload program: {}", err)),
+                        Ok(data) => DaemonResponse::Memory(data),
+                        Err(GenericError::Nack(_)) => {
+                            DaemonResponse::BasicAck { successful: false }
+                        }
+                        Err(err) => return Err(err.into()),
                     },
-                ))
+                )
+            }
+            DaemonCommand::StreamLogs { level } => {
+                let mut records = self.logs.subscribe();
+                let mut closing = self.closing.subscribe();
+                info!("Streaming logs at {} and above to a client", level);
+                let mut stream = stream.lock().await;
+                loop {
+                    // Don't log in here: each record would be streamed, and log another.
+                    // The stream ends when the client disconnects or the daemon shuts down.
+                    let record = select! {
+                        record = records.recv() => record,
+                        _ = closing.wait_for(|closing| *closing) => break,
+                    };
+                    match record {
+                        Ok(record) if record.level <= level => {
+                            if write_response(&mut stream, &DaemonResponse::Log(record))
+                                .await
+                                .is_err()
+                            {
+                                // The client went away
+                                break;
+                            }
+                        }
+                        Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+                None
             }
             DaemonCommand::Shutdown => {
                 info!("Received shutdown command");
-                super::shutdown();
+                self.shutdown.notify_one();
+                None
             }
             DaemonCommand::Reconnect => {
-                let mut connection = self.brain_connection.lock().await;
-                *connection = setup_connection(self.connection_type).await?;
+                self.reconnect().await?;
                 Some(DaemonResponse::BasicAck { successful: true })
             }
+            DaemonCommand::RecentErrors { limit } => {
+                Some(DaemonResponse::RecentErrors(recent_errors::recent(limit)))
+            }
+            DaemonCommand::ProtectedSlots => {
+                let device = self.device(route).await?;
+                let brain = brain_id(&mut device.lock().await.connection)
+                    .await?
+                    .ok_or(DaemonError::UnknownBrainId)?;
+                let slots = self.protection.lock().await.slots(brain);
+                Some(DaemonResponse::ProtectedSlots(slots))
+            }
+            DaemonCommand::SetSlotProtection { slot, protected } => {
+                let device = self.device(route).await?;
+                let brain = brain_id(&mut device.lock().await.connection)
+                    .await?
+                    .ok_or(DaemonError::UnknownBrainId)?;
+                self.protection.lock().await.set(brain, slot, protected)?;
+                info!(
+                    "{} slot {} on brain {:08x}",
+                    if protected {
+                        "Protected"
+                    } else {
+                        "Unprotected"
+                    },
+                    slot,
+                    brain
+                );
+                Some(DaemonResponse::BasicAck { successful: true })
+            }
+            DaemonCommand::SwitchToUsb => {
+                // Only read the routes here, so other clients aren't held up while the brain
+                // is identified and the USB ports are searched
+                let (old_id, handle, in_use) = {
+                    let devices = self.devices.read().await;
+                    let bound = devices
+                        .get(&route)
+                        .ok_or(DaemonError::UnknownRoute(route))?;
+                    if bound.connection_type != DeviceConnectionType::Bluetooth {
+                        return Err(DaemonError::NotBluetooth(route));
+                    }
+                    let in_use = devices
+                        .iter()
+                        .filter(|(other_route, _)| **other_route != route)
+                        .map(|(_, other)| other.id.clone())
+                        .collect::<Vec<_>>();
+                    (bound.id.clone(), bound.device.clone(), in_use)
+                };
+                let mut device = handle.lock().await;
+                let brain = brain_id(&mut device.connection)
+                    .await?
+                    .ok_or(DaemonError::UnknownBrainId)?;
+                let (id, connection) =
+                    find_usb_connection(brain, &self.connect_options.ports, &in_use)
+                        .await?
+                        .ok_or(DaemonError::NoUsbConnection)?;
+
+                info!("Switching route {} from {} to {}", route, old_id, id);
+                let connection_type = connection.connection_type().into();
+                // Dropping the Bluetooth connection disconnects it
+                device.connection = connection;
+                // The routes are locked before devices everywhere else, so let go of the device
+                // before updating its route
+                drop(device);
+
+                let mut devices = self.devices.write().await;
+                match devices.get_mut(&route) {
+                    // A reconnect may have replaced the route in the meantime
+                    Some(bound) if Arc::ptr_eq(&bound.device, &handle) => {
+                        self.route_table.lock().await.alias(id.clone(), route);
+                        bound.id = id;
+                        bound.connection_type = connection_type;
+                    }
+                    _ => warn!("Route {} changed while switching it to USB", route),
+                }
+                Some(DaemonResponse::BasicAck { successful: true })
+            }
+            DaemonCommand::ListDevices => {
+                let connected = self
+                    .devices
+                    .read()
+                    .await
+                    .iter()
+                    .map(|(route, bound)| ConnectedDevice {
+                        route: *route,
+                        id: bound.id.to_string(),
+                        connection_type: bound.connection_type,
+                    })
+                    .collect();
+                Some(DaemonResponse::Devices(connected))
+            }
             DaemonCommand::RequestPair => {
-                let mut connection = self.brain_connection.lock().await;
-                Some(match *connection {
+                let device = self.device(route).await?;
+                let mut device = device.lock().await;
+                Some(match device.connection {
                     GenericConnection::Bluetooth(ref mut connection) => {
                         connection
                             .request_pairing()
@@ -170,8 +1371,9 @@ impl Daemon {
                 })
             }
             DaemonCommand::PairingPin(pin) => {
-                let mut connection = self.brain_connection.lock().await;
-                Some(match *connection {
+                let device = self.device(route).await?;
+                let mut device = device.lock().await;
+                Some(match device.connection {
                     GenericConnection::Bluetooth(ref mut connection) => {
                         connection
                             .authenticate_pairing(pin)
@@ -187,31 +1389,1049 @@ impl Daemon {
         Ok(response)
     }
 
+    /// Handles a client's connection, telling the client if handling it panics.
+    ///
+    /// The panic only ends this connection. The daemon keeps serving other clients.
+    async fn serve_client(self: Arc<Self>, stream: UnixStream) {
+        self.serve_client_with(stream, Self::handle_connection)
+            .await
+    }
+
+    /// Serves a client with `handle`, telling the client if it panics.
+    async fn serve_client_with<F>(
+        self: Arc<Self>,
+        stream: UnixStream,
+        handle: impl FnOnce(Arc<Self>, BufReader<UnixStream>) -> F,
+    ) where
+        F: Future<Output = Result<(), DaemonError>> + Send + 'static,
+    {
+        // A second handle to the socket, to report a panic on after the first is gone
+        let (stream, report_to) = match duplicate(stream) {
+            Ok(streams) => streams,
+            Err(err) => {
+                error!("Failed to set up connection: {}", err);
+                return;
+            }
+        };
+        let handled = spawn(handle(self.clone(), BufReader::new(stream))).await;
+        let panic = match handled {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => {
+                error!("Failed to handle connection: {}", e);
+                return;
+            }
+            Err(err) if err.is_panic() => err.into_panic(),
+            Err(_) => return,
+        };
+
+        let message = panic_message(&*panic);
+        let incident = self.incidents.fetch_add(1, Ordering::Relaxed);
+        error!("Internal error (incident {}): {}", incident, message);
+        let response = DaemonResponse::InternalError { message, incident };
+        let reported = async {
+            let mut stream = BufReader::new(UnixStream::from_std(report_to)?);
+            write_response(&mut stream, &response).await
+        };
+        if let Err(err) = reported.await {
+            debug!(
+                "Couldn't report incident {} to the client: {}",
+                incident, err
+            );
+        }
+    }
+
     async fn handle_connection(
         self: Arc<Self>,
-        mut stream: BufReader<UnixStream>,
+        stream: BufReader<UnixStream>,
     ) -> Result<(), DaemonError> {
-        info!("Accepted connection from client");
-        let mut content = String::new();
-        stream.read_line(&mut content).await?;
-
+        debug!("Accepted connection from client");
         let stream = Arc::new(Mutex::new(stream));
-        let command: DaemonCommand = serde_json::from_str(&content)?;
-        debug!("Received command: {:?}", command);
-        let response = match self.perform_command(command, stream.clone()).await {
-            Ok(response) => response,
-            Err(e) => {
-                error!("Failed to perform command: {}", e);
-                Some(DaemonResponse::BasicAck { successful: false })
+        let mut closing = self.closing.subscribe();
+        // Clients that poll send their requests one after another over the same connection.
+        // Requests on one connection aren't multiplexed: each is finished before the next is
+        // read, so a client running uploads at once opens a connection for each.
+        loop {
+            let next = select! {
+                // Don't start another command once shutting down
+                biased;
+                _ = closing.wait_for(|closing| *closing) => None,
+                request = async { read_request(&mut *stream.lock().await).await } => Some(request?),
+            };
+            let request = match next {
+                Some(Some(request)) => request,
+                Some(None) => {
+                    debug!("Client disconnected");
+                    return Ok(());
+                }
+                None => {
+                    debug!("Telling a client that the daemon is shutting down");
+                    let mut stream = stream.lock().await;
+                    // The client may have gone already, which is fine
+                    let _ = write_response(&mut stream, &DaemonResponse::ShuttingDown).await;
+                    return Ok(());
+                }
+            };
+
+            debug!("Received request: {:?}", request);
+            let response = match self.clone().perform_command(request, stream.clone()).await {
+                Ok(response) => response,
+                Err(e) => {
+                    error!("Failed to perform command: {}", e);
+                    Some(DaemonResponse::BasicAck { successful: false })
+                }
+            };
+            if let Some(response) = response {
+                write_response(&mut *stream.lock().await, &response).await?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroU32, path::Path};
+
+    use tokio::time::timeout;
+    use v5d_interface::{
+        get_response, send_command, send_command_to, BootState, Transfer, TransferEvent,
+    };
+    use vex_v5_serial::{connection::serial::SerialDevice, packets::file::FileExitAction};
+
+    use super::*;
+    use crate::testing::{
+        test_dir, Brain, PtyBrain, ERASE_FILE, EXIT_FILE_TRANSFER, GET_FILE_METADATA,
+        INIT_FILE_TRANSFER, WRITE_FILE,
+    };
+
+    /// A daemon listening on `socket_path`, connected to `devices` in order.
+    fn daemon_for(socket_path: &Path, devices: &[SerialDevice]) -> Daemon {
+        let connections = devices
+            .iter()
+            .map(|device| {
+                let connection = device.connect(Duration::from_secs(1)).unwrap();
+                (DeviceId::Serial(device.system_port()), connection.into())
+            })
+            .collect();
+        let mut route_table = RouteTable::default();
+        Daemon {
+            socket: setup_socket(socket_path).unwrap(),
+            socket_path: socket_path.to_owned(),
+            devices: RwLock::new(into_routes(&mut route_table, connections).unwrap()),
+            jobs: Jobs::default(),
+            route_table: Mutex::new(route_table),
+            connection_type: ConnectionType::Serial,
+            connect_options: ConnectOptions {
+                max_connect_attempts: NonZeroU32::new(1),
+                bluetooth_connect_attempts: NonZeroU32::MIN,
+                bluetooth: Default::default(),
+                ports: Default::default(),
+            },
+            logs: broadcast::channel(16).0,
+            shutdown: Arc::new(Notify::new()),
+            closing: watch::channel(false).0,
+            protection: Mutex::new(Protection::load_from(Some(
+                socket_path.with_file_name("protected-slots.json"),
+            ))),
+            incidents: AtomicU64::new(1),
+        }
+    }
+
+    /// A monolith program for `slot`.
+    fn program(slot: u8, data: Vec<u8>) -> ProgramUpload {
+        ProgramUpload {
+            name: format!("program {slot}"),
+            description: String::new(),
+            icon: "USER029x.bmp".to_owned(),
+            program_type: "test".to_owned(),
+            slot: Slot::try_from(slot).unwrap(),
+            compression: false,
+            after_upload: AfterFileUpload::DoNothing,
+            data: ProgramData::Monolith(data),
+            replace: false,
+            overwrite_protected: false,
+        }
+    }
+
+    /// Reads responses to an upload until it completes.
+    async fn upload_result(stream: &mut BufReader<UnixStream>) -> Result<(), TransferError> {
+        loop {
+            match get_response(stream).await.unwrap() {
+                DaemonResponse::TransferProgress(_) => {}
+                DaemonResponse::TransferComplete(result) => return result,
+                response => panic!("unexpected response {response:?}"),
+            }
+        }
+    }
+
+    async fn upload(socket_path: &Path, route: u8, slot: u8) -> Result<(), TransferError> {
+        let stream = BufReader::new(UnixStream::connect(socket_path).await.unwrap());
+        let upload = program(slot, vec![0xAB; 10_000]);
+        let mut transfer = Transfer::start(stream, route, DaemonCommand::UploadProgram(upload))
+            .await
+            .unwrap();
+        loop {
+            if let TransferEvent::Complete(result) = transfer.next_event().await.unwrap() {
+                return result;
+            }
+        }
+    }
+
+    // Each upload must reach only the brain on its own route, even while both are running
+    #[tokio::test(flavor = "multi_thread")]
+    async fn uploads_to_two_devices_at_once() {
+        let brains = [PtyBrain::spawn().unwrap(), PtyBrain::spawn().unwrap()];
+        let socket_path = test_dir("two-devices").join("v5d.sock");
+        let daemon = daemon_for(&socket_path, &brains.each_ref().map(PtyBrain::device));
+        let shutdown = daemon.shutdown.clone();
+        let serving = spawn(daemon.run());
+
+        let (first, second) = timeout(Duration::from_secs(30), async {
+            join!(upload(&socket_path, 0, 1), upload(&socket_path, 1, 2))
+        })
+        .await
+        .expect("uploads took too long");
+        first.unwrap();
+        second.unwrap();
+
+        assert!(brains[0].started_transfer_of("slot0.bin"));
+        assert!(!brains[0].started_transfer_of("slot1.bin"));
+        assert!(brains[1].started_transfer_of("slot1.bin"));
+        assert!(!brains[1].started_transfer_of("slot0.bin"));
+
+        shutdown.notify_one();
+        serving.await.unwrap();
+    }
+
+    // An upload holds only its own device, so other clients' queries about the devices, or to
+    // another device, aren't kept waiting for it
+    #[tokio::test(flavor = "multi_thread")]
+    async fn queries_answer_promptly_during_a_long_upload() {
+        let mut slow = Brain::default();
+        slow.reply_delay = Duration::from_millis(20);
+        let brains = [
+            PtyBrain::spawn_with(slow).unwrap(),
+            PtyBrain::spawn().unwrap(),
+        ];
+        let socket_path = test_dir("queries-during-upload").join("v5d.sock");
+        let daemon = daemon_for(&socket_path, &brains.each_ref().map(PtyBrain::device));
+        let shutdown = daemon.shutdown.clone();
+        let serving = spawn(daemon.run());
+
+        let mut uploading = BufReader::new(UnixStream::connect(&socket_path).await.unwrap());
+        let upload = program(1, vec![0xAB; 100 * 4096]);
+        send_command(&mut uploading, DaemonCommand::UploadProgram(upload))
+            .await
+            .unwrap();
+        loop {
+            match get_response(&mut uploading).await.unwrap() {
+                DaemonResponse::TransferProgress(progress)
+                    if progress.step == UploadStep::Monolith =>
+                {
+                    break
+                }
+                DaemonResponse::TransferProgress(_) => {}
+                response => panic!("unexpected response {response:?}"),
+            }
+        }
+
+        let started = Instant::now();
+        match query(&socket_path, DaemonCommand::ListDevices).await {
+            DaemonResponse::Devices(devices) => assert_eq!(devices.len(), 2),
+            response => panic!("unexpected response {response:?}"),
+        }
+        let mut stream = BufReader::new(UnixStream::connect(&socket_path).await.unwrap());
+        send_command_to(&mut stream, 1, DaemonCommand::ProgramNames)
+            .await
+            .unwrap();
+        match get_response(&mut stream).await.unwrap() {
+            DaemonResponse::ProgramNames(names) => assert!(names.unwrap().is_empty()),
+            response => panic!("unexpected response {response:?}"),
+        }
+        let took = started.elapsed();
+        assert!(
+            took < Duration::from_millis(500),
+            "the queries took {took:?}"
+        );
+
+        timeout(Duration::from_secs(30), upload_result(&mut uploading))
+            .await
+            .expect("the upload took too long")
+            .unwrap();
+        // The queries really did overlap the upload
+        assert!(started.elapsed() > took * 2);
+
+        shutdown.notify_one();
+        serving.await.unwrap();
+    }
+
+    // A connection handles one request at a time, so uploads sent over the same connection
+    // run one after the other, each to its own route
+    #[tokio::test(flavor = "multi_thread")]
+    async fn uploads_to_two_devices_over_one_connection() {
+        let brains = [PtyBrain::spawn().unwrap(), PtyBrain::spawn().unwrap()];
+        let socket_path = test_dir("two-devices-one-connection").join("v5d.sock");
+        let daemon = daemon_for(&socket_path, &brains.each_ref().map(PtyBrain::device));
+        let shutdown = daemon.shutdown.clone();
+        let serving = spawn(daemon.run());
+
+        let mut stream = BufReader::new(UnixStream::connect(&socket_path).await.unwrap());
+        timeout(Duration::from_secs(30), async {
+            for (route, slot) in [(0, 1), (1, 2)] {
+                let upload = program(slot, vec![0xAB; 10_000]);
+                send_command_to(&mut stream, route, DaemonCommand::UploadProgram(upload))
+                    .await
+                    .unwrap();
+                upload_result(&mut stream).await.unwrap();
+            }
+        })
+        .await
+        .expect("uploads took too long");
+
+        assert!(brains[0].started_transfer_of("slot0.bin"));
+        assert!(!brains[0].started_transfer_of("slot1.bin"));
+        assert!(brains[1].started_transfer_of("slot1.bin"));
+        assert!(!brains[1].started_transfer_of("slot0.bin"));
+
+        shutdown.notify_one();
+        serving.await.unwrap();
+    }
+
+    // Cancelling stops the daemon sending chunks, and leaves the brain with no transfer open
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cancelling_aborts_the_upload_on_the_brain() {
+        const CHUNKS: usize = 500;
+        let brain = PtyBrain::spawn().unwrap();
+        let socket_path = test_dir("cancel").join("v5d.sock");
+        let daemon = daemon_for(&socket_path, &[brain.device()]);
+        let shutdown = daemon.shutdown.clone();
+        let serving = spawn(daemon.run());
+
+        let stream = BufReader::new(UnixStream::connect(&socket_path).await.unwrap());
+        let upload = program(1, vec![0xAB; CHUNKS * 4096]);
+        let mut transfer = Transfer::start(stream, 0, DaemonCommand::UploadProgram(upload))
+            .await
+            .unwrap();
+        let result = timeout(Duration::from_secs(30), async {
+            let mut cancelled = false;
+            loop {
+                match transfer.next_event().await.unwrap() {
+                    TransferEvent::Progress(_) if !cancelled => {
+                        transfer.cancel().await.unwrap();
+                        cancelled = true;
+                    }
+                    TransferEvent::Progress(_) => {}
+                    TransferEvent::Complete(result) => return result,
+                }
+            }
+        })
+        .await
+        .expect("the cancelled upload took too long to end");
+        assert_eq!(result.unwrap_err().message, "Upload cancelled");
+
+        let received = brain.received();
+        let chunks = received
+            .iter()
+            .filter(|packet| packet.ext_id == WRITE_FILE)
+            .count();
+        assert!(chunks < CHUNKS, "all {chunks} chunks were sent");
+        let last = received.last().unwrap();
+        assert_eq!(last.ext_id, EXIT_FILE_TRANSFER);
+        assert_eq!(last.payload, [FileExitAction::DoNothing as u8]);
+
+        shutdown.notify_one();
+        serving.await.unwrap();
+    }
+
+    // A client that hangs up mid-upload, as v5ctl does when --timeout fires, gets the upload
+    // aborted on the brain just as if it had cancelled
+    #[tokio::test(flavor = "multi_thread")]
+    async fn hanging_up_mid_upload_aborts_it() {
+        const CHUNKS: usize = 500;
+        let brain = PtyBrain::spawn().unwrap();
+        let socket_path = test_dir("hang-up").join("v5d.sock");
+        let daemon = daemon_for(&socket_path, &[brain.device()]);
+        let shutdown = daemon.shutdown.clone();
+        let serving = spawn(daemon.run());
+
+        let mut stream = BufReader::new(UnixStream::connect(&socket_path).await.unwrap());
+        let command = DaemonCommand::UploadProgram(program(1, vec![0xAB; CHUNKS * 4096]));
+        send_command(&mut stream, command).await.unwrap();
+        // Hang up once the program itself is being sent, after the INI's transfer has ended
+        loop {
+            match get_response(&mut stream).await.unwrap() {
+                DaemonResponse::TransferProgress(progress)
+                    if progress.step == UploadStep::Monolith && progress.sent > 0 =>
+                {
+                    break
+                }
+                DaemonResponse::TransferProgress(_) => {}
+                response => panic!("unexpected response {response:?}"),
+            }
+        }
+        drop(stream);
+
+        // Nothing answers the client anymore, so watch for the abort on the brain
+        timeout(Duration::from_secs(30), async {
+            loop {
+                let last = brain.received().last().cloned().unwrap();
+                if last.ext_id == EXIT_FILE_TRANSFER {
+                    assert_eq!(last.payload, [FileExitAction::DoNothing as u8]);
+                    break;
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the abandoned upload was never aborted");
+        let chunks = brain
+            .received()
+            .iter()
+            .filter(|packet| packet.ext_id == WRITE_FILE)
+            .count();
+        assert!(chunks < CHUNKS, "all {chunks} chunks were sent");
+        // The device is free for the next client
+        upload(&socket_path, 0, 2).await.unwrap();
+
+        shutdown.notify_one();
+        serving.await.unwrap();
+    }
+
+    /// Starts a background upload of `data` to slot 1 on route 0, returning its job's ID.
+    async fn start_job(socket_path: &Path, data: Vec<u8>) -> JobId {
+        let mut stream = BufReader::new(UnixStream::connect(socket_path).await.unwrap());
+        let upload = program(1, data);
+        send_command(
+            &mut stream,
+            DaemonCommand::UploadProgramInBackground(upload),
+        )
+        .await
+        .unwrap();
+        match get_response(&mut stream).await.unwrap() {
+            DaemonResponse::JobStarted(id) => id,
+            response => panic!("unexpected response {response:?}"),
+        }
+    }
+
+    /// Polls a job's status until it has finished, returning how it ended.
+    async fn job_result(socket_path: &Path, id: JobId) -> Result<(), TransferError> {
+        let mut stream = BufReader::new(UnixStream::connect(socket_path).await.unwrap());
+        timeout(Duration::from_secs(30), async {
+            loop {
+                send_command(&mut stream, DaemonCommand::JobStatus(id))
+                    .await
+                    .unwrap();
+                match get_response(&mut stream).await.unwrap() {
+                    DaemonResponse::JobStatus(Some(status)) => {
+                        if let Some(result) = status.result {
+                            return result;
+                        }
+                    }
+                    response => panic!("unexpected response {response:?}"),
+                }
+                sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("the job took too long")
+    }
+
+    // A client that detaches part way through a background upload leaves it running to the end
+    #[tokio::test(flavor = "multi_thread")]
+    async fn detaching_from_a_job_leaves_it_running() {
+        const CHUNKS: usize = 200;
+        let brain = PtyBrain::spawn().unwrap();
+        let socket_path = test_dir("detach").join("v5d.sock");
+        let daemon = daemon_for(&socket_path, &[brain.device()]);
+        let shutdown = daemon.shutdown.clone();
+        let serving = spawn(daemon.run());
+
+        let id = start_job(&socket_path, vec![0xAB; CHUNKS * 4096]).await;
+        let mut attached = BufReader::new(UnixStream::connect(&socket_path).await.unwrap());
+        send_command(&mut attached, DaemonCommand::AttachJob(id))
+            .await
+            .unwrap();
+        let detached_at = loop {
+            match get_response(&mut attached).await.unwrap() {
+                DaemonResponse::TransferProgress(progress)
+                    if progress.step == UploadStep::Monolith =>
+                {
+                    break progress
+                }
+                DaemonResponse::TransferProgress(_) => {}
+                response => panic!("unexpected response {response:?}"),
             }
         };
-        if let Some(response) = response {
-            let mut content = serde_json::to_string(&response)?;
-            content.push('\n');
-            let content_bytes = content.as_bytes();
-            stream.lock().await.write_all(content_bytes).await?;
+        assert!(!detached_at.is_complete());
+        drop(attached);
+
+        job_result(&socket_path, id).await.unwrap();
+        let received = brain.received();
+        // The INI takes one chunk
+        let chunks = received
+            .iter()
+            .filter(|packet| packet.ext_id == WRITE_FILE)
+            .count();
+        assert_eq!(chunks, CHUNKS + 1);
+        assert_eq!(received.last().unwrap().ext_id, EXIT_FILE_TRANSFER);
+
+        shutdown.notify_one();
+        serving.await.unwrap();
+    }
+
+    // Attaching to a finished job replays where each section got to and how the job ended,
+    // and leaves the connection free for more requests
+    #[tokio::test(flavor = "multi_thread")]
+    async fn attaching_after_a_job_finished_replays_it() {
+        let brain = PtyBrain::spawn().unwrap();
+        let socket_path = test_dir("attach-finished").join("v5d.sock");
+        let daemon = daemon_for(&socket_path, &[brain.device()]);
+        let shutdown = daemon.shutdown.clone();
+        let serving = spawn(daemon.run());
+
+        let id = start_job(&socket_path, vec![0xAB; 10_000]).await;
+        job_result(&socket_path, id).await.unwrap();
+
+        let mut stream = BufReader::new(UnixStream::connect(&socket_path).await.unwrap());
+        send_command(&mut stream, DaemonCommand::AttachJob(id))
+            .await
+            .unwrap();
+        let mut replayed = Vec::new();
+        let result = loop {
+            match get_response(&mut stream).await.unwrap() {
+                DaemonResponse::TransferProgress(progress) => replayed.push(progress),
+                DaemonResponse::TransferComplete(result) => break result,
+                response => panic!("unexpected response {response:?}"),
+            }
+        };
+        result.unwrap();
+        let steps = replayed
+            .iter()
+            .map(|progress| progress.step)
+            .collect::<Vec<_>>();
+        assert_eq!(steps, [UploadStep::Ini, UploadStep::Monolith]);
+        assert!(replayed.iter().all(SectionProgress::is_complete));
+
+        send_command(&mut stream, DaemonCommand::ListDevices)
+            .await
+            .unwrap();
+        assert!(matches!(
+            get_response(&mut stream).await.unwrap(),
+            DaemonResponse::Devices(_)
+        ));
+
+        shutdown.notify_one();
+        serving.await.unwrap();
+    }
+
+    // Sizes are worked out after compression, so each section's progress only goes up and
+    // ends at exactly its compressed size
+    #[tokio::test(flavor = "multi_thread")]
+    async fn compressed_upload_progress_ends_at_100_percent() {
+        let brain = PtyBrain::spawn().unwrap();
+        let socket_path = test_dir("compressed-progress").join("v5d.sock");
+        let daemon = daemon_for(&socket_path, &[brain.device()]);
+        let shutdown = daemon.shutdown.clone();
+        let serving = spawn(daemon.run());
+
+        let upload = ProgramUpload {
+            compression: true,
+            ..program(1, vec![0; 200_000])
+        };
+        let mut stream = BufReader::new(UnixStream::connect(&socket_path).await.unwrap());
+        send_command(&mut stream, DaemonCommand::UploadProgram(upload))
+            .await
+            .unwrap();
+        let mut updates = Vec::new();
+        let result = timeout(Duration::from_secs(30), async {
+            loop {
+                match get_response(&mut stream).await.unwrap() {
+                    DaemonResponse::TransferProgress(progress) => updates.push(progress),
+                    DaemonResponse::TransferComplete(result) => return result,
+                    response => panic!("unexpected response {response:?}"),
+                }
+            }
+        })
+        .await
+        .expect("the upload took too long");
+        result.unwrap();
+
+        for step in [UploadStep::Ini, UploadStep::Monolith] {
+            let section = updates
+                .iter()
+                .filter(|progress| progress.step == step)
+                .collect::<Vec<_>>();
+            let last = section.last().unwrap();
+            assert!(section.iter().all(|progress| progress.total == last.total));
+            assert!(section.windows(2).all(|pair| pair[0].sent <= pair[1].sent));
+            assert_eq!(last.sent, last.total);
+            assert_eq!(last.percent(), 100.0);
         }
+        let monolith = updates.last().unwrap();
+        assert!(
+            monolith.total < 200_000 / 10,
+            "the program wasn't compressed"
+        );
 
-        Ok(())
+        shutdown.notify_one();
+        serving.await.unwrap();
+    }
+
+    // The brain's bare NACK while a program runs is reported as the program being in the way,
+    // and stopping it lets the same upload through, as `v5ctl upload --force-stop` does
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_running_program_blocks_uploads_until_stopped() {
+        let mut brain = Brain::default();
+        brain.running_program = 1;
+        let brain = PtyBrain::spawn_with(brain).unwrap();
+        let socket_path = test_dir("busy").join("v5d.sock");
+        let daemon = daemon_for(&socket_path, &[brain.device()]);
+        let shutdown = daemon.shutdown.clone();
+        let serving = spawn(daemon.run());
+
+        let error = upload(&socket_path, 0, 2).await.unwrap_err();
+        assert!(error.program_running);
+        match query(&socket_path, DaemonCommand::StopProgram).await {
+            DaemonResponse::BasicAck { successful: true } => {}
+            response => panic!("unexpected response {response:?}"),
+        }
+        assert_eq!(brain.brain().running_program, 0);
+        upload(&socket_path, 0, 2).await.unwrap();
+
+        shutdown.notify_one();
+        serving.await.unwrap();
+    }
+
+    // A write refused part way through the cold section, which holds the library, is
+    // reported as failing there rather than in the INI or the hot section
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_failure_in_the_cold_section_names_it() {
+        let mut brain = Brain::default();
+        let mut inits = 0;
+        let mut cold_writes = 0;
+        brain.refuse = Some(Box::new(move |packet| {
+            match packet.ext_id {
+                INIT_FILE_TRANSFER => inits += 1,
+                // The INI comes first, so the second transfer is the cold section
+                WRITE_FILE if inits == 2 => cold_writes += 1,
+                _ => {}
+            }
+            cold_writes == 2
+        }));
+        let brain = PtyBrain::spawn_with(brain).unwrap();
+        let socket_path = test_dir("cold-section-failure").join("v5d.sock");
+        let daemon = daemon_for(&socket_path, &[brain.device()]);
+        let shutdown = daemon.shutdown.clone();
+        let serving = spawn(daemon.run());
+
+        let upload = ProgramUpload {
+            data: ProgramData::HotCold {
+                hot: Some(vec![0xCD; 10_000]),
+                cold: Some(vec![0xAB; 20_000]),
+            },
+            ..program(1, Vec::new())
+        };
+        let mut stream = BufReader::new(UnixStream::connect(&socket_path).await.unwrap());
+        send_command(&mut stream, DaemonCommand::UploadProgram(upload))
+            .await
+            .unwrap();
+        let error = timeout(Duration::from_secs(30), upload_result(&mut stream))
+            .await
+            .expect("the upload took too long")
+            .unwrap_err();
+        assert_eq!(error.step, Some(UploadStep::Cold));
+
+        shutdown.notify_one();
+        serving.await.unwrap();
+    }
+
+    #[test]
+    fn failed_sections_are_worked_out_from_progress() {
+        use UploadStep::{Cold, Hot, Ini};
+        let sections = [Ini, Cold, Hot];
+        let progress = |step, sent| {
+            Some(SectionProgress {
+                step,
+                sent,
+                total: 100,
+            })
+        };
+
+        // Nothing was sent, so the first section failed to start
+        assert_eq!(failed_section(&sections, None), Some(Ini));
+        assert_eq!(failed_section(&sections, progress(Cold, 40)), Some(Cold));
+        // A finished section was followed by the next one failing to start
+        assert_eq!(failed_section(&sections, progress(Ini, 100)), Some(Cold));
+        assert_eq!(failed_section(&sections, progress(Cold, 100)), Some(Hot));
+        // Closing the last section failed
+        assert_eq!(failed_section(&sections, progress(Hot, 100)), Some(Hot));
+    }
+
+    /// Sends one command to route 0 on a new connection, returning the response.
+    async fn query(socket_path: &Path, command: DaemonCommand) -> DaemonResponse {
+        let mut stream = BufReader::new(UnixStream::connect(socket_path).await.unwrap());
+        send_command(&mut stream, command).await.unwrap();
+        get_response(&mut stream).await.unwrap()
+    }
+
+    /// The INI an upload of a program called `name` to `slot` writes.
+    fn ini(slot: Slot, name: &str) -> Vec<u8> {
+        serde_ini::to_vec(&ProgramIniConfig {
+            program: Program {
+                description: "drives".to_owned(),
+                icon: "USER029x.bmp".to_owned(),
+                iconalt: String::new(),
+                slot: slot.to_zero_based(),
+                name: name.to_owned(),
+            },
+            project: Project {
+                ide: "test".to_owned(),
+            },
+        })
+        .unwrap()
+    }
+
+    // Applying an action re-uploads the slot's INI byte for byte, ending with the action
+    #[tokio::test(flavor = "multi_thread")]
+    async fn applying_an_exit_action_keeps_the_ini() {
+        let slot = Slot::try_from(2).unwrap();
+        let original = ini(slot, "drive");
+        let mut brain = Brain::default();
+        brain.store("slot1.ini", original.clone());
+        let brain = PtyBrain::spawn_with(brain).unwrap();
+        let socket_path = test_dir("apply-exit-action").join("v5d.sock");
+        let daemon = daemon_for(&socket_path, &[brain.device()]);
+        let shutdown = daemon.shutdown.clone();
+        let serving = spawn(daemon.run());
+
+        let command = DaemonCommand::ApplyExitAction {
+            slot,
+            action: AfterFileUpload::RunProgram,
+        };
+        match query(&socket_path, command).await {
+            DaemonResponse::TransferComplete(result) => result.unwrap(),
+            response => panic!("unexpected response {response:?}"),
+        }
+
+        assert!(brain.started_transfer_of("slot1.ini"));
+        let last = brain.received().pop().unwrap();
+        assert_eq!(last.ext_id, EXIT_FILE_TRANSFER);
+        assert_eq!(last.payload, [FileExitAction::RunProgram as u8]);
+        assert_eq!(brain.brain().file("slot1.ini").unwrap().data, original);
+        assert_eq!(brain.brain().running_program, 2);
+
+        shutdown.notify_one();
+        serving.await.unwrap();
+    }
+
+    // Clients can't choose a vendor yet, so everything they ask for works on user files
+    #[tokio::test(flavor = "multi_thread")]
+    async fn client_commands_address_user_files() {
+        let stored = Slot::try_from(2).unwrap();
+        let mut brain = Brain::default();
+        brain.store("slot1.ini", ini(stored, "drive"));
+        brain.store("slot1.bin", vec![0xAB; 1000]);
+        let brain = PtyBrain::spawn_with(brain).unwrap();
+        let socket_path = test_dir("user-vendor").join("v5d.sock");
+        let daemon = daemon_for(&socket_path, &[brain.device()]);
+        let shutdown = daemon.shutdown.clone();
+        let serving = spawn(daemon.run());
+
+        let upload = ProgramUpload {
+            replace: true,
+            ..program(3, vec![0xCD; 1000])
+        };
+        let commands = [
+            DaemonCommand::UploadProgram(upload),
+            DaemonCommand::ReadProgram { slot: stored },
+            DaemonCommand::ProgramNames,
+            DaemonCommand::ApplyExitAction {
+                slot: stored,
+                action: AfterFileUpload::DoNothing,
+            },
+            DaemonCommand::EraseSlot {
+                slot: Slot::try_from(3).unwrap(),
+            },
+        ];
+        for command in commands {
+            let mut stream = BufReader::new(UnixStream::connect(&socket_path).await.unwrap());
+            send_command(&mut stream, command).await.unwrap();
+            let response = loop {
+                match get_response(&mut stream).await.unwrap() {
+                    DaemonResponse::TransferProgress(_) => {}
+                    response => break response,
+                }
+            };
+            match response {
+                DaemonResponse::TransferComplete(result) => result.unwrap(),
+                DaemonResponse::Program(result) => assert!(result.unwrap().is_some()),
+                DaemonResponse::ProgramNames(result) => assert!(!result.unwrap().is_empty()),
+                response => panic!("unexpected response {response:?}"),
+            }
+        }
+
+        let naming_files = brain
+            .received()
+            .into_iter()
+            .filter(|packet| {
+                [INIT_FILE_TRANSFER, GET_FILE_METADATA, ERASE_FILE].contains(&packet.ext_id)
+            })
+            .collect::<Vec<_>>();
+        assert!(naming_files
+            .iter()
+            .any(|packet| packet.ext_id == ERASE_FILE));
+        for packet in naming_files {
+            assert_eq!(
+                packet.vendor(),
+                FileVendor::User as u8,
+                "{} used another vendor",
+                packet.file_name()
+            );
+        }
+
+        shutdown.notify_one();
+        serving.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn applying_an_exit_action_to_an_empty_slot_fails() {
+        let brain = PtyBrain::spawn().unwrap();
+        let socket_path = test_dir("apply-exit-action-empty").join("v5d.sock");
+        let daemon = daemon_for(&socket_path, &[brain.device()]);
+        let shutdown = daemon.shutdown.clone();
+        let serving = spawn(daemon.run());
+
+        let command = DaemonCommand::ApplyExitAction {
+            slot: Slot::try_from(2).unwrap(),
+            action: AfterFileUpload::RunProgram,
+        };
+        match query(&socket_path, command).await {
+            DaemonResponse::TransferComplete(result) => {
+                assert_eq!(result.unwrap_err().message, "Slot 2 is empty")
+            }
+            response => panic!("unexpected response {response:?}"),
+        }
+        assert!(!brain.started_transfer_of("slot1.ini"));
+        assert_eq!(brain.brain().running_program, 0);
+
+        shutdown.notify_one();
+        serving.await.unwrap();
+    }
+
+    // Polling clients keep one connection open for many requests
+    #[tokio::test]
+    async fn serves_several_requests_per_connection() {
+        let brain = PtyBrain::spawn().unwrap();
+        let socket_path = test_dir("several-requests").join("v5d.sock");
+        let daemon = daemon_for(&socket_path, &[brain.device()]);
+        let shutdown = daemon.shutdown.clone();
+        let serving = spawn(daemon.run());
+
+        let mut stream = BufReader::new(UnixStream::connect(&socket_path).await.unwrap());
+        for _ in 0..3 {
+            send_command(&mut stream, DaemonCommand::ListDevices)
+                .await
+                .unwrap();
+            match get_response(&mut stream).await.unwrap() {
+                DaemonResponse::Devices(devices) => assert_eq!(devices.len(), 1),
+                response => panic!("unexpected response {response:?}"),
+            }
+        }
+
+        shutdown.notify_one();
+        serving.await.unwrap();
+    }
+
+    // Clients connected when the daemon shuts down are told why their connection ends, and
+    // the socket file doesn't outlive the daemon
+    #[tokio::test]
+    async fn shutting_down_tells_clients_and_removes_the_socket() {
+        let socket_path = test_dir("shutdown").join("v5d.sock");
+        let daemon = daemon_for(&socket_path, &[]);
+        let shutdown = daemon.shutdown.clone();
+        let logs = daemon.logs.clone();
+        let serving = spawn(daemon.run());
+        assert!(socket_path.exists());
+
+        let mut idle = BufReader::new(UnixStream::connect(&socket_path).await.unwrap());
+        send_command(&mut idle, DaemonCommand::ListDevices)
+            .await
+            .unwrap();
+        get_response(&mut idle).await.unwrap();
+
+        let mut streaming = BufReader::new(UnixStream::connect(&socket_path).await.unwrap());
+        let level = log::Level::Info;
+        send_command(&mut streaming, DaemonCommand::StreamLogs { level })
+            .await
+            .unwrap();
+        // Records only reach the client once the daemon is streaming to it
+        loop {
+            let _ = logs.send(LogRecord {
+                level,
+                target: "test".to_owned(),
+                message: "hello".to_owned(),
+            });
+            let response = timeout(Duration::from_millis(50), get_response(&mut streaming)).await;
+            if let Ok(response) = response {
+                assert!(matches!(response.unwrap(), DaemonResponse::Log(_)));
+                break;
+            }
+        }
+
+        shutdown.notify_one();
+        serving.await.unwrap();
+
+        assert!(!socket_path.exists());
+        for client in [&mut idle, &mut streaming] {
+            let response = loop {
+                match get_response(client).await {
+                    // Records sent before the notice
+                    Ok(DaemonResponse::Log(_)) => continue,
+                    response => break response,
+                }
+            };
+            assert_eq!(
+                response.unwrap_err().kind(),
+                io::ErrorKind::ConnectionAborted
+            );
+        }
+    }
+
+    #[test]
+    fn only_a_big_clock_jump_counts_as_sleep() {
+        let second = Duration::from_secs(1);
+        // Drift, and a wall clock that was set back, aren't sleep
+        assert_eq!(
+            time_asleep(5 * second, Some(5 * second + 10 * second)),
+            None
+        );
+        assert_eq!(time_asleep(5 * second, None), None);
+        assert_eq!(time_asleep(5 * second, Some(2 * second)), None);
+        assert_eq!(
+            time_asleep(5 * second, Some(125 * second)),
+            Some(120 * second)
+        );
+    }
+
+    // After a sleep, devices whose ports went stale are found by their unanswered pings, and
+    // nothing is reconnected if every device still answers
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stale_devices_are_found_after_waking() {
+        let (awake, stale) = (PtyBrain::spawn().unwrap(), PtyBrain::spawn().unwrap());
+        let socket_path = test_dir("stale-devices").join("v5d.sock");
+        let daemon = daemon_for(&socket_path, &[awake.device(), stale.device()]);
+
+        daemon.revalidate_devices().await.unwrap();
+        assert_eq!(daemon.devices.read().await.len(), 2);
+
+        stale.brain().unresponsive = true;
+        assert_eq!(daemon.unresponsive_routes().await, [1]);
+        stale.brain().unresponsive = false;
+        assert!(daemon.unresponsive_routes().await.is_empty());
+    }
+
+    // A panic while handling one client is reported to it as an internal error, and the
+    // daemon goes on serving the next client
+    #[tokio::test]
+    async fn a_panic_is_reported_to_its_client_only() {
+        let socket_path = test_dir("panic").join("v5d.sock");
+        let daemon = Arc::new(daemon_for(&socket_path, &[]));
+
+        let (client, server) = UnixStream::pair().unwrap();
+        let serving = spawn(daemon.clone().serve_client_with(server, |_, _| async {
+            panic!("injected panic");
+        }));
+        let mut client = BufReader::new(client);
+        let err = get_response(&mut client).await.unwrap_err();
+        serving.await.unwrap();
+        let message = err.to_string();
+        assert!(message.contains("injected panic"), "{message}");
+        assert!(message.contains("incident number 1"), "{message}");
+
+        let (client, server) = UnixStream::pair().unwrap();
+        spawn(daemon.clone().serve_client(server));
+        let mut client = BufReader::new(client);
+        send_command(&mut client, DaemonCommand::ListDevices)
+            .await
+            .unwrap();
+        assert!(matches!(
+            get_response(&mut client).await.unwrap(),
+            DaemonResponse::Devices(devices) if devices.is_empty()
+        ));
+    }
+
+    // A protected slot refuses uploads unless they're forced, and only on the brain it was
+    // protected on
+    #[tokio::test(flavor = "multi_thread")]
+    async fn protected_slots_refuse_uploads_unless_forced() {
+        let mut brain = Brain::default();
+        brain.unique_id = 0xABCD;
+        let brain = PtyBrain::spawn_with(brain).unwrap();
+        let socket_path = test_dir("protected").join("v5d.sock");
+        let daemon = daemon_for(&socket_path, &[brain.device()]);
+        let shutdown = daemon.shutdown.clone();
+        let serving = spawn(daemon.run());
+
+        let slot = Slot::try_from(2).unwrap();
+        let protect = DaemonCommand::SetSlotProtection {
+            slot,
+            protected: true,
+        };
+        match query(&socket_path, protect).await {
+            DaemonResponse::BasicAck { successful: true } => {}
+            response => panic!("unexpected response {response:?}"),
+        }
+        match query(&socket_path, DaemonCommand::ProtectedSlots).await {
+            DaemonResponse::ProtectedSlots(slots) => assert_eq!(slots, [slot]),
+            response => panic!("unexpected response {response:?}"),
+        }
+
+        let error = upload(&socket_path, 0, 2).await.unwrap_err();
+        assert!(error.slot_protected);
+        assert!(!brain.started_transfer_of("slot1.bin"));
+        upload(&socket_path, 0, 3).await.unwrap();
+
+        let mut stream = BufReader::new(UnixStream::connect(&socket_path).await.unwrap());
+        let forced = ProgramUpload {
+            overwrite_protected: true,
+            ..program(2, vec![0xAB; 1000])
+        };
+        send_command(&mut stream, DaemonCommand::UploadProgram(forced))
+            .await
+            .unwrap();
+        upload_result(&mut stream).await.unwrap();
+        assert!(brain.started_transfer_of("slot1.bin"));
+
+        // The same slot on another brain isn't protected
+        brain.brain().unique_id = 0x1234;
+        upload(&socket_path, 0, 2).await.unwrap();
+
+        shutdown.notify_one();
+        serving.await.unwrap();
+    }
+
+    // The boot mode is read from where the brain's status reports it
+    #[tokio::test(flavor = "multi_thread")]
+    async fn the_boot_state_is_read_from_the_brain() {
+        let mut brain = Brain::default();
+        // The ROM bootloader bit, among settings
+        brain.boot_flags = 0xF000 | 1 << 1;
+        let brain = PtyBrain::spawn_with(brain).unwrap();
+        let socket_path = test_dir("boot-state").join("v5d.sock");
+        let daemon = daemon_for(&socket_path, &[brain.device()]);
+        let shutdown = daemon.shutdown.clone();
+        let serving = spawn(daemon.run());
+
+        match query(&socket_path, DaemonCommand::BootState).await {
+            DaemonResponse::BootState(state) => assert_eq!(state, Some(BootState::RomBootloader)),
+            response => panic!("unexpected response {response:?}"),
+        }
+        brain.brain().boot_flags = 0;
+        match query(&socket_path, DaemonCommand::BootState).await {
+            DaemonResponse::BootState(state) => assert_eq!(state, Some(BootState::Normal)),
+            response => panic!("unexpected response {response:?}"),
+        }
+
+        shutdown.notify_one();
+        serving.await.unwrap();
     }
 }
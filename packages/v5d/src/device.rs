@@ -0,0 +1,120 @@
+use std::{collections::HashMap, fmt, time::Instant};
+
+use v5d_interface::Slot;
+use vex_v5_serial::connection::generic::GenericConnection;
+
+use crate::daemon::DaemonError;
+
+/// Identifies a device across reconnects.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DeviceId {
+    /// The path of the device's system serial port.
+    Serial(String),
+    /// The platform's identifier for the Bluetooth peripheral.
+    Bluetooth(String),
+}
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Serial(port) => write!(f, "serial:{}", port),
+            Self::Bluetooth(id) => write!(f, "bluetooth:{}", id),
+        }
+    }
+}
+
+/// Hands out routes so that a device gets the same route every time it connects.
+#[derive(Debug, Default)]
+pub struct RouteTable {
+    routes: HashMap<DeviceId, u8>,
+}
+impl RouteTable {
+    /// Returns the device's route, assigning the next unused one if it hasn't been seen before.
+    ///
+    /// Fails once every route has been handed out, since routes are a single byte.
+    pub fn route(&mut self, id: &DeviceId) -> Result<u8, DaemonError> {
+        if let Some(route) = self.routes.get(id) {
+            return Ok(*route);
+        }
+        let next = u8::try_from(self.routes.len())
+            .map_err(|_| DaemonError::OutOfRoutes(id.to_string()))?;
+        self.routes.insert(id.clone(), next);
+        Ok(next)
+    }
+
+    /// Gives `id` an existing route, for when a device is reached another way.
+    pub fn alias(&mut self, id: DeviceId, route: u8) {
+        self.routes.insert(id, route);
+    }
+}
+
+/// A program the daemon started on a device.
+#[derive(Debug, Clone, Copy)]
+pub struct LaunchedProgram {
+    pub slot: Slot,
+    pub started_at: Instant,
+}
+
+/// A device connection along with what the daemon knows about the device.
+pub struct Device {
+    pub connection: GenericConnection,
+    /// When the daemon connected to the device.
+    pub connected_at: Instant,
+    /// The last program the daemon ran on the device, if it might still be running.
+    pub launched_program: Option<LaunchedProgram>,
+    /// User program output read through the FIFO that didn't fit in the client's last read.
+    pub pending_user_output: Vec<u8>,
+}
+impl Device {
+    pub fn new(connection: GenericConnection) -> Self {
+        Self {
+            connection,
+            connected_at: Instant::now(),
+            launched_program: None,
+            pending_user_output: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn serial(port: &str) -> DeviceId {
+        DeviceId::Serial(port.to_owned())
+    }
+
+    #[test]
+    fn a_device_keeps_its_route() {
+        let mut table = RouteTable::default();
+        let first = table.route(&serial("/dev/ttyACM0")).unwrap();
+        table.route(&serial("/dev/ttyACM2")).unwrap();
+        assert_eq!(table.route(&serial("/dev/ttyACM0")).unwrap(), first);
+    }
+
+    #[test]
+    fn new_devices_get_new_routes() {
+        let mut table = RouteTable::default();
+        let first = table.route(&serial("/dev/ttyACM0")).unwrap();
+        table.alias(DeviceId::Bluetooth("brain".to_owned()), first);
+        let second = table.route(&serial("/dev/ttyACM2")).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(
+            table
+                .route(&DeviceId::Bluetooth("brain".to_owned()))
+                .unwrap(),
+            first
+        );
+    }
+
+    #[test]
+    fn routes_run_out_after_256_devices() {
+        let mut table = RouteTable::default();
+        for port in 0..256 {
+            table.route(&serial(&format!("/dev/ttyACM{port}"))).unwrap();
+        }
+        assert!(matches!(
+            table.route(&serial("/dev/ttyACM256")),
+            Err(DaemonError::OutOfRoutes(_))
+        ));
+    }
+}
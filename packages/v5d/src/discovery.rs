@@ -0,0 +1,318 @@
+//! Finding serial devices when the user has limited which ports the daemon may use.
+
+use std::{fmt, str::FromStr};
+
+use log::{debug, warn};
+use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
+use vex_v5_serial::connection::serial::{
+    self, SerialDevice, SerialError, V5_BRAIN_USB_PID, V5_CONTROLLER_USB_PID, VEX_USB_VID,
+};
+
+/// Identifies serial ports, by path or by the USB IDs of what they belong to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PortMatch {
+    Path(String),
+    UsbId { vid: u16, pid: u16 },
+}
+impl PortMatch {
+    fn matches(&self, port: &SerialPortInfo) -> bool {
+        match self {
+            Self::Path(path) => port.port_name == *path,
+            Self::UsbId { vid, pid } => matches!(
+                &port.port_type,
+                SerialPortType::UsbPort(info) if info.vid == *vid && info.pid == *pid
+            ),
+        }
+    }
+}
+impl FromStr for PortMatch {
+    type Err = String;
+
+    /// Parses a `VID:PID` pair in hex, like `2888:0501`, or else a port path.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let usb_id = s.split_once(':').and_then(|(vid, pid)| {
+            Some(Self::UsbId {
+                vid: u16::from_str_radix(vid, 16).ok()?,
+                pid: u16::from_str_radix(pid, 16).ok()?,
+            })
+        });
+        match usb_id {
+            Some(usb_id) => Ok(usb_id),
+            None if s.is_empty() => Err("a port can't be empty".to_string()),
+            None => Ok(Self::Path(s.to_string())),
+        }
+    }
+}
+impl fmt::Display for PortMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Path(path) => f.write_str(path),
+            Self::UsbId { vid, pid } => write!(f, "{vid:04x}:{pid:04x}"),
+        }
+    }
+}
+
+/// Which serial ports the daemon may open.
+///
+/// An excluded port is never opened, even if it is also included.
+#[derive(Debug, Clone, Default)]
+pub struct PortFilter {
+    /// If not empty, only these ports are used and nothing else is detected.
+    pub include: Vec<String>,
+    pub exclude: Vec<PortMatch>,
+}
+impl PortFilter {
+    fn allows(&self, port: &SerialPortInfo) -> bool {
+        if self.exclude.iter().any(|exclude| exclude.matches(port)) {
+            return false;
+        }
+        self.include.is_empty() || self.include.contains(&port.port_name)
+    }
+
+    /// Warns about ports the filter names that aren't plugged in.
+    ///
+    /// They aren't errors, since they may be plugged in later.
+    pub fn check(&self) {
+        if self.include.is_empty() && self.exclude.is_empty() {
+            return;
+        }
+        let ports = match serialport::available_ports() {
+            Ok(ports) => ports,
+            Err(err) => {
+                warn!("Couldn't list serial ports to check --port and --exclude-port: {err}");
+                return;
+            }
+        };
+        for path in &self.include {
+            if !ports.iter().any(|port| port.port_name == *path) {
+                warn!("Serial port {path} isn't plugged in. It will be used once it is");
+            }
+        }
+        for exclude in &self.exclude {
+            if !ports.iter().any(|port| exclude.matches(port)) {
+                warn!("No serial port matches excluded port {exclude}");
+            }
+        }
+    }
+}
+
+/// What a port is for, worked out from its USB interface like the serial library does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PortKind {
+    System,
+    User,
+    Controller,
+}
+
+fn port_kind(info: &UsbPortInfo) -> Option<PortKind> {
+    match info.pid {
+        V5_CONTROLLER_USB_PID => Some(PortKind::Controller),
+        V5_BRAIN_USB_PID => {
+            let interface = info.interface?;
+            #[cfg(target_os = "macos")]
+            let interface = interface.checked_sub(1)?;
+            match interface {
+                0 => Some(PortKind::System),
+                2 => Some(PortKind::User),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Finds the VEX devices on the ports `filter` allows, without opening any port.
+///
+/// With an empty filter this is the serial library's own search. Otherwise the ports are
+/// filtered first, so an excluded port that would confuse the library's search is never
+/// looked at. A brain's system and user ports are paired by USB serial number.
+pub fn find_devices(filter: &PortFilter) -> Result<Vec<SerialDevice>, SerialError> {
+    if filter.include.is_empty() && filter.exclude.is_empty() {
+        return serial::find_devices();
+    }
+
+    let ports = serialport::available_ports().map_err(SerialError::SerialportError)?;
+    Ok(devices_on(ports, filter))
+}
+
+/// Finds the VEX devices among `ports` that `filter` allows.
+fn devices_on(ports: Vec<SerialPortInfo>, filter: &PortFilter) -> Vec<SerialDevice> {
+    let mut system_ports = Vec::new();
+    let mut user_ports = Vec::new();
+    let mut devices = Vec::new();
+    for port in ports.into_iter().filter(|port| filter.allows(port)) {
+        let SerialPortType::UsbPort(info) = &port.port_type else {
+            // Included ports are the user's call, even if they don't look like VEX devices
+            if !filter.include.is_empty() {
+                devices.push(SerialDevice::Unknown {
+                    system_port: port.port_name,
+                });
+            }
+            continue;
+        };
+        if info.vid != VEX_USB_VID && filter.include.is_empty() {
+            continue;
+        }
+        match port_kind(info) {
+            Some(PortKind::System) => {
+                system_ports.push((port.port_name, info.serial_number.clone()))
+            }
+            Some(PortKind::User) => user_ports.push((port.port_name, info.serial_number.clone())),
+            Some(PortKind::Controller) => devices.push(SerialDevice::Controller {
+                system_port: port.port_name,
+            }),
+            None if !filter.include.is_empty() => devices.push(SerialDevice::Unknown {
+                system_port: port.port_name,
+            }),
+            None => debug!("Skipping serial port {} of unknown type", port.port_name),
+        }
+    }
+
+    for (system_port, serial_number) in system_ports {
+        let user_port = user_ports
+            .iter()
+            .position(|(_, user_serial)| *user_serial == serial_number)
+            .map(|index| user_ports.remove(index).0);
+        devices.push(match user_port {
+            Some(user_port) => SerialDevice::Brain {
+                system_port,
+                user_port,
+            },
+            None => SerialDevice::Unknown { system_port },
+        });
+    }
+    // A user port on its own can't be talked to
+    for (user_port, _) in user_ports {
+        debug!("Skipping user port {} with no system port", user_port);
+    }
+
+    devices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A USB port on interface `interface`, numbered as Linux and Windows do.
+    fn usb_port(name: &str, pid: u16, serial_number: &str, interface: u8) -> SerialPortInfo {
+        #[cfg(target_os = "macos")]
+        let interface = interface + 1;
+        SerialPortInfo {
+            port_name: name.to_string(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid: VEX_USB_VID,
+                pid,
+                serial_number: Some(serial_number.to_string()),
+                manufacturer: None,
+                product: None,
+                interface: Some(interface),
+            }),
+        }
+    }
+
+    /// Two brains, and a dongle that isn't a VEX device.
+    fn ports() -> Vec<SerialPortInfo> {
+        vec![
+            usb_port("/dev/ttyACM0", V5_BRAIN_USB_PID, "A", 0),
+            usb_port("/dev/ttyACM1", V5_BRAIN_USB_PID, "A", 2),
+            usb_port("/dev/ttyACM2", V5_BRAIN_USB_PID, "B", 0),
+            usb_port("/dev/ttyACM3", V5_BRAIN_USB_PID, "B", 2),
+            SerialPortInfo {
+                port_name: "/dev/ttyUSB0".to_string(),
+                port_type: SerialPortType::Unknown,
+            },
+        ]
+    }
+
+    /// The devices `filter` finds among [`ports`], as `kind system_port [user_port]`.
+    fn found(filter: PortFilter) -> Vec<String> {
+        devices_on(ports(), &filter)
+            .into_iter()
+            .map(|device| match device {
+                SerialDevice::Brain {
+                    system_port,
+                    user_port,
+                } => format!("brain {system_port} {user_port}"),
+                SerialDevice::Controller { system_port } => format!("controller {system_port}"),
+                SerialDevice::Unknown { system_port } => format!("unknown {system_port}"),
+            })
+            .collect()
+    }
+
+    fn paths(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn ports_are_matched_by_path_or_usb_id() {
+        assert_eq!(
+            "2888:0501".parse(),
+            Ok(PortMatch::UsbId {
+                vid: 0x2888,
+                pid: 0x0501
+            })
+        );
+        assert_eq!(
+            "/dev/ttyACM0".parse(),
+            Ok(PortMatch::Path("/dev/ttyACM0".to_string()))
+        );
+        assert!("".parse::<PortMatch>().is_err());
+    }
+
+    #[test]
+    fn brain_ports_are_paired_by_serial_number() {
+        let filter = PortFilter {
+            include: vec![],
+            exclude: vec![PortMatch::Path("/dev/ttyUSB0".to_string())],
+        };
+        assert_eq!(
+            found(filter),
+            [
+                "brain /dev/ttyACM0 /dev/ttyACM1",
+                "brain /dev/ttyACM2 /dev/ttyACM3"
+            ]
+        );
+    }
+
+    #[test]
+    fn excluding_wins_over_including() {
+        let filter = PortFilter {
+            include: paths(&["/dev/ttyACM0", "/dev/ttyACM1"]),
+            exclude: vec![PortMatch::Path("/dev/ttyACM0".to_string())],
+        };
+        // Without its system port, the brain's user port can't be used
+        assert!(found(filter).is_empty());
+
+        let filter = PortFilter {
+            include: paths(&["/dev/ttyACM0", "/dev/ttyACM1"]),
+            exclude: vec![PortMatch::Path("/dev/ttyACM1".to_string())],
+        };
+        // Without its user port, the brain can't be recognized
+        assert_eq!(found(filter), ["unknown /dev/ttyACM0"]);
+    }
+
+    #[test]
+    fn including_ports_limits_discovery_to_them() {
+        let filter = PortFilter {
+            include: paths(&["/dev/ttyACM2", "/dev/ttyACM3", "/dev/ttyUSB0"]),
+            exclude: vec![],
+        };
+        // An included port is used even if it doesn't look like a VEX device
+        assert_eq!(
+            found(filter),
+            ["unknown /dev/ttyUSB0", "brain /dev/ttyACM2 /dev/ttyACM3"]
+        );
+    }
+
+    #[test]
+    fn excluding_a_usb_id_skips_every_port_with_it() {
+        let filter = PortFilter {
+            include: vec![],
+            exclude: vec![PortMatch::UsbId {
+                vid: VEX_USB_VID,
+                pid: V5_BRAIN_USB_PID,
+            }],
+        };
+        assert!(found(filter).is_empty());
+    }
+}
@@ -0,0 +1,237 @@
+//! Tests against a real brain, built with the `hardware-tests` feature.
+//!
+//! They are all `#[ignore]`d, so they only run when asked for:
+//!
+//! ```text
+//! V5_SACRIFICIAL_SLOT=8 cargo test -p v5d --features hardware-tests -- --ignored --test-threads=1
+//! ```
+//!
+//! Before running them:
+//!
+//! - Only connect a brain you're allowed to modify. The upload test replaces the program in
+//!   `V5_SACRIFICIAL_SLOT` (8 if unset) and restores it afterwards, but a test that is killed
+//!   part way leaves the test program there. Only single-file (monolith) programs are restored
+//!   exactly; if the slot holds a hot/cold program, the test refuses to run.
+//! - Stop any running program, since the brain refuses uploads while one runs.
+//! - Use `--test-threads=1`. Each test starts its own daemon, and only one can hold the port.
+//! - Set `V5_HARDWARE_PORT` to the brain's serial port to skip discovery, for example when
+//!   other VEX devices are plugged in. Without it, the first brain found is used.
+//!
+//! Each daemon listens on its own socket in the temp directory, never on the one a running v5d
+//! uses, so these tests can't be confused with a real daemon. Screenshots, the terminal echo
+//! and contention for the brain with other tools aren't covered.
+
+use std::{
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    io::BufReader,
+    join,
+    net::UnixStream,
+    spawn,
+    sync::{broadcast, Notify},
+    task::JoinHandle,
+    time::timeout,
+};
+use v5d_interface::{
+    get_response, send_command, AfterFileUpload, DaemonCommand, DaemonResponse, ProgramData,
+    ProgramUpload, Slot,
+};
+
+use crate::{connection::ConnectOptions, daemon::Daemon, discovery::PortFilter, ConnectionType};
+
+/// A daemon connected to the brain, on a socket of its own.
+struct Fixture {
+    socket_path: PathBuf,
+    shutdown: Arc<Notify>,
+    serving: JoinHandle<()>,
+}
+
+impl Fixture {
+    async fn start() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .subsec_nanos();
+        let socket_path =
+            std::env::temp_dir().join(format!("v5d-hardware-{}-{nanos}.sock", std::process::id()));
+        let connect_options = ConnectOptions {
+            max_connect_attempts: NonZeroU32::new(3),
+            bluetooth_connect_attempts: NonZeroU32::MIN,
+            bluetooth: Default::default(),
+            ports: PortFilter {
+                include: std::env::var("V5_HARDWARE_PORT").into_iter().collect(),
+                exclude: vec![],
+            },
+        };
+        let shutdown = Arc::new(Notify::new());
+        let daemon = Daemon::new(
+            socket_path.clone(),
+            ConnectionType::Serial,
+            connect_options,
+            broadcast::channel(16).0,
+            shutdown.clone(),
+        )
+        .await
+        .expect("no brain found; is one plugged in?");
+        Self {
+            socket_path,
+            shutdown,
+            serving: spawn(daemon.run()),
+        }
+    }
+
+    async fn query(&self, command: DaemonCommand) -> DaemonResponse {
+        query(&self.socket_path, command).await
+    }
+
+    async fn stop(self) {
+        self.shutdown.notify_one();
+        self.serving.await.unwrap();
+        assert!(!self.socket_path.exists());
+    }
+}
+
+async fn query(socket_path: &Path, command: DaemonCommand) -> DaemonResponse {
+    let mut stream = BufReader::new(UnixStream::connect(socket_path).await.unwrap());
+    send_command(&mut stream, command).await.unwrap();
+    timeout(Duration::from_secs(60), get_response(&mut stream))
+        .await
+        .expect("the daemon took too long to answer")
+        .unwrap()
+}
+
+fn sacrificial_slot() -> Slot {
+    let slot = std::env::var("V5_SACRIFICIAL_SLOT").map_or(8, |slot| {
+        slot.parse()
+            .expect("V5_SACRIFICIAL_SLOT must be a slot number")
+    });
+    Slot::try_from(slot).unwrap()
+}
+
+async fn read_program(fixture: &Fixture, slot: Slot) -> Option<ProgramUpload> {
+    match fixture.query(DaemonCommand::ReadProgram { slot }).await {
+        DaemonResponse::Program(result) => result.unwrap(),
+        response => panic!("unexpected response {response:?}"),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs a brain"]
+async fn finds_the_brain_and_shuts_down() {
+    let fixture = Fixture::start().await;
+    match fixture.query(DaemonCommand::ListDevices).await {
+        DaemonResponse::Devices(devices) => assert_eq!(devices.len(), 1),
+        response => panic!("unexpected response {response:?}"),
+    }
+    match fixture.query(DaemonCommand::PingBrain).await {
+        DaemonResponse::BrainPing(latency) => assert!(latency.is_some()),
+        response => panic!("unexpected response {response:?}"),
+    }
+    fixture.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs a brain"]
+async fn two_clients_share_the_brain() {
+    let fixture = Fixture::start().await;
+    let (first, second) = join!(
+        fixture.query(DaemonCommand::PingBrain),
+        fixture.query(DaemonCommand::PingBrain)
+    );
+    for response in [first, second] {
+        assert!(matches!(response, DaemonResponse::BrainPing(Some(_))));
+    }
+    fixture.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs a brain, and replaces the program in V5_SACRIFICIAL_SLOT"]
+async fn uploads_and_reads_back_a_program() {
+    let fixture = Fixture::start().await;
+    let slot = sacrificial_slot();
+    let backup = read_program(&fixture, slot).await;
+    if let Some(backup) = &backup {
+        assert!(
+            matches!(backup.data, ProgramData::Monolith(_)),
+            "slot {slot} holds a hot/cold program, which can't be restored exactly"
+        );
+    }
+
+    let data: Vec<u8> = (0..40_000u32).map(|i| (i % 251) as u8).collect();
+    let upload = ProgramUpload {
+        name: "v5d hardware test".to_owned(),
+        description: "Safe to delete".to_owned(),
+        icon: "USER029x.bmp".to_owned(),
+        program_type: "test".to_owned(),
+        slot,
+        compression: true,
+        after_upload: AfterFileUpload::DoNothing,
+        data: ProgramData::Monolith(data.clone()),
+        replace: true,
+        overwrite_protected: false,
+    };
+    let uploaded = upload_program(&fixture, upload).await;
+    let read_back = read_program(&fixture, slot).await;
+    let names = match fixture.query(DaemonCommand::ProgramNames).await {
+        DaemonResponse::ProgramNames(result) => result,
+        response => panic!("unexpected response {response:?}"),
+    };
+    let exit_action = fixture
+        .query(DaemonCommand::ApplyExitAction {
+            slot,
+            action: AfterFileUpload::DoNothing,
+        })
+        .await;
+
+    // Restore the slot before checking anything, so a failure doesn't leave the test program
+    let restored = match backup {
+        Some(mut backup) => {
+            backup.replace = true;
+            backup.after_upload = AfterFileUpload::DoNothing;
+            upload_program(&fixture, backup).await
+        }
+        None => match fixture.query(DaemonCommand::EraseSlot { slot }).await {
+            DaemonResponse::TransferComplete(result) => result.map_err(|error| error.to_string()),
+            response => panic!("unexpected response {response:?}"),
+        },
+    };
+    restored.expect("couldn't restore the sacrificial slot");
+
+    uploaded.unwrap();
+    let read_back = read_back.expect("the uploaded program wasn't found");
+    assert_eq!(read_back.name, "v5d hardware test");
+    assert!(matches!(read_back.data, ProgramData::Monolith(read) if read == data));
+    assert!(names
+        .unwrap()
+        .contains(&(slot, "v5d hardware test".to_owned())));
+    assert!(matches!(
+        exit_action,
+        DaemonResponse::TransferComplete(Ok(()))
+    ));
+    fixture.stop().await;
+}
+
+async fn upload_program(fixture: &Fixture, upload: ProgramUpload) -> Result<(), String> {
+    let mut stream = BufReader::new(UnixStream::connect(&fixture.socket_path).await.unwrap());
+    send_command(&mut stream, DaemonCommand::UploadProgram(upload))
+        .await
+        .unwrap();
+    loop {
+        let response = timeout(Duration::from_secs(120), get_response(&mut stream))
+            .await
+            .expect("the upload stalled")
+            .unwrap();
+        match response {
+            DaemonResponse::TransferProgress(_) => {}
+            DaemonResponse::TransferComplete(result) => {
+                return result.map_err(|error| error.to_string())
+            }
+            response => panic!("unexpected response {response:?}"),
+        }
+    }
+}
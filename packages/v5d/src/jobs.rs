@@ -0,0 +1,103 @@
+//! Transfers that keep running after the client that started them disconnects.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use tokio::sync::{broadcast, Mutex, Notify};
+use v5d_interface::{DaemonResponse, JobId, JobStatus, SectionProgress, TransferError};
+
+/// How many events an attached client may fall behind by before it skips ahead.
+const UPDATE_BUFFER: usize = 256;
+
+#[derive(Debug, Default)]
+struct JobState {
+    progress: Vec<SectionProgress>,
+    result: Option<Result<(), TransferError>>,
+}
+
+/// A background transfer and everything that has happened in it so far.
+#[derive(Debug)]
+pub struct Job {
+    route: u8,
+    /// Notified to stop the transfer.
+    pub cancel: Arc<Notify>,
+    state: Mutex<JobState>,
+    updates: broadcast::Sender<DaemonResponse>,
+}
+impl Job {
+    pub fn new(route: u8) -> Self {
+        Self {
+            route,
+            cancel: Arc::new(Notify::new()),
+            state: Mutex::default(),
+            updates: broadcast::channel(UPDATE_BUFFER).0,
+        }
+    }
+
+    /// Records a transfer event and passes it on to any attached clients.
+    pub async fn record(&self, event: DaemonResponse) {
+        let mut state = self.state.lock().await;
+        match &event {
+            DaemonResponse::TransferProgress(progress) => {
+                match state.progress.iter_mut().find(|p| p.step == progress.step) {
+                    Some(latest) => *latest = *progress,
+                    None => state.progress.push(*progress),
+                }
+            }
+            DaemonResponse::TransferComplete(result) => state.result = Some(result.clone()),
+            _ => {}
+        }
+        // Nobody being attached isn't an error
+        let _ = self.updates.send(event);
+    }
+
+    /// Returns the events that bring a client up to date with the job,
+    /// and a receiver for the rest of them if the job hasn't finished.
+    pub async fn attach(
+        &self,
+    ) -> (
+        Vec<DaemonResponse>,
+        Option<broadcast::Receiver<DaemonResponse>>,
+    ) {
+        let state = self.state.lock().await;
+        let mut catch_up: Vec<_> = state
+            .progress
+            .iter()
+            .map(|&progress| DaemonResponse::TransferProgress(progress))
+            .collect();
+        match &state.result {
+            Some(result) => {
+                catch_up.push(DaemonResponse::TransferComplete(result.clone()));
+                (catch_up, None)
+            }
+            // Subscribing while holding the state lock means no event is missed or repeated
+            None => (catch_up, Some(self.updates.subscribe())),
+        }
+    }
+
+    pub async fn status(&self) -> JobStatus {
+        let state = self.state.lock().await;
+        JobStatus {
+            route: self.route,
+            progress: state.progress.clone(),
+            result: state.result.clone(),
+        }
+    }
+}
+
+/// Every background job started since the daemon launched.
+#[derive(Debug, Default)]
+pub struct Jobs {
+    jobs: Mutex<BTreeMap<JobId, Arc<Job>>>,
+}
+impl Jobs {
+    pub async fn add(&self, job: Arc<Job>) -> JobId {
+        let mut jobs = self.jobs.lock().await;
+        let id = jobs.keys().next_back().map_or(1, |id| id + 1);
+        jobs.insert(id, job);
+        id
+    }
+
+    pub async fn get(&self, id: JobId) -> Option<Arc<Job>> {
+        self.jobs.lock().await.get(&id).cloned()
+    }
+}
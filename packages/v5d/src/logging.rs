@@ -0,0 +1,61 @@
+use log::{LevelFilter, Log, Metadata, Record};
+use simplelog::{Config, SharedLogger};
+use tokio::sync::broadcast;
+use v5d_interface::LogRecord;
+
+/// The most detailed level the daemon logs at, on the console and to subscribers.
+pub const LOG_LEVEL: LevelFilter = LevelFilter::Debug;
+
+/// How many records a slow subscriber can fall behind before it starts missing them.
+const LOG_BUFFER: usize = 1024;
+
+/// Publishes the daemon's log records to clients subscribed with `StreamLogs`.
+///
+/// Sending never blocks or logs, so logging about a subscriber's own connection
+/// can't deadlock or feed back into itself.
+pub struct StreamLogger {
+    records: broadcast::Sender<LogRecord>,
+}
+impl StreamLogger {
+    pub fn new() -> Self {
+        Self {
+            records: broadcast::channel(LOG_BUFFER).0,
+        }
+    }
+
+    /// A handle for subscribing to records once the logger has been installed.
+    pub fn sender(&self) -> broadcast::Sender<LogRecord> {
+        self.records.clone()
+    }
+}
+impl Log for StreamLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= LOG_LEVEL && self.records.receiver_count() > 0
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            // Fails only when nobody is subscribed
+            let _ = self.records.send(LogRecord {
+                level: record.level(),
+                target: record.target().to_string(),
+                message: record.args().to_string(),
+            });
+        }
+    }
+
+    fn flush(&self) {}
+}
+impl SharedLogger for StreamLogger {
+    fn level(&self) -> LevelFilter {
+        LOG_LEVEL
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        self
+    }
+}
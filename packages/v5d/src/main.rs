@@ -1,12 +1,27 @@
+mod commands;
 mod connection;
 mod daemon;
+mod device;
+mod discovery;
+#[cfg(all(test, feature = "hardware-tests"))]
+mod hardware_tests;
+mod jobs;
+mod logging;
+mod protection;
+mod recent_errors;
+mod self_test;
+#[cfg(test)]
+mod testing;
 
-use std::io;
+use std::{io, num::NonZeroU32, path::Path, sync::Arc};
 
 use clap::Parser;
+use connection::{BluetoothSelection, ConnectOptions};
 use daemon::Daemon;
+use discovery::{PortFilter, PortMatch};
 use log::info;
-use tokio::net::UnixListener;
+use logging::StreamLogger;
+use tokio::{net::UnixListener, select, sync::Notify};
 use v5d_interface::socket_path;
 
 #[derive(Debug, Clone, Copy, clap::ValueEnum)]
@@ -18,46 +33,127 @@ enum ConnectionType {
 
 #[derive(clap::Parser, Debug)]
 struct Args {
-    #[arg(long, short)]
-    connection_type: ConnectionType,
-}
+    #[arg(long, short, required_unless_present = "self_test")]
+    connection_type: Option<ConnectionType>,
+
+    /// Give up and exit after this many failed searches for devices.
+    /// Each serial search or 10 second Bluetooth scan counts as one attempt.
+    /// With `auto`, the daemon only exits once both have used up their attempts.
+    /// Retries forever if omitted.
+    #[arg(long)]
+    max_connect_attempts: Option<NonZeroU32>,
+
+    /// How many times to try connecting to a Bluetooth brain before giving up
+    #[arg(long, default_value = "3")]
+    bluetooth_connect_attempts: NonZeroU32,
+
+    /// Connect to the Bluetooth brain with this id, as `v5ctl devices` shows it. Can be repeated.
+    /// Without this or --all-bluetooth, only the first brain found is connected to
+    #[arg(long = "bluetooth-device", value_name = "ID")]
+    bluetooth_devices: Vec<String>,
 
-/// Creates a UNIX socket to communicate with the V5 Daemon
-pub fn setup_socket() -> io::Result<UnixListener> {
-    let path = socket_path();
+    /// Connect to every Bluetooth brain in range. At an event this includes other teams' brains
+    #[arg(long, conflicts_with = "bluetooth_devices")]
+    all_bluetooth: bool,
 
-    let socket = UnixListener::bind(&path)?;
+    /// Only use this serial port, instead of searching for devices. Can be repeated
+    #[arg(long = "port", value_name = "PATH")]
+    ports: Vec<String>,
+
+    /// Never open this serial port, given as a path or a USB VID:PID in hex like 2888:0501.
+    /// Can be repeated
+    #[arg(long = "exclude-port", value_name = "PORT")]
+    exclude_ports: Vec<PortMatch>,
+
+    /// Check that the daemon could start, print a report and exit.
+    /// Exits with an error if any check fails
+    #[arg(long)]
+    self_test: bool,
+
+    /// Print the self-test report as JSON
+    #[arg(long, requires = "self_test")]
+    json: bool,
+}
+
+/// Creates a UNIX socket at `path` to communicate with the V5 Daemon
+pub fn setup_socket(path: &Path) -> io::Result<UnixListener> {
+    let socket = UnixListener::bind(path)?;
 
     info!("UNIX socket created and bound to {:?}", path);
     info!("Listening for incoming connections...");
     Ok(socket)
 }
 
-pub fn shutdown() -> ! {
-    info!("Shutting down...");
-    // Clean up the socket file
-    let _ = std::fs::remove_file(socket_path());
-    info!("Shutdown complete!");
-    std::process::exit(0);
-}
-
-fn on_shutdown() {
-    shutdown();
+/// Removes the UNIX socket file at `path` so that no new clients can connect.
+pub fn remove_socket(path: &Path) {
+    let _ = std::fs::remove_file(path);
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
-    simplelog::TermLogger::init(
-        log::LevelFilter::Debug,
-        Default::default(),
-        simplelog::TerminalMode::Mixed,
-        simplelog::ColorChoice::Auto,
-    )?;
-    ctrlc::set_handler(on_shutdown)?;
+    // The report is the output, so don't mix logs into it.
+    if args.self_test {
+        if !self_test::self_test(args.connection_type, args.json).await {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    let connection_type = args
+        .connection_type
+        .expect("clap requires a connection type outside of self-test");
+    let connect_options = ConnectOptions {
+        max_connect_attempts: args.max_connect_attempts,
+        bluetooth_connect_attempts: args.bluetooth_connect_attempts,
+        bluetooth: if args.all_bluetooth {
+            BluetoothSelection::All
+        } else if !args.bluetooth_devices.is_empty() {
+            BluetoothSelection::Ids(args.bluetooth_devices)
+        } else {
+            BluetoothSelection::First
+        },
+        ports: PortFilter {
+            include: args.ports,
+            exclude: args.exclude_ports,
+        },
+    };
+
+    let stream_logger = StreamLogger::new();
+    let logs = stream_logger.sender();
+    simplelog::CombinedLogger::init(vec![
+        simplelog::TermLogger::new(
+            logging::LOG_LEVEL,
+            Default::default(),
+            simplelog::TerminalMode::Mixed,
+            simplelog::ColorChoice::Auto,
+        ),
+        Box::new(stream_logger),
+        Box::new(recent_errors::ErrorLogger),
+    ])?;
+    connect_options.ports.check();
+
+    let shutdown = Arc::new(Notify::new());
+    ctrlc::set_handler({
+        let shutdown = shutdown.clone();
+        move || shutdown.notify_one()
+    })?;
 
-    let daemon = Daemon::new(args.connection_type).await?;
+    let connecting = Daemon::new(
+        socket_path(),
+        connection_type,
+        connect_options,
+        logs,
+        shutdown.clone(),
+    );
+    let daemon = select! {
+        daemon = connecting => daemon?,
+        _ = shutdown.notified() => {
+            info!("Shut down before connecting to a device");
+            remove_socket(&socket_path());
+            return Ok(());
+        }
+    };
     daemon.run().await;
 
     Ok(())
@@ -0,0 +1,152 @@
+//! Slots the user has protected from uploads, kept per brain across restarts.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::PathBuf,
+};
+
+use log::{info, warn};
+use v5d_interface::Slot;
+
+/// Where protection is saved, or `None` if the platform has no data directory.
+fn state_path() -> Option<PathBuf> {
+    dirs_next::data_dir().map(|dir| dir.join("v5d").join("protected-slots.json"))
+}
+
+/// The protected slots of every brain, by the brain's unique ID.
+#[derive(Debug, Default)]
+pub struct Protection {
+    slots: BTreeMap<u32, BTreeSet<u8>>,
+    /// Where changes are saved.
+    path: Option<PathBuf>,
+}
+impl Protection {
+    /// Loads the saved protection. A missing file means nothing is protected.
+    pub fn load() -> Self {
+        Self::load_from(state_path())
+    }
+
+    /// Loads the protection saved at `path`, saving changes there too.
+    pub fn load_from(path: Option<PathBuf>) -> Self {
+        let Some(path) = path else {
+            return Self::default();
+        };
+        let slots = match fs::read(&path) {
+            Ok(json) => match serde_json::from_slice(&json) {
+                Ok(slots) => {
+                    info!("Loaded protected slots from {}", path.display());
+                    slots
+                }
+                Err(err) => {
+                    warn!(
+                        "Ignoring malformed protected slots in {}: {}",
+                        path.display(),
+                        err
+                    );
+                    BTreeMap::new()
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => {
+                warn!(
+                    "Couldn't read protected slots from {}: {}",
+                    path.display(),
+                    err
+                );
+                BTreeMap::new()
+            }
+        };
+        Self {
+            slots,
+            path: Some(path),
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let path = self
+            .path
+            .as_ref()
+            .ok_or_else(|| io::Error::other("There is no data directory to save protection in"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Written whole and renamed, so a crash never leaves half a file
+        let temp = path.with_extension("json.tmp");
+        fs::write(&temp, serde_json::to_vec_pretty(&self.slots)?)?;
+        fs::rename(&temp, path)
+    }
+
+    /// Returns whether any brain has a protected slot.
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    pub fn is_protected(&self, brain: u32, slot: Slot) -> bool {
+        self.slots
+            .get(&brain)
+            .is_some_and(|slots| slots.contains(&slot.get()))
+    }
+
+    pub fn slots(&self, brain: u32) -> Vec<Slot> {
+        self.slots
+            .get(&brain)
+            .into_iter()
+            .flatten()
+            .filter_map(|&slot| Slot::try_from(slot).ok())
+            .collect()
+    }
+
+    /// Protects or unprotects a slot, and saves the change.
+    pub fn set(&mut self, brain: u32, slot: Slot, protected: bool) -> io::Result<()> {
+        let slots = self.slots.entry(brain).or_default();
+        if protected {
+            slots.insert(slot.get());
+        } else {
+            slots.remove(&slot.get());
+            if slots.is_empty() {
+                self.slots.remove(&brain);
+            }
+        }
+        self.save()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::test_dir;
+
+    fn slot(slot: u8) -> Slot {
+        Slot::try_from(slot).unwrap()
+    }
+
+    #[test]
+    fn protection_is_kept_per_brain_across_restarts() {
+        let path = test_dir("protection").join("protected-slots.json");
+        let mut protection = Protection::load_from(Some(path.clone()));
+        assert!(protection.is_empty());
+        protection.set(0x1234, slot(2), true).unwrap();
+        protection.set(0x1234, slot(5), true).unwrap();
+        protection.set(0x5678, slot(3), true).unwrap();
+
+        let mut protection = Protection::load_from(Some(path.clone()));
+        assert!(protection.is_protected(0x1234, slot(2)));
+        assert!(!protection.is_protected(0x5678, slot(2)));
+        assert_eq!(protection.slots(0x1234), [slot(2), slot(5)]);
+        assert_eq!(protection.slots(0x5678), [slot(3)]);
+        assert!(protection.slots(0x9999).is_empty());
+
+        protection.set(0x5678, slot(3), false).unwrap();
+        protection.set(0x1234, slot(2), false).unwrap();
+        protection.set(0x1234, slot(5), false).unwrap();
+        assert!(Protection::load_from(Some(path)).is_empty());
+    }
+
+    #[test]
+    fn a_malformed_file_protects_nothing() {
+        let path = test_dir("malformed-protection").join("protected-slots.json");
+        fs::write(&path, "{\"not\": \"slots\"}").unwrap();
+        assert!(Protection::load_from(Some(path)).is_empty());
+    }
+}
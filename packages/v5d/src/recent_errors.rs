@@ -0,0 +1,124 @@
+//! The daemon's most recent errors, kept in memory for clients to ask about.
+//!
+//! Users asking for help often can't find the daemon's console output, or it has already
+//! scrolled away. `v5ctl errors` reads this history instead.
+
+use std::{collections::VecDeque, sync::Mutex, time::SystemTime};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use simplelog::{Config, SharedLogger};
+use v5d_interface::ErrorRecord;
+
+/// How many errors are kept. Older ones are forgotten first.
+const CAPACITY: usize = 100;
+
+static RECENT_ERRORS: Mutex<VecDeque<ErrorRecord>> = Mutex::new(VecDeque::new());
+
+/// Returns up to `limit` of the most recent errors, oldest first.
+pub fn recent(limit: Option<usize>) -> Vec<ErrorRecord> {
+    let errors = RECENT_ERRORS.lock().unwrap_or_else(|err| err.into_inner());
+    let skip = limit.map_or(0, |limit| errors.len().saturating_sub(limit));
+    errors.iter().skip(skip).cloned().collect()
+}
+
+/// Masks the digits of a message that mentions a pin or token, so pairing pins and the
+/// like never end up in the history.
+fn scrub(message: String) -> String {
+    let lowercase = message.to_lowercase();
+    if !lowercase.contains("pin") && !lowercase.contains("token") {
+        return message;
+    }
+    message
+        .chars()
+        .map(|c| if c.is_ascii_digit() { '*' } else { c })
+        .collect()
+}
+
+/// Records error-level log records in the history.
+pub struct ErrorLogger;
+impl Log for ErrorLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() == Level::Error
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let error = ErrorRecord {
+            time: SystemTime::now(),
+            target: record.target().to_string(),
+            message: scrub(record.args().to_string()),
+        };
+        // A panic while holding the lock leaves the history usable
+        let mut errors = RECENT_ERRORS.lock().unwrap_or_else(|err| err.into_inner());
+        if errors.len() == CAPACITY {
+            errors.pop_front();
+        }
+        errors.push_back(error);
+    }
+
+    fn flush(&self) {}
+}
+impl SharedLogger for ErrorLogger {
+    fn level(&self) -> LevelFilter {
+        LevelFilter::Error
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pins_and_tokens_are_scrubbed() {
+        assert_eq!(
+            scrub("Pairing PIN 1234 was wrong".into()),
+            "Pairing PIN **** was wrong"
+        );
+        assert_eq!(scrub("bad token abc42".into()), "bad token abc**");
+        // Only messages about secrets lose their digits
+        assert_eq!(
+            scrub("Failed to open /dev/ttyACM1".into()),
+            "Failed to open /dev/ttyACM1"
+        );
+    }
+
+    // The history keeps only the newest errors, and never sees records below error level
+    #[test]
+    fn the_history_is_bounded_and_scrubbed() {
+        for i in 0..CAPACITY + 5 {
+            ErrorLogger.log(
+                &Record::builder()
+                    .level(Level::Error)
+                    .target("test")
+                    .args(format_args!("error {i} with pin 5678"))
+                    .build(),
+            );
+        }
+        ErrorLogger.log(
+            &Record::builder()
+                .level(Level::Warn)
+                .args(format_args!("just a warning"))
+                .build(),
+        );
+
+        let all = recent(None);
+        assert_eq!(all.len(), CAPACITY);
+        assert!(all.iter().all(|error| !error.message.contains("5678")));
+        let last = recent(Some(2));
+        assert_eq!(last.len(), 2);
+        assert_eq!(
+            last[1].message,
+            format!("error {} with pin ****", "*".repeat(3))
+        );
+    }
+}
@@ -0,0 +1,169 @@
+//! `v5d --self-test`: checks that the daemon could start, without leaving it running.
+
+use std::fmt::Display;
+
+use serde_json::json;
+use tokio::{
+    io::BufReader,
+    net::{UnixListener, UnixStream},
+    spawn,
+};
+use v5d_interface::{get_response, send_command, socket_path, DaemonCommand, DaemonResponse};
+use vex_v5_serial::connection::serial::{self, SerialDevice};
+
+use crate::{
+    connection::bluetooth_adapter_count,
+    daemon::{read_request, write_response, DaemonError},
+    remove_socket, setup_socket, ConnectionType,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Pass,
+    /// Something is missing that the daemon can run without.
+    Warn,
+    Fail,
+}
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Outcome::Pass => "pass",
+            Outcome::Warn => "warn",
+            Outcome::Fail => "FAIL",
+        })
+    }
+}
+
+struct Check {
+    name: &'static str,
+    outcome: Outcome,
+    detail: String,
+}
+
+/// Binds the daemon's socket and removes it again.
+fn check_socket() -> (Outcome, String) {
+    let path = socket_path();
+    match setup_socket(&path) {
+        Ok(_) => {
+            remove_socket(&path);
+            (Outcome::Pass, "created, bound and removed".to_owned())
+        }
+        Err(err) => (
+            Outcome::Fail,
+            format!("{err} (is another daemon already running?)"),
+        ),
+    }
+}
+
+/// Lists serial devices without opening them.
+fn check_serial() -> (Outcome, String) {
+    match serial::find_devices() {
+        Ok(devices) if devices.is_empty() => (Outcome::Pass, "no devices plugged in".to_owned()),
+        Ok(devices) => {
+            let devices = devices
+                .iter()
+                .map(|device| match device {
+                    SerialDevice::Brain { system_port, .. } => format!("brain on {system_port}"),
+                    SerialDevice::Controller { system_port } => {
+                        format!("controller on {system_port}")
+                    }
+                    SerialDevice::Unknown { system_port } => {
+                        format!("unknown device on {system_port}")
+                    }
+                })
+                .collect::<Vec<_>>();
+            (Outcome::Pass, devices.join(", "))
+        }
+        Err(err) => (Outcome::Fail, err.to_string()),
+    }
+}
+
+/// Looks for a Bluetooth adapter. Its absence only matters when Bluetooth was asked for.
+async fn check_bluetooth(connection_type: Option<ConnectionType>) -> (Outcome, String) {
+    let missing = match connection_type {
+        Some(ConnectionType::Bluetooth) => Outcome::Fail,
+        _ => Outcome::Warn,
+    };
+    match bluetooth_adapter_count().await {
+        Ok(0) => (missing, "no adapter found".to_owned()),
+        Ok(count) => (Outcome::Pass, format!("{count} adapter(s) found")),
+        Err(err) => (missing, err.to_string()),
+    }
+}
+
+/// Answers one request over an ephemeral socket, using the same framing as the daemon.
+async fn check_loopback() -> (Outcome, String) {
+    async fn loopback() -> Result<DaemonResponse, DaemonError> {
+        let path = std::env::temp_dir().join(format!("v5d-self-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        let server = spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let mut stream = BufReader::new(stream);
+            read_request(&mut stream).await?;
+            write_response(&mut stream, &DaemonResponse::BasicAck { successful: true }).await
+        });
+
+        let mut client = BufReader::new(UnixStream::connect(&path).await?);
+        let _ = std::fs::remove_file(&path);
+        send_command(&mut client, DaemonCommand::ListDevices).await?;
+        let response = get_response(&mut client).await?;
+        server.await.map_err(std::io::Error::from)??;
+        Ok(response)
+    }
+
+    match loopback().await {
+        Ok(DaemonResponse::BasicAck { successful: true }) => (
+            Outcome::Pass,
+            "request and response round-tripped".to_owned(),
+        ),
+        Ok(response) => (Outcome::Fail, format!("unexpected response {response:?}")),
+        Err(err) => (Outcome::Fail, err.to_string()),
+    }
+}
+
+/// Runs every check and prints a report. Returns whether there were no hard failures.
+///
+/// There is no data directory or config file yet, so neither is checked.
+pub async fn self_test(connection_type: Option<ConnectionType>, json: bool) -> bool {
+    let mut checks = Vec::new();
+    let mut record = |name, (outcome, detail)| {
+        checks.push(Check {
+            name,
+            outcome,
+            detail,
+        })
+    };
+    record("socket", check_socket());
+    record("serial", check_serial());
+    record("bluetooth", check_bluetooth(connection_type).await);
+    record("loopback", check_loopback().await);
+
+    let passed = checks.iter().all(|check| check.outcome != Outcome::Fail);
+    if json {
+        let checks = checks
+            .iter()
+            .map(|check| {
+                json!({
+                    "name": check.name,
+                    "outcome": check.outcome.to_string().to_lowercase(),
+                    "detail": check.detail,
+                })
+            })
+            .collect::<Vec<_>>();
+        println!("{}", json!({ "passed": passed, "checks": checks }));
+    } else {
+        for check in &checks {
+            println!("[{:4}] {:<9} {}", check.outcome, check.name, check.detail);
+        }
+        println!(
+            "{}",
+            if passed {
+                "Self-test passed"
+            } else {
+                "Self-test failed"
+            }
+        );
+    }
+    passed
+}
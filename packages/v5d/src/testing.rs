@@ -0,0 +1,605 @@
+//! Stand-ins for real devices, so the daemon can be tested without a brain plugged in.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    future::poll_fn,
+    io::{self, Read, Write},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::PathBuf,
+    ptr,
+    sync::{Arc, Mutex, MutexGuard},
+    task::Poll,
+    thread,
+    time::Duration,
+};
+
+use vex_v5_serial::{
+    commands::file::COLD_START,
+    connection::serial::SerialDevice,
+    connection::{serial::SerialError, Connection, ConnectionType},
+    crc::VEX_CRC16,
+    decode::Decode,
+    encode::Encode,
+    packets::{
+        cdc2::Cdc2Ack,
+        file::{FileExitAction, FileInitAction, FileLoadAction, FileVendor},
+    },
+    varint::VarU16,
+};
+
+/// The CDC2 extended ID that starts a file transfer.
+pub const INIT_FILE_TRANSFER: u8 = 0x11;
+/// The CDC2 extended ID that ends a file transfer.
+pub const EXIT_FILE_TRANSFER: u8 = 0x12;
+/// The CDC2 extended ID that sends one chunk of a file.
+pub const WRITE_FILE: u8 = 0x13;
+/// The CDC2 extended ID that reads one chunk of a file.
+pub const READ_FILE: u8 = 0x14;
+/// The CDC2 extended ID that runs or stops a program.
+pub const LOAD_FILE_ACTION: u8 = 0x18;
+/// The CDC2 extended ID that reads a file's metadata.
+pub const GET_FILE_METADATA: u8 = 0x19;
+/// The CDC2 extended ID that deletes a file.
+pub const ERASE_FILE: u8 = 0x1B;
+/// The CDC2 extended ID that reads the system flags, including the running program.
+pub const GET_SYSTEM_FLAGS: u8 = 0x20;
+/// The CDC2 extended ID that reads the firmware versions and the brain's unique ID.
+const GET_SYSTEM_STATUS: u8 = 0x22;
+
+/// The simple CDC ID that asks for the brain's firmware version, which is how brains are pinged.
+const GET_SYSTEM_VERSION: u8 = 0xA4;
+
+/// Where the file name starts in a file transfer's first packet.
+const TRANSFER_FILE_NAME: usize = 28;
+/// Where the file name starts in packets that name a file after its vendor and an option.
+const FILE_NAME: usize = 2;
+
+const CDC2_ID: u8 = 0x56;
+const DEVICE_BOUND_HEADER: [u8; 4] = [0xC9, 0x36, 0xB8, 0x47];
+const HOST_BOUND_HEADER: [u8; 2] = [0xAA, 0x55];
+
+/// A CDC2 packet the host sent to a fake brain.
+#[derive(Debug, Clone)]
+pub struct Packet {
+    pub ext_id: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    /// The vendor of the file the packet is about.
+    ///
+    /// Only meaningful for packets that name a file.
+    pub fn vendor(&self) -> u8 {
+        match self.ext_id {
+            INIT_FILE_TRANSFER => self.payload[2],
+            _ => self.payload[0],
+        }
+    }
+
+    /// The name of the file the packet is about.
+    ///
+    /// Only meaningful for packets that name a file.
+    pub fn file_name(&self) -> String {
+        match self.ext_id {
+            INIT_FILE_TRANSFER => string_at(&self.payload, TRANSFER_FILE_NAME),
+            _ => string_at(&self.payload, FILE_NAME),
+        }
+    }
+}
+
+/// A file stored on a fake brain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredFile {
+    pub data: Vec<u8>,
+    pub load_address: u32,
+}
+
+/// A file transfer a fake brain has open.
+struct OpenTransfer {
+    action: FileInitAction,
+    vendor: u8,
+    name: String,
+    /// The size the host said it would write.
+    size: u32,
+    file: StoredFile,
+}
+
+/// Whether a fake brain should refuse a packet.
+pub type Refusal = Box<dyn FnMut(&Packet) -> bool + Send>;
+
+/// The files a fake brain stores and the program it runs, and how it answers packets.
+///
+/// Like a real brain, it refuses to write files while a program is running. Packets it
+/// doesn't model are acknowledged without doing anything.
+#[derive(Default)]
+pub struct Brain {
+    /// Stored files, by vendor and name.
+    pub files: BTreeMap<(u8, String), StoredFile>,
+    /// The slot of the running program, numbered from 1, or 0 if nothing is running.
+    pub running_program: u8,
+    /// Decides which packets to NACK instead of handling, to simulate failures.
+    pub refuse: Option<Refusal>,
+    /// How long to take to answer each packet.
+    pub reply_delay: Duration,
+    /// Ignores every packet, like a port that went stale while the host slept.
+    pub unresponsive: bool,
+    /// The unique ID the brain reports in its system status.
+    pub unique_id: u32,
+    /// The status flags holding the brain's settings and boot mode.
+    pub boot_flags: u16,
+    transfer: Option<OpenTransfer>,
+    received: Vec<Packet>,
+}
+impl Brain {
+    /// Stores a user file as if it had been uploaded.
+    pub fn store(&mut self, name: &str, data: Vec<u8>) {
+        let file = StoredFile {
+            data,
+            load_address: COLD_START,
+        };
+        self.files
+            .insert((FileVendor::User as u8, name.to_string()), file);
+    }
+
+    /// A user file, if it's stored.
+    pub fn file(&self, name: &str) -> Option<&StoredFile> {
+        self.files.get(&(FileVendor::User as u8, name.to_string()))
+    }
+
+    /// Replies to a simple CDC packet. Only version queries are answered.
+    fn answer_simple(&self, id: u8) -> Option<Vec<u8>> {
+        // Firmware 1.1.0, from a brain with no flags set
+        let body = [1, 1, 0, 0, 0, 0x10, 0];
+        (id == GET_SYSTEM_VERSION).then(|| {
+            let mut reply = HOST_BOUND_HEADER.to_vec();
+            reply.extend([id, body.len() as u8]);
+            reply.extend(body);
+            reply
+        })
+    }
+
+    /// Handles `packet`, returning the reply.
+    fn answer(&mut self, packet: Packet) -> Vec<u8> {
+        let refused = self.refuse.as_mut().is_some_and(|refuse| refuse(&packet));
+        let result = if refused {
+            Err(Cdc2Ack::Nack)
+        } else {
+            self.handle(&packet)
+        };
+        let ext_id = packet.ext_id;
+        self.received.push(packet);
+        match result {
+            // Read chunks are the one reply without an acknowledgement
+            Ok(data) if ext_id == READ_FILE => reply([&[ext_id][..], &data].concat()),
+            Ok(data) => reply([&[ext_id, Cdc2Ack::Ack as u8][..], &data].concat()),
+            Err(nack) => {
+                // vex-v5-serial decodes a NACK's payload like an acknowledgement's, so give it
+                // enough bytes to decode. A file's metadata decodes as missing from a zero vendor
+                let padding = match ext_id {
+                    INIT_FILE_TRANSFER => 10,
+                    GET_SYSTEM_FLAGS => 7,
+                    GET_FILE_METADATA => 1,
+                    _ => 0,
+                };
+                let mut body = vec![ext_id, nack as u8];
+                body.resize(body.len() + padding, 0);
+                reply(body)
+            }
+        }
+    }
+
+    /// Carries out `packet`, returning the payload of its reply.
+    fn handle(&mut self, packet: &Packet) -> Result<Vec<u8>, Cdc2Ack> {
+        let payload = &packet.payload;
+        match packet.ext_id {
+            INIT_FILE_TRANSFER => {
+                let action = if payload[0] == FileInitAction::Write as u8 {
+                    FileInitAction::Write
+                } else {
+                    FileInitAction::Read
+                };
+                let (vendor, name) = (packet.vendor(), packet.file_name());
+                let file = match action {
+                    FileInitAction::Write if self.running_program != 0 => {
+                        return Err(Cdc2Ack::Nack)
+                    }
+                    FileInitAction::Write => StoredFile {
+                        data: Vec::new(),
+                        load_address: u32_at(payload, 8),
+                    },
+                    FileInitAction::Read => self
+                        .files
+                        .get(&(vendor, name.clone()))
+                        .cloned()
+                        .ok_or(Cdc2Ack::Nack)?,
+                };
+                let size = match action {
+                    FileInitAction::Write => u32_at(payload, 4),
+                    FileInitAction::Read => file.data.len() as u32,
+                };
+                self.transfer = Some(OpenTransfer {
+                    action,
+                    vendor,
+                    name,
+                    size,
+                    file,
+                });
+                // A window of 0 lets the host pick its chunk size. The CRC isn't checked
+                let mut reply = 0u16.to_le_bytes().to_vec();
+                reply.extend(size.to_le_bytes());
+                reply.extend([0; 4]);
+                Ok(reply)
+            }
+            WRITE_FILE => {
+                let transfer = self.transfer.as_mut().ok_or(Cdc2Ack::Nack)?;
+                let offset = u32_at(payload, 0)
+                    .checked_sub(transfer.file.load_address)
+                    .ok_or(Cdc2Ack::Nack)? as usize;
+                let chunk = &payload[4..];
+                let data = &mut transfer.file.data;
+                data.resize(data.len().max(offset + chunk.len()), 0);
+                data[offset..offset + chunk.len()].copy_from_slice(chunk);
+                Ok(Vec::new())
+            }
+            READ_FILE => {
+                let transfer = self.transfer.as_ref().ok_or(Cdc2Ack::Nack)?;
+                let address = u32_at(payload, 0);
+                let size = u16::from_le_bytes([payload[4], payload[5]]) as usize;
+                let offset = address
+                    .checked_sub(transfer.file.load_address)
+                    .ok_or(Cdc2Ack::Nack)? as usize;
+                let mut chunk = transfer
+                    .file
+                    .data
+                    .get(offset..)
+                    .unwrap_or_default()
+                    .to_vec();
+                chunk.resize(size, 0);
+                Ok([&address.to_le_bytes()[..], &chunk].concat())
+            }
+            EXIT_FILE_TRANSFER => {
+                let mut transfer = self.transfer.take().ok_or(Cdc2Ack::Nack)?;
+                if let FileInitAction::Write = transfer.action {
+                    // Chunks are padded, so the last can run past the end of the file
+                    transfer.file.data.truncate(transfer.size as usize);
+                    self.files
+                        .insert((transfer.vendor, transfer.name.clone()), transfer.file);
+                }
+                if payload[0] == FileExitAction::RunProgram as u8 {
+                    self.running_program = slot_of(&transfer.name);
+                }
+                Ok(Vec::new())
+            }
+            GET_FILE_METADATA => {
+                let file = self
+                    .files
+                    .get(&(packet.vendor(), packet.file_name()))
+                    .ok_or(Cdc2Ack::Nack)?;
+                let mut reply = vec![FileVendor::User as u8];
+                reply.extend((file.data.len() as u32).to_le_bytes());
+                reply.extend(file.load_address.to_le_bytes());
+                // CRC, file type, timestamp and version
+                reply.extend([0; 4]);
+                let extension = packet.file_name().rsplit('.').next().unwrap().to_string();
+                reply.extend(format!("{extension:\0<3}\0").bytes());
+                reply.extend([0; 8]);
+                Ok(reply)
+            }
+            ERASE_FILE => {
+                let key = (packet.vendor(), packet.file_name());
+                self.files.remove(&key).ok_or(Cdc2Ack::Nack)?;
+                Ok(Vec::new())
+            }
+            LOAD_FILE_ACTION => {
+                self.running_program = if payload[1] == FileLoadAction::Stop as u8 {
+                    0
+                } else {
+                    slot_of(&packet.file_name())
+                };
+                Ok(Vec::new())
+            }
+            GET_SYSTEM_FLAGS => Ok(vec![0, 0, 0, 0, 0, 0, self.running_program]),
+            GET_SYSTEM_STATUS => {
+                // The versions, which nothing checks
+                let mut reply = vec![0; 17];
+                reply.extend(self.unique_id.to_le_bytes());
+                // The other two sets of flags
+                reply.extend([0; 4]);
+                reply.extend(self.boot_flags.to_le_bytes());
+                // Two unknown bytes, and the golden image's and NXP chip's versions
+                reply.extend([0; 10]);
+                Ok(reply)
+            }
+            _ => Ok(Vec::new()),
+        }
+    }
+}
+
+/// The slot, numbered from 1, that a program file like `slot0.bin` belongs to.
+fn slot_of(file_name: &str) -> u8 {
+    file_name
+        .strip_prefix("slot")
+        .and_then(|rest| rest.split(['.', '_']).next())
+        .and_then(|slot| slot.parse::<u8>().ok())
+        .map_or(0, |slot| slot + 1)
+}
+
+fn u32_at(payload: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(payload[offset..offset + 4].try_into().unwrap())
+}
+
+/// The null-terminated string at `offset`.
+fn string_at(payload: &[u8], offset: usize) -> String {
+    let bytes = &payload[offset..];
+    let end = bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+/// A fake [`Brain`] reached through a pair of ptys.
+///
+/// The system port gets the packets; nothing is ever written to the user port.
+pub struct PtyBrain {
+    system_port: String,
+    user_port: String,
+    brain: Arc<Mutex<Brain>>,
+    // The user port hangs up if its controlling side is closed, even though nothing uses it
+    _user_controller: File,
+    // And the ptys hang up once every handle to their device side is closed, so keep one
+    // open until the brain is dropped
+    _devices: [OwnedFd; 2],
+}
+impl PtyBrain {
+    /// A brain with no files and nothing running.
+    pub fn spawn() -> io::Result<Self> {
+        Self::spawn_with(Brain::default())
+    }
+
+    pub fn spawn_with(brain: Brain) -> io::Result<Self> {
+        let (system, system_device, system_port) = open_pty()?;
+        let (user, user_device, user_port) = open_pty()?;
+        let brain = Arc::new(Mutex::new(brain));
+        thread::spawn({
+            let brain = brain.clone();
+            move || answer_packets(system, &brain)
+        });
+        Ok(Self {
+            system_port,
+            user_port,
+            brain,
+            _user_controller: user,
+            _devices: [system_device, user_device],
+        })
+    }
+
+    pub fn device(&self) -> SerialDevice {
+        SerialDevice::Brain {
+            user_port: self.user_port.clone(),
+            system_port: self.system_port.clone(),
+        }
+    }
+
+    /// The brain's state, which can be changed between commands.
+    pub fn brain(&self) -> MutexGuard<'_, Brain> {
+        self.brain.lock().unwrap()
+    }
+
+    /// Every CDC2 packet received so far.
+    pub fn received(&self) -> Vec<Packet> {
+        self.brain().received.clone()
+    }
+
+    /// Whether a file transfer was started for a file named `name`.
+    pub fn started_transfer_of(&self, name: &str) -> bool {
+        self.received().iter().any(|packet| {
+            packet.ext_id == INIT_FILE_TRANSFER
+                && packet
+                    .payload
+                    .windows(name.len())
+                    .any(|window| window == name.as_bytes())
+        })
+    }
+}
+
+/// Opens a pty, returning its controlling side, its device side and the device's path.
+fn open_pty() -> io::Result<(File, OwnedFd, String)> {
+    let (mut controller, mut device) = (0, 0);
+    // SAFETY: both pointers are valid for writes, and the optional arguments may be null.
+    let result = unsafe {
+        libc::openpty(
+            &mut controller,
+            &mut device,
+            ptr::null_mut(),
+            ptr::null(),
+            ptr::null(),
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `openpty` succeeded, so both are open descriptors that nothing else owns.
+    let (controller, device) =
+        unsafe { (File::from_raw_fd(controller), OwnedFd::from_raw_fd(device)) };
+    let path = pty_path(&device);
+    Ok((controller, device, path))
+}
+
+/// A path that opens the pty's device side.
+///
+/// Goes through `/proc` since the pty isn't always visible in `/dev/pts`, like in containers.
+fn pty_path(device: &OwnedFd) -> String {
+    format!("/proc/{}/fd/{}", std::process::id(), device.as_raw_fd())
+}
+
+/// Answers packets until the host side of the pty goes away.
+fn answer_packets(mut port: File, brain: &Mutex<Brain>) {
+    while let Ok(packet) = read_packet(&mut port) {
+        let (reply, delay) = {
+            let mut brain = brain.lock().unwrap();
+            if brain.unresponsive {
+                continue;
+            }
+            let reply = match packet {
+                Incoming::Simple(id) => brain.answer_simple(id),
+                Incoming::Cdc2(packet) => Some(brain.answer(packet)),
+            };
+            let Some(reply) = reply else {
+                continue;
+            };
+            (reply, brain.reply_delay)
+        };
+        thread::sleep(delay);
+        if port.write_all(&reply).is_err() {
+            break;
+        }
+    }
+}
+
+/// A device-bound packet.
+enum Incoming {
+    /// A simple CDC packet, by its ID.
+    Simple(u8),
+    Cdc2(Packet),
+}
+
+/// Reads one device-bound packet.
+fn read_packet(port: &mut impl Read) -> io::Result<Incoming> {
+    let mut matched = 0;
+    while matched < DEVICE_BOUND_HEADER.len() {
+        let byte = read_u8(port)?;
+        matched = if byte == DEVICE_BOUND_HEADER[matched] {
+            matched + 1
+        } else {
+            usize::from(byte == DEVICE_BOUND_HEADER[0])
+        };
+    }
+    let id = read_u8(port)?;
+    if id != CDC2_ID {
+        // The only simple packets the daemon sends have no payload
+        return Ok(Incoming::Simple(id));
+    }
+    let ext_id = read_u8(port)?;
+    let first = read_u8(port)?;
+    let size = if first & 0x80 != 0 {
+        u16::from_be_bytes([first & 0x7F, read_u8(port)?])
+    } else {
+        u16::from(first)
+    };
+    let mut payload = vec![0; usize::from(size)];
+    port.read_exact(&mut payload)?;
+    // The CRC
+    port.read_exact(&mut [0; 2])?;
+    Ok(Incoming::Cdc2(Packet { ext_id, payload }))
+}
+
+fn read_u8(port: &mut impl Read) -> io::Result<u8> {
+    let mut byte = [0];
+    port.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+/// The reply a brain that accepts everything sends to `packet`.
+fn ack(packet: &Packet) -> Vec<u8> {
+    let mut body = vec![packet.ext_id, Cdc2Ack::Ack as u8];
+    if packet.ext_id == INIT_FILE_TRANSFER {
+        // Window size, file size and CRC. A window of 0 lets the host pick its chunk size
+        body.extend([0; 10]);
+    }
+    reply(body)
+}
+
+/// A host-bound packet carrying `body`.
+fn reply(body: Vec<u8>) -> Vec<u8> {
+    let mut reply = HOST_BOUND_HEADER.to_vec();
+    reply.push(CDC2_ID);
+    // The size covers the CRC too
+    reply.extend(VarU16::new(body.len() as u16 + 2).encode().unwrap());
+    reply.extend(body);
+    reply.extend(VEX_CRC16.checksum(&reply).to_be_bytes());
+    reply
+}
+
+/// A connection to a brain that acknowledges every CDC2 packet, where every send and receive
+/// takes one extra poll.
+///
+/// Polling a command over it with a waker that does nothing advances the command by one
+/// await point per poll, so a test can drop the command at each of them in turn.
+#[derive(Debug, Default)]
+pub struct MockConnection {
+    /// Replies not yet received. Like the real connections, a reply waits here until a
+    /// receive asks for its packet type.
+    incoming: Vec<Vec<u8>>,
+    transfer_open: bool,
+}
+impl MockConnection {
+    /// Whether the brain has a file transfer open.
+    pub fn transfer_open(&self) -> bool {
+        self.transfer_open
+    }
+}
+impl Connection for MockConnection {
+    type Error = SerialError;
+
+    fn connection_type(&self) -> ConnectionType {
+        ConnectionType::Wired
+    }
+
+    async fn send_packet(&mut self, packet: impl Encode) -> Result<(), SerialError> {
+        yield_once().await;
+        let Incoming::Cdc2(packet) = read_packet(&mut packet.encode()?.as_slice())? else {
+            return Ok(());
+        };
+        match packet.ext_id {
+            INIT_FILE_TRANSFER => self.transfer_open = true,
+            EXIT_FILE_TRANSFER => self.transfer_open = false,
+            _ => {}
+        }
+        self.incoming.push(ack(&packet));
+        Ok(())
+    }
+
+    async fn receive_packet<P: Decode>(&mut self, _timeout: Duration) -> Result<P, SerialError> {
+        yield_once().await;
+        let position = self
+            .incoming
+            .iter()
+            .position(|reply| P::decode(reply.clone()).is_ok())
+            .ok_or(SerialError::Timeout)?;
+        Ok(P::decode(self.incoming.remove(position))?)
+    }
+
+    async fn read_user(&mut self, _buf: &mut [u8]) -> Result<usize, SerialError> {
+        yield_once().await;
+        Ok(0)
+    }
+
+    async fn write_user(&mut self, buf: &[u8]) -> Result<usize, SerialError> {
+        yield_once().await;
+        Ok(buf.len())
+    }
+}
+
+/// Returns pending once, asking to be polled again straight away.
+async fn yield_once() {
+    let mut yielded = false;
+    poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+    .await
+}
+
+/// A directory for one test's files, emptied before the test uses it.
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("v5d-test-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}